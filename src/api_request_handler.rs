@@ -1,7 +1,16 @@
+use crate::api_schema;
+use crate::client_ip::TrustedProxies;
+use crate::ingestion_queue::IngestionQueue;
 use crate::nip98_auth;
-use crate::notification_manager::notification_manager::UserNotificationSettings;
-use crate::relay_connection::RelayConnection;
+use crate::notification_manager::notification_manager::{DeviceMetadata, DeviceRegistration, HashtagSubscription, UserNotificationSettings, WalCheckpointMode, MAX_HASHTAG_SUBSCRIPTIONS};
+use crate::notification_manager::push_payload;
+use crate::notification_manager::webhook_client::Webhook;
+use crate::notification_manager::push_provider::TokenType;
+use crate::event_rate_limiter::EventRateLimiter;
+use crate::relay_connection::{RelayConnection, RelayPolicy};
 use http_body_util::Full;
+use nostr::bitcoin::hashes::sha256::Hash as Sha256Hash;
+use nostr::bitcoin::hashes::Hash;
 use hyper::body::Buf;
 use hyper::body::Bytes;
 use hyper::body::Incoming;
@@ -16,20 +25,55 @@ use crate::notification_manager::NotificationManager;
 use hyper::Method;
 use log;
 use serde_json::{json, Value};
-use std::collections::HashMap;
+use std::collections::{HashMap, HashSet};
+use std::io::{Read, Write};
+use std::net::IpAddr;
+use std::path::{Component, Path, PathBuf};
 use std::sync::Arc;
 use thiserror::Error;
 
+// The time window covered by the admin stats
+const ADMIN_STATS_WINDOW_SECONDS: u64 = 24 * 60 * 60;
+// How far back the daily delivery summaries in the admin stats go
+const ADMIN_STATS_DAILY_SUMMARY_RETENTION_SECONDS: u64 = 30 * 24 * 60 * 60;
+// The maximum size of a decompressed request body, so that a small compressed body cannot exhaust memory
+const MAX_DECOMPRESSED_BODY_SIZE: u64 = 1024 * 1024;
+// Smaller responses are sent uncompressed, since compressing them saves next to nothing
+const MIN_COMPRESSED_RESPONSE_SIZE: usize = 1024;
+// The maximum size of a relay websocket message and frame. Nostr events are well below this
+const MAX_WEBSOCKET_MESSAGE_SIZE: usize = 1024 * 1024;
+const MAX_WEBSOCKET_FRAME_SIZE: usize = 256 * 1024;
+// The maximum number of devices in a batch registration
+const MAX_BATCH_REGISTRATIONS: usize = 50;
+// The page size of the audit log, by default and at most
+const DEFAULT_AUDIT_LOG_PAGE_SIZE: usize = 100;
+const MAX_AUDIT_LOG_PAGE_SIZE: usize = 1000;
+// The maximum length of a client-provided `X-Request-Id`. Longer ones are replaced with a generated ID
+const MAX_REQUEST_ID_LENGTH: usize = 128;
+
 pub struct APIHandler {
     notification_manager: Arc<NotificationManager>,
     base_url: String,
+    admin_pubkeys: HashSet<nostr::PublicKey>,
+    relay_policy: RelayPolicy,
+    ingestion_queue: Arc<IngestionQueue>,
+    event_rate_limiter: Arc<EventRateLimiter>,
+    // The directory database backups are written to. Backups are disabled if unset
+    db_backup_dir: Option<PathBuf>,
+    trusted_proxies: TrustedProxies,
 }
 
 impl APIHandler {
-    pub fn new(notification_manager: Arc<NotificationManager>, base_url: String) -> Self {
+    pub fn new(notification_manager: Arc<NotificationManager>, base_url: String, admin_pubkeys: HashSet<nostr::PublicKey>, relay_policy: RelayPolicy, ingestion_queue: Arc<IngestionQueue>, event_rate_limiter: Arc<EventRateLimiter>, db_backup_dir: Option<PathBuf>, trusted_proxies: TrustedProxies) -> Self {
         APIHandler {
             notification_manager,
             base_url,
+            admin_pubkeys,
+            relay_policy,
+            ingestion_queue,
+            event_rate_limiter,
+            db_backup_dir,
+            trusted_proxies,
         }
     }
     
@@ -38,10 +82,13 @@ impl APIHandler {
     pub async fn handle_http_request(
         &self,
         req: Request<Incoming>,
+        peer_ip: IpAddr,
     ) -> Result<Response<Full<Bytes>>, hyper::http::Error> {
+        let client_ip = self.trusted_proxies.client_ip(peer_ip, req.headers());
+
         // Check if the request is a websocket upgrade request.
         if hyper_tungstenite::is_upgrade_request(&req) {
-            return match self.handle_websocket_upgrade(req).await {
+            return match self.handle_websocket_upgrade(req, client_ip).await {
                 Ok(response) => Ok(response),
                 Err(err) => {
                     log::error!("Error handling websocket upgrade request: {}", err);
@@ -54,8 +101,45 @@ impl APIHandler {
             };
         }
 
+        let response_encoding = ResponseEncoding::negotiate(&req);
+
+        // Readiness checks are answered without authentication, so that load balancers can probe them
+        if req.method() == Method::GET && req.uri().path() == "/readyz" {
+            return Self::build_http_response(self.handle_readiness_check().await, response_encoding, None);
+        }
+
+        // The API description is public, so that client bindings can be generated from it
+        if req.method() == Method::GET && req.uri().path() == "/openapi.json" {
+            return Self::build_http_response(APIResponse {
+                status: StatusCode::OK,
+                body: api_schema::openapi_document(&self.base_url),
+            }, response_encoding, None);
+        }
+
+        // Capabilities are public, so that clients can build their settings screens before signing in
+        if req.method() == Method::GET && req.uri().path() == "/capabilities" {
+            return Self::build_http_response(APIResponse {
+                status: StatusCode::OK,
+                body: json!(self.notification_manager.capabilities()),
+            }, response_encoding, None);
+        }
+
+        // Metrics are answered without authentication, so that Prometheus can scrape them
+        if req.method() == Method::GET && req.uri().path() == "/metrics" {
+            return Ok(Response::builder()
+                .header("Content-Type", "text/plain; version=0.0.4")
+                .status(StatusCode::OK)
+                .body(http_body_util::Full::new(Bytes::from(
+                    self.notification_manager.render_metrics(),
+                )))?);
+        }
+
+        // Clients poll settings and lists, so answer unchanged GET responses with 304 Not Modified
+        let is_get_request = req.method() == Method::GET;
+        let if_none_match = req.headers().get(hyper::header::IF_NONE_MATCH).and_then(|value| value.to_str().ok()).map(|value| value.to_string());
+
         // If not, handle the request as a normal API request.
-        let final_api_response: APIResponse = match self.try_to_handle_http_request(req).await {
+        let final_api_response: APIResponse = match self.try_to_handle_http_request(req, client_ip).await {
             Ok(api_response) => APIResponse {
                 status: api_response.status,
                 body: api_response.body,
@@ -68,12 +152,33 @@ impl APIHandler {
                             status: StatusCode::UNAUTHORIZED,
                             body: json!({ "error": "Unauthorized", "message": message }),
                         },
+                        APIError::InsufficientScope(method) => APIResponse {
+                            status: StatusCode::FORBIDDEN,
+                            body: json!({ "error": "Forbidden", "message": format!("The authorization is read-only and cannot be used for {} requests", method) }),
+                        },
+                        APIError::UnsupportedContentEncoding(encoding) => APIResponse {
+                            status: StatusCode::UNSUPPORTED_MEDIA_TYPE,
+                            body: json!({ "error": "Unsupported Content-Encoding", "message": format!("Only gzip is supported, got: {}", encoding) }),
+                        },
+                        APIError::InvalidCompressedBody(message) => APIResponse {
+                            status: StatusCode::BAD_REQUEST,
+                            body: json!({ "error": "Invalid compressed body", "message": message }),
+                        },
+                        APIError::UnsupportedContentType(content_type) => APIResponse {
+                            status: StatusCode::UNSUPPORTED_MEDIA_TYPE,
+                            body: json!({ "error": "Unsupported Content-Type", "message": format!("Request bodies must be application/json, got: {}", content_type) }),
+                        },
+                        APIError::InvalidJsonBody { message, line, column } => APIResponse {
+                            status: StatusCode::BAD_REQUEST,
+                            body: json!({ "error": "Invalid JSON body", "message": message, "line": line, "column": column }),
+                        },
                     }
                 } else {
                     // Otherwise, return a 500 status code
                     let random_case_uuid = uuid::Uuid::new_v4();
                     log::error!(
-                        "Error handling request: {} (Case ID: {})",
+                        "Error handling request from {}: {} (Case ID: {})",
+                        client_ip,
                         err,
                         random_case_uuid
                     );
@@ -85,25 +190,67 @@ impl APIHandler {
             }
         };
 
-        Ok(Response::builder()
+        let etag = match is_get_request && final_api_response.status == StatusCode::OK {
+            true => Some(weak_etag(&final_api_response.body)),
+            false => None,
+        };
+        if let (Some(etag), Some(if_none_match)) = (&etag, &if_none_match) {
+            if etag_matches(if_none_match, etag) {
+                return not_modified_response(etag);
+            }
+        }
+
+        Self::build_http_response(final_api_response, response_encoding, etag.as_deref())
+    }
+
+    fn build_http_response(
+        api_response: APIResponse,
+        response_encoding: Option<ResponseEncoding>,
+        etag: Option<&str>,
+    ) -> Result<Response<Full<Bytes>>, hyper::http::Error> {
+        let body = api_response.body.to_string().into_bytes();
+        let mut response_builder = Response::builder()
             .header("Content-Type", "application/json")
             .header("Access-Control-Allow-Origin", "*")
-            .status(final_api_response.status)
-            .body(http_body_util::Full::new(Bytes::from(
-                final_api_response.body.to_string(),
-            )))?)
+            .header("Vary", "Accept-Encoding")
+            .status(api_response.status);
+        if let Some(etag) = etag {
+            response_builder = response_builder.header("ETag", etag);
+        }
+        let (response_builder, body) = match response_encoding {
+            Some(response_encoding) if body.len() >= MIN_COMPRESSED_RESPONSE_SIZE => match response_encoding.encode(&body) {
+                Ok(compressed_body) => (response_builder.header("Content-Encoding", response_encoding.as_header_value()), compressed_body),
+                Err(e) => {
+                    log::warn!("Failed to compress response, sending it uncompressed: {}", e);
+                    (response_builder, body)
+                }
+            },
+            _ => (response_builder, body),
+        };
+        Ok(response_builder.body(http_body_util::Full::new(Bytes::from(body)))?)
     }
 
     async fn handle_websocket_upgrade(
         &self,
         mut req: Request<Incoming>,
+        client_ip: IpAddr,
     ) -> Result<Response<Full<Bytes>>, Box<dyn std::error::Error>> {
-        let (response, websocket) = hyper_tungstenite::upgrade(&mut req, None)?;
-        log::info!("New websocket connection.");
+        // Bound the memory a single connection can take up.
+        // permessage-deflate is not negotiated: tungstenite rejects frames with the compression bit set, so clients asking for it get uncompressed frames
+        let websocket_config = hyper_tungstenite::tungstenite::protocol::WebSocketConfig {
+            max_message_size: Some(MAX_WEBSOCKET_MESSAGE_SIZE),
+            max_frame_size: Some(MAX_WEBSOCKET_FRAME_SIZE),
+            ..Default::default()
+        };
+        let (response, websocket) = hyper_tungstenite::upgrade(&mut req, Some(websocket_config))?;
+        log::info!("New websocket connection from {}", client_ip);
 
         let new_notification_manager = self.notification_manager.clone();
+        let ingestion_queue = self.ingestion_queue.clone();
+        let relay_policy = self.relay_policy.clone();
+        let event_rate_limiter = self.event_rate_limiter.clone();
         tokio::spawn(async move {
-            match RelayConnection::run(websocket, new_notification_manager).await {
+            match RelayConnection::run(websocket, new_notification_manager, ingestion_queue, relay_policy, event_rate_limiter).await {
                 Ok(_) => {}
                 Err(e) => {
                     log::error!("Error with websocket connection: {:?}", e);
@@ -117,49 +264,140 @@ impl APIHandler {
     async fn try_to_handle_http_request(
         &self,
         mut req: Request<Incoming>,
+        client_ip: IpAddr,
     ) -> Result<APIResponse, Box<dyn std::error::Error>> {
         let parsed_request = self.parse_http_request(&mut req).await?;
-        let api_response: APIResponse = self.handle_parsed_http_request(&parsed_request).await?;
+        let api_response = self.handle_parsed_http_request(&parsed_request).await;
+        if Self::is_audited_request(&parsed_request) {
+            let status = api_response.as_ref().map_or(StatusCode::INTERNAL_SERVER_ERROR, |api_response| api_response.status);
+            self.record_audit_log_entry(&parsed_request, status).await;
+        }
+        let api_response: APIResponse = api_response?;
         log::info!(
-            "[{}] {} (Authorized pubkey: {}): {}",
+            "[{}] {} (Authorized pubkey: {}, client IP: {}, request ID: {}): {}",
             req.method(),
             req.uri(),
             parsed_request.authorized_pubkey,
+            client_ip,
+            parsed_request.request_id,
             api_response.status
         );
         Ok(api_response)
     }
 
+    // MARK: - Audit log
+
+    /// Whether a request is recorded in the audit log: every admin API request, and the user actions that delete data
+    fn is_audited_request(parsed_request: &ParsedRequest) -> bool {
+        parsed_request.uri.starts_with("/admin/")
+            || route_match(&Method::DELETE, "/user-info/:pubkey/:deviceToken", parsed_request).is_some()
+            || route_match(&Method::POST, "/user-info/:pubkey/:deviceToken/switch", parsed_request).is_some()
+            || route_match(&Method::POST, "/devices/:deviceToken/pubkeys/unbind", parsed_request).is_some()
+    }
+
+    async fn record_audit_log_entry(&self, parsed_request: &ParsedRequest, status: StatusCode) {
+        // The action has already happened by now, so a failure to record it is logged instead of failing the request
+        if let Err(e) = self
+            .notification_manager
+            .record_audit_log_entry(
+                &parsed_request.authorized_pubkey,
+                parsed_request.method.as_str(),
+                &parsed_request.uri,
+                status.as_u16(),
+                &parsed_request.request_id,
+            )
+            .await
+        {
+            log::error!("Failed to record audit log entry for request {}: {}", parsed_request.request_id, e);
+        }
+    }
+
     async fn parse_http_request(
         &self,
         req: &mut Request<Incoming>,
     ) -> Result<ParsedRequest, Box<dyn std::error::Error>> {
-        // 1. Read the request body
+        // 1. Read the request body, decompressing it if needed. NIP-98 hashes the decompressed payload
         let body_buffer = req.body_mut().collect().await?.aggregate();
-        let body_bytes = body_buffer.chunk();
+        let body_bytes = Self::decode_body(req, body_buffer.chunk())?;
         let body_bytes = if body_bytes.is_empty() {
             None
         } else {
-            Some(body_bytes)
+            Some(body_bytes.as_slice())
         };
 
         // 2. NIP-98 authentication
         let authorized_pubkey = match self.authenticate(&req, body_bytes).await? {
-            Ok(pubkey) => pubkey,
+            Ok((pubkey, scope)) if scope.allows_method(req.method()) => pubkey,
+            Ok(_) => {
+                return Err(Box::new(APIError::InsufficientScope(req.method().to_string())));
+            }
             Err(auth_error) => {
                 return Err(Box::new(APIError::AuthenticationError(auth_error)));
             }
         };
 
-        // 3. Parse the request
+        // 3. Only JSON bodies are accepted on requests that carry one
+        if body_bytes.is_some() && [Method::POST, Method::PUT, Method::PATCH].contains(req.method()) {
+            Self::check_content_type(req)?;
+        }
+
+        // 4. Parse the request
         Ok(ParsedRequest {
             uri: req.uri().path().to_string(),
+            query: req.uri().query().map(|query| query.to_string()),
             method: req.method().clone(),
             body_bytes: body_bytes.map(|b| b.to_vec()),
             authorized_pubkey,
+            request_id: Self::request_id(req),
         })
     }
     
+    /// The `X-Request-Id` the client or a proxy sent, so that audit log entries can be correlated with their logs, or a new ID otherwise
+    fn request_id(req: &Request<Incoming>) -> String {
+        match req.headers().get("x-request-id").and_then(|value| value.to_str().ok()).map(|value| value.trim()) {
+            Some(request_id) if !request_id.is_empty() && request_id.len() <= MAX_REQUEST_ID_LENGTH => request_id.to_string(),
+            _ => uuid::Uuid::new_v4().to_string(),
+        }
+    }
+    
+    /// Checks that the request body is declared as JSON. Parameters such as `charset` are ignored
+    fn check_content_type(req: &Request<Incoming>) -> Result<(), APIError> {
+        let content_type = req
+            .headers()
+            .get(hyper::header::CONTENT_TYPE)
+            .map(|content_type| content_type.to_str().unwrap_or_default().to_string())
+            .unwrap_or_default();
+        let media_type = content_type.split(';').next().unwrap_or_default().trim().to_ascii_lowercase();
+        match media_type.as_str() {
+            "application/json" => Ok(()),
+            "" => Err(APIError::UnsupportedContentType("none".to_string())),
+            _ => Err(APIError::UnsupportedContentType(content_type)),
+        }
+    }
+
+    /// Decodes the request body according to its `Content-Encoding` header
+    fn decode_body(req: &Request<Incoming>, body_bytes: &[u8]) -> Result<Vec<u8>, APIError> {
+        let content_encoding = match req.headers().get(hyper::header::CONTENT_ENCODING) {
+            Some(content_encoding) => content_encoding.to_str().unwrap_or_default().trim().to_ascii_lowercase(),
+            None => return Ok(body_bytes.to_vec()),
+        };
+        match content_encoding.as_str() {
+            "" | "identity" => Ok(body_bytes.to_vec()),
+            "gzip" | "x-gzip" => {
+                let mut decompressed_body = Vec::new();
+                flate2::read::GzDecoder::new(body_bytes)
+                    .take(MAX_DECOMPRESSED_BODY_SIZE + 1)
+                    .read_to_end(&mut decompressed_body)
+                    .map_err(|e| APIError::InvalidCompressedBody(e.to_string()))?;
+                if decompressed_body.len() as u64 > MAX_DECOMPRESSED_BODY_SIZE {
+                    return Err(APIError::InvalidCompressedBody(format!("Decompressed body exceeds {} bytes", MAX_DECOMPRESSED_BODY_SIZE)));
+                }
+                Ok(decompressed_body)
+            }
+            _ => Err(APIError::UnsupportedContentEncoding(content_encoding)),
+        }
+    }
+    
     // MARK: - Router
 
     async fn handle_parsed_http_request(
@@ -167,6 +405,22 @@ impl APIHandler {
         parsed_request: &ParsedRequest,
     ) -> Result<APIResponse, Box<dyn std::error::Error>> {
         
+        if let Some(url_params) = route_match(&Method::POST, "/user-info/:pubkey/batch", &parsed_request) {
+            return self.handle_user_info_batch(parsed_request, &url_params).await;
+        }
+        
+        if let Some(url_params) = route_match(&Method::GET, "/accounts/:pubkey/overview", &parsed_request) {
+            return self.get_account_overview(parsed_request, &url_params).await;
+        }
+        
+        if let Some(url_params) = route_match(&Method::GET, "/user-info/:pubkey/hashtags", &parsed_request) {
+            return self.get_hashtag_subscriptions(parsed_request, &url_params).await;
+        }
+        
+        if let Some(url_params) = route_match(&Method::PUT, "/user-info/:pubkey/hashtags", &parsed_request) {
+            return self.set_hashtag_subscriptions(parsed_request, &url_params).await;
+        }
+        
         if let Some(url_params) = route_match(&Method::PUT, "/user-info/:pubkey/:deviceToken", &parsed_request) {
             return self.handle_user_info(parsed_request, &url_params).await;
         }
@@ -175,6 +429,10 @@ impl APIHandler {
             return self.handle_user_info_remove(parsed_request, &url_params).await;
         }
         
+        if let Some(url_params) = route_match(&Method::POST, "/user-info/:pubkey/:deviceToken/switch", &parsed_request) {
+            return self.handle_account_switch(parsed_request, &url_params).await;
+        }
+        
         if let Some(url_params) = route_match(&Method::GET, "/user-info/:pubkey/:deviceToken/preferences", &parsed_request) {
             return self.get_user_settings(parsed_request, &url_params).await;
         }
@@ -183,6 +441,70 @@ impl APIHandler {
             return self.set_user_settings(parsed_request, &url_params).await;
         }
         
+        if let Some(url_params) = route_match(&Method::PATCH, "/user-info/:pubkey/:deviceToken/preferences", &parsed_request) {
+            return self.update_user_settings(parsed_request, &url_params).await;
+        }
+        
+        if let Some(url_params) = route_match(&Method::GET, "/devices/:deviceToken/pubkeys", &parsed_request) {
+            return self.get_device_pubkeys(parsed_request, &url_params).await;
+        }
+        
+        if let Some(url_params) = route_match(&Method::POST, "/devices/:deviceToken/pubkeys/unbind", &parsed_request) {
+            return self.unbind_device_pubkeys(parsed_request, &url_params).await;
+        }
+        
+        if let Some(url_params) = route_match(&Method::GET, "/devices/:deviceToken/linked-pubkeys", &parsed_request) {
+            return self.get_device_linked_pubkeys(parsed_request, &url_params).await;
+        }
+        
+        if let Some(url_params) = route_match(&Method::PUT, "/devices/:deviceToken/linked-pubkeys", &parsed_request) {
+            return self.set_device_linked_pubkeys(parsed_request, &url_params).await;
+        }
+        
+        if let Some(url_params) = route_match(&Method::PUT, "/live-activities/:pubkey/:activityToken", &parsed_request) {
+            return self.handle_live_activity_registration(parsed_request, &url_params).await;
+        }
+        
+        if let Some(url_params) = route_match(&Method::DELETE, "/live-activities/:pubkey/:activityToken", &parsed_request) {
+            return self.handle_live_activity_end(parsed_request, &url_params).await;
+        }
+        
+        if route_match(&Method::POST, "/diagnostics/trace", &parsed_request).is_some() {
+            return self.handle_notification_trace(parsed_request).await;
+        }
+        
+        if route_match(&Method::GET, "/admin/stats", &parsed_request).is_some() {
+            return self.get_admin_stats(parsed_request).await;
+        }
+        
+        if let Some(url_params) = route_match(&Method::GET, "/admin/users/:pubkey/debug", &parsed_request) {
+            return self.get_user_debug_info(parsed_request, &url_params).await;
+        }
+        
+        if route_match(&Method::GET, "/admin/held-events", &parsed_request).is_some() {
+            return self.get_held_events(parsed_request).await;
+        }
+        
+        if let Some(url_params) = route_match(&Method::POST, "/admin/held-events/:eventId/approve", &parsed_request) {
+            return self.handle_held_event_approval(parsed_request, &url_params).await;
+        }
+        
+        if let Some(url_params) = route_match(&Method::DELETE, "/admin/held-events/:eventId", &parsed_request) {
+            return self.handle_held_event_discard(parsed_request, &url_params).await;
+        }
+        
+        if route_match(&Method::POST, "/admin/db/backup", &parsed_request).is_some() {
+            return self.handle_db_backup(parsed_request).await;
+        }
+        
+        if route_match(&Method::POST, "/admin/db/checkpoint", &parsed_request).is_some() {
+            return self.handle_wal_checkpoint(parsed_request).await;
+        }
+        
+        if route_match(&Method::GET, "/admin/audit-log", &parsed_request).is_some() {
+            return self.get_audit_log(parsed_request).await;
+        }
+        
         Ok(APIResponse {
             status: StatusCode::NOT_FOUND,
             body: json!({ "error": "Not found" }),
@@ -195,7 +517,7 @@ impl APIHandler {
         &self,
         req: &Request<Incoming>,
         body_bytes: Option<&[u8]>,
-    ) -> Result<Result<nostr::PublicKey, String>, Box<dyn std::error::Error>> {
+    ) -> Result<Result<(nostr::PublicKey, nip98_auth::AuthScope), String>, Box<dyn std::error::Error>> {
         let auth_header = match req.headers().get("Authorization") {
             Some(header) => header,
             None => return Ok(Err("Authorization header not found".to_string())),
@@ -210,8 +532,31 @@ impl APIHandler {
         .await)
     }
     
+    fn is_admin(&self, pubkey: &nostr::PublicKey) -> bool {
+        self.admin_pubkeys.contains(pubkey)
+    }
+    
     // MARK: - Endpoint handlers
 
+    async fn handle_readiness_check(&self) -> APIResponse {
+        let relay_health = self.notification_manager.relay_health().await;
+        let status = if relay_health.is_healthy() {
+            StatusCode::OK
+        } else {
+            StatusCode::SERVICE_UNAVAILABLE
+        };
+        APIResponse {
+            status,
+            body: json!({
+                "status": if relay_health.is_healthy() { "ok" } else { "unavailable" },
+                "relays": {
+                    "connected": relay_health.connected_relays,
+                    "total": relay_health.total_relays,
+                },
+            }),
+        }
+    }
+
     async fn handle_user_info(
         &self,
         req: &ParsedRequest,
@@ -252,11 +597,156 @@ impl APIHandler {
             });
         }
         
+        // Early return if the optional `token_type` is unknown
+        let body = req.body_json()?;
+        let token_type = match body.get("token_type").map(|token_type| token_type.as_str().and_then(TokenType::parse)) {
+            None => TokenType::Apns,
+            Some(Some(token_type)) => token_type,
+            Some(None) => return Ok(APIResponse {
+                status: StatusCode::BAD_REQUEST,
+                body: json!({ "error": "Invalid token_type", "message": "token_type must be \"apns\"" }),
+            }),
+        };
+        
+        // Early return if `deviceToken` does not look like a valid token of its type
+        if !self.notification_manager.is_device_token_valid(device_token, Some(token_type)) {
+            return Ok(APIResponse {
+                status: StatusCode::BAD_REQUEST,
+                body: json!({ "error": "Invalid deviceToken", "message": "deviceToken must be a 64 character hex string" }),
+            });
+        }
+        
+        // Early return if the optional webhook is invalid
+        let webhook: Option<Webhook> = match body.get("webhook").cloned().map(from_value::<Webhook>) {
+            None => None,
+            Some(Ok(webhook)) => Some(webhook),
+            Some(Err(_)) => return Ok(APIResponse {
+                status: StatusCode::BAD_REQUEST,
+                body: json!({ "error": "Invalid webhook", "message": "webhook must have a url and a secret" }),
+            }),
+        };
+        if let Some(webhook) = &webhook {
+            if let Err(message) = webhook.validate().await {
+                return Ok(APIResponse {
+                    status: StatusCode::BAD_REQUEST,
+                    body: json!({ "error": "Invalid webhook", "message": message }),
+                });
+            }
+        }
+        
+        // Early return if the optional APNS tenant is not configured
+        let apns_tenant = body.get("tenant").and_then(|tenant| tenant.as_str());
+        if let Some(apns_tenant) = apns_tenant {
+            if !self.notification_manager.is_apns_tenant_known(apns_tenant) {
+                return Ok(APIResponse {
+                    status: StatusCode::BAD_REQUEST,
+                    body: json!({ "error": "Invalid tenant", "message": format!("Unknown tenant: {}", apns_tenant) }),
+                });
+            }
+        }
+        
+        // Proceed with the main logic after passing all checks
+        let created = self.notification_manager.save_user_device_info_if_not_present(pubkey, device_token).await?;
+        self.notification_manager.set_device_token_type(&pubkey, device_token, token_type).await?;
+        // Registering without a webhook goes back to APNS
+        self.notification_manager.set_device_webhook(&pubkey, device_token, webhook.as_ref()).await?;
+        if let Some(apns_tenant) = apns_tenant {
+            self.notification_manager.set_device_apns_tenant(&pubkey, device_token, apns_tenant).await?;
+        }
+        // Devices that do not ask for a payload version get the legacy layout, so that older app builds keep working
+        let requested_payload_version = body.get("payload_version").and_then(|version| version.as_u64()).map(|version| version.min(u32::MAX as u64) as u32);
+        let payload_version = push_payload::negotiate_payload_version(requested_payload_version);
+        self.notification_manager.set_device_payload_version(&pubkey, device_token, payload_version).await?;
+        let device_metadata: DeviceMetadata = from_value(body).unwrap_or_default();
+        if !device_metadata.is_empty() {
+            self.notification_manager.save_device_metadata(&pubkey, device_token, &device_metadata).await?;
+        }
+        if created {
+            Ok(APIResponse {
+                status: StatusCode::CREATED,
+                body: json!({ "message": "User info saved successfully", "payload_version": payload_version }),
+            })
+        } else {
+            Ok(APIResponse {
+                status: StatusCode::OK,
+                body: json!({ "message": "User info already registered", "payload_version": payload_version }),
+            })
+        }
+    }
+
+    async fn handle_user_info_batch(
+        &self,
+        req: &ParsedRequest,
+        url_params: &HashMap<&str, String>,
+    ) -> Result<APIResponse, Box<dyn std::error::Error>> {
+        // Early return if `pubkey` is missing
+        let pubkey = match url_params.get("pubkey") {
+            Some(key) => key,
+            None => return Ok(APIResponse {
+                status: StatusCode::BAD_REQUEST,
+                body: json!({ "error": "pubkey is required on the URL" }),
+            }),
+        };
+        
+        // Validate the `pubkey` and prepare it for use
+        let pubkey = match nostr::PublicKey::from_hex(pubkey) {
+            Ok(key) => key,
+            Err(_) => return Ok(APIResponse {
+                status: StatusCode::BAD_REQUEST,
+                body: json!({ "error": "Invalid pubkey" }),
+            }),
+        };
+    
+        // Early return if `pubkey` does not match `req.authorized_pubkey`
+        if pubkey != req.authorized_pubkey {
+            return Ok(APIResponse {
+                status: StatusCode::FORBIDDEN,
+                body: json!({ "error": "Forbidden" }),
+            });
+        }
+        
+        // Parse the registrations
+        let body = req.body_json()?;
+        let registrations: Vec<DeviceRegistration> = match body.get("devices").cloned().map(from_value) {
+            Some(Ok(registrations)) => registrations,
+            _ => return Ok(APIResponse {
+                status: StatusCode::BAD_REQUEST,
+                body: json!({ "error": "devices must be an array of device registrations" }),
+            }),
+        };
+        
+        // Early return if there are too many registrations to apply in one go
+        if registrations.len() > MAX_BATCH_REGISTRATIONS {
+            return Ok(APIResponse {
+                status: StatusCode::BAD_REQUEST,
+                body: json!({ "error": "Too many devices", "message": format!("At most {} devices can be registered at once", MAX_BATCH_REGISTRATIONS) }),
+            });
+        }
+        
+        // Early return if any device token does not look like a valid device token, so that nothing is saved
+        if let Some(registration) = registrations.iter().find(|registration| {
+            !self.notification_manager.is_device_token_valid(&registration.device_token, Some(registration.token_type.unwrap_or(TokenType::Apns)))
+        }) {
+            return Ok(APIResponse {
+                status: StatusCode::BAD_REQUEST,
+                body: json!({ "error": "Invalid deviceToken", "message": "deviceToken must be a 64 character hex string", "device_token": registration.device_token }),
+            });
+        }
+        
         // Proceed with the main logic after passing all checks
-        self.notification_manager.save_user_device_info_if_not_present(pubkey, device_token).await?;
+        let results = self.notification_manager.save_user_device_registrations(&pubkey, &registrations).await?;
+        let devices: Vec<Value> = registrations
+            .iter()
+            .zip(results)
+            .map(|(registration, (created, payload_version))| json!({
+                "device_token": registration.device_token,
+                "created": created,
+                "payload_version": payload_version,
+            }))
+            .collect();
         Ok(APIResponse {
             status: StatusCode::OK,
-            body: json!({ "message": "User info saved successfully" }),
+            body: json!({ "devices": devices }),
         })
     }
 
@@ -354,10 +844,10 @@ impl APIHandler {
 
         let settings: UserNotificationSettings = match from_value(body.clone()) {
             Ok(settings) => settings,
-            Err(_) => {
+            Err(e) => {
                 return Ok(APIResponse {
                     status: StatusCode::BAD_REQUEST,
-                    body: json!({ "error": "Invalid settings" }),
+                    body: json!({ "error": "Invalid settings", "message": e.to_string() }),
                 });
             }
         };
@@ -369,7 +859,8 @@ impl APIHandler {
         });
     }
     
-    async fn get_user_settings(
+    /// Merges only the provided fields into the stored settings, so that older clients do not reset settings they don't know about
+    async fn update_user_settings(
         &self,
         req: &ParsedRequest,
         url_params: &HashMap<&str, String>,
@@ -409,59 +900,1043 @@ impl APIHandler {
             });
         }
         
+        // Early return if the body is not a JSON object
+        let body = req.body_json()?;
+        let partial_settings = match body.as_object() {
+            Some(partial_settings) => partial_settings,
+            None => return Ok(APIResponse {
+                status: StatusCode::BAD_REQUEST,
+                body: json!({ "error": "Invalid settings" }),
+            }),
+        };
+        
         // Proceed with the main logic after passing all checks
-        let settings = self.notification_manager.get_user_notification_settings(&req.authorized_pubkey, device_token.to_string()).await?;
+        let current_settings = self.notification_manager.get_user_notification_settings(&req.authorized_pubkey, device_token.to_string()).await?;
+        let mut merged_settings = json!(current_settings);
+        for (key, value) in partial_settings {
+            if merged_settings.get(key).is_none() {
+                // Settings from a newer client schema are ignored, so that clients and server can be updated independently
+                log::debug!("Ignoring unknown setting: {}", key);
+                continue;
+            }
+            merged_settings[key] = value.clone();
+        }
+        let settings: UserNotificationSettings = match from_value(merged_settings) {
+            Ok(settings) => settings,
+            Err(_) => {
+                return Ok(APIResponse {
+                    status: StatusCode::BAD_REQUEST,
+                    body: json!({ "error": "Invalid settings" }),
+                });
+            }
+        };
         
+        self.notification_manager.save_user_notification_settings(&req.authorized_pubkey, device_token.to_string(), settings).await?;
         Ok(APIResponse {
             status: StatusCode::OK,
-            body: json!(settings),
+            body: json!({ "message": "User settings saved successfully" }),
         })
     }
-}
-
-// MARK: - Extensions
-
-impl Clone for APIHandler {
-    fn clone(&self) -> Self {
-        APIHandler {
-            notification_manager: self.notification_manager.clone(),
-            base_url: self.base_url.clone(),
-        }
-    }
-}
-
-// MARK: - Helper types
-
-// Define enum error types including authentication error
-#[derive(Debug, Error)]
-enum APIError {
-    #[error("Authentication error: {0}")]
-    AuthenticationError(String),
-}
-
-struct ParsedRequest {
-    uri: String,
-    method: Method,
-    body_bytes: Option<Vec<u8>>,
-    authorized_pubkey: nostr::PublicKey,
-}
-
-impl ParsedRequest {
-    fn body_json(&self) -> Result<serde_json::Value, Box<dyn std::error::Error>> {
-        if let Some(body_bytes) = &self.body_bytes {
-            Ok(serde_json::from_slice(body_bytes)?)
-        } else {
-            Ok(json!({}))
+    
+    async fn handle_account_switch(
+        &self,
+        req: &ParsedRequest,
+        url_params: &HashMap<&str, String>,
+    ) -> Result<APIResponse, Box<dyn std::error::Error>> {
+        // Early return if `deviceToken` is missing
+        let device_token = match url_params.get("deviceToken") {
+            Some(token) => token,
+            None => return Ok(APIResponse {
+                status: StatusCode::BAD_REQUEST,
+                body: json!({ "error": "deviceToken is required on the URL" }),
+            }),
+        };
+        
+        // Early return if `deviceToken` does not look like a valid device token
+        if !self.notification_manager.is_device_token_valid(device_token, None) {
+            return Ok(APIResponse {
+                status: StatusCode::BAD_REQUEST,
+                body: json!({ "error": "Invalid deviceToken", "message": "deviceToken must be a 64 character hex string" }),
+            });
         }
-    }
-}
-
+        
+        // Early return if `pubkey` is missing
+        let pubkey = match url_params.get("pubkey") {
+            Some(key) => key,
+            None => return Ok(APIResponse {
+                status: StatusCode::BAD_REQUEST,
+                body: json!({ "error": "pubkey is required on the URL" }),
+            }),
+        };
+        
+        // Validate the `pubkey` and prepare it for use
+        let pubkey = match nostr::PublicKey::from_hex(pubkey) {
+            Ok(key) => key,
+            Err(_) => return Ok(APIResponse {
+                status: StatusCode::BAD_REQUEST,
+                body: json!({ "error": "Invalid pubkey" }),
+            }),
+        };
+        
+        // Early return if `pubkey` does not match `req.authorized_pubkey`
+        if pubkey != req.authorized_pubkey {
+            return Ok(APIResponse {
+                status: StatusCode::FORBIDDEN,
+                body: json!({ "error": "Forbidden" }),
+            });
+        }
+        
+        // Proceed with the main logic after passing all checks. Early return if `pubkey` is not registered on the device
+        let switched_away_pubkeys = match self.notification_manager.switch_device_account(&pubkey, device_token).await? {
+            Some(switched_away_pubkeys) => switched_away_pubkeys,
+            None => return Ok(APIResponse {
+                status: StatusCode::FORBIDDEN,
+                body: json!({ "error": "Forbidden", "message": "pubkey is not registered on this device" }),
+            }),
+        };
+        let settings = self.notification_manager.get_user_notification_settings(&pubkey, device_token.to_string()).await?;
+        
+        Ok(APIResponse {
+            status: StatusCode::OK,
+            body: json!({
+                "settings": settings,
+                "switched_away_pubkeys": switched_away_pubkeys.iter().map(|pubkey| pubkey.to_hex()).collect::<Vec<String>>(),
+            }),
+        })
+    }
+    
+    async fn get_user_settings(
+        &self,
+        req: &ParsedRequest,
+        url_params: &HashMap<&str, String>,
+    ) -> Result<APIResponse, Box<dyn std::error::Error>> {
+        // Early return if `deviceToken` is missing
+        let device_token = match url_params.get("deviceToken") {
+            Some(token) => token,
+            None => return Ok(APIResponse {
+                status: StatusCode::BAD_REQUEST,
+                body: json!({ "error": "deviceToken is required on the URL" }),
+            }),
+        };
+        
+        // Early return if `pubkey` is missing
+        let pubkey = match url_params.get("pubkey") {
+            Some(key) => key,
+            None => return Ok(APIResponse {
+                status: StatusCode::BAD_REQUEST,
+                body: json!({ "error": "pubkey is required on the URL" }),
+            }),
+        };
+        
+        // Validate the `pubkey` and prepare it for use
+        let pubkey = match nostr::PublicKey::from_hex(pubkey) {
+            Ok(key) => key,
+            Err(_) => return Ok(APIResponse {
+                status: StatusCode::BAD_REQUEST,
+                body: json!({ "error": "Invalid pubkey" }),
+            }),
+        };
+        
+        // Early return if `pubkey` does not match `req.authorized_pubkey`
+        if pubkey != req.authorized_pubkey {
+            return Ok(APIResponse {
+                status: StatusCode::FORBIDDEN,
+                body: json!({ "error": "Forbidden" }),
+            });
+        }
+        
+        // Proceed with the main logic after passing all checks
+        let settings = self.notification_manager.get_user_notification_settings(&req.authorized_pubkey, device_token.to_string()).await?;
+        
+        Ok(APIResponse {
+            status: StatusCode::OK,
+            body: json!(settings),
+        })
+    }
+    
+    async fn get_device_pubkeys(
+        &self,
+        req: &ParsedRequest,
+        url_params: &HashMap<&str, String>,
+    ) -> Result<APIResponse, Box<dyn std::error::Error>> {
+        // Early return if `deviceToken` is missing
+        let device_token = match url_params.get("deviceToken") {
+            Some(token) => token,
+            None => return Ok(APIResponse {
+                status: StatusCode::BAD_REQUEST,
+                body: json!({ "error": "deviceToken is required on the URL" }),
+            }),
+        };
+        
+        // Early return if the authorized pubkey is not bound to this device token
+        let bound_pubkeys = self.notification_manager.get_device_token_pubkeys(device_token).await?;
+        if !bound_pubkeys.contains(&req.authorized_pubkey) {
+            return Ok(APIResponse {
+                status: StatusCode::FORBIDDEN,
+                body: json!({ "error": "Forbidden" }),
+            });
+        }
+        
+        let pubkeys: Vec<String> = bound_pubkeys.iter().map(|pubkey| pubkey.to_hex()).collect();
+        // Lets clients notice that pushes stopped arriving (e.g. a stale device token) and register again
+        let last_notified_at: serde_json::Map<String, Value> = self.notification_manager
+            .get_device_last_notified_at(device_token)
+            .await?
+            .into_iter()
+            .map(|(pubkey, last_notified_at)| (pubkey.to_hex(), json!(last_notified_at.map(|timestamp| timestamp.as_u64()))))
+            .collect();
+        Ok(APIResponse {
+            status: StatusCode::OK,
+            body: json!({ "pubkeys": pubkeys, "last_notified_at": last_notified_at }),
+        })
+    }
+    
+    async fn unbind_device_pubkeys(
+        &self,
+        req: &ParsedRequest,
+        url_params: &HashMap<&str, String>,
+    ) -> Result<APIResponse, Box<dyn std::error::Error>> {
+        // Early return if `deviceToken` is missing
+        let device_token = match url_params.get("deviceToken") {
+            Some(token) => token,
+            None => return Ok(APIResponse {
+                status: StatusCode::BAD_REQUEST,
+                body: json!({ "error": "deviceToken is required on the URL" }),
+            }),
+        };
+        
+        // Early return if `deviceToken` does not look like a valid device token
+        if !self.notification_manager.is_device_token_valid(device_token, None) {
+            return Ok(APIResponse {
+                status: StatusCode::BAD_REQUEST,
+                body: json!({ "error": "Invalid deviceToken", "message": "deviceToken must be a 64 character hex string" }),
+            });
+        }
+        
+        // Parse the pubkeys to unbind
+        let body = req.body_json()?;
+        let requested_pubkeys: Vec<String> = match body.get("pubkeys").cloned().map(from_value) {
+            Some(Ok(pubkeys)) => pubkeys,
+            _ => return Ok(APIResponse {
+                status: StatusCode::BAD_REQUEST,
+                body: json!({ "error": "pubkeys must be an array of hex encoded pubkeys" }),
+            }),
+        };
+        let mut unbound_pubkeys = std::collections::HashSet::new();
+        for pubkey in requested_pubkeys {
+            match nostr::PublicKey::from_hex(&pubkey) {
+                Ok(key) => { unbound_pubkeys.insert(key); },
+                Err(_) => return Ok(APIResponse {
+                    status: StatusCode::BAD_REQUEST,
+                    body: json!({ "error": "Invalid pubkey", "pubkey": pubkey }),
+                }),
+            }
+        }
+        
+        // Early return if the requester is not bound to this device.
+        // Knowing a device token is not enough to change its accounts, or anyone could unbind the other accounts of a device.
+        // Pubkeys can only be bound by registering the device with their own authentication
+        let bound_pubkeys = self.notification_manager.get_device_token_pubkeys(device_token).await?;
+        if !bound_pubkeys.contains(&req.authorized_pubkey) {
+            return Ok(APIResponse {
+                status: StatusCode::FORBIDDEN,
+                body: json!({ "error": "Forbidden" }),
+            });
+        }
+        
+        // Proceed with the main logic after passing all checks
+        let remaining_pubkeys = self.notification_manager.unbind_device_token_pubkeys(device_token, &unbound_pubkeys).await?;
+        let pubkeys: Vec<String> = remaining_pubkeys.iter().map(|pubkey| pubkey.to_hex()).collect();
+        Ok(APIResponse {
+            status: StatusCode::OK,
+            body: json!({ "pubkeys": pubkeys }),
+        })
+    }
+    
+    async fn get_account_overview(
+        &self,
+        req: &ParsedRequest,
+        url_params: &HashMap<&str, String>,
+    ) -> Result<APIResponse, Box<dyn std::error::Error>> {
+        // Early return if `pubkey` is missing
+        let pubkey = match url_params.get("pubkey") {
+            Some(key) => key,
+            None => return Ok(APIResponse {
+                status: StatusCode::BAD_REQUEST,
+                body: json!({ "error": "pubkey is required on the URL" }),
+            }),
+        };
+        
+        // Validate the `pubkey` and prepare it for use
+        let pubkey = match nostr::PublicKey::from_hex(pubkey) {
+            Ok(key) => key,
+            Err(_) => return Ok(APIResponse {
+                status: StatusCode::BAD_REQUEST,
+                body: json!({ "error": "Invalid pubkey" }),
+            }),
+        };
+    
+        // Early return if `pubkey` does not match `req.authorized_pubkey`
+        if pubkey != req.authorized_pubkey {
+            return Ok(APIResponse {
+                status: StatusCode::FORBIDDEN,
+                body: json!({ "error": "Forbidden" }),
+            });
+        }
+        
+        // Everything the settings screen shows, so that it can load with a single request
+        let devices = self.notification_manager.get_account_devices(&pubkey).await?;
+        let hashtag_subscriptions = self.notification_manager.get_hashtag_subscriptions(&pubkey).await?;
+        Ok(APIResponse {
+            status: StatusCode::OK,
+            body: json!({
+                "pubkey": pubkey.to_hex(),
+                "devices": devices,
+                "hashtags": hashtag_subscriptions,
+            }),
+        })
+    }
+
+    async fn get_hashtag_subscriptions(
+        &self,
+        req: &ParsedRequest,
+        url_params: &HashMap<&str, String>,
+    ) -> Result<APIResponse, Box<dyn std::error::Error>> {
+        // Early return if `pubkey` is missing
+        let pubkey = match url_params.get("pubkey") {
+            Some(key) => key,
+            None => return Ok(APIResponse {
+                status: StatusCode::BAD_REQUEST,
+                body: json!({ "error": "pubkey is required on the URL" }),
+            }),
+        };
+        
+        // Validate the `pubkey` and prepare it for use
+        let pubkey = match nostr::PublicKey::from_hex(pubkey) {
+            Ok(key) => key,
+            Err(_) => return Ok(APIResponse {
+                status: StatusCode::BAD_REQUEST,
+                body: json!({ "error": "Invalid pubkey" }),
+            }),
+        };
+    
+        // Early return if `pubkey` does not match `req.authorized_pubkey`
+        if pubkey != req.authorized_pubkey {
+            return Ok(APIResponse {
+                status: StatusCode::FORBIDDEN,
+                body: json!({ "error": "Forbidden" }),
+            });
+        }
+        
+        let subscriptions = self.notification_manager.get_hashtag_subscriptions(&pubkey).await?;
+        Ok(APIResponse {
+            status: StatusCode::OK,
+            body: json!({ "hashtags": subscriptions }),
+        })
+    }
+
+    async fn set_hashtag_subscriptions(
+        &self,
+        req: &ParsedRequest,
+        url_params: &HashMap<&str, String>,
+    ) -> Result<APIResponse, Box<dyn std::error::Error>> {
+        // Early return if `pubkey` is missing
+        let pubkey = match url_params.get("pubkey") {
+            Some(key) => key,
+            None => return Ok(APIResponse {
+                status: StatusCode::BAD_REQUEST,
+                body: json!({ "error": "pubkey is required on the URL" }),
+            }),
+        };
+        
+        // Validate the `pubkey` and prepare it for use
+        let pubkey = match nostr::PublicKey::from_hex(pubkey) {
+            Ok(key) => key,
+            Err(_) => return Ok(APIResponse {
+                status: StatusCode::BAD_REQUEST,
+                body: json!({ "error": "Invalid pubkey" }),
+            }),
+        };
+    
+        // Early return if `pubkey` does not match `req.authorized_pubkey`
+        if pubkey != req.authorized_pubkey {
+            return Ok(APIResponse {
+                status: StatusCode::FORBIDDEN,
+                body: json!({ "error": "Forbidden" }),
+            });
+        }
+        
+        // Parse the new set of hashtags
+        let body = req.body_json()?;
+        let requested_subscriptions: Vec<HashtagSubscription> = match body.get("hashtags").cloned().map(from_value) {
+            Some(Ok(subscriptions)) => subscriptions,
+            _ => return Ok(APIResponse {
+                status: StatusCode::BAD_REQUEST,
+                body: json!({ "error": "hashtags must be an array of hashtag subscriptions" }),
+            }),
+        };
+        
+        // Early return if any hashtag is empty
+        if let Some(subscription) = requested_subscriptions.iter().find(|subscription| subscription.normalized_hashtag().is_empty()) {
+            return Ok(APIResponse {
+                status: StatusCode::BAD_REQUEST,
+                body: json!({ "error": "Invalid hashtag", "hashtag": subscription.hashtag }),
+            });
+        }
+        
+        // Early return if there are too many hashtags
+        if requested_subscriptions.len() > MAX_HASHTAG_SUBSCRIPTIONS {
+            return Ok(APIResponse {
+                status: StatusCode::BAD_REQUEST,
+                body: json!({ "error": "Too many hashtags", "message": format!("At most {} hashtags can be subscribed to", MAX_HASHTAG_SUBSCRIPTIONS) }),
+            });
+        }
+        
+        // Proceed with the main logic after passing all checks
+        self.notification_manager.replace_hashtag_subscriptions(&pubkey, &requested_subscriptions).await?;
+        let subscriptions = self.notification_manager.get_hashtag_subscriptions(&pubkey).await?;
+        Ok(APIResponse {
+            status: StatusCode::OK,
+            body: json!({ "hashtags": subscriptions }),
+        })
+    }
+
+    async fn get_device_linked_pubkeys(
+        &self,
+        req: &ParsedRequest,
+        url_params: &HashMap<&str, String>,
+    ) -> Result<APIResponse, Box<dyn std::error::Error>> {
+        // Early return if `deviceToken` is missing
+        let device_token = match url_params.get("deviceToken") {
+            Some(token) => token,
+            None => return Ok(APIResponse {
+                status: StatusCode::BAD_REQUEST,
+                body: json!({ "error": "deviceToken is required on the URL" }),
+            }),
+        };
+        
+        // Early return if the authorized pubkey is not bound to this device token
+        let bound_pubkeys = self.notification_manager.get_device_token_pubkeys(device_token).await?;
+        if !bound_pubkeys.contains(&req.authorized_pubkey) {
+            return Ok(APIResponse {
+                status: StatusCode::FORBIDDEN,
+                body: json!({ "error": "Forbidden" }),
+            });
+        }
+        
+        let linked_pubkeys = self.notification_manager.get_device_linked_pubkeys(device_token).await?;
+        let pubkeys: Vec<String> = linked_pubkeys.iter().map(|pubkey| pubkey.to_hex()).collect();
+        Ok(APIResponse {
+            status: StatusCode::OK,
+            body: json!({ "pubkeys": pubkeys }),
+        })
+    }
+    
+    async fn set_device_linked_pubkeys(
+        &self,
+        req: &ParsedRequest,
+        url_params: &HashMap<&str, String>,
+    ) -> Result<APIResponse, Box<dyn std::error::Error>> {
+        // Early return if `deviceToken` is missing
+        let device_token = match url_params.get("deviceToken") {
+            Some(token) => token,
+            None => return Ok(APIResponse {
+                status: StatusCode::BAD_REQUEST,
+                body: json!({ "error": "deviceToken is required on the URL" }),
+            }),
+        };
+        
+        // Parse the new set of pubkeys
+        let body = req.body_json()?;
+        let requested_pubkeys: Vec<String> = match body.get("pubkeys").cloned().map(from_value) {
+            Some(Ok(pubkeys)) => pubkeys,
+            _ => return Ok(APIResponse {
+                status: StatusCode::BAD_REQUEST,
+                body: json!({ "error": "pubkeys must be an array of hex encoded pubkeys" }),
+            }),
+        };
+        let mut new_pubkeys = std::collections::HashSet::new();
+        for pubkey in requested_pubkeys {
+            match nostr::PublicKey::from_hex(&pubkey) {
+                Ok(key) => { new_pubkeys.insert(key); },
+                Err(_) => return Ok(APIResponse {
+                    status: StatusCode::BAD_REQUEST,
+                    body: json!({ "error": "Invalid pubkey", "pubkey": pubkey }),
+                }),
+            }
+        }
+        
+        // Early return if the authorized pubkey is not bound to this device token.
+        // Linking only suppresses notifications on this device, so the linked pubkeys themselves need no proof of control.
+        let bound_pubkeys = self.notification_manager.get_device_token_pubkeys(device_token).await?;
+        if !bound_pubkeys.contains(&req.authorized_pubkey) {
+            return Ok(APIResponse {
+                status: StatusCode::FORBIDDEN,
+                body: json!({ "error": "Forbidden" }),
+            });
+        }
+        
+        // Proceed with the main logic after passing all checks
+        self.notification_manager.replace_device_linked_pubkeys(device_token, &new_pubkeys).await?;
+        let pubkeys: Vec<String> = new_pubkeys.iter().map(|pubkey| pubkey.to_hex()).collect();
+        Ok(APIResponse {
+            status: StatusCode::OK,
+            body: json!({ "pubkeys": pubkeys }),
+        })
+    }
+    
+    async fn handle_live_activity_registration(
+        &self,
+        req: &ParsedRequest,
+        url_params: &HashMap<&str, String>,
+    ) -> Result<APIResponse, Box<dyn std::error::Error>> {
+        // Early return if `activityToken` is missing or invalid
+        let activity_token = match url_params.get("activityToken") {
+            Some(token) if NotificationManager::is_live_activity_token_valid(token) => token,
+            _ => return Ok(APIResponse {
+                status: StatusCode::BAD_REQUEST,
+                body: json!({ "error": "Invalid activityToken", "message": "activityToken must be a hex string" }),
+            }),
+        };
+        
+        // Early return if `pubkey` is missing, invalid, or does not match `req.authorized_pubkey`
+        let pubkey = match url_params.get("pubkey").map(|pubkey| nostr::PublicKey::from_hex(pubkey)) {
+            Some(Ok(pubkey)) => pubkey,
+            _ => return Ok(APIResponse {
+                status: StatusCode::BAD_REQUEST,
+                body: json!({ "error": "Invalid pubkey" }),
+            }),
+        };
+        if pubkey != req.authorized_pubkey {
+            return Ok(APIResponse {
+                status: StatusCode::FORBIDDEN,
+                body: json!({ "error": "Forbidden" }),
+            });
+        }
+        
+        // Early return if the tracked event is missing or invalid
+        let body = req.body_json()?;
+        let event_id = match body.get("event_id").and_then(|event_id| event_id.as_str()).map(nostr::EventId::from_hex) {
+            Some(Ok(event_id)) => event_id,
+            _ => return Ok(APIResponse {
+                status: StatusCode::BAD_REQUEST,
+                body: json!({ "error": "event_id is required in the body" }),
+            }),
+        };
+        
+        // Proceed with the main logic after passing all checks
+        self.notification_manager.save_live_activity_token(&pubkey, activity_token, &event_id).await?;
+        Ok(APIResponse {
+            status: StatusCode::OK,
+            body: json!({ "message": "Live Activity registered successfully" }),
+        })
+    }
+    
+    async fn handle_live_activity_end(
+        &self,
+        req: &ParsedRequest,
+        url_params: &HashMap<&str, String>,
+    ) -> Result<APIResponse, Box<dyn std::error::Error>> {
+        // Early return if `activityToken` is missing
+        let activity_token = match url_params.get("activityToken") {
+            Some(token) => token,
+            None => return Ok(APIResponse {
+                status: StatusCode::BAD_REQUEST,
+                body: json!({ "error": "activityToken is required on the URL" }),
+            }),
+        };
+        
+        // Early return if `pubkey` is missing, invalid, or does not match `req.authorized_pubkey`
+        let pubkey = match url_params.get("pubkey").map(|pubkey| nostr::PublicKey::from_hex(pubkey)) {
+            Some(Ok(pubkey)) => pubkey,
+            _ => return Ok(APIResponse {
+                status: StatusCode::BAD_REQUEST,
+                body: json!({ "error": "Invalid pubkey" }),
+            }),
+        };
+        if pubkey != req.authorized_pubkey {
+            return Ok(APIResponse {
+                status: StatusCode::FORBIDDEN,
+                body: json!({ "error": "Forbidden" }),
+            });
+        }
+        
+        // Proceed with the main logic after passing all checks
+        self.notification_manager.end_live_activity(&pubkey, activity_token).await?;
+        Ok(APIResponse {
+            status: StatusCode::OK,
+            body: json!({ "message": "Live Activity ended successfully" }),
+        })
+    }
+    
+    // MARK: - Admin endpoint handlers
+    
+    async fn handle_notification_trace(
+        &self,
+        req: &ParsedRequest,
+    ) -> Result<APIResponse, Box<dyn std::error::Error>> {
+        let body = req.body_json()?;
+        
+        // Early return if the recipient is missing or invalid
+        let recipient = match body.get("recipient").and_then(|recipient| recipient.as_str()).map(nostr::PublicKey::from_hex) {
+            Some(Ok(recipient)) => recipient,
+            _ => return Ok(APIResponse {
+                status: StatusCode::BAD_REQUEST,
+                body: json!({ "error": "Invalid recipient", "message": "recipient must be a hex pubkey" }),
+            }),
+        };
+        
+        // Early return if the authorized pubkey is neither the recipient nor an admin
+        if recipient != req.authorized_pubkey && !self.is_admin(&req.authorized_pubkey) {
+            return Ok(APIResponse {
+                status: StatusCode::FORBIDDEN,
+                body: json!({ "error": "Forbidden" }),
+            });
+        }
+        
+        // Early return if the event is missing or invalid. It is synthetic, so it does not have to be signed
+        let event: nostr::Event = match body.get("event").cloned().map(from_value) {
+            Some(Ok(event)) => event,
+            _ => return Ok(APIResponse {
+                status: StatusCode::BAD_REQUEST,
+                body: json!({ "error": "Invalid event", "message": "event must be a Nostr event" }),
+            }),
+        };
+        
+        // Proceed with the main logic after passing all checks
+        let trace = self.notification_manager.trace_notification(&event, &recipient).await?;
+        Ok(APIResponse {
+            status: StatusCode::OK,
+            body: json!(trace),
+        })
+    }
+    
+    async fn get_admin_stats(
+        &self,
+        req: &ParsedRequest,
+    ) -> Result<APIResponse, Box<dyn std::error::Error>> {
+        // Early return if the authorized pubkey is not an admin
+        if !self.is_admin(&req.authorized_pubkey) {
+            return Ok(APIResponse {
+                status: StatusCode::FORBIDDEN,
+                body: json!({ "error": "Forbidden" }),
+            });
+        }
+        
+        let one_day_ago = nostr::Timestamp::now() - ADMIN_STATS_WINDOW_SECONDS;
+        let delivery_stats = self.notification_manager.get_delivery_stats(one_day_ago).await?;
+        let summaries_since = nostr::Timestamp::now() - ADMIN_STATS_DAILY_SUMMARY_RETENTION_SECONDS;
+        let daily_summaries = self.notification_manager.get_delivery_daily_summaries(summaries_since).await?;
+        Ok(APIResponse {
+            status: StatusCode::OK,
+            body: json!({ "deliveries": delivery_stats, "daily_summaries": daily_summaries, "ingestion_queue": self.ingestion_queue.stats() }),
+        })
+    }
+    
+    async fn get_user_debug_info(
+        &self,
+        req: &ParsedRequest,
+        url_params: &HashMap<&str, String>,
+    ) -> Result<APIResponse, Box<dyn std::error::Error>> {
+        // Early return if the authorized pubkey is not an admin
+        if !self.is_admin(&req.authorized_pubkey) {
+            return Ok(APIResponse {
+                status: StatusCode::FORBIDDEN,
+                body: json!({ "error": "Forbidden" }),
+            });
+        }
+        
+        // Early return if `pubkey` is missing or invalid
+        let pubkey = match url_params.get("pubkey").map(|pubkey| nostr::PublicKey::from_hex(pubkey)) {
+            Some(Ok(pubkey)) => pubkey,
+            _ => return Ok(APIResponse {
+                status: StatusCode::BAD_REQUEST,
+                body: json!({ "error": "Invalid pubkey" }),
+            }),
+        };
+        
+        // Proceed with the main logic after passing all checks
+        let debug_info = self.notification_manager.get_user_debug_info(&pubkey).await?;
+        Ok(APIResponse {
+            status: StatusCode::OK,
+            body: json!(debug_info),
+        })
+    }
+    
+    async fn get_held_events(
+        &self,
+        req: &ParsedRequest,
+    ) -> Result<APIResponse, Box<dyn std::error::Error>> {
+        // Early return if the authorized pubkey is not an admin
+        if !self.is_admin(&req.authorized_pubkey) {
+            return Ok(APIResponse {
+                status: StatusCode::FORBIDDEN,
+                body: json!({ "error": "Forbidden" }),
+            });
+        }
+        
+        let held_events = self.notification_manager.get_held_events().await?;
+        Ok(APIResponse {
+            status: StatusCode::OK,
+            body: json!({ "held_events": held_events }),
+        })
+    }
+    
+    async fn handle_held_event_approval(
+        &self,
+        req: &ParsedRequest,
+        url_params: &HashMap<&str, String>,
+    ) -> Result<APIResponse, Box<dyn std::error::Error>> {
+        // Early return if the authorized pubkey is not an admin
+        if !self.is_admin(&req.authorized_pubkey) {
+            return Ok(APIResponse {
+                status: StatusCode::FORBIDDEN,
+                body: json!({ "error": "Forbidden" }),
+            });
+        }
+        
+        // Early return if `eventId` is missing or invalid
+        let event_id = match url_params.get("eventId").map(|event_id| nostr::EventId::from_hex(event_id)) {
+            Some(Ok(event_id)) => event_id,
+            _ => return Ok(APIResponse {
+                status: StatusCode::BAD_REQUEST,
+                body: json!({ "error": "Invalid eventId" }),
+            }),
+        };
+        
+        // Proceed with the main logic after passing all checks
+        match self.notification_manager.approve_held_event(&event_id).await? {
+            Some(recipient_count) => Ok(APIResponse {
+                status: StatusCode::OK,
+                body: json!({ "message": "Held event approved", "recipient_count": recipient_count }),
+            }),
+            None => Ok(APIResponse {
+                status: StatusCode::NOT_FOUND,
+                body: json!({ "error": "Event is not held" }),
+            }),
+        }
+    }
+    
+    async fn handle_held_event_discard(
+        &self,
+        req: &ParsedRequest,
+        url_params: &HashMap<&str, String>,
+    ) -> Result<APIResponse, Box<dyn std::error::Error>> {
+        // Early return if the authorized pubkey is not an admin
+        if !self.is_admin(&req.authorized_pubkey) {
+            return Ok(APIResponse {
+                status: StatusCode::FORBIDDEN,
+                body: json!({ "error": "Forbidden" }),
+            });
+        }
+        
+        // Early return if `eventId` is missing or invalid
+        let event_id = match url_params.get("eventId").map(|event_id| nostr::EventId::from_hex(event_id)) {
+            Some(Ok(event_id)) => event_id,
+            _ => return Ok(APIResponse {
+                status: StatusCode::BAD_REQUEST,
+                body: json!({ "error": "Invalid eventId" }),
+            }),
+        };
+        
+        // Proceed with the main logic after passing all checks
+        if !self.notification_manager.discard_held_event(&event_id).await? {
+            return Ok(APIResponse {
+                status: StatusCode::NOT_FOUND,
+                body: json!({ "error": "Event is not held" }),
+            });
+        }
+        Ok(APIResponse {
+            status: StatusCode::OK,
+            body: json!({ "message": "Held event discarded" }),
+        })
+    }
+    
+    async fn handle_db_backup(
+        &self,
+        req: &ParsedRequest,
+    ) -> Result<APIResponse, Box<dyn std::error::Error>> {
+        // Early return if the authorized pubkey is not an admin
+        if !self.is_admin(&req.authorized_pubkey) {
+            return Ok(APIResponse {
+                status: StatusCode::FORBIDDEN,
+                body: json!({ "error": "Forbidden" }),
+            });
+        }
+        
+        // Early return if backups are not enabled
+        let db_backup_dir = match &self.db_backup_dir {
+            Some(db_backup_dir) => db_backup_dir,
+            None => return Ok(APIResponse {
+                status: StatusCode::NOT_FOUND,
+                body: json!({ "error": "Database backups are disabled", "message": "Set DB_BACKUP_DIR to enable them" }),
+            }),
+        };
+        
+        // Early return if the file name is missing, or would escape the backup directory
+        let body = req.body_json()?;
+        let file_name = match body.get("file_name").and_then(|file_name| file_name.as_str()) {
+            Some(file_name) if is_plain_file_name(file_name) => file_name,
+            _ => return Ok(APIResponse {
+                status: StatusCode::BAD_REQUEST,
+                body: json!({ "error": "Invalid file_name", "message": "file_name must be a plain file name, without directories" }),
+            }),
+        };
+        
+        // Early return if the backup would overwrite an existing file
+        let destination_path = db_backup_dir.join(file_name);
+        if destination_path.exists() {
+            return Ok(APIResponse {
+                status: StatusCode::CONFLICT,
+                body: json!({ "error": "Backup file already exists" }),
+            });
+        }
+        
+        // Proceed with the main logic after passing all checks
+        let started_at = std::time::Instant::now();
+        self.notification_manager.backup_database(destination_path.clone()).await?;
+        log::info!("Database backed up to {}", destination_path.display());
+        Ok(APIResponse {
+            status: StatusCode::OK,
+            body: json!({
+                "message": "Database backed up successfully",
+                "path": destination_path.display().to_string(),
+                "duration_ms": started_at.elapsed().as_millis() as u64,
+            }),
+        })
+    }
+    
+    async fn handle_wal_checkpoint(
+        &self,
+        req: &ParsedRequest,
+    ) -> Result<APIResponse, Box<dyn std::error::Error>> {
+        // Early return if the authorized pubkey is not an admin
+        if !self.is_admin(&req.authorized_pubkey) {
+            return Ok(APIResponse {
+                status: StatusCode::FORBIDDEN,
+                body: json!({ "error": "Forbidden" }),
+            });
+        }
+        
+        // Early return if the checkpoint mode is invalid
+        let body = req.body_json()?;
+        let mode: WalCheckpointMode = match body.get("mode").cloned().map(from_value) {
+            None => WalCheckpointMode::default(),
+            Some(Ok(mode)) => mode,
+            Some(Err(_)) => return Ok(APIResponse {
+                status: StatusCode::BAD_REQUEST,
+                body: json!({ "error": "Invalid mode", "message": "mode must be one of passive, full, restart or truncate" }),
+            }),
+        };
+        
+        // Proceed with the main logic after passing all checks
+        let checkpoint_result = self.notification_manager.checkpoint_wal(mode).await?;
+        Ok(APIResponse {
+            status: StatusCode::OK,
+            body: json!(checkpoint_result),
+        })
+    }
+    
+    async fn get_audit_log(
+        &self,
+        req: &ParsedRequest,
+    ) -> Result<APIResponse, Box<dyn std::error::Error>> {
+        // Early return if the authorized pubkey is not an admin
+        if !self.is_admin(&req.authorized_pubkey) {
+            return Ok(APIResponse {
+                status: StatusCode::FORBIDDEN,
+                body: json!({ "error": "Forbidden" }),
+            });
+        }
+        
+        // Early return if the `actor` filter is invalid
+        let actor_pubkey = match req.query_param("actor").map(|actor| nostr::PublicKey::from_hex(&actor)) {
+            None => None,
+            Some(Ok(actor_pubkey)) => Some(actor_pubkey),
+            Some(Err(_)) => return Ok(APIResponse {
+                status: StatusCode::BAD_REQUEST,
+                body: json!({ "error": "Invalid actor", "message": "actor must be a hex pubkey" }),
+            }),
+        };
+        
+        // Early return if the pagination parameters are invalid
+        let before = match req.query_param("before").map(|before| before.parse::<i64>()) {
+            None => None,
+            Some(Ok(before)) => Some(before),
+            Some(Err(_)) => return Ok(APIResponse {
+                status: StatusCode::BAD_REQUEST,
+                body: json!({ "error": "Invalid before", "message": "before must be the id of an audit log entry" }),
+            }),
+        };
+        let limit = match req.query_param("limit").map(|limit| limit.parse::<usize>()) {
+            None => DEFAULT_AUDIT_LOG_PAGE_SIZE,
+            Some(Ok(limit)) if (1..=MAX_AUDIT_LOG_PAGE_SIZE).contains(&limit) => limit,
+            Some(_) => return Ok(APIResponse {
+                status: StatusCode::BAD_REQUEST,
+                body: json!({ "error": "Invalid limit", "message": format!("limit must be between 1 and {}", MAX_AUDIT_LOG_PAGE_SIZE) }),
+            }),
+        };
+        
+        // Proceed with the main logic after passing all checks
+        let entries = self.notification_manager.get_audit_log(actor_pubkey, before, limit).await?;
+        Ok(APIResponse {
+            status: StatusCode::OK,
+            body: json!({ "entries": entries }),
+        })
+    }
+}
+
+// MARK: - Extensions
+
+impl Clone for APIHandler {
+    fn clone(&self) -> Self {
+        APIHandler {
+            notification_manager: self.notification_manager.clone(),
+            base_url: self.base_url.clone(),
+            admin_pubkeys: self.admin_pubkeys.clone(),
+            relay_policy: self.relay_policy.clone(),
+            ingestion_queue: self.ingestion_queue.clone(),
+            event_rate_limiter: self.event_rate_limiter.clone(),
+            db_backup_dir: self.db_backup_dir.clone(),
+            trusted_proxies: self.trusted_proxies.clone(),
+        }
+    }
+}
+
+// MARK: - Helper types
+
+// Define enum error types including authentication error
+#[derive(Debug, Error)]
+enum APIError {
+    #[error("Authentication error: {0}")]
+    AuthenticationError(String),
+    #[error("Authorization scope does not allow {0} requests")]
+    InsufficientScope(String),
+    #[error("Unsupported Content-Encoding: {0}")]
+    UnsupportedContentEncoding(String),
+    #[error("Invalid compressed body: {0}")]
+    InvalidCompressedBody(String),
+    #[error("Unsupported Content-Type: {0}")]
+    UnsupportedContentType(String),
+    #[error("Invalid JSON body at line {line}, column {column}: {message}")]
+    InvalidJsonBody { message: String, line: usize, column: usize },
+}
+
+struct ParsedRequest {
+    uri: String,
+    query: Option<String>,
+    method: Method,
+    body_bytes: Option<Vec<u8>>,
+    authorized_pubkey: nostr::PublicKey,
+    request_id: String,
+}
+
+impl ParsedRequest {
+    fn body_json(&self) -> Result<serde_json::Value, Box<dyn std::error::Error>> {
+        if let Some(body_bytes) = &self.body_bytes {
+            // Malformed bodies are the client's fault, so they are reported with where parsing failed instead of as a 500
+            serde_json::from_slice(body_bytes).map_err(|e| {
+                Box::new(APIError::InvalidJsonBody { message: e.to_string(), line: e.line(), column: e.column() }) as Box<dyn std::error::Error>
+            })
+        } else {
+            Ok(json!({}))
+        }
+    }
+
+    /// The percent-decoded value of a query string parameter
+    fn query_param(&self, name: &str) -> Option<String> {
+        form_urlencoded::parse(self.query.as_deref()?.as_bytes())
+            .find(|(key, _)| key == name)
+            .map(|(_, value)| value.into_owned())
+    }
+}
+
 struct APIResponse {
     status: StatusCode,
     body: Value,
 }
 
+/// A response compression the client accepts
+#[derive(Debug, Clone, Copy, PartialEq)]
+enum ResponseEncoding {
+    Gzip,
+    Deflate,
+}
+
+impl ResponseEncoding {
+    /// Picks the response encoding from the request's `Accept-Encoding` header, preferring gzip. Returns `None` to send the response uncompressed
+    fn negotiate(req: &Request<Incoming>) -> Option<Self> {
+        let accept_encoding = req.headers().get(hyper::header::ACCEPT_ENCODING)?.to_str().ok()?;
+        let accepted_encodings: Vec<String> = accept_encoding
+            .split(',')
+            .filter_map(|encoding| {
+                let mut parts = encoding.split(';');
+                let name = parts.next()?.trim().to_ascii_lowercase();
+                // `q=0` means the encoding is not acceptable
+                let is_refused = parts.any(|parameter| {
+                    parameter.trim().strip_prefix("q=").and_then(|q| q.trim().parse::<f32>().ok()) == Some(0.0)
+                });
+                if is_refused { None } else { Some(name) }
+            })
+            .collect();
+        let accepts = |name: &str| accepted_encodings.iter().any(|encoding| encoding == name || encoding == "*");
+        if accepts("gzip") {
+            Some(ResponseEncoding::Gzip)
+        } else if accepts("deflate") {
+            Some(ResponseEncoding::Deflate)
+        } else {
+            None
+        }
+    }
+
+    fn as_header_value(&self) -> &'static str {
+        match self {
+            ResponseEncoding::Gzip => "gzip",
+            ResponseEncoding::Deflate => "deflate",
+        }
+    }
+
+    fn encode(&self, body: &[u8]) -> std::io::Result<Vec<u8>> {
+        match self {
+            ResponseEncoding::Gzip => {
+                let mut encoder = flate2::write::GzEncoder::new(Vec::new(), flate2::Compression::default());
+                encoder.write_all(body)?;
+                encoder.finish()
+            }
+            // HTTP `deflate` is the zlib format, not raw deflate
+            ResponseEncoding::Deflate => {
+                let mut encoder = flate2::write::ZlibEncoder::new(Vec::new(), flate2::Compression::default());
+                encoder.write_all(body)?;
+                encoder.finish()
+            }
+        }
+    }
+}
+
 // MARK: - Helper functions
+
+/// A weak ETag of a JSON response body. Weak, since the same JSON may be sent with different encodings
+fn weak_etag(body: &Value) -> String {
+    let digest = Sha256Hash::hash(body.to_string().as_bytes());
+    format!("W/\"{}\"", &digest.to_string()[..32])
+}
+
+/// The answer to a conditional GET whose `If-None-Match` matches the ETag of the response.
+/// A 304 must carry the headers the 200 would have, so that caches keep the stored response valid for the same requests
+fn not_modified_response(etag: &str) -> Result<Response<Full<Bytes>>, hyper::http::Error> {
+    Response::builder()
+        .header("ETag", etag)
+        .header("Access-Control-Allow-Origin", "*")
+        .header("Vary", "Accept-Encoding")
+        .status(StatusCode::NOT_MODIFIED)
+        .body(http_body_util::Full::new(Bytes::new()))
+}
+
+/// Checks if an `If-None-Match` header value matches the ETag, using the weak comparison `If-None-Match` calls for
+fn etag_matches(if_none_match: &str, etag: &str) -> bool {
+    let opaque_tag = |tag: &str| tag.trim().trim_start_matches("W/").to_string();
+    if_none_match.trim() == "*" || if_none_match.split(',').any(|candidate| opaque_tag(candidate) == opaque_tag(etag))
+}
  
 /// Matches the request to a specified route, returning a hashmap of the route parameters
 /// e.g. GET /user/:id/info route against request GET /user/123/info matches to { "id": "123" }
@@ -489,3 +1964,79 @@ fn route_match<'a>(method: &Method, path: &'a str, req: &ParsedRequest) -> Optio
 
     Some(params)
 }
+
+/// Checks that the path is a single file name, so that it can be safely joined to a directory
+fn is_plain_file_name(path: &str) -> bool {
+    let mut components = Path::new(path).components();
+    matches!((components.next(), components.next()), (Some(Component::Normal(_)), None))
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn request_with_query(query: Option<&str>) -> ParsedRequest {
+        ParsedRequest {
+            uri: "/user-info/pubkey/token".to_string(),
+            query: query.map(|query| query.to_string()),
+            method: Method::GET,
+            body_bytes: None,
+            authorized_pubkey: nostr::Keys::generate().public_key(),
+            request_id: "request".to_string(),
+        }
+    }
+
+    // MARK: - Query parameters
+
+    #[test]
+    fn query_params_are_percent_decoded() {
+        let request = request_with_query(Some("relay=wss%3A%2F%2Frelay.damus.io&name=a+b%26c"));
+
+        assert_eq!(request.query_param("relay").as_deref(), Some("wss://relay.damus.io"));
+        assert_eq!(request.query_param("name").as_deref(), Some("a b&c"));
+    }
+
+    #[test]
+    fn missing_query_params_are_none() {
+        assert_eq!(request_with_query(Some("limit=10")).query_param("offset"), None);
+        assert_eq!(request_with_query(None).query_param("limit"), None);
+    }
+
+    #[test]
+    fn first_of_repeated_query_params_is_used() {
+        assert_eq!(request_with_query(Some("limit=10&limit=20")).query_param("limit").as_deref(), Some("10"));
+    }
+
+    // MARK: - ETags
+
+    #[test]
+    fn etag_is_weak_and_depends_on_the_body() {
+        let etag = weak_etag(&json!({ "enabled": true }));
+
+        assert!(etag.starts_with("W/\"") && etag.ends_with('"'));
+        assert_eq!(etag, weak_etag(&json!({ "enabled": true })));
+        assert_ne!(etag, weak_etag(&json!({ "enabled": false })));
+    }
+
+    #[test]
+    fn etag_matches_weakly_and_in_lists() {
+        let etag = weak_etag(&json!({}));
+        let strong_etag = etag.trim_start_matches("W/").to_string();
+
+        assert!(etag_matches(&etag, &etag));
+        assert!(etag_matches(&strong_etag, &etag));
+        assert!(etag_matches(&format!("\"other\", {}", etag), &etag));
+        assert!(etag_matches(" * ", &etag));
+        assert!(!etag_matches("W/\"other\"", &etag));
+    }
+
+    #[test]
+    fn not_modified_response_keeps_the_headers_of_the_full_response() {
+        let response = not_modified_response("W/\"tag\"").unwrap();
+
+        assert_eq!(response.status(), StatusCode::NOT_MODIFIED);
+        assert_eq!(response.headers().get("ETag").unwrap(), "W/\"tag\"");
+        assert_eq!(response.headers().get("Vary").unwrap(), "Accept-Encoding");
+        assert_eq!(response.headers().get("Access-Control-Allow-Origin").unwrap(), "*");
+    }
+}
@@ -0,0 +1,733 @@
+use serde_json::{json, Value};
+
+// Keep in sync with the routes in `APIHandler::handle_parsed_http_request`
+
+/// Builds the OpenAPI 3.0 description of the HTTP API, so that client teams can generate bindings from it
+pub fn openapi_document(base_url: &str) -> Value {
+    json!({
+        "openapi": "3.0.3",
+        "info": {
+            "title": "Notepush API",
+            "version": env!("CARGO_PKG_VERSION"),
+            "description": "Registers devices for Nostr push notifications. All endpoints except `/readyz`, `/metrics` and `/openapi.json` require NIP-98 authentication. Successful authenticated `GET` responses carry a weak `ETag`, and answer `304 Not Modified` when it matches `If-None-Match`. Admin requests and destructive user actions are recorded in an audit log along with their `X-Request-Id` header, or an ID generated for them.",
+        },
+        "servers": [{ "url": base_url }],
+        "security": [{ "nip98": [] }],
+        "paths": {
+            "/readyz": {
+                "get": {
+                    "summary": "Readiness check",
+                    "security": [],
+                    "responses": {
+                        "200": json_response("Ready to serve", "#/components/schemas/ReadinessStatus"),
+                        "503": json_response("Not connected to any relay", "#/components/schemas/ReadinessStatus"),
+                    },
+                },
+            },
+            "/openapi.json": {
+                "get": {
+                    "summary": "This API description",
+                    "security": [],
+                    "responses": {
+                        "200": { "description": "OpenAPI document", "content": { "application/json": {} } },
+                    },
+                },
+            },
+            "/capabilities": {
+                "get": {
+                    "summary": "Describe the notification kinds, digests, sounds, settings options and payload versions this server supports, for building settings screens",
+                    "security": [],
+                    "responses": {
+                        "200": json_response("Server capabilities", "#/components/schemas/ServerCapabilities"),
+                    },
+                },
+            },
+            "/metrics": {
+                "get": {
+                    "summary": "Event processing latency histograms and per-relay fetch subscription metrics, in the Prometheus text format",
+                    "security": [],
+                    "responses": {
+                        "200": { "description": "Prometheus metrics", "content": { "text/plain": {} } },
+                    },
+                },
+            },
+            "/user-info/{pubkey}/{deviceToken}": {
+                "parameters": [path_parameter("pubkey"), path_parameter("deviceToken")],
+                "put": {
+                    "summary": "Register a device token for a pubkey",
+                    "requestBody": json_request_body("#/components/schemas/DeviceRegistration", false),
+                    "responses": {
+                        "200": json_response("User info already registered", "#/components/schemas/DeviceRegistrationResult"),
+                        "201": json_response("User info saved successfully", "#/components/schemas/DeviceRegistrationResult"),
+                        "400": error_response(),
+                        "401": error_response(),
+                    },
+                },
+                "delete": {
+                    "summary": "Unregister a device token for a pubkey. Notifications stop immediately, but its settings are kept for a grace period and restored if it is registered again",
+                    "responses": {
+                        "200": message_response("User info removed successfully"),
+                        "400": error_response(),
+                        "401": error_response(),
+                    },
+                },
+            },
+            "/accounts/{pubkey}/overview": {
+                "parameters": [path_parameter("pubkey")],
+                "get": {
+                    "summary": "Get the devices (with their settings) and hashtag subscriptions of a pubkey in one response, for settings screens",
+                    "responses": {
+                        "200": json_response("Account overview", "#/components/schemas/AccountOverview"),
+                        "400": error_response(),
+                        "401": error_response(),
+                        "403": error_response(),
+                    },
+                },
+            },
+            "/user-info/{pubkey}/hashtags": {
+                "parameters": [path_parameter("pubkey")],
+                "get": {
+                    "summary": "List the hashtags a pubkey is subscribed to",
+                    "responses": {
+                        "200": json_response("Hashtag subscriptions", "#/components/schemas/HashtagSubscriptions"),
+                        "401": error_response(),
+                        "403": error_response(),
+                    },
+                },
+                "put": {
+                    "summary": "Replace the hashtags a pubkey is subscribed to. Notifications for each hashtag are rate capped per server instance, and the cap starts over when it restarts",
+                    "requestBody": json_request_body("#/components/schemas/HashtagSubscriptions", true),
+                    "responses": {
+                        "200": json_response("Hashtag subscriptions", "#/components/schemas/HashtagSubscriptions"),
+                        "400": error_response(),
+                        "401": error_response(),
+                        "403": error_response(),
+                    },
+                },
+            },
+            "/user-info/{pubkey}/batch": {
+                "parameters": [path_parameter("pubkey")],
+                "post": {
+                    "summary": "Register several devices with their settings at once (e.g. when restoring from a backup), in a single transaction",
+                    "requestBody": json_request_body("#/components/schemas/BatchDeviceRegistration", true),
+                    "responses": {
+                        "200": json_response("Registered devices", "#/components/schemas/BatchDeviceRegistrationResult"),
+                        "400": error_response(),
+                        "401": error_response(),
+                        "403": error_response(),
+                    },
+                },
+            },
+            "/user-info/{pubkey}/{deviceToken}/switch": {
+                "parameters": [path_parameter("pubkey"), path_parameter("deviceToken")],
+                "post": {
+                    "summary": "Switch the account notified on a device. The other accounts on it stop being notified, but keep their settings for switching back",
+                    "description": "The pubkey must already be registered on the device (or have been switched away from), or the switch is forbidden",
+                    "responses": {
+                        "200": json_response("Settings of the account switched to", "#/components/schemas/AccountSwitchResult"),
+                        "400": error_response(),
+                        "401": error_response(),
+                        "403": error_response(),
+                    },
+                },
+            },
+            "/user-info/{pubkey}/{deviceToken}/preferences": {
+                "parameters": [path_parameter("pubkey"), path_parameter("deviceToken")],
+                "get": {
+                    "summary": "Get the notification settings of a device",
+                    "responses": {
+                        "200": json_response("Notification settings", "#/components/schemas/UserNotificationSettings"),
+                        "400": error_response(),
+                        "401": error_response(),
+                    },
+                },
+                "put": {
+                    "summary": "Replace the notification settings of a device",
+                    "requestBody": json_request_body("#/components/schemas/UserNotificationSettings", true),
+                    "responses": {
+                        "200": message_response("User settings saved successfully"),
+                        "400": error_response(),
+                        "401": error_response(),
+                    },
+                },
+                "patch": {
+                    "summary": "Update only the given notification settings of a device",
+                    "requestBody": json_request_body("#/components/schemas/UserNotificationSettings", true),
+                    "responses": {
+                        "200": message_response("User settings saved successfully"),
+                        "400": error_response(),
+                        "401": error_response(),
+                    },
+                },
+            },
+            "/devices/{deviceToken}/pubkeys": {
+                "parameters": [path_parameter("deviceToken")],
+                "get": {
+                    "summary": "List the pubkeys bound to a device token",
+                    "responses": {
+                        "200": json_response("Bound pubkeys", "#/components/schemas/DevicePubkeysWithActivity"),
+                        "401": error_response(),
+                        "403": error_response(),
+                    },
+                },
+            },
+            "/devices/{deviceToken}/pubkeys/unbind": {
+                "parameters": [path_parameter("deviceToken")],
+                "post": {
+                    "summary": "Unbind pubkeys from a device token, as if each of them removed the device",
+                    "description": "Only a pubkey bound to the device token can unbind its pubkeys. Pubkeys are bound by registering the device with their own authentication",
+                    "requestBody": json_request_body("#/components/schemas/DevicePubkeys", true),
+                    "responses": {
+                        "200": json_response("The pubkeys that remain bound", "#/components/schemas/DevicePubkeys"),
+                        "400": error_response(),
+                        "401": error_response(),
+                        "403": error_response(),
+                    },
+                },
+            },
+            "/devices/{deviceToken}/linked-pubkeys": {
+                "parameters": [path_parameter("deviceToken")],
+                "get": {
+                    "summary": "List the pubkeys linked to a device token, whose events do not notify the device",
+                    "responses": {
+                        "200": json_response("Linked pubkeys", "#/components/schemas/DevicePubkeys"),
+                        "401": error_response(),
+                        "403": error_response(),
+                    },
+                },
+                "put": {
+                    "summary": "Replace the pubkeys linked to a device token (e.g. the user's other accounts)",
+                    "requestBody": json_request_body("#/components/schemas/DevicePubkeys", true),
+                    "responses": {
+                        "200": json_response("Linked pubkeys", "#/components/schemas/DevicePubkeys"),
+                        "400": error_response(),
+                        "401": error_response(),
+                        "403": error_response(),
+                    },
+                },
+            },
+            "/live-activities/{pubkey}/{activityToken}": {
+                "parameters": [path_parameter("pubkey"), path_parameter("activityToken")],
+                "put": {
+                    "summary": "Register a Live Activity token that tracks the zaps of an event",
+                    "requestBody": json_request_body("#/components/schemas/LiveActivityRegistration", true),
+                    "responses": {
+                        "200": message_response("Live Activity registered successfully"),
+                        "400": error_response(),
+                        "401": error_response(),
+                    },
+                },
+                "delete": {
+                    "summary": "End a Live Activity",
+                    "responses": {
+                        "200": message_response("Live Activity ended successfully"),
+                        "400": error_response(),
+                        "401": error_response(),
+                    },
+                },
+            },
+            "/admin/stats": {
+                "get": {
+                    "summary": "Delivery statistics (admin pubkeys only)",
+                    "responses": {
+                        "200": { "description": "Delivery statistics", "content": { "application/json": { "schema": { "type": "object" } } } },
+                        "401": error_response(),
+                        "403": error_response(),
+                    },
+                },
+            },
+            "/diagnostics/trace": {
+                "post": {
+                    "summary": "Run a synthetic event through the notification pipeline for a recipient without sending anything, and trace every decision (the recipient or admin pubkeys only)",
+                    "requestBody": json_request_body("#/components/schemas/NotificationTraceRequest", true),
+                    "responses": {
+                        "200": json_response("Notification trace", "#/components/schemas/NotificationTrace"),
+                        "400": error_response(),
+                        "401": error_response(),
+                        "403": error_response(),
+                    },
+                },
+            },
+            "/admin/db/backup": {
+                "post": {
+                    "summary": "Write a consistent snapshot of the database to the backup directory (admin pubkeys only)",
+                    "requestBody": json_request_body("#/components/schemas/DatabaseBackupRequest", true),
+                    "responses": {
+                        "200": { "description": "Backup written", "content": { "application/json": { "schema": { "type": "object" } } } },
+                        "400": error_response(),
+                        "401": error_response(),
+                        "403": error_response(),
+                        "404": error_response(),
+                        "409": error_response(),
+                    },
+                },
+            },
+            "/admin/users/{pubkey}/debug": {
+                "parameters": [path_parameter("pubkey")],
+                "get": {
+                    "summary": "Show the cached lists, devices and recent delivery attempts of a pubkey, for debugging its notifications (admin pubkeys only)",
+                    "responses": {
+                        "200": json_response("User debug info", "#/components/schemas/UserDebugInfo"),
+                        "400": error_response(),
+                        "401": error_response(),
+                        "403": error_response(),
+                    },
+                },
+            },
+            "/admin/held-events": {
+                "get": {
+                    "summary": "List the events held back by the flood guard (admin pubkeys only)",
+                    "responses": {
+                        "200": json_response("Held events", "#/components/schemas/HeldEvents"),
+                        "401": error_response(),
+                        "403": error_response(),
+                    },
+                },
+            },
+            "/admin/held-events/{eventId}/approve": {
+                "parameters": [path_parameter("eventId")],
+                "post": {
+                    "summary": "Send the notifications of a held event (admin pubkeys only)",
+                    "responses": {
+                        "200": message_response("Held event approved"),
+                        "400": error_response(),
+                        "401": error_response(),
+                        "403": error_response(),
+                        "404": error_response(),
+                    },
+                },
+            },
+            "/admin/held-events/{eventId}": {
+                "parameters": [path_parameter("eventId")],
+                "delete": {
+                    "summary": "Discard a held event without notifying anyone (admin pubkeys only)",
+                    "responses": {
+                        "200": message_response("Held event discarded"),
+                        "400": error_response(),
+                        "401": error_response(),
+                        "403": error_response(),
+                        "404": error_response(),
+                    },
+                },
+            },
+            "/admin/db/checkpoint": {
+                "post": {
+                    "summary": "Checkpoint the database write-ahead log (admin pubkeys only)",
+                    "requestBody": json_request_body("#/components/schemas/WalCheckpointRequest", false),
+                    "responses": {
+                        "200": json_response("Checkpoint result", "#/components/schemas/WalCheckpointResult"),
+                        "400": error_response(),
+                        "401": error_response(),
+                        "403": error_response(),
+                    },
+                },
+            },
+            "/admin/audit-log": {
+                "get": {
+                    "summary": "List the audit log of admin requests and destructive user actions, newest first (admin pubkeys only)",
+                    "parameters": [
+                        { "name": "actor", "in": "query", "required": false, "schema": { "type": "string" }, "description": "Only list the requests of this hex pubkey" },
+                        { "name": "before", "in": "query", "required": false, "schema": { "type": "integer" }, "description": "The id of the last entry of the previous page" },
+                        { "name": "limit", "in": "query", "required": false, "schema": { "type": "integer", "minimum": 1, "maximum": 1000, "default": 100 } },
+                    ],
+                    "responses": {
+                        "200": json_response("Audit log entries", "#/components/schemas/AuditLog"),
+                        "400": error_response(),
+                        "401": error_response(),
+                        "403": error_response(),
+                    },
+                },
+            },
+        },
+        "components": {
+            "securitySchemes": {
+                "nip98": {
+                    "type": "http",
+                    "scheme": "Nostr",
+                    "description": "A base64 encoded NIP-98 event in the `Authorization: Nostr <event>` header. A `[\"scope\", \"read\"]` tag restricts it to `GET` requests, for read-only tools",
+                },
+            },
+            "schemas": {
+                "Message": {
+                    "type": "object",
+                    "properties": { "message": { "type": "string" } },
+                },
+                "Error": {
+                    "type": "object",
+                    "properties": {
+                        "error": { "type": "string" },
+                        "message": { "type": "string" },
+                        // Where parsing failed, for malformed JSON bodies
+                        "line": { "type": "integer" },
+                        "column": { "type": "integer" },
+                    },
+                    "required": ["error"],
+                },
+                "ReadinessStatus": {
+                    "type": "object",
+                    "properties": {
+                        "status": { "type": "string", "enum": ["ok", "unavailable"] },
+                        "relays": {
+                            "type": "object",
+                            "properties": { "connected": { "type": "integer" }, "total": { "type": "integer" } },
+                        },
+                    },
+                },
+                "DeviceRegistration": {
+                    "type": "object",
+                    "properties": {
+                        "webhook": {
+                            "type": "object",
+                            "properties": { "url": { "type": "string", "format": "uri" }, "secret": { "type": "string" } },
+                            "required": ["url", "secret"],
+                            "description": "The url must use https and resolve to a public address. Registering without a webhook removes the one set before",
+                        },
+                        "token_type": { "type": "string", "enum": ["apns"], "description": "The kind of device token, which decides the push provider notifications are sent through. Defaults to `apns`" },
+                        "tenant": { "type": "string", "description": "The ID of the app whose APNS credentials are used, if not the default app" },
+                        "locale": { "type": "string" },
+                        "app_version": { "type": "string" },
+                        "os_version": { "type": "string" },
+                        "payload_version": { "type": "integer", "description": "The newest push payload version the app understands. Defaults to 1, the legacy layout. 2 is the `notepush` envelope, and 3 sends large events as base64 of their zstd-compressed JSON in `event_zstd` instead of `event`" },
+                    },
+                },
+                "BatchDeviceRegistration": {
+                    "type": "object",
+                    "properties": {
+                        "devices": {
+                            "type": "array",
+                            "maxItems": 50,
+                            "items": {
+                                "type": "object",
+                                "properties": {
+                                    "device_token": { "type": "string" },
+                                    "token_type": { "type": "string", "enum": ["apns"] },
+                                    "settings": { "$ref": "#/components/schemas/UserNotificationSettings" },
+                                    "payload_version": { "type": "integer" },
+                                    "locale": { "type": "string" },
+                                    "app_version": { "type": "string" },
+                                    "os_version": { "type": "string" },
+                                },
+                                "required": ["device_token"],
+                            },
+                        },
+                    },
+                    "required": ["devices"],
+                },
+                "BatchDeviceRegistrationResult": {
+                    "type": "object",
+                    "properties": {
+                        "devices": {
+                            "type": "array",
+                            "items": {
+                                "type": "object",
+                                "properties": {
+                                    "device_token": { "type": "string" },
+                                    "created": { "type": "boolean" },
+                                    "payload_version": { "type": "integer" },
+                                },
+                            },
+                        },
+                    },
+                },
+                "DeviceRegistrationResult": {
+                    "type": "object",
+                    "properties": {
+                        "message": { "type": "string" },
+                        "payload_version": { "type": "integer", "description": "The push payload version this device will receive" },
+                    },
+                },
+                "UserNotificationSettings": {
+                    "type": "object",
+                    "properties": {
+                        "settings_version": { "type": "integer", "readOnly": true },
+                        "zap_notifications_enabled": { "type": "boolean" },
+                        "mention_notifications_enabled": { "type": "boolean" },
+                        "repost_notifications_enabled": { "type": "boolean" },
+                        "reaction_notifications_enabled": { "type": "boolean" },
+                        "dm_notifications_enabled": { "type": "boolean" },
+                        "only_notifications_from_following_enabled": { "type": "boolean" },
+                        "strangers_to_requests_folder_enabled": { "type": "boolean" },
+                        "dm_only_from_following_enabled": { "type": "boolean", "description": "Only notify about NIP-04 DMs from follows. NIP-17 DM senders are only known to the app" },
+                        "weekly_summary_enabled": { "type": "boolean", "description": "Opt in to a weekly push summarizing the mentions and zaps of the past week" },
+                        "sensitive_content_filter": { "type": "string", "enum": ["show", "blank", "suppress"], "description": "What to do with notifications about events with a content warning or a sensitive hashtag" },
+                        "mention_min_proof_of_work": { "type": "integer", "minimum": 0, "maximum": 255, "description": "The minimum NIP-13 proof-of-work difficulty of text notes from non-follows. 0 disables it" },
+                        "delivery_window": {
+                            "type": "object",
+                            "nullable": true,
+                            "description": "A daily window outside of which the listed kinds are held back and sent as a digest once it opens. DMs and zaps are always delivered right away",
+                            "properties": {
+                                "start_minute": { "type": "integer", "minimum": 0, "maximum": 1439, "description": "Minutes after local midnight" },
+                                "end_minute": { "type": "integer", "minimum": 0, "maximum": 1439, "description": "Minutes after local midnight. A window ending before it starts spans midnight" },
+                                "utc_offset_minutes": { "type": "integer", "description": "The offset of the device's local time from UTC" },
+                                "kinds": { "type": "array", "items": { "type": "string", "enum": ["reply", "mention", "repost", "reaction"] } },
+                            },
+                            "required": ["start_minute", "end_minute", "kinds"],
+                        },
+                    },
+                },
+                "DevicePubkeys": {
+                    "type": "object",
+                    "properties": { "pubkeys": { "type": "array", "items": { "type": "string" } } },
+                    "required": ["pubkeys"],
+                },
+                "AccountOverview": {
+                    "type": "object",
+                    "properties": {
+                        "pubkey": { "type": "string" },
+                        "devices": {
+                            "type": "array",
+                            "items": {
+                                "type": "object",
+                                "properties": {
+                                    "device_token": { "type": "string" },
+                                    "added_at": { "type": "integer", "nullable": true },
+                                    "last_notified_at": { "type": "integer", "nullable": true },
+                                    "payload_version": { "type": "integer" },
+                                    "locale": { "type": "string", "nullable": true },
+                                    "app_version": { "type": "string", "nullable": true },
+                                    "os_version": { "type": "string", "nullable": true },
+                                    "settings": { "$ref": "#/components/schemas/UserNotificationSettings" },
+                                    "linked_pubkeys": { "type": "array", "items": { "type": "string" } },
+                                },
+                            },
+                        },
+                        "hashtags": { "$ref": "#/components/schemas/HashtagSubscriptions/properties/hashtags" },
+                    },
+                },
+                "UserDebugInfo": {
+                    "type": "object",
+                    "properties": {
+                        "pubkey": { "type": "string" },
+                        "cache": {
+                            "type": "object",
+                            "description": "The cached lists of the pubkey. A null entry is not cached, so the next lookup fetches it from the relays",
+                            "properties": {
+                                "mute_list": { "$ref": "#/components/schemas/CacheEntryStatus" },
+                                "contact_list": { "$ref": "#/components/schemas/CacheEntryStatus" },
+                                "relay_list": { "$ref": "#/components/schemas/CacheEntryStatus" },
+                                "profile": { "$ref": "#/components/schemas/CacheEntryStatus" },
+                            },
+                        },
+                        "devices": { "$ref": "#/components/schemas/AccountOverview/properties/devices" },
+                        "recent_deliveries": {
+                            "type": "array",
+                            "description": "The most recent delivery attempts, newest first",
+                            "items": {
+                                "type": "object",
+                                "properties": {
+                                    "event_id": { "type": "string" },
+                                    "device_token": { "type": "string" },
+                                    "apns_id": { "type": "string", "nullable": true },
+                                    "status": { "type": "integer", "nullable": true, "description": "The HTTP status APNS answered with" },
+                                    "reason": { "type": "string", "nullable": true },
+                                    "success": { "type": "boolean" },
+                                    "sent_at": { "type": "integer" },
+                                },
+                            },
+                        },
+                    },
+                },
+                "CacheEntryStatus": {
+                    "type": "object",
+                    "nullable": true,
+                    "properties": {
+                        "age_seconds": { "type": "integer" },
+                        "expired": { "type": "boolean" },
+                        "found": { "type": "boolean", "description": "false if the relays answered that the pubkey has no such list" },
+                    },
+                },
+                "ServerCapabilities": {
+                    "type": "object",
+                    "properties": {
+                        "settings_version": { "type": "integer" },
+                        "payload_versions": { "type": "array", "items": { "type": "integer" } },
+                        "notification_kinds": {
+                            "type": "array",
+                            "items": {
+                                "type": "object",
+                                "properties": {
+                                    "kind": { "type": "string" },
+                                    "event_kinds": { "type": "array", "items": { "type": "integer" } },
+                                    "setting": { "type": "string", "description": "The UserNotificationSettings field that enables this kind" },
+                                    "silent": { "type": "boolean" },
+                                },
+                            },
+                        },
+                        "digests": {
+                            "type": "array",
+                            "items": {
+                                "type": "object",
+                                "properties": { "digest": { "type": "string" }, "setting": { "type": "string" } },
+                            },
+                        },
+                        "sounds": { "type": "array", "items": { "type": "string" } },
+                        "sensitive_content_filters": { "type": "array", "items": { "type": "string", "enum": ["show", "blank", "suppress"] } },
+                        "max_hashtag_subscriptions": { "type": "integer" },
+                        "hashtag_scopes": { "type": "array", "items": { "type": "string", "enum": ["following", "global"] } },
+                    },
+                },
+                "AccountSwitchResult": {
+                    "type": "object",
+                    "properties": {
+                        "settings": { "$ref": "#/components/schemas/UserNotificationSettings" },
+                        "switched_away_pubkeys": { "type": "array", "items": { "type": "string" } },
+                    },
+                },
+                "HashtagSubscriptions": {
+                    "type": "object",
+                    "properties": {
+                        "hashtags": {
+                            "type": "array",
+                            "maxItems": 50,
+                            "items": {
+                                "type": "object",
+                                "properties": {
+                                    "hashtag": { "type": "string", "description": "Matched case-insensitively, without the leading `#`" },
+                                    "scope": { "type": "string", "enum": ["following", "global"], "default": "following", "description": "Whether only notes from follows or from anyone trigger notifications" },
+                                },
+                                "required": ["hashtag"],
+                            },
+                        },
+                    },
+                    "required": ["hashtags"],
+                },
+                "DevicePubkeysWithActivity": {
+                    "type": "object",
+                    "properties": {
+                        "pubkeys": { "type": "array", "items": { "type": "string" } },
+                        "last_notified_at": {
+                            "type": "object",
+                            "description": "The UNIX timestamp of the last successful notification to the device for each bound pubkey, or null if there was none",
+                            "additionalProperties": { "type": "integer", "nullable": true },
+                        },
+                    },
+                    "required": ["pubkeys", "last_notified_at"],
+                },
+                "HeldEvents": {
+                    "type": "object",
+                    "properties": {
+                        "held_events": {
+                            "type": "array",
+                            "items": {
+                                "type": "object",
+                                "properties": {
+                                    "event_id": { "type": "string" },
+                                    "kind": { "type": "integer" },
+                                    "author": { "type": "string" },
+                                    "recipient_count": { "type": "integer", "description": "The number of pubkeys it would have notified when it was held" },
+                                    "held_at": { "type": "integer" },
+                                },
+                            },
+                        },
+                    },
+                },
+                "NotificationTraceRequest": {
+                    "type": "object",
+                    "properties": {
+                        "event": { "type": "object", "description": "A Nostr event. It does not have to be signed" },
+                        "recipient": { "type": "string", "description": "The hex pubkey to trace the notification for" },
+                    },
+                    "required": ["event", "recipient"],
+                },
+                "NotificationTrace": {
+                    "type": "object",
+                    "properties": {
+                        "event_id": { "type": "string" },
+                        "recipient": { "type": "string" },
+                        "would_notify": { "type": "boolean", "description": "Whether every step passed, on at least one device" },
+                        "steps": { "type": "array", "items": { "$ref": "#/components/schemas/TraceStep" } },
+                        "devices": {
+                            "type": "array",
+                            "items": {
+                                "type": "object",
+                                "properties": {
+                                    "device_token": { "type": "string" },
+                                    "steps": { "type": "array", "items": { "$ref": "#/components/schemas/TraceStep" } },
+                                },
+                            },
+                        },
+                    },
+                },
+                "TraceStep": {
+                    "type": "object",
+                    "properties": {
+                        "check": { "type": "string", "description": "e.g. registered, not_muted, settings or payload_size" },
+                        "passed": { "type": "boolean" },
+                        "detail": { "type": "string" },
+                    },
+                    "required": ["check", "passed"],
+                },
+                "AuditLog": {
+                    "type": "object",
+                    "properties": {
+                        "entries": {
+                            "type": "array",
+                            "items": {
+                                "type": "object",
+                                "properties": {
+                                    "id": { "type": "integer" },
+                                    "actor_pubkey": { "type": "string" },
+                                    "method": { "type": "string" },
+                                    "path": { "type": "string" },
+                                    "status": { "type": "integer", "description": "The HTTP status of the response" },
+                                    "request_id": { "type": "string" },
+                                    "created_at": { "type": "integer" },
+                                },
+                            },
+                        },
+                    },
+                },
+                "DatabaseBackupRequest": {
+                    "type": "object",
+                    "properties": { "file_name": { "type": "string", "description": "A file name within the backup directory" } },
+                    "required": ["file_name"],
+                },
+                "WalCheckpointRequest": {
+                    "type": "object",
+                    "properties": { "mode": { "type": "string", "enum": ["passive", "full", "restart", "truncate"], "default": "passive" } },
+                },
+                "WalCheckpointResult": {
+                    "type": "object",
+                    "properties": {
+                        "busy": { "type": "boolean" },
+                        "wal_pages": { "type": "integer" },
+                        "checkpointed_pages": { "type": "integer" },
+                    },
+                },
+                "LiveActivityRegistration": {
+                    "type": "object",
+                    "properties": { "event_id": { "type": "string" } },
+                    "required": ["event_id"],
+                },
+            },
+        },
+    })
+}
+
+// MARK: - Helpers
+
+fn path_parameter(name: &str) -> Value {
+    json!({ "name": name, "in": "path", "required": true, "schema": { "type": "string" } })
+}
+
+fn json_request_body(schema_ref: &str, required: bool) -> Value {
+    json!({
+        "required": required,
+        "content": { "application/json": { "schema": { "$ref": schema_ref } } },
+    })
+}
+
+fn json_response(description: &str, schema_ref: &str) -> Value {
+    json!({
+        "description": description,
+        "content": { "application/json": { "schema": { "$ref": schema_ref } } },
+    })
+}
+
+fn message_response(description: &str) -> Value {
+    json_response(description, "#/components/schemas/Message")
+}
+
+fn error_response() -> Value {
+    json_response("Error", "#/components/schemas/Error")
+}
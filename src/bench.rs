@@ -0,0 +1,215 @@
+use crate::ingestion_queue::{EnqueueError, IngestionQueue, QueuedEventMaxAges};
+use crate::notepush_env::NotePushEnv;
+use crate::notification_manager::push_provider::{PushMessage, PushProvider, PushReceipt, TokenType};
+use futures::future::BoxFuture;
+use nostr::{EventBuilder, Keys, Tag};
+use std::collections::{HashMap, HashSet};
+use std::sync::atomic::{AtomicUsize, Ordering};
+use std::sync::Arc;
+use std::time::{Duration, Instant};
+
+const DEFAULT_BENCH_EVENTS: usize = 1000;
+const DEFAULT_BENCH_FAN_OUT: usize = 10;
+// How long to wait before retrying when the ingestion queue is above its high-water mark
+const BACKPRESSURE_RETRY_DELAY: Duration = Duration::from_millis(1);
+// How often to check whether all events were processed
+const COMPLETION_POLL_INTERVAL: Duration = Duration::from_millis(10);
+// How long to wait for all events to be processed before giving up, e.g. when some were dropped
+const COMPLETION_TIMEOUT: Duration = Duration::from_secs(600);
+
+/// What the benchmark drives through the server
+struct BenchOptions {
+    // The number of synthetic events
+    events: usize,
+    // The number of registered recipients tagged in every event
+    fan_out: usize,
+    // The number of ingestion workers processing events concurrently
+    workers: usize,
+}
+
+impl BenchOptions {
+    /// Parses `--events N`, `--fan-out N` and `--workers N`, falling back to the defaults and the configured ingestion workers
+    fn parse(mut args: impl Iterator<Item = String>, default_workers: usize) -> Result<Self, String> {
+        let mut options = BenchOptions {
+            events: DEFAULT_BENCH_EVENTS,
+            fan_out: DEFAULT_BENCH_FAN_OUT,
+            workers: default_workers,
+        };
+        while let Some(flag) = args.next() {
+            let value = args
+                .next()
+                .and_then(|value| value.parse::<usize>().ok())
+                .filter(|value| *value > 0)
+                .ok_or_else(|| format!("{} needs a positive number", flag))?;
+            match flag.as_str() {
+                "--events" => options.events = value,
+                "--fan-out" => options.fan_out = value,
+                "--workers" => options.workers = value,
+                _ => return Err(format!("Unknown option {}", flag)),
+            }
+        }
+        Ok(options)
+    }
+}
+
+// MARK: - Running the benchmark
+
+/// Drives synthetic events through the ingestion queue and the notification pipeline, and prints the throughput and per-phase latency.
+/// Uses an in-memory database and delivers through a mock push provider, so that neither production data nor APNS are touched,
+/// while the push phase still runs. Relay data is assumed not to exist, since the benchmark measures this server rather than the relays
+pub async fn run(mut env: NotePushEnv, args: impl Iterator<Item = String>) -> Result<(), Box<dyn std::error::Error>> {
+    let options = BenchOptions::parse(args, env.ingestion_workers)?;
+    // Every connection to `:memory:` opens its own database, so the pool must hold a single connection
+    env.db_path = ":memory:".to_string();
+    env.db_pool_size = 1;
+    env.db_encryption_key = None;
+    env.db_encryption_key_path = None;
+    env.shard_count = 1;
+    env.shard_index = 0;
+    env.event_min_age_seconds = None;
+    // Every event is a mass notification by design, so the guards against them must not interfere
+    env.flood_guard_threshold = 0;
+    env.spam_max_pubkey_tags = None;
+    env.max_processed_pubkey_tags = env.max_processed_pubkey_tags.max(options.fan_out);
+    // Injected faults would make the setup fail and the numbers meaningless
+    env.fault_injection_probabilities.clear();
+
+    let mut notification_manager = crate::build_notification_manager(&env).await;
+    let sent_pushes = Arc::new(AtomicUsize::new(0));
+    notification_manager.replace_push_provider(TokenType::Apns, Box::new(MockPushProvider { sent_pushes: sent_pushes.clone() }));
+    let notification_manager = Arc::new(notification_manager);
+
+    // MARK: - Setup recipients and events
+
+    let author = Keys::generate();
+    let recipients: Vec<Keys> = (0..options.fan_out).map(|_| Keys::generate()).collect();
+    for (index, recipient) in recipients.iter().enumerate() {
+        let device_token = format!("{:064x}", index);
+        notification_manager.save_user_device_info(recipient.public_key(), &device_token).await?;
+    }
+    let mut pubkeys: HashSet<_> = recipients.iter().map(|recipient| recipient.public_key()).collect();
+    pubkeys.insert(author.public_key());
+    notification_manager.assume_no_relay_data_for_pubkeys(&pubkeys).await;
+
+    let mut events = Vec::with_capacity(options.events);
+    for index in 0..options.events {
+        let tags = recipients.iter().map(|recipient| Tag::public_key(recipient.public_key()));
+        events.push(EventBuilder::text_note(format!("Benchmark note {}", index), tags).to_event(&author)?);
+    }
+
+    // MARK: - Drive the events
+
+    let ingestion_queue = IngestionQueue::start(
+        notification_manager.clone(),
+        env.ingestion_queue_capacity,
+        env.ingestion_queue_high_water_mark,
+        options.workers,
+        // Events that waited long in the queue must still be processed, or they would never be counted as done
+        QueuedEventMaxAges {
+            max_age_by_kind: HashMap::new(),
+            default_max_age: Duration::MAX,
+        },
+    );
+    println!(
+        "Sending {} events to {} recipients each with {} workers",
+        options.events, options.fan_out, options.workers
+    );
+    let started_at = Instant::now();
+    for event in events {
+        loop {
+            match ingestion_queue.try_enqueue(event.clone()) {
+                Ok(()) => break,
+                Err(EnqueueError::Backpressure) => tokio::time::sleep(BACKPRESSURE_RETRY_DELAY).await,
+                Err(EnqueueError::Closed) => return Err("The ingestion workers stopped".into()),
+            }
+        }
+    }
+    while processed_event_count(&notification_manager) < options.events as u64 {
+        if started_at.elapsed() > COMPLETION_TIMEOUT {
+            return Err(format!(
+                "Timed out after {:?} with {} of {} events processed",
+                COMPLETION_TIMEOUT,
+                processed_event_count(&notification_manager),
+                options.events
+            )
+            .into());
+        }
+        tokio::time::sleep(COMPLETION_POLL_INTERVAL).await;
+    }
+    let elapsed = started_at.elapsed();
+
+    // MARK: - Report
+
+    let sent_pushes = sent_pushes.load(Ordering::SeqCst);
+    println!("Processed {} events in {:.2?}", options.events, elapsed);
+    println!("Events/sec: {:.1}", options.events as f64 / elapsed.as_secs_f64());
+    println!(
+        "Notifications delivered: {} of {} ({:.1}/sec)",
+        sent_pushes,
+        options.events * options.fan_out,
+        sent_pushes as f64 / elapsed.as_secs_f64()
+    );
+    println!("{:<16}{:>10}{:>14}{:>14}", "phase", "count", "mean", "p95 <=");
+    for summary in notification_manager.latency_summaries() {
+        let p95_upper_bound = match summary.p95_upper_bound {
+            Some(bound) => format!("{:.2?}", bound),
+            None => "+Inf".to_string(),
+        };
+        println!(
+            "{:<16}{:>10}{:>14}{:>14}",
+            summary.phase,
+            summary.count,
+            format!("{:.2?}", summary.mean),
+            p95_upper_bound
+        );
+    }
+    Ok(())
+}
+
+/// The number of events the ingestion workers finished processing
+fn processed_event_count(notification_manager: &crate::notification_manager::NotificationManager) -> u64 {
+    notification_manager
+        .latency_summaries()
+        .iter()
+        .find(|summary| summary.phase == "total")
+        .map(|summary| summary.count)
+        .unwrap_or(0)
+}
+
+// MARK: - Mock push provider
+
+/// Accepts every push without sending it, counting them.
+/// Stands in for APNS, so that the benchmark measures this server rather than Apple's
+struct MockPushProvider {
+    sent_pushes: Arc<AtomicUsize>,
+}
+
+impl PushProvider for MockPushProvider {
+    fn name(&self) -> &'static str {
+        "mock"
+    }
+
+    fn validate_token(&self, _token: &str) -> bool {
+        true
+    }
+
+    fn payload_size(&self, message: &PushMessage) -> Result<usize, Box<dyn std::error::Error>> {
+        let alert_size = message.alert.as_ref().map_or(0, |alert| alert.title.len() + alert.body.len());
+        let data_size: usize = message.data.iter().map(|(key, value)| key.len() + value.to_string().len()).sum();
+        Ok(alert_size + data_size)
+    }
+
+    fn send<'a>(&'a self, _message: &'a PushMessage) -> BoxFuture<'a, Option<PushReceipt>> {
+        Box::pin(async move {
+            self.sent_pushes.fetch_add(1, Ordering::SeqCst);
+            Some(PushReceipt {
+                message_id: None,
+                status: Some(200),
+                reason: None,
+                success: true,
+                is_token_unusable: false,
+                destination: "mock".to_string(),
+            })
+        })
+    }
+}
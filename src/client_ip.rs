@@ -0,0 +1,193 @@
+use hyper::HeaderMap;
+use ipnet::IpNet;
+use std::net::IpAddr;
+
+/// The reverse proxies (e.g. nginx) allowed to tell us the client IP through the `Forwarded` or `X-Forwarded-For` headers
+#[derive(Debug, Clone, Default)]
+pub struct TrustedProxies {
+    networks: Vec<IpNet>,
+}
+
+impl TrustedProxies {
+    // MARK: - Initialization
+
+    /// Parses a comma-separated list of CIDRs or plain IP addresses, returning the first invalid entry on failure
+    pub fn parse(trusted_proxies: &str) -> Result<Self, String> {
+        let mut networks = Vec::new();
+        for entry in trusted_proxies.split(',').map(|entry| entry.trim()).filter(|entry| !entry.is_empty()) {
+            let network = match entry.parse::<IpNet>() {
+                Ok(network) => network,
+                Err(_) => entry.parse::<IpAddr>().map(IpNet::from).map_err(|_| entry.to_string())?,
+            };
+            networks.push(network);
+        }
+        Ok(TrustedProxies { networks })
+    }
+
+    // MARK: - Client IP extraction
+
+    /// Works out the IP of the client from the address of the peer and the forwarding headers it sent.
+    /// Forwarding headers are only believed from trusted proxies, and the client is the rightmost untrusted hop.
+    pub fn client_ip(&self, peer_ip: IpAddr, headers: &HeaderMap) -> IpAddr {
+        if !self.is_trusted(&peer_ip) {
+            return peer_ip;
+        }
+        // `Forwarded` (RFC 7239) takes precedence, since it is the standard and harder to confuse with client-provided values
+        let forwarded_hops = match Self::forwarded_hops(headers) {
+            hops if !hops.is_empty() => hops,
+            _ => Self::x_forwarded_for_hops(headers),
+        };
+        let mut client_ip = peer_ip;
+        for hop in forwarded_hops.into_iter().rev() {
+            let hop = match hop {
+                Some(hop) => hop,
+                // Obfuscated or unparsable hops end the chain of trust
+                None => break,
+            };
+            client_ip = hop;
+            if !self.is_trusted(&hop) {
+                break;
+            }
+        }
+        client_ip
+    }
+
+    fn is_trusted(&self, ip: &IpAddr) -> bool {
+        let ip = Self::canonical_ip(*ip);
+        self.networks.iter().any(|network| network.contains(&ip))
+    }
+
+    /// Maps IPv4-mapped IPv6 addresses (e.g. `::ffff:127.0.0.1` on a dual-stack socket) to plain IPv4
+    fn canonical_ip(ip: IpAddr) -> IpAddr {
+        match ip {
+            IpAddr::V6(ipv6) => ipv6.to_ipv4_mapped().map(IpAddr::V4).unwrap_or(ip),
+            ip => ip,
+        }
+    }
+
+    /// The `for=` hops of all `Forwarded` headers, in order
+    fn forwarded_hops(headers: &HeaderMap) -> Vec<Option<IpAddr>> {
+        headers
+            .get_all(hyper::header::FORWARDED)
+            .iter()
+            .filter_map(|value| value.to_str().ok())
+            .flat_map(|value| value.split(','))
+            .filter_map(|element| {
+                element
+                    .split(';')
+                    .filter_map(|pair| pair.split_once('='))
+                    .find(|(name, _)| name.trim().eq_ignore_ascii_case("for"))
+                    .map(|(_, node)| Self::parse_node(node))
+            })
+            .collect()
+    }
+
+    /// The hops of all `X-Forwarded-For` headers, in order
+    fn x_forwarded_for_hops(headers: &HeaderMap) -> Vec<Option<IpAddr>> {
+        headers
+            .get_all("x-forwarded-for")
+            .iter()
+            .filter_map(|value| value.to_str().ok())
+            .flat_map(|value| value.split(','))
+            .map(Self::parse_node)
+            .collect()
+    }
+
+    /// Parses a node such as `192.0.2.1`, `"[2001:db8::1]:4711"` or `192.0.2.1:4711`, returning `None` for obfuscated nodes
+    fn parse_node(node: &str) -> Option<IpAddr> {
+        let node = node.trim().trim_matches('"');
+        if let Ok(ip) = node.parse::<IpAddr>() {
+            return Some(Self::canonical_ip(ip));
+        }
+        let host = match node.strip_prefix('[') {
+            Some(bracketed) => bracketed.split(']').next()?,
+            None => node.rsplit_once(':').map(|(host, _port)| host)?,
+        };
+        host.parse::<IpAddr>().ok().map(Self::canonical_ip)
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn ip(ip: &str) -> IpAddr {
+        ip.parse().unwrap()
+    }
+
+    fn headers(pairs: &[(&'static str, &str)]) -> HeaderMap {
+        let mut headers = HeaderMap::new();
+        for (name, value) in pairs {
+            headers.append(*name, value.parse().unwrap());
+        }
+        headers
+    }
+
+    // MARK: - Parsing
+
+    #[test]
+    fn trusted_proxies_accept_cidrs_and_plain_addresses() {
+        let trusted_proxies = TrustedProxies::parse(" 10.0.0.0/8, 192.0.2.1,,2001:db8::/32 ").unwrap();
+
+        assert!(trusted_proxies.is_trusted(&ip("10.1.2.3")));
+        assert!(trusted_proxies.is_trusted(&ip("192.0.2.1")));
+        assert!(!trusted_proxies.is_trusted(&ip("192.0.2.2")));
+        assert!(trusted_proxies.is_trusted(&ip("2001:db8::1")));
+    }
+
+    #[test]
+    fn trusted_proxies_report_the_first_invalid_entry() {
+        assert_eq!(TrustedProxies::parse("10.0.0.0/8, nginx, 10.0.0.0/40").unwrap_err(), "nginx");
+    }
+
+    #[test]
+    fn no_proxies_are_trusted_by_default() {
+        let trusted_proxies = TrustedProxies::parse("").unwrap();
+
+        assert_eq!(trusted_proxies.client_ip(ip("127.0.0.1"), &headers(&[("x-forwarded-for", "203.0.113.7")])), ip("127.0.0.1"));
+    }
+
+    // MARK: - Client IP extraction
+
+    #[test]
+    fn forwarding_headers_of_untrusted_peers_are_ignored() {
+        let trusted_proxies = TrustedProxies::parse("10.0.0.1").unwrap();
+
+        assert_eq!(trusted_proxies.client_ip(ip("198.51.100.1"), &headers(&[("x-forwarded-for", "203.0.113.7")])), ip("198.51.100.1"));
+    }
+
+    #[test]
+    fn client_is_the_rightmost_untrusted_hop() {
+        let trusted_proxies = TrustedProxies::parse("10.0.0.0/8").unwrap();
+        // The leftmost hop was made up by the client
+        let headers = headers(&[("x-forwarded-for", "1.1.1.1, 203.0.113.7, 10.0.0.2")]);
+
+        assert_eq!(trusted_proxies.client_ip(ip("10.0.0.1"), &headers), ip("203.0.113.7"));
+    }
+
+    #[test]
+    fn forwarded_header_takes_precedence_over_x_forwarded_for() {
+        let trusted_proxies = TrustedProxies::parse("10.0.0.1").unwrap();
+        let headers = headers(&[
+            ("forwarded", "for=\"[2001:db8::7]:4711\";proto=https"),
+            ("x-forwarded-for", "203.0.113.7"),
+        ]);
+
+        assert_eq!(trusted_proxies.client_ip(ip("10.0.0.1"), &headers), ip("2001:db8::7"));
+    }
+
+    #[test]
+    fn obfuscated_hops_end_the_chain_of_trust() {
+        let trusted_proxies = TrustedProxies::parse("10.0.0.0/8").unwrap();
+        let headers = headers(&[("forwarded", "for=203.0.113.7, for=_hidden, for=10.0.0.2")]);
+
+        assert_eq!(trusted_proxies.client_ip(ip("10.0.0.1"), &headers), ip("10.0.0.2"));
+    }
+
+    #[test]
+    fn ipv4_mapped_peers_are_matched_as_ipv4() {
+        let trusted_proxies = TrustedProxies::parse("127.0.0.1").unwrap();
+
+        assert_eq!(trusted_proxies.client_ip(ip("::ffff:127.0.0.1"), &headers(&[("x-forwarded-for", "203.0.113.7:8080")])), ip("203.0.113.7"));
+    }
+}
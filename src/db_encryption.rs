@@ -0,0 +1,53 @@
+use r2d2::CustomizeConnection;
+use rusqlite::Connection;
+use thiserror::Error;
+
+/// Unlocks every pooled connection of a SQLCipher-encrypted database with the given key
+#[derive(Debug)]
+pub struct DatabaseEncryptionKey {
+    key: String,
+}
+
+impl DatabaseEncryptionKey {
+    // MARK: - Initialization
+
+    /// Gets the key from its value, or from the first line of the file at `key_path`. Returns `None` if neither is set
+    pub fn load(key: Option<String>, key_path: Option<&str>) -> Result<Option<Self>, DatabaseEncryptionError> {
+        let key = match (key, key_path) {
+            (Some(key), _) => key,
+            (None, Some(key_path)) => std::fs::read_to_string(key_path)?
+                .lines()
+                .next()
+                .unwrap_or_default()
+                .to_string(),
+            (None, None) => return Ok(None),
+        };
+        if key.is_empty() {
+            return Err(DatabaseEncryptionError::EmptyKey);
+        }
+        // Plain SQLite silently ignores the key, which would leave the database unencrypted
+        if !cfg!(feature = "sqlcipher") {
+            return Err(DatabaseEncryptionError::SqlCipherUnavailable);
+        }
+        Ok(Some(DatabaseEncryptionKey { key }))
+    }
+}
+
+impl CustomizeConnection<Connection, rusqlite::Error> for DatabaseEncryptionKey {
+    fn on_acquire(&self, connection: &mut Connection) -> Result<(), rusqlite::Error> {
+        connection.pragma_update(None, "key", &self.key)?;
+        // SQLCipher only checks the key when the database is first read, so read it now to fail early on a wrong key
+        connection.query_row("SELECT COUNT(*) FROM sqlite_master", [], |row| row.get::<_, i64>(0))?;
+        Ok(())
+    }
+}
+
+#[derive(Debug, Error)]
+pub enum DatabaseEncryptionError {
+    #[error("Failed to read the database encryption key file: {0}")]
+    KeyFileUnreadable(#[from] std::io::Error),
+    #[error("The database encryption key is empty")]
+    EmptyKey,
+    #[error("A database encryption key is set, but notepush was built without the `sqlcipher` feature")]
+    SqlCipherUnavailable,
+}
@@ -0,0 +1,97 @@
+use nostr::PublicKey;
+use std::collections::HashMap;
+use tokio::sync::Mutex;
+use tokio::time::Instant;
+
+// Above this many tracked pubkeys, the buckets that have refilled completely are pruned
+const MAX_TRACKED_PUBKEYS_BEFORE_PRUNING: usize = 10_000;
+
+/// Limits the rate of messages sent to the embedded relay, per connection and per NIP-42 authenticated pubkey,
+/// so that a single client cannot flood the ingestion pipeline
+pub struct EventRateLimiter {
+    connection_messages_per_second: u32,
+    pubkey_events_per_second: u32,
+    burst: u32,
+    // Shared by all connections, since a pubkey can open several of them
+    pubkey_buckets: Mutex<HashMap<PublicKey, TokenBucket>>,
+}
+
+/// A token bucket for a single connection or pubkey
+pub struct TokenBucket {
+    tokens: f64,
+    // The bucket size, i.e. how many messages may burst at once
+    capacity: f64,
+    tokens_per_second: f64,
+    last_refill: Instant,
+}
+
+impl EventRateLimiter {
+    // MARK: - Initialization
+
+    /// Creates a limiter. A rate of 0 disables the corresponding limit
+    pub fn new(connection_messages_per_second: u32, pubkey_events_per_second: u32, burst: u32) -> Self {
+        EventRateLimiter {
+            connection_messages_per_second,
+            pubkey_events_per_second,
+            burst,
+            pubkey_buckets: Mutex::new(HashMap::new()),
+        }
+    }
+
+    /// A new bucket for a connection, or `None` if connections are not rate limited
+    pub fn connection_bucket(&self) -> Option<TokenBucket> {
+        (self.connection_messages_per_second > 0)
+            .then(|| TokenBucket::new(self.connection_messages_per_second, self.burst))
+    }
+
+    // MARK: - Limiting
+
+    /// Takes a token for an event published by an authenticated pubkey, returning whether the event is allowed
+    pub async fn allow_pubkey_event(&self, pubkey: &PublicKey) -> bool {
+        if self.pubkey_events_per_second == 0 {
+            return true;
+        }
+        let mut pubkey_buckets = self.pubkey_buckets.lock().await;
+        if pubkey_buckets.len() > MAX_TRACKED_PUBKEYS_BEFORE_PRUNING {
+            pubkey_buckets.retain(|_, bucket| !bucket.is_full());
+        }
+        pubkey_buckets
+            .entry(*pubkey)
+            .or_insert_with(|| TokenBucket::new(self.pubkey_events_per_second, self.burst))
+            .try_take()
+    }
+}
+
+impl TokenBucket {
+    pub fn new(tokens_per_second: u32, burst: u32) -> Self {
+        let capacity = burst.max(1) as f64;
+        TokenBucket {
+            tokens: capacity,
+            capacity,
+            tokens_per_second: tokens_per_second as f64,
+            last_refill: Instant::now(),
+        }
+    }
+
+    /// Takes a token if one is available, returning whether it was
+    pub fn try_take(&mut self) -> bool {
+        self.refill();
+        if self.tokens >= 1.0 {
+            self.tokens -= 1.0;
+            return true;
+        }
+        false
+    }
+
+    fn is_full(&mut self) -> bool {
+        self.refill();
+        self.tokens >= self.capacity
+    }
+
+    fn refill(&mut self) {
+        let now = Instant::now();
+        let elapsed = now.duration_since(self.last_refill).as_secs_f64();
+        self.tokens = (self.tokens + elapsed * self.tokens_per_second).min(self.capacity);
+        self.last_refill = now;
+    }
+}
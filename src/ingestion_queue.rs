@@ -0,0 +1,145 @@
+use crate::notification_manager::latency_metrics::ProcessingPhase;
+use crate::notification_manager::NotificationManager;
+use nostr::{Event, Kind};
+use serde::Serialize;
+use std::collections::HashMap;
+use std::sync::atomic::{AtomicUsize, Ordering};
+use std::sync::Arc;
+use tokio::sync::{mpsc, Mutex};
+use tokio::time::{Duration, Instant};
+
+/// A bounded queue of events waiting to be processed by the notification workers.
+/// Relay connections enqueue events and answer right away, instead of holding the connection until every notification is sent.
+pub struct IngestionQueue {
+    sender: mpsc::Sender<QueuedEvent>,
+    depth: Arc<AtomicUsize>,
+    capacity: usize,
+    // Above this depth, new events are rejected so that clients back off
+    high_water_mark: usize,
+}
+
+struct QueuedEvent {
+    event: Event,
+    enqueued_at: Instant,
+}
+
+/// How long events may wait in the queue before their notifications are no longer worth delivering (e.g. old reactions after an outage)
+#[derive(Debug, Clone)]
+pub struct QueuedEventMaxAges {
+    pub max_age_by_kind: HashMap<Kind, Duration>,
+    // Used for kinds without their own max age
+    pub default_max_age: Duration,
+}
+
+impl QueuedEventMaxAges {
+    fn max_age(&self, kind: Kind) -> Duration {
+        self.max_age_by_kind.get(&kind).cloned().unwrap_or(self.default_max_age)
+    }
+}
+
+#[derive(Debug, PartialEq)]
+pub enum EnqueueError {
+    // The queue is above its high-water mark
+    Backpressure,
+    // The workers have stopped
+    Closed,
+}
+
+#[derive(Serialize, Debug)]
+pub struct IngestionQueueStats {
+    pub depth: usize,
+    pub capacity: usize,
+    pub high_water_mark: usize,
+}
+
+impl IngestionQueue {
+    // MARK: - Initialization
+
+    /// Creates the queue and spawns the workers that drain it
+    pub fn start(
+        notification_manager: Arc<NotificationManager>,
+        capacity: usize,
+        high_water_mark: usize,
+        worker_count: usize,
+        max_ages: QueuedEventMaxAges,
+    ) -> Arc<Self> {
+        let (sender, receiver) = mpsc::channel(capacity.max(1));
+        let receiver = Arc::new(Mutex::new(receiver));
+        let depth = Arc::new(AtomicUsize::new(0));
+        for _ in 0..worker_count.max(1) {
+            tokio::spawn(Self::run_worker(
+                receiver.clone(),
+                depth.clone(),
+                notification_manager.clone(),
+                max_ages.clone(),
+            ));
+        }
+        Arc::new(IngestionQueue {
+            sender,
+            depth,
+            capacity,
+            high_water_mark: high_water_mark.min(capacity),
+        })
+    }
+
+    // MARK: - Enqueueing
+
+    pub fn try_enqueue(&self, event: Event) -> Result<(), EnqueueError> {
+        if self.depth() >= self.high_water_mark {
+            return Err(EnqueueError::Backpressure);
+        }
+        self.depth.fetch_add(1, Ordering::SeqCst);
+        match self.sender.try_send(QueuedEvent { event, enqueued_at: Instant::now() }) {
+            Ok(()) => Ok(()),
+            Err(e) => {
+                self.depth.fetch_sub(1, Ordering::SeqCst);
+                match e {
+                    mpsc::error::TrySendError::Full(_) => Err(EnqueueError::Backpressure),
+                    mpsc::error::TrySendError::Closed(_) => Err(EnqueueError::Closed),
+                }
+            }
+        }
+    }
+
+    pub fn depth(&self) -> usize {
+        self.depth.load(Ordering::SeqCst)
+    }
+
+    pub fn stats(&self) -> IngestionQueueStats {
+        IngestionQueueStats {
+            depth: self.depth(),
+            capacity: self.capacity,
+            high_water_mark: self.high_water_mark,
+        }
+    }
+
+    // MARK: - Workers
+
+    async fn run_worker(
+        receiver: Arc<Mutex<mpsc::Receiver<QueuedEvent>>>,
+        depth: Arc<AtomicUsize>,
+        notification_manager: Arc<NotificationManager>,
+        max_ages: QueuedEventMaxAges,
+    ) {
+        loop {
+            let QueuedEvent { event, enqueued_at } = {
+                let mut receiver = receiver.lock().await;
+                match receiver.recv().await {
+                    Some(queued_event) => queued_event,
+                    None => return,
+                }
+            };   // Release the lock here, so other workers can pick up events while this one is processed
+            depth.fetch_sub(1, Ordering::SeqCst);
+            let time_in_queue = enqueued_at.elapsed();
+            notification_manager.record_processing_latency(ProcessingPhase::Queue, time_in_queue);
+            if time_in_queue > max_ages.max_age(event.kind) {
+                log::info!("Dropping event {} that waited {:?} in the queue, its notifications are stale", event.id, time_in_queue);
+                continue;
+            }
+            if let Err(e) = notification_manager.send_notifications_if_needed(&event, None).await {
+                log::error!("Failed to send notifications for event {}: {}", event.id, e);
+            }
+            notification_manager.record_processing_latency(ProcessingPhase::Total, enqueued_at.elapsed());
+        }
+    }
+}
@@ -0,0 +1,42 @@
+use std::io::Write;
+
+/// How log lines are written
+#[derive(Debug, Clone, Copy, PartialEq)]
+pub enum LogFormat {
+    // Human-readable lines, for local development
+    Pretty,
+    // One JSON object per line, for log aggregation in production
+    Json,
+}
+
+impl LogFormat {
+    pub fn parse(value: &str) -> Option<LogFormat> {
+        match value.to_lowercase().as_str() {
+            "pretty" => Some(LogFormat::Pretty),
+            "json" => Some(LogFormat::Json),
+            _ => None,
+        }
+    }
+}
+
+/// Sets up the logger with the given format and filters (e.g. `info,notepush::notification_manager=debug`).
+/// `RUST_LOG` still takes precedence when it is set, for quick debugging.
+pub fn init(format: LogFormat, filters: &str) {
+    let mut builder = env_logger::Builder::new();
+    builder.parse_filters(filters);
+    if let Ok(rust_log) = std::env::var("RUST_LOG") {
+        builder.parse_filters(&rust_log);
+    }
+    if format == LogFormat::Json {
+        builder.format(|buf, record| {
+            let line = serde_json::json!({
+                "timestamp": chrono::Utc::now().to_rfc3339(),
+                "level": record.level().to_string(),
+                "target": record.target(),
+                "message": record.args().to_string(),
+            });
+            writeln!(buf, "{}", line)
+        });
+    }
+    builder.init();
+}
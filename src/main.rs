@@ -1,9 +1,9 @@
 #![forbid(unsafe_code)]
+use futures::FutureExt;
 use hyper_util::rt::TokioIo;
 use std::sync::Arc;
 use tokio::net::TcpListener;
 mod notification_manager;
-use env_logger;
 use log;
 use r2d2_sqlite::SqliteConnectionManager;
 mod relay_connection;
@@ -11,55 +11,149 @@ use r2d2;
 mod notepush_env;
 use notepush_env::NotePushEnv;
 mod api_request_handler;
+mod api_schema;
+mod bench;
+mod client_ip;
+mod db_encryption;
+mod event_rate_limiter;
+mod ingestion_queue;
+mod logging;
 mod nip98_auth;
 mod utils;
 
+// The delay before accepting again after an accept error, doubled on every consecutive error up to the maximum
+const MIN_ACCEPT_ERROR_BACKOFF: std::time::Duration = std::time::Duration::from_millis(10);
+const MAX_ACCEPT_ERROR_BACKOFF: std::time::Duration = std::time::Duration::from_secs(1);
+// How long a pooled connection waits for another one's write lock before failing with "database is locked"
+const DB_BUSY_TIMEOUT: std::time::Duration = std::time::Duration::from_secs(5);
+
 #[tokio::main]
 async fn main() -> Result<(), Box<dyn std::error::Error + Send + Sync>> {
     // MARK: - Setup basics
 
-    env_logger::init();
-
     let env = NotePushEnv::load_env().expect("Failed to load environment variables");
+    logging::init(env.log_format, &env.log_filters);
+
+    // `notepush bench [--events N] [--fan-out N] [--workers N]` measures the notification pipeline instead of serving
+    let mut args = std::env::args().skip(1);
+    if args.next().as_deref() == Some("bench") {
+        return bench::run(env, args).await.map_err(|e| e.to_string().into());
+    }
+
     let listener = TcpListener::bind(&env.relay_address())
         .await
         .expect("Failed to bind to address");
     log::info!("Server running at {}", env.relay_address());
 
-    let manager = SqliteConnectionManager::file(env.db_path.clone());
-    let pool: r2d2::Pool<SqliteConnectionManager> =
-        r2d2::Pool::new(manager).expect("Failed to create SQLite connection pool");
-    // Notification manager is a shared resource that will be used by all connections via a mutex and an atomic reference counter.
-    // This is shared to avoid data races when reading/writing to the sqlite database, and reduce outgoing relay connections.
-    let notification_manager = Arc::new(
-        notification_manager::NotificationManager::new(
-            pool,
-            env.relay_url.clone(),
-            env.apns_private_key_path.clone(),
-            env.apns_private_key_id.clone(),
-            env.apns_team_id.clone(),
-            env.apns_environment.clone(),
-            env.apns_topic.clone(),
-            env.nostr_event_cache_max_age,
-        )
-        .await
-        .expect("Failed to create notification manager"),
+    // Notification manager is a shared resource that will be used by all connections via an atomic reference counter.
+    // This is shared to reuse its database connection pool, and reduce outgoing relay connections.
+    let notification_manager = Arc::new(build_notification_manager(&env).await);
+
+    // MARK: - Background jobs
+
+    tokio::spawn(notification_manager::NotificationManager::run_delivery_analytics_job(
+        notification_manager.clone(),
+    ));
+    tokio::spawn(notification_manager::NotificationManager::run_device_purge_job(
+        notification_manager.clone(),
+    ));
+    tokio::spawn(notification_manager::NotificationManager::run_row_cap_job(
+        notification_manager.clone(),
+    ));
+    tokio::spawn(notification_manager::NotificationManager::run_weekly_summary_job(
+        notification_manager.clone(),
+    ));
+    tokio::spawn(notification_manager::NotificationManager::run_deferred_digest_job(
+        notification_manager.clone(),
+    ));
+    tokio::spawn(notification_manager::NotificationManager::run_pending_notification_redrive_job(
+        notification_manager.clone(),
+    ));
+    tokio::spawn(notification_manager::NotificationManager::run_hashtag_rate_cap_prune_job(
+        notification_manager.clone(),
+    ));
+    tokio::spawn(notification_manager::NotificationManager::run_held_event_expiry_job(
+        notification_manager.clone(),
+    ));
+    tokio::spawn(notification_manager::DmRelaySubscriber::run(
+        notification_manager.clone(),
+    ));
+
+    let ingestion_queue = ingestion_queue::IngestionQueue::start(
+        notification_manager.clone(),
+        env.ingestion_queue_capacity,
+        env.ingestion_queue_high_water_mark,
+        env.ingestion_workers,
+        ingestion_queue::QueuedEventMaxAges {
+            max_age_by_kind: env.queued_event_max_ages.clone(),
+            default_max_age: env.queued_event_default_max_age,
+        },
     );
+
     let api_handler = Arc::new(api_request_handler::APIHandler::new(
         notification_manager.clone(),
         env.api_base_url.clone(),
+        env.admin_pubkeys.clone(),
+        relay_connection::RelayPolicy {
+            ok_accepts: env.relay_ok_accepts,
+            ok_message: env.relay_ok_message.clone(),
+            accepted_kinds: env.relay_accepted_kinds.clone(),
+            max_event_size: env.relay_max_event_size,
+            max_event_tags: env.relay_max_event_tags,
+        },
+        ingestion_queue.clone(),
+        Arc::new(event_rate_limiter::EventRateLimiter::new(
+            env.relay_connection_messages_per_second,
+            env.relay_pubkey_events_per_second,
+            env.relay_message_burst,
+        )),
+        env.db_backup_dir.clone().map(std::path::PathBuf::from),
+        client_ip::TrustedProxies::parse(env.trusted_proxies.as_deref().unwrap_or_default())
+            .unwrap_or_else(|entry| panic!("Invalid entry in TRUSTED_PROXIES: {}", entry)),
     ));
 
+    // MARK: - Connection handling
+
+    let connection_permits = Arc::new(tokio::sync::Semaphore::new(env.max_connections));
+    // Every connection task, so that they can be counted and closed on shutdown
+    let mut connections = tokio::task::JoinSet::new();
+    let mut accept_error_backoff = MIN_ACCEPT_ERROR_BACKOFF;
+    let shutdown_signal = tokio::signal::ctrl_c();
+    tokio::pin!(shutdown_signal);
+
     loop {
-        let (stream, _) = listener.accept().await?;
+        // Reap the tasks of closed connections
+        while let Some(Some(_)) = connections.join_next().now_or_never() {}
+
+        // Wait for a free connection slot before accepting, so that excess connections queue up in the listen backlog
+        let connection_permit = tokio::select! {
+            permit = connection_permits.clone().acquire_owned() => permit?,
+            _ = &mut shutdown_signal => break,
+        };
+        let (stream, peer_addr) = tokio::select! {
+            accept_result = listener.accept() => match accept_result {
+                Ok(accepted) => accepted,
+                Err(err) => {
+                    // Accept errors are usually transient (e.g. running out of file descriptors), so back off instead of exiting
+                    log::error!("Failed to accept connection, retrying in {:?}: {}", accept_error_backoff, err);
+                    tokio::time::sleep(accept_error_backoff).await;
+                    accept_error_backoff = (accept_error_backoff * 2).min(MAX_ACCEPT_ERROR_BACKOFF);
+                    continue;
+                }
+            },
+            _ = &mut shutdown_signal => break,
+        };
+        accept_error_backoff = MIN_ACCEPT_ERROR_BACKOFF;
         let io = TokioIo::new(stream);
         let api_handler_clone = api_handler.clone();
         let mut http = hyper::server::conn::http1::Builder::new();
         http.keep_alive(true);
 
-        tokio::task::spawn(async move {
+        connections.spawn(async move {
+            // Hold the slot until the connection closes
+            let _connection_permit = connection_permit;
             let service =
-                hyper::service::service_fn(|req| api_handler_clone.handle_http_request(req));
+                hyper::service::service_fn(|req| api_handler_clone.handle_http_request(req, peer_addr.ip()));
 
             let connection = http.serve_connection(io, service).with_upgrades();
 
@@ -68,4 +162,99 @@ async fn main() -> Result<(), Box<dyn std::error::Error + Send + Sync>> {
             }
         });
     }
+
+    log::info!("Shutting down, closing {} open connections", connections.len());
+    connections.shutdown().await;
+    Ok(())
+}
+
+/// Opens the database and creates the notification manager as configured. Shared with the benchmark, so that it measures the real setup
+async fn build_notification_manager(env: &NotePushEnv) -> notification_manager::NotificationManager {
+    let manager = SqliteConnectionManager::file(env.db_path.clone())
+        .with_init(|connection| connection.busy_timeout(DB_BUSY_TIMEOUT));
+    let mut pool_builder = r2d2::Pool::builder().max_size(env.db_pool_size);
+    if let Some(db_encryption_key) = db_encryption::DatabaseEncryptionKey::load(
+        env.db_encryption_key.clone(),
+        env.db_encryption_key_path.as_deref(),
+    )
+    .expect("Failed to load the database encryption key")
+    {
+        pool_builder = pool_builder.connection_customizer(Box::new(db_encryption_key));
+    }
+    let pool: r2d2::Pool<SqliteConnectionManager> = pool_builder
+        .build(manager)
+        .expect("Failed to create SQLite connection pool");
+    let webhook_signing_keys = env.webhook_signing_secret_key.as_deref().map(|secret_key| {
+        let keys = nostr::Keys::parse(secret_key).expect("Invalid WEBHOOK_SIGNING_SECRET_KEY");
+        log::info!("Signing webhook payloads as {}", keys.public_key().to_hex());
+        keys
+    });
+    notification_manager::NotificationManager::new(
+        pool,
+        notification_manager::NotificationManagerConfig {
+            relay_url: env.relay_url.clone(),
+            fallback_relay_urls: env.fallback_relay_urls.clone(),
+            apns_private_key_path: env.apns_private_key_path.clone(),
+            apns_private_key_id: env.apns_private_key_id.clone(),
+            apns_team_id: env.apns_team_id.clone(),
+            apns_environment: env.apns_environment.clone(),
+            apns_topic: env.apns_topic.clone(),
+            apns_extra_ca_roots_path: env.apns_extra_ca_roots_path.clone(),
+            apns_use_built_in_ca_roots: env.apns_use_built_in_ca_roots,
+            apns_host: env.apns_host.clone(),
+            apns_port: env.apns_port,
+            cache_max_age: env.nostr_event_cache_max_age,
+            relay_list_cache_max_age: env.relay_list_cache_max_age,
+            note_fetch_timeout: env.note_fetch_timeout,
+            note_fetch_limit: env.note_fetch_limit,
+            relay_fetch_max_concurrent_subscriptions: env.relay_fetch_max_concurrent_subscriptions,
+            relay_fetch_subscriptions_per_second: env.relay_fetch_subscriptions_per_second,
+            relay_fetch_burst: env.relay_fetch_burst,
+            recipient_shard: notification_manager::RecipientShard::new(env.shard_count, env.shard_index)
+                .expect("SHARD_INDEX must be smaller than SHARD_COUNT"),
+            notification_templates: match &env.notification_templates_path {
+                Some(path) => notification_manager::NotificationTemplates::load(path)
+                    .expect("Failed to load notification templates"),
+                None => notification_manager::NotificationTemplates::default(),
+            },
+            push_body_max_length: env.push_body_max_length,
+            silent_push_kinds: env.silent_push_kinds.clone(),
+            event_inclusion_policies: env.event_inclusion_policies.clone(),
+            event_max_age_seconds: env.event_max_age_seconds,
+            event_min_age_seconds: env.event_min_age_seconds,
+            spam_filter: notification_manager::SpamFilter::from_config(
+                &match &env.spam_content_denylist_path {
+                    Some(path) => notification_manager::SpamFilter::read_content_denylist(path)
+                        .expect("Failed to read the spam content denylist"),
+                    None => vec![],
+                },
+                env.spam_min_proof_of_work,
+                env.spam_max_pubkey_tags,
+            )
+            .expect("Invalid regular expression in the spam content denylist"),
+            max_processed_pubkey_tags: env.max_processed_pubkey_tags,
+            max_processed_event_tags: env.max_processed_event_tags,
+            flood_guard_threshold: env.flood_guard_threshold,
+            flood_guard_mode: env.flood_guard_mode,
+            follow_list_unavailable_policy: env.follow_list_unavailable_policy,
+            report_suppression_threshold: env.report_suppression_threshold,
+            report_max_age: env.report_max_age,
+            sensitive_hashtags: env.sensitive_hashtags.clone(),
+            device_removal_grace_period: env.device_removal_grace_period,
+            notifications_max_rows: env.notifications_max_rows,
+            deliveries_max_rows: env.deliveries_max_rows,
+            webhook_signing_keys,
+            apns_tenant_configs: match &env.apns_tenants_path {
+                Some(path) => notification_manager::apns_tenants::ApnsTenantConfig::load_all(path)
+                    .expect("Failed to load APNS tenants"),
+                None => std::collections::HashMap::new(),
+            },
+            apns_max_in_flight_sends: env.apns_max_in_flight_sends,
+            apns_sends_per_second: env.apns_sends_per_second,
+            apns_send_burst: env.apns_send_burst,
+            fault_injector: notification_manager::fault_injector::FaultInjector::new(env.fault_injection_probabilities.clone()),
+        },
+    )
+    .await
+    .expect("Failed to create notification manager")
 }
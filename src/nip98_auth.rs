@@ -6,12 +6,39 @@ use nostr::util::hex;
 use serde_json::Value;
 use super::utils::time_delta::TimeDelta;
 
+/// What an auth note allows its bearer to do, from its optional `scope` tag
+#[derive(Debug, Clone, Copy, PartialEq)]
+pub enum AuthScope {
+    // No `scope` tag. Everything the pubkey is allowed to do
+    Full,
+    // `["scope", "read"]`. Only `GET` requests, e.g. for companion tools that show the notification history
+    ReadOnly,
+}
+
+impl AuthScope {
+    fn from_tag_content(scope: Option<&str>) -> Result<Self, String> {
+        match scope {
+            None => Ok(AuthScope::Full),
+            Some("read") => Ok(AuthScope::ReadOnly),
+            // Fail closed, so that a scope this server does not know about never grants more than intended
+            Some(scope) => Err(format!("Unknown scope in Nostr authorization header: {}", scope)),
+        }
+    }
+
+    pub fn allows_method(&self, method: &hyper::Method) -> bool {
+        match self {
+            AuthScope::Full => true,
+            AuthScope::ReadOnly => method == hyper::Method::GET,
+        }
+    }
+}
+
 pub async fn nip98_verify_auth_header(
     auth_header: String,
     url: &str,
     method: &str,
     body: Option<&[u8]>,
-) -> Result<nostr::PublicKey, String> {
+) -> Result<(nostr::PublicKey, AuthScope), String> {
     if auth_header.is_empty() {
         return Err("Nostr authorization header missing".to_string());
     }
@@ -63,6 +90,8 @@ pub async fn nip98_verify_auth_header(
         ));
     }
 
+    let scope = AuthScope::from_tag_content(note.get_tag_content(nostr::TagKind::Custom("scope".into())))?;
+
     let current_time: nostr::Timestamp = nostr::Timestamp::now();
     let note_created_at: nostr::Timestamp = note.created_at();
     let time_delta = TimeDelta::subtracting(current_time, note_created_at);
@@ -104,5 +133,5 @@ pub async fn nip98_verify_auth_header(
         return Err("Auth note id or signature is invalid".to_string());
     }
 
-    Ok(note.pubkey)
+    Ok((note.pubkey, scope))
 }
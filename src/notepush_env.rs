@@ -1,14 +1,58 @@
+use crate::logging::LogFormat;
+use crate::notification_manager::apns_connection::DEFAULT_APNS_PORT;
+use crate::notification_manager::notification_manager::FloodGuardMode;
+use crate::notification_manager::push_payload::EventInclusion;
+use crate::notification_manager::fault_injector::Fault;
+use crate::notification_manager::nostr_network_helper::FollowListUnavailablePolicy;
 use a2;
 use dotenv::dotenv;
 use std::env;
 
+const DEFAULT_LOG_FILTERS: &str = "info";
 const DEFAULT_DB_PATH: &str = "./apns_notifications.db";
+const DEFAULT_DB_POOL_SIZE: u32 = 10;
 const DEFAULT_HOST: &str = "0.0.0.0";
 const DEFAULT_PORT: &str = "8000";
+const DEFAULT_MAX_CONNECTIONS: usize = 1024;
 const DEFAULT_RELAY_URL: &str = "wss://relay.damus.io";
 const DEFAULT_NOSTR_EVENT_CACHE_MAX_AGE: u64 = 60 * 60; // 1 hour
+const DEFAULT_RELAY_LIST_CACHE_MAX_AGE: u64 = 6 * 60 * 60; // 6 hours
+const DEFAULT_NOTE_FETCH_TIMEOUT_MS: u64 = 5000;
+const DEFAULT_NOTE_FETCH_LIMIT: usize = 1;
+// Relay fetch limits are opt-in, since a limit below the notification fan-out makes fetches queue up behind each other
+const DEFAULT_RELAY_FETCH_MAX_CONCURRENT_SUBSCRIPTIONS: usize = 0;
+const DEFAULT_RELAY_FETCH_SUBSCRIPTIONS_PER_SECOND: u32 = 0;
+const DEFAULT_PUSH_BODY_MAX_LENGTH: usize = 256;
+const DEFAULT_EVENT_MAX_AGE_SECONDS: u64 = 7 * 24 * 60 * 60; // 1 week
+const DEFAULT_MAX_PROCESSED_PUBKEY_TAGS: usize = 250;
+const DEFAULT_MAX_PROCESSED_EVENT_TAGS: usize = 50;
+const DEFAULT_FLOOD_GUARD_THRESHOLD: usize = 500;
+const DEFAULT_REPORT_SUPPRESSION_THRESHOLD: usize = 3;
+const DEFAULT_REPORT_MAX_AGE: u64 = 90 * 24 * 60 * 60; // 90 days
+const DEFAULT_SENSITIVE_HASHTAGS: &str = "nsfw,nude,nudity,porn";
+const DEFAULT_DEVICE_REMOVAL_GRACE_PERIOD: u64 = 30 * 24 * 60 * 60; // 30 days
+const DEFAULT_SHARD_COUNT: u64 = 1;
+const DEFAULT_SHARD_INDEX: u64 = 0;
+const DEFAULT_INGESTION_QUEUE_CAPACITY: usize = 10_000;
+const DEFAULT_INGESTION_QUEUE_HIGH_WATER_MARK: usize = 8_000;
+const DEFAULT_INGESTION_WORKERS: usize = 4;
+// Reactions and reposts go stale quickly, DMs and zaps stay relevant for longer
+const DEFAULT_QUEUED_EVENT_MAX_AGES: &str = "1:3600,4:86400,6:900,7:900,16:900,1059:86400,9735:3600";
+const DEFAULT_QUEUED_EVENT_MAX_AGE_SECONDS: u64 = 60 * 60; // 1 hour
+const DEFAULT_APNS_MAX_IN_FLIGHT_SENDS: usize = 100;
+const DEFAULT_APNS_SENDS_PER_SECOND: u32 = 500;
+const DEFAULT_RELAY_OK_MESSAGE: &str = "blocked: This relay does not store events";
+const DEFAULT_RELAY_MAX_EVENT_SIZE: usize = 64 * 1024; // 64 KiB
+const DEFAULT_RELAY_MAX_EVENT_TAGS: usize = 2000;
+const DEFAULT_RELAY_CONNECTION_MESSAGES_PER_SECOND: u32 = 20;
+const DEFAULT_RELAY_PUBKEY_EVENTS_PER_SECOND: u32 = 50;
+const DEFAULT_RELAY_MESSAGE_BURST: u32 = 100;
 
 pub struct NotePushEnv {
+    // Whether logs are written as human-readable lines or as JSON
+    pub log_format: LogFormat,
+    // The log level, optionally per module (e.g. `info,notepush::notification_manager=debug`)
+    pub log_filters: String,
     // The path to the Apple private key .p8 file
     pub apns_private_key_path: String,
     // The Apple private key ID
@@ -19,28 +63,153 @@ pub struct NotePushEnv {
     pub apns_environment: a2::client::Endpoint,
     // The topic to send notifications to (The Apple app bundle ID)
     pub apns_topic: String,
+    // The path to a TOML file with the APNS credentials of additional apps (tenants) served by this instance
+    pub apns_tenants_path: Option<String>,
+    // A PEM bundle of additional CA roots to trust for APNS connections, and whether the built-in roots are trusted as well
+    pub apns_extra_ca_roots_path: Option<String>,
+    pub apns_use_built_in_ca_roots: bool,
+    // An APNS host to use instead of Apple's (e.g. a mock server for testing), and the port to connect to (443, or 2197 where 443 is blocked)
+    pub apns_host: Option<String>,
+    pub apns_port: u16,
+    // The maximum number of concurrent APNS sends, and the sustained send rate (0 for unlimited) and burst size across all tenants
+    pub apns_max_in_flight_sends: usize,
+    pub apns_sends_per_second: u32,
+    pub apns_send_burst: u32,
     // The path to the SQLite database file
     pub db_path: String,
+    // The maximum number of database connections, i.e. how many queries can run at once
+    pub db_pool_size: u32,
+    // The SQLCipher key of the database, given directly or as a file containing it. Unset means the database is not encrypted
+    pub db_encryption_key: Option<String>,
+    pub db_encryption_key_path: Option<String>,
+    // The directory the admin API writes database backups to. Unset disables backups
+    pub db_backup_dir: Option<String>,
     // The host and port to bind the relay and API to
     pub host: String,
     pub port: String,
+    // The maximum number of concurrent connections. Further connections wait to be accepted until others close
+    pub max_connections: usize,
+    // Comma-separated CIDRs of the reverse proxies whose `Forwarded` and `X-Forwarded-For` headers are trusted for the client IP
+    pub trusted_proxies: Option<String>,
+    // The nostr secret key (nsec or hex) used to sign webhook payloads, so that receivers can authenticate this instance
+    pub webhook_signing_secret_key: Option<String>,
     pub api_base_url: String, // The base URL of where the API server is hosted for NIP-98 auth checks
     // The URL of the Nostr relay server to connect to for getting mutelists
     pub relay_url: String,
+    // Relays to fall back to when the main relay does not have a list or is down
+    pub fallback_relay_urls: Vec<String>,
     // The max age of the Nostr event cache, in seconds
     pub nostr_event_cache_max_age: std::time::Duration,
+    // How long NIP-65 relay lists are cached. They rarely change, so this is longer than for other events
+    pub relay_list_cache_max_age: std::time::Duration,
+    // How long to wait for a relay to answer when fetching a note (e.g. a mute list)
+    pub note_fetch_timeout: std::time::Duration,
+    // The `limit` used on the subscription filters when fetching a note
+    pub note_fetch_limit: usize,
+    // The maximum number of concurrent fetch subscriptions to each relay, the rate at which they may be opened, and how many may burst above it
+    pub relay_fetch_max_concurrent_subscriptions: usize,
+    pub relay_fetch_subscriptions_per_second: u32,
+    pub relay_fetch_burst: u32,
+    // The number of instances recipients are split across, and which of those shards this instance handles
+    pub shard_count: u64,
+    pub shard_index: u64,
+    // The pubkeys allowed to use the admin API
+    pub admin_pubkeys: std::collections::HashSet<nostr::PublicKey>,
+    // The path to a TOML file with custom notification text templates
+    pub notification_templates_path: Option<String>,
+    // The maximum length of the note content shown in a notification body, in user-perceived characters
+    pub push_body_max_length: usize,
+    // Event kinds that wake the app silently (e.g. contact list changes, settings sync) instead of showing a notification
+    pub silent_push_kinds: std::collections::HashSet<nostr::Kind>,
+    // How much of the event the pushes of each kind carry (full event, ID only or none). Kinds without a policy carry the full event
+    pub event_inclusion_policies: std::collections::HashMap<nostr::Kind, EventInclusion>,
+    // Events older than this (in seconds) do not trigger notifications
+    pub event_max_age_seconds: u64,
+    // Events younger than this (in seconds) do not trigger notifications. Negative values tolerate events that many seconds in the future
+    pub event_min_age_seconds: Option<i64>,
+    // A file with one regular expression per line. Events with matching content never trigger notifications
+    pub spam_content_denylist_path: Option<String>,
+    // The minimum NIP-13 proof-of-work difficulty an event needs to trigger notifications
+    pub spam_min_proof_of_work: Option<u8>,
+    // Events that mention more pubkeys than this never trigger notifications
+    pub spam_max_pubkey_tags: Option<usize>,
+    // The maximum number of `p` and `e` tags of an event that are processed. Events with more are truncated instead of rejected
+    pub max_processed_pubkey_tags: usize,
+    pub max_processed_event_tags: usize,
+    // Events that would notify more pubkeys than this (0 disables it) are degraded to low-priority collapsible pushes, or held for approval
+    pub flood_guard_threshold: usize,
+    pub flood_guard_mode: FloodGuardMode,
+    // What "only from following" checks do when the recipient's contact list cannot be fetched: fail open, fail closed, or use the expired cached one
+    pub follow_list_unavailable_policy: FollowListUnavailablePolicy,
+    // The number of NIP-56 reports a user must file against an author before that author's events stop notifying them. 0 disables report-based suppression
+    pub report_suppression_threshold: usize,
+    // How long a report counts towards that threshold
+    pub report_max_age: std::time::Duration,
+    // Hashtags (lowercase, without `#`) that mark an event as sensitive, in addition to a NIP-36 content warning
+    pub sensitive_hashtags: std::collections::HashSet<String>,
+    // How long removed devices keep their settings before being purged. Re-registering within it restores them
+    pub device_removal_grace_period: std::time::Duration,
+    // The maximum number of rows kept in the notifications and deliveries tables. The oldest rows above it are evicted. Unset means no cap
+    pub notifications_max_rows: Option<u64>,
+    pub deliveries_max_rows: Option<u64>,
+    // The maximum number of events waiting to be processed, the depth above which new events are rejected, and the number of workers processing them
+    pub ingestion_queue_capacity: usize,
+    pub ingestion_queue_high_water_mark: usize,
+    pub ingestion_workers: usize,
+    // How long events of each kind may wait in the ingestion queue before they are dropped, and the max age for other kinds
+    pub queued_event_max_ages: std::collections::HashMap<nostr::Kind, std::time::Duration>,
+    pub queued_event_default_max_age: std::time::Duration,
+    // Whether the embedded relay answers events with `OK true` instead of `OK false`, and the message it sends along
+    pub relay_ok_accepts: bool,
+    pub relay_ok_message: String,
+    // Event kinds the embedded relay accepts. Empty means all kinds
+    pub relay_accepted_kinds: std::collections::HashSet<nostr::Kind>,
+    // The maximum size in bytes of a message received by the embedded relay, and the maximum number of tags of an event
+    pub relay_max_event_size: usize,
+    pub relay_max_event_tags: usize,
+    // The rate of messages the embedded relay accepts per connection, the rate of events per authenticated pubkey, and how many may burst above them
+    pub relay_connection_messages_per_second: u32,
+    pub relay_pubkey_events_per_second: u32,
+    pub relay_message_burst: u32,
+    // The probability of injecting each fault, for testing the error paths in staging. Empty (no faults) in production
+    pub fault_injection_probabilities: std::collections::HashMap<Fault, f64>,
 }
 
 impl NotePushEnv {
     pub fn load_env() -> Result<NotePushEnv, env::VarError> {
         dotenv().ok();
+        let log_format = env::var("LOG_FORMAT")
+            .ok()
+            .and_then(|format| LogFormat::parse(&format))
+            .unwrap_or(LogFormat::Pretty);
+        let log_filters = env::var("LOG_LEVEL").unwrap_or(DEFAULT_LOG_FILTERS.to_string());
         let apns_private_key_path = env::var("APNS_AUTH_PRIVATE_KEY_FILE_PATH")?;
         let apns_private_key_id = env::var("APNS_AUTH_PRIVATE_KEY_ID")?;
         let apns_team_id = env::var("APPLE_TEAM_ID")?;
         let db_path = env::var("DB_PATH").unwrap_or(DEFAULT_DB_PATH.to_string());
+        let db_pool_size = env::var("DB_POOL_SIZE")
+            .unwrap_or(DEFAULT_DB_POOL_SIZE.to_string())
+            .parse::<u32>()
+            .unwrap_or(DEFAULT_DB_POOL_SIZE)
+            .max(1);
+        let db_encryption_key = env::var("DB_ENCRYPTION_KEY").ok();
+        let db_encryption_key_path = env::var("DB_ENCRYPTION_KEY_FILE").ok();
+        let db_backup_dir = env::var("DB_BACKUP_DIR").ok();
         let host = env::var("HOST").unwrap_or(DEFAULT_HOST.to_string());
         let port = env::var("PORT").unwrap_or(DEFAULT_PORT.to_string());
+        let max_connections = env::var("MAX_CONNECTIONS")
+            .unwrap_or(DEFAULT_MAX_CONNECTIONS.to_string())
+            .parse::<usize>()
+            .unwrap_or(DEFAULT_MAX_CONNECTIONS);
+        let trusted_proxies = env::var("TRUSTED_PROXIES").ok();
+        let webhook_signing_secret_key = env::var("WEBHOOK_SIGNING_SECRET_KEY").ok().filter(|key| !key.is_empty());
         let relay_url = env::var("RELAY_URL").unwrap_or(DEFAULT_RELAY_URL.to_string());
+        let fallback_relay_urls = env::var("FALLBACK_RELAY_URLS")
+            .unwrap_or_default()
+            .split(',')
+            .map(|url| url.trim().to_string())
+            .filter(|url| !url.is_empty())
+            .collect();
         let apns_environment_string =
             env::var("APNS_ENVIRONMENT").unwrap_or("development".to_string());
         let api_base_url = env::var("API_BASE_URL").unwrap_or(format!("https://{}:{}", host, port));
@@ -50,24 +219,310 @@ impl NotePushEnv {
             _ => a2::client::Endpoint::Sandbox,
         };
         let apns_topic = env::var("APNS_TOPIC")?;
+        let apns_tenants_path = env::var("APNS_TENANTS_PATH").ok();
+        let apns_extra_ca_roots_path = env::var("APNS_EXTRA_CA_ROOTS_PATH").ok().filter(|path| !path.is_empty());
+        let apns_use_built_in_ca_roots = env::var("APNS_DISABLE_BUILT_IN_CA_ROOTS")
+            .map(|value| value != "true" && value != "1")
+            .unwrap_or(true);
+        let apns_host = env::var("APNS_HOST").ok().filter(|host| !host.is_empty());
+        let apns_port = env::var("APNS_PORT")
+            .unwrap_or(DEFAULT_APNS_PORT.to_string())
+            .parse::<u16>()
+            .unwrap_or(DEFAULT_APNS_PORT);
+        let apns_max_in_flight_sends = env::var("APNS_MAX_IN_FLIGHT_SENDS")
+            .unwrap_or(DEFAULT_APNS_MAX_IN_FLIGHT_SENDS.to_string())
+            .parse::<usize>()
+            .unwrap_or(DEFAULT_APNS_MAX_IN_FLIGHT_SENDS);
+        let apns_sends_per_second = env::var("APNS_SENDS_PER_SECOND")
+            .unwrap_or(DEFAULT_APNS_SENDS_PER_SECOND.to_string())
+            .parse::<u32>()
+            .unwrap_or(DEFAULT_APNS_SENDS_PER_SECOND);
+        let apns_send_burst = env::var("APNS_SEND_BURST")
+            .ok()
+            .and_then(|burst| burst.parse::<u32>().ok())
+            .unwrap_or(apns_sends_per_second);
         let nostr_event_cache_max_age = env::var("NOSTR_EVENT_CACHE_MAX_AGE")
             .unwrap_or(DEFAULT_NOSTR_EVENT_CACHE_MAX_AGE.to_string())
             .parse::<u64>()
             .map(|s| std::time::Duration::from_secs(s))
             .unwrap_or(std::time::Duration::from_secs(DEFAULT_NOSTR_EVENT_CACHE_MAX_AGE));
+        let relay_list_cache_max_age = env::var("RELAY_LIST_CACHE_MAX_AGE")
+            .unwrap_or(DEFAULT_RELAY_LIST_CACHE_MAX_AGE.to_string())
+            .parse::<u64>()
+            .map(|s| std::time::Duration::from_secs(s))
+            .unwrap_or(std::time::Duration::from_secs(DEFAULT_RELAY_LIST_CACHE_MAX_AGE));
+        let note_fetch_timeout = env::var("NOTE_FETCH_TIMEOUT_MS")
+            .unwrap_or(DEFAULT_NOTE_FETCH_TIMEOUT_MS.to_string())
+            .parse::<u64>()
+            .map(|ms| std::time::Duration::from_millis(ms))
+            .unwrap_or(std::time::Duration::from_millis(DEFAULT_NOTE_FETCH_TIMEOUT_MS));
+        let note_fetch_limit = env::var("NOTE_FETCH_LIMIT")
+            .unwrap_or(DEFAULT_NOTE_FETCH_LIMIT.to_string())
+            .parse::<usize>()
+            .unwrap_or(DEFAULT_NOTE_FETCH_LIMIT);
+        let relay_fetch_max_concurrent_subscriptions = env::var("RELAY_FETCH_MAX_CONCURRENT_SUBSCRIPTIONS")
+            .unwrap_or(DEFAULT_RELAY_FETCH_MAX_CONCURRENT_SUBSCRIPTIONS.to_string())
+            .parse::<usize>()
+            .unwrap_or(DEFAULT_RELAY_FETCH_MAX_CONCURRENT_SUBSCRIPTIONS);
+        let relay_fetch_subscriptions_per_second = env::var("RELAY_FETCH_SUBSCRIPTIONS_PER_SECOND")
+            .unwrap_or(DEFAULT_RELAY_FETCH_SUBSCRIPTIONS_PER_SECOND.to_string())
+            .parse::<u32>()
+            .unwrap_or(DEFAULT_RELAY_FETCH_SUBSCRIPTIONS_PER_SECOND);
+        let relay_fetch_burst = env::var("RELAY_FETCH_BURST")
+            .ok()
+            .and_then(|burst| burst.parse::<u32>().ok())
+            .unwrap_or(relay_fetch_max_concurrent_subscriptions.max(relay_fetch_subscriptions_per_second as usize) as u32);
+        let admin_pubkeys = env::var("ADMIN_PUBKEYS")
+            .unwrap_or_default()
+            .split(',')
+            .map(|pubkey| pubkey.trim())
+            .filter(|pubkey| !pubkey.is_empty())
+            .filter_map(|pubkey| match nostr::PublicKey::parse(pubkey) {
+                Ok(pubkey) => Some(pubkey),
+                Err(_) => {
+                    log::warn!("Ignoring invalid admin pubkey: {}", pubkey);
+                    None
+                }
+            })
+            .collect();
+        let notification_templates_path = env::var("NOTIFICATION_TEMPLATES_PATH").ok();
+        let push_body_max_length = env::var("PUSH_BODY_MAX_LENGTH")
+            .unwrap_or(DEFAULT_PUSH_BODY_MAX_LENGTH.to_string())
+            .parse::<usize>()
+            .unwrap_or(DEFAULT_PUSH_BODY_MAX_LENGTH);
+        let silent_push_kinds = env::var("SILENT_PUSH_KINDS")
+            .unwrap_or_default()
+            .split(',')
+            .filter_map(|kind| kind.trim().parse::<u16>().ok())
+            .map(nostr::Kind::from)
+            .collect();
+        let event_inclusion_policies = env::var("EVENT_INCLUSION_POLICIES")
+            .unwrap_or_default()
+            .split(',')
+            .map(|entry| entry.trim())
+            .filter(|entry| !entry.is_empty())
+            .filter_map(|entry| {
+                let policy = entry.split_once(':').and_then(|(kind, event_inclusion)| {
+                    Some((
+                        nostr::Kind::from(kind.trim().parse::<u16>().ok()?),
+                        EventInclusion::parse(event_inclusion.trim())?,
+                    ))
+                });
+                if policy.is_none() {
+                    log::warn!("Ignoring invalid event inclusion policy: {}", entry);
+                }
+                policy
+            })
+            .collect();
+        let event_max_age_seconds = env::var("EVENT_MAX_AGE_SECONDS")
+            .unwrap_or(DEFAULT_EVENT_MAX_AGE_SECONDS.to_string())
+            .parse::<u64>()
+            .unwrap_or(DEFAULT_EVENT_MAX_AGE_SECONDS);
+        let event_min_age_seconds = env::var("EVENT_MIN_AGE_SECONDS")
+            .ok()
+            .and_then(|seconds| seconds.parse::<i64>().ok());
+        let spam_content_denylist_path = env::var("SPAM_CONTENT_DENYLIST_PATH").ok();
+        let spam_min_proof_of_work = env::var("SPAM_MIN_PROOF_OF_WORK")
+            .ok()
+            .and_then(|difficulty| difficulty.parse::<u8>().ok());
+        let spam_max_pubkey_tags = env::var("SPAM_MAX_PUBKEY_TAGS")
+            .ok()
+            .and_then(|max_pubkey_tags| max_pubkey_tags.parse::<usize>().ok());
+        let max_processed_pubkey_tags = env::var("MAX_PROCESSED_PUBKEY_TAGS")
+            .unwrap_or(DEFAULT_MAX_PROCESSED_PUBKEY_TAGS.to_string())
+            .parse::<usize>()
+            .unwrap_or(DEFAULT_MAX_PROCESSED_PUBKEY_TAGS);
+        let max_processed_event_tags = env::var("MAX_PROCESSED_EVENT_TAGS")
+            .unwrap_or(DEFAULT_MAX_PROCESSED_EVENT_TAGS.to_string())
+            .parse::<usize>()
+            .unwrap_or(DEFAULT_MAX_PROCESSED_EVENT_TAGS);
+        let flood_guard_threshold = env::var("FLOOD_GUARD_THRESHOLD")
+            .unwrap_or(DEFAULT_FLOOD_GUARD_THRESHOLD.to_string())
+            .parse::<usize>()
+            .unwrap_or(DEFAULT_FLOOD_GUARD_THRESHOLD);
+        let flood_guard_mode = env::var("FLOOD_GUARD_MODE")
+            .ok()
+            .and_then(|mode| FloodGuardMode::parse(&mode))
+            .unwrap_or(FloodGuardMode::Degrade);
+        let follow_list_unavailable_policy = env::var("FOLLOW_LIST_UNAVAILABLE_POLICY")
+            .ok()
+            .and_then(|policy| FollowListUnavailablePolicy::parse(&policy))
+            .unwrap_or(FollowListUnavailablePolicy::FailClosed);
+        let report_suppression_threshold = env::var("REPORT_SUPPRESSION_THRESHOLD")
+            .unwrap_or(DEFAULT_REPORT_SUPPRESSION_THRESHOLD.to_string())
+            .parse::<usize>()
+            .unwrap_or(DEFAULT_REPORT_SUPPRESSION_THRESHOLD);
+        let report_max_age = env::var("REPORT_MAX_AGE")
+            .unwrap_or(DEFAULT_REPORT_MAX_AGE.to_string())
+            .parse::<u64>()
+            .map(|s| std::time::Duration::from_secs(s))
+            .unwrap_or(std::time::Duration::from_secs(DEFAULT_REPORT_MAX_AGE));
+        let sensitive_hashtags = env::var("SENSITIVE_HASHTAGS")
+            .unwrap_or(DEFAULT_SENSITIVE_HASHTAGS.to_string())
+            .split(',')
+            .map(|hashtag| hashtag.trim().trim_start_matches('#').to_lowercase())
+            .filter(|hashtag| !hashtag.is_empty())
+            .collect();
+        let device_removal_grace_period = env::var("DEVICE_REMOVAL_GRACE_PERIOD")
+            .unwrap_or(DEFAULT_DEVICE_REMOVAL_GRACE_PERIOD.to_string())
+            .parse::<u64>()
+            .map(|s| std::time::Duration::from_secs(s))
+            .unwrap_or(std::time::Duration::from_secs(DEFAULT_DEVICE_REMOVAL_GRACE_PERIOD));
+        let notifications_max_rows = env::var("NOTIFICATIONS_MAX_ROWS")
+            .ok()
+            .and_then(|max_rows| max_rows.parse::<u64>().ok());
+        let deliveries_max_rows = env::var("DELIVERIES_MAX_ROWS")
+            .ok()
+            .and_then(|max_rows| max_rows.parse::<u64>().ok());
+        let ingestion_queue_capacity = env::var("INGESTION_QUEUE_CAPACITY")
+            .unwrap_or(DEFAULT_INGESTION_QUEUE_CAPACITY.to_string())
+            .parse::<usize>()
+            .unwrap_or(DEFAULT_INGESTION_QUEUE_CAPACITY);
+        let ingestion_queue_high_water_mark = env::var("INGESTION_QUEUE_HIGH_WATER_MARK")
+            .unwrap_or(DEFAULT_INGESTION_QUEUE_HIGH_WATER_MARK.to_string())
+            .parse::<usize>()
+            .unwrap_or(DEFAULT_INGESTION_QUEUE_HIGH_WATER_MARK);
+        let ingestion_workers = env::var("INGESTION_WORKERS")
+            .unwrap_or(DEFAULT_INGESTION_WORKERS.to_string())
+            .parse::<usize>()
+            .unwrap_or(DEFAULT_INGESTION_WORKERS);
+        let queued_event_max_ages = env::var("QUEUED_EVENT_MAX_AGES")
+            .unwrap_or(DEFAULT_QUEUED_EVENT_MAX_AGES.to_string())
+            .split(',')
+            .filter_map(|entry| {
+                let (kind, seconds) = entry.trim().split_once(':')?;
+                Some((
+                    nostr::Kind::from(kind.trim().parse::<u16>().ok()?),
+                    std::time::Duration::from_secs(seconds.trim().parse::<u64>().ok()?),
+                ))
+            })
+            .collect();
+        let queued_event_default_max_age = env::var("QUEUED_EVENT_DEFAULT_MAX_AGE_SECONDS")
+            .unwrap_or(DEFAULT_QUEUED_EVENT_MAX_AGE_SECONDS.to_string())
+            .parse::<u64>()
+            .map(|s| std::time::Duration::from_secs(s))
+            .unwrap_or(std::time::Duration::from_secs(DEFAULT_QUEUED_EVENT_MAX_AGE_SECONDS));
+        let relay_ok_accepts = env::var("RELAY_OK_ACCEPTS")
+            .map(|value| value == "true" || value == "1")
+            .unwrap_or(false);
+        let relay_ok_message = env::var("RELAY_OK_MESSAGE").unwrap_or(DEFAULT_RELAY_OK_MESSAGE.to_string());
+        let relay_accepted_kinds = env::var("RELAY_ACCEPTED_KINDS")
+            .unwrap_or_default()
+            .split(',')
+            .filter_map(|kind| kind.trim().parse::<u16>().ok())
+            .map(nostr::Kind::from)
+            .collect();
+        let relay_max_event_size = env::var("RELAY_MAX_EVENT_SIZE")
+            .unwrap_or(DEFAULT_RELAY_MAX_EVENT_SIZE.to_string())
+            .parse::<usize>()
+            .unwrap_or(DEFAULT_RELAY_MAX_EVENT_SIZE);
+        let relay_max_event_tags = env::var("RELAY_MAX_EVENT_TAGS")
+            .unwrap_or(DEFAULT_RELAY_MAX_EVENT_TAGS.to_string())
+            .parse::<usize>()
+            .unwrap_or(DEFAULT_RELAY_MAX_EVENT_TAGS);
+        let relay_connection_messages_per_second = env::var("RELAY_CONNECTION_MESSAGES_PER_SECOND")
+            .unwrap_or(DEFAULT_RELAY_CONNECTION_MESSAGES_PER_SECOND.to_string())
+            .parse::<u32>()
+            .unwrap_or(DEFAULT_RELAY_CONNECTION_MESSAGES_PER_SECOND);
+        let relay_pubkey_events_per_second = env::var("RELAY_PUBKEY_EVENTS_PER_SECOND")
+            .unwrap_or(DEFAULT_RELAY_PUBKEY_EVENTS_PER_SECOND.to_string())
+            .parse::<u32>()
+            .unwrap_or(DEFAULT_RELAY_PUBKEY_EVENTS_PER_SECOND);
+        let relay_message_burst = env::var("RELAY_MESSAGE_BURST")
+            .unwrap_or(DEFAULT_RELAY_MESSAGE_BURST.to_string())
+            .parse::<u32>()
+            .unwrap_or(DEFAULT_RELAY_MESSAGE_BURST);
+        let shard_count = env::var("SHARD_COUNT")
+            .unwrap_or(DEFAULT_SHARD_COUNT.to_string())
+            .parse::<u64>()
+            .unwrap_or(DEFAULT_SHARD_COUNT);
+        let shard_index = env::var("SHARD_INDEX")
+            .unwrap_or(DEFAULT_SHARD_INDEX.to_string())
+            .parse::<u64>()
+            .unwrap_or(DEFAULT_SHARD_INDEX);
+
+        let fault_injection_probabilities = env::var("FAULT_INJECTION")
+            .unwrap_or_default()
+            .split(',')
+            .filter_map(|entry| {
+                let (fault, probability) = entry.trim().split_once(':')?;
+                Some((Fault::parse(fault.trim())?, probability.trim().parse::<f64>().ok()?))
+            })
+            .collect();
 
         Ok(NotePushEnv {
+            log_format,
+            log_filters,
             apns_private_key_path,
             apns_private_key_id,
             apns_team_id,
             apns_environment,
             apns_topic,
+            apns_tenants_path,
+            apns_extra_ca_roots_path,
+            apns_use_built_in_ca_roots,
+            apns_host,
+            apns_port,
+            apns_max_in_flight_sends,
+            apns_sends_per_second,
+            apns_send_burst,
             db_path,
+            db_pool_size,
+            db_encryption_key,
+            db_encryption_key_path,
+            db_backup_dir,
             host,
             port,
+            max_connections,
+            trusted_proxies,
+            webhook_signing_secret_key,
             api_base_url,
             relay_url,
-            nostr_event_cache_max_age
+            fallback_relay_urls,
+            nostr_event_cache_max_age,
+            relay_list_cache_max_age,
+            note_fetch_timeout,
+            note_fetch_limit,
+            relay_fetch_max_concurrent_subscriptions,
+            relay_fetch_subscriptions_per_second,
+            relay_fetch_burst,
+            shard_count,
+            shard_index,
+            admin_pubkeys,
+            notification_templates_path,
+            push_body_max_length,
+            silent_push_kinds,
+            event_inclusion_policies,
+            event_max_age_seconds,
+            event_min_age_seconds,
+            spam_content_denylist_path,
+            spam_min_proof_of_work,
+            spam_max_pubkey_tags,
+            max_processed_pubkey_tags,
+            max_processed_event_tags,
+            flood_guard_threshold,
+            flood_guard_mode,
+            follow_list_unavailable_policy,
+            report_suppression_threshold,
+            report_max_age,
+            sensitive_hashtags,
+            device_removal_grace_period,
+            notifications_max_rows,
+            deliveries_max_rows,
+            ingestion_queue_capacity,
+            ingestion_queue_high_water_mark,
+            ingestion_workers,
+            queued_event_max_ages,
+            queued_event_default_max_age,
+            relay_ok_accepts,
+            relay_ok_message,
+            relay_accepted_kinds,
+            relay_max_event_size,
+            relay_max_event_tags,
+            relay_connection_messages_per_second,
+            relay_pubkey_events_per_second,
+            relay_message_burst,
+            fault_injection_probabilities,
         })
     }
 
@@ -0,0 +1,134 @@
+use base64::prelude::*;
+use openssl::ec::EcKey;
+use openssl::ecdsa::EcdsaSig;
+use openssl::pkey::{PKey, Private};
+use serde_json::json;
+use std::time::Duration;
+use thiserror::Error;
+
+const APNS_REQUEST_TIMEOUT: Duration = Duration::from_secs(10);
+// The standard APNS port. Apple also accepts connections on 2197, for networks that block outgoing 443
+pub const DEFAULT_APNS_PORT: u16 = 443;
+
+/// The HTTP/2 client every APNS request goes through, for regular pushes of all tenants and Live Activity pushes alike.
+///
+/// The `a2` client builds its TLS connector privately, so requests are sent with this client instead,
+/// which lets operators configure the CA roots APNS servers are verified against, and the host and port to connect to
+pub struct ApnsConnection {
+    http_client: reqwest::Client,
+    // A host to use instead of Apple's (e.g. a mock server for testing)
+    host_override: Option<String>,
+    port: u16,
+}
+
+impl ApnsConnection {
+    // MARK: - Initialization
+
+    /// Creates the client, trusting the CA roots in the PEM bundle at `extra_ca_roots_path` in addition to
+    /// (or, if `use_built_in_ca_roots` is false, instead of) the built-in ones
+    pub fn new(
+        extra_ca_roots_path: Option<&str>,
+        use_built_in_ca_roots: bool,
+        host_override: Option<String>,
+        port: u16,
+    ) -> Result<Self, Box<dyn std::error::Error>> {
+        let mut http_client_builder = reqwest::Client::builder()
+            .timeout(APNS_REQUEST_TIMEOUT)
+            .http2_prior_knowledge()
+            .tls_built_in_root_certs(use_built_in_ca_roots);
+        // Lets operators trust Apple's rotated CAs without a code release
+        if let Some(extra_ca_roots_path) = extra_ca_roots_path {
+            let certificates = reqwest::Certificate::from_pem_bundle(&std::fs::read(extra_ca_roots_path)?)?;
+            if certificates.is_empty() {
+                return Err(format!("{} contains no PEM certificates", extra_ca_roots_path).into());
+            }
+            log::info!("Trusting {} extra CA roots for APNS connections", certificates.len());
+            for certificate in certificates {
+                http_client_builder = http_client_builder.add_root_certificate(certificate);
+            }
+        } else if !use_built_in_ca_roots {
+            return Err("The built-in CA roots can only be disabled for APNS connections when extra ones are configured".into());
+        }
+        if let Some(host_override) = &host_override {
+            log::warn!("Sending APNS pushes to {}:{} instead of Apple's servers", host_override, port);
+        }
+        Ok(ApnsConnection {
+            http_client: http_client_builder.build()?,
+            host_override,
+            port,
+        })
+    }
+
+    // MARK: - Sending
+
+    /// Starts a push request to a device (or Live Activity) token in the environment
+    pub fn post(&self, environment: &a2::client::Endpoint, token: &str) -> reqwest::RequestBuilder {
+        let host = self.host_override.as_deref().unwrap_or(match environment {
+            a2::client::Endpoint::Production => "api.push.apple.com",
+            a2::client::Endpoint::Sandbox => "api.sandbox.push.apple.com",
+        });
+        self.http_client.post(format!("https://{}:{}/3/device/{}", host, self.port, token))
+    }
+
+    /// Sends a push request, turning APNS error statuses into `ApnsError::Rejected`
+    pub async fn send(request: reqwest::RequestBuilder) -> Result<a2::Response, ApnsError> {
+        let response = request.send().await?;
+        let code = response.status().as_u16();
+        let apns_id = response
+            .headers()
+            .get("apns-id")
+            .and_then(|apns_id| apns_id.to_str().ok())
+            .map(|apns_id| apns_id.to_string());
+        if response.status().is_success() {
+            return Ok(a2::Response { error: None, apns_id, code });
+        }
+        let error = response.json::<a2::ErrorBody>().await.ok();
+        Err(ApnsError::Rejected(a2::Response { error, apns_id, code }))
+    }
+}
+
+/// Why a push request to APNS failed
+#[derive(Debug, Error)]
+pub enum ApnsError {
+    #[error("APNS rejected the push with status {}", .0.code)]
+    Rejected(a2::Response),
+    #[error("APNS request failed: {0}")]
+    Request(#[from] reqwest::Error),
+    #[error("Failed to sign the APNS provider token: {0}")]
+    ProviderToken(#[from] openssl::error::ErrorStack),
+}
+
+/// Signs the provider tokens (JWT) of APNS token-based authentication with a team's private key
+pub struct ApnsSigner {
+    private_key: EcKey<Private>,
+    key_id: String,
+    team_id: String,
+}
+
+impl ApnsSigner {
+    /// Loads the private key from the .p8 file at `key_path`
+    pub fn load(key_path: &str, key_id: String, team_id: String) -> Result<Self, Box<dyn std::error::Error>> {
+        let private_key_pem = std::fs::read(key_path)?;
+        let private_key = PKey::private_key_from_pem(&private_key_pem)?.ec_key()?;
+        Ok(ApnsSigner { private_key, key_id, team_id })
+    }
+
+    /// Creates an ES256 signed JWT, as required by APNS token-based authentication
+    pub fn sign(&self, issued_at: u64) -> Result<String, ApnsError> {
+        let header = json!({ "alg": "ES256", "kid": self.key_id });
+        let claims = json!({ "iss": self.team_id, "iat": issued_at });
+        let signing_input = format!(
+            "{}.{}",
+            BASE64_URL_SAFE_NO_PAD.encode(header.to_string()),
+            BASE64_URL_SAFE_NO_PAD.encode(claims.to_string())
+        );
+        let digest = openssl::sha::sha256(signing_input.as_bytes());
+        let signature = EcdsaSig::sign(&digest, &self.private_key)?;
+
+        // JWS uses the raw `r || s` form of the signature instead of DER
+        let mut raw_signature = signature.r().to_vec_padded(32)?;
+        raw_signature.extend(signature.s().to_vec_padded(32)?);
+
+        Ok(format!("{}.{}", signing_input, BASE64_URL_SAFE_NO_PAD.encode(raw_signature)))
+    }
+}
@@ -0,0 +1,136 @@
+use super::apns_connection::ApnsError;
+use super::apns_tenants::ApnsTenants;
+use super::fault_injector::{Fault, FaultInjector};
+use super::push_provider::{PushMessage, PushPriority, PushProvider, PushReceipt};
+use super::send_rate_limiter::SendRateLimiter;
+use a2::{CollapseId, DefaultNotificationBuilder, NotificationBuilder, NotificationOptions, Priority, PushType};
+use futures::future::BoxFuture;
+
+const APNS_DEVICE_TOKEN_LENGTH: usize = 64;
+
+/// Delivers pushes through APNS, with the credentials of the app (tenant) each device belongs to
+pub struct ApnsProvider {
+    tenants: ApnsTenants,
+    // Shared by all tenants, since they share our network and APNS throttles per provider
+    send_rate_limiter: SendRateLimiter,
+    fault_injector: std::sync::Arc<FaultInjector>,
+}
+
+impl ApnsProvider {
+    pub fn new(tenants: ApnsTenants, send_rate_limiter: SendRateLimiter, fault_injector: std::sync::Arc<FaultInjector>) -> Self {
+        ApnsProvider {
+            tenants,
+            send_rate_limiter,
+            fault_injector,
+        }
+    }
+
+    /// Builds the APNS payload of a push. Silent pushes must use the background push type and priority, or APNS rejects them
+    fn payload<'a>(&self, message: &'a PushMessage, topic: &'a str) -> a2::request::payload::Payload<'a> {
+        let mut builder = DefaultNotificationBuilder::new();
+        if let Some(alert) = &message.alert {
+            builder = builder.set_title(&alert.title).set_body(&alert.body);
+            if let Some(subtitle) = &alert.subtitle {
+                builder = builder.set_subtitle(subtitle);
+            }
+        }
+        if message.alert.is_none() || message.is_processed_by_app {
+            builder = builder.set_content_available();
+        }
+        if message.alert.is_some() && message.is_processed_by_app {
+            builder = builder.set_mutable_content();
+        }
+        let priority = match message.alert {
+            Some(_) => message.priority,
+            None => Some(PushPriority::Normal),
+        };
+        let mut payload = builder.build(&message.token, NotificationOptions {
+            apns_push_type: message.alert.is_none().then_some(PushType::Background),
+            apns_priority: priority.map(|priority| match priority {
+                PushPriority::High => Priority::High,
+                PushPriority::Normal => Priority::Normal,
+            }),
+            apns_collapse_id: message.collapse_id.as_deref().and_then(|collapse_id| CollapseId::new(collapse_id).ok()),
+            ..Default::default()
+        });
+        payload.options.apns_topic = Some(topic);
+        for (key, value) in &message.data {
+            payload.data.insert(*key, value.clone());
+        }
+        payload
+    }
+
+    /// Checks if the APNS response tells us that the device token will never be deliverable again
+    fn is_device_token_unusable(response: &a2::Response) -> bool {
+        match response.error.as_ref().map(|error_body| &error_body.reason) {
+            Some(a2::ErrorReason::Unregistered) => true,
+            Some(a2::ErrorReason::BadDeviceToken) => true,
+            _ => false,
+        }
+    }
+}
+
+impl PushProvider for ApnsProvider {
+    fn name(&self) -> &'static str {
+        "apns"
+    }
+
+    fn validate_token(&self, token: &str) -> bool {
+        token.len() == APNS_DEVICE_TOKEN_LENGTH && token.chars().all(|c| c.is_ascii_hexdigit())
+    }
+
+    fn has_tenant(&self, tenant_id: &str) -> bool {
+        self.tenants.contains(tenant_id)
+    }
+
+    fn payload_size(&self, message: &PushMessage) -> Result<usize, Box<dyn std::error::Error>> {
+        let tenant = self.tenants.get(message.tenant_id.as_deref());
+        Ok(serde_json::to_vec(&self.payload(message, &tenant.topic))?.len())
+    }
+
+    fn send<'a>(&'a self, message: &'a PushMessage) -> BoxFuture<'a, Option<PushReceipt>> {
+        Box::pin(async move {
+            let tenant = self.tenants.get(message.tenant_id.as_deref());
+            let _send_permit = self.send_rate_limiter.acquire().await;
+            let send_result = if self.fault_injector.should_inject(Fault::ApnsFailure) {
+                Err(FaultInjector::apns_failure())
+            } else {
+                match tenant.send(self.payload(message, &tenant.topic)).await {
+                    Some(send_result) => send_result,
+                    None => {
+                        log::warn!("Not sending push to device token '{}', APNS provider token is backing off", message.token);
+                        return None;
+                    }
+                }
+            };
+            let destination = tenant.topic.clone();
+            let receipt = match send_result {
+                Ok(response) => PushReceipt {
+                    message_id: response.apns_id,
+                    status: Some(response.code),
+                    reason: None,
+                    success: true,
+                    is_token_unusable: false,
+                    destination,
+                },
+                Err(ApnsError::Rejected(response)) => PushReceipt {
+                    is_token_unusable: Self::is_device_token_unusable(&response),
+                    reason: response.error.as_ref().map(|error_body| format!("{:?}", error_body.reason)),
+                    message_id: response.apns_id,
+                    status: Some(response.code),
+                    success: false,
+                    destination,
+                },
+                Err(e) => PushReceipt {
+                    message_id: None,
+                    status: None,
+                    reason: Some(e.to_string()),
+                    success: false,
+                    is_token_unusable: false,
+                    destination,
+                },
+            };
+            Some(receipt)
+        })
+    }
+}
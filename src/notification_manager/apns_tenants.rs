@@ -0,0 +1,249 @@
+use super::apns_connection::{ApnsConnection, ApnsError, ApnsSigner};
+use serde::Deserialize;
+use std::collections::HashMap;
+use std::sync::Arc;
+use tokio::sync::Mutex;
+use tokio::time::{Duration, Instant};
+
+// Apple rejects provider tokens older than one hour, so they are refreshed before that
+const PROVIDER_TOKEN_MAX_AGE: Duration = Duration::from_secs(50 * 60);
+// APNS throttles provider token refreshes that happen more often than every 20 minutes (TooManyProviderTokenUpdates)
+const PROVIDER_TOKEN_MIN_REFRESH_INTERVAL: Duration = Duration::from_secs(20 * 60);
+const PROVIDER_TOKEN_MIN_BACKOFF: Duration = Duration::from_secs(60);
+const PROVIDER_TOKEN_MAX_BACKOFF: Duration = Duration::from_secs(20 * 60);
+
+/// The credentials of an app (e.g. a fork or white-label build) that shares this notepush instance.
+///
+/// Example TOML file, keyed by tenant ID:
+/// ```toml
+/// [my-fork]
+/// topic = "com.example.myfork"
+/// team_id = "ABCDE12345"
+/// key_id = "FGHIJ67890"
+/// key_path = "./my-fork.p8"
+/// environment = "production"
+/// ```
+#[derive(Deserialize, Debug, Clone)]
+pub struct ApnsTenantConfig {
+    // The app bundle ID
+    pub topic: String,
+    pub team_id: String,
+    pub key_id: String,
+    // The path to the Apple private key .p8 file
+    pub key_path: String,
+    // `development` (default) or `production`
+    #[serde(default)]
+    pub environment: Option<String>,
+}
+
+impl ApnsTenantConfig {
+    /// Loads the tenant configurations from a TOML file
+    pub fn load_all(path: &str) -> Result<HashMap<String, ApnsTenantConfig>, Box<dyn std::error::Error>> {
+        let contents = std::fs::read_to_string(path)?;
+        Ok(toml::from_str(&contents)?)
+    }
+
+    fn endpoint(&self) -> a2::client::Endpoint {
+        match self.environment.as_deref() {
+            Some("production") => a2::client::Endpoint::Production,
+            _ => a2::client::Endpoint::Sandbox,
+        }
+    }
+}
+
+/// An APNS client for one app
+pub struct ApnsTenant {
+    pub topic: String,
+    // Shared by all tenants, since it only holds the connection settings
+    connection: Arc<ApnsConnection>,
+    environment: a2::client::Endpoint,
+    signer: ApnsSigner,
+    provider_token_state: Mutex<ProviderTokenState>,
+}
+
+/// Tracks the provider token (JWT) lifecycle, so that error storms do not make us refresh it more often than APNS allows
+struct ProviderTokenState {
+    // The current provider token, and when it was issued
+    token: String,
+    issued_at: Instant,
+    // Sending is paused until then, after APNS rejected our provider token
+    backoff_until: Option<Instant>,
+    backoff: Duration,
+}
+
+impl ApnsTenant {
+    pub fn new(
+        topic: String,
+        team_id: &str,
+        key_id: &str,
+        key_path: &str,
+        environment: a2::client::Endpoint,
+        connection: Arc<ApnsConnection>,
+    ) -> Result<Self, Box<dyn std::error::Error>> {
+        let signer = ApnsSigner::load(key_path, key_id.to_string(), team_id.to_string())?;
+        let token = signer.sign(nostr::Timestamp::now().as_u64())?;
+        Ok(ApnsTenant {
+            topic,
+            connection,
+            environment,
+            signer,
+            provider_token_state: Mutex::new(ProviderTokenState {
+                token,
+                issued_at: Instant::now(),
+                backoff_until: None,
+                backoff: PROVIDER_TOKEN_MIN_BACKOFF,
+            }),
+        })
+    }
+
+    // MARK: - Sending
+
+    /// Sends a notification, unless sending is paused because APNS rejected our provider token.
+    /// Returns `None` when the notification was not sent because of that
+    pub async fn send(&self, payload: a2::request::payload::Payload<'_>) -> Option<Result<a2::Response, ApnsError>> {
+        if self.is_in_provider_token_backoff().await {
+            return None;
+        }
+        let provider_token = match self.current_provider_token().await {
+            Ok(provider_token) => provider_token,
+            Err(e) => return Some(Err(e)),
+        };
+        let send_result = ApnsConnection::send(self.build_request(&payload, &provider_token)).await;
+        match &send_result {
+            Err(ApnsError::Rejected(response)) => match response.error.as_ref().map(|error_body| &error_body.reason) {
+                Some(a2::ErrorReason::TooManyProviderTokenUpdates) => self.back_off_provider_token(false).await,
+                Some(a2::ErrorReason::ExpiredProviderToken) | Some(a2::ErrorReason::InvalidProviderToken) => {
+                    self.back_off_provider_token(true).await
+                }
+                _ => self.reset_provider_token_backoff().await,
+            },
+            Ok(_) => self.reset_provider_token_backoff().await,
+            Err(_) => {}
+        }
+        Some(send_result)
+    }
+
+    /// Builds the HTTP/2 request of a push, with the APNS headers of its options
+    fn build_request(&self, payload: &a2::request::payload::Payload<'_>, provider_token: &str) -> reqwest::RequestBuilder {
+        let options = &payload.options;
+        let mut request = self
+            .connection
+            .post(&self.environment, payload.device_token)
+            .header("authorization", format!("bearer {}", provider_token))
+            .json(payload);
+        if let Some(apns_topic) = options.apns_topic {
+            request = request.header("apns-topic", apns_topic);
+        }
+        if let Some(apns_push_type) = &options.apns_push_type {
+            request = request.header("apns-push-type", apns_push_type.to_string());
+        }
+        if let Some(apns_priority) = &options.apns_priority {
+            request = request.header("apns-priority", apns_priority.to_string());
+        }
+        if let Some(apns_collapse_id) = &options.apns_collapse_id {
+            request = request.header("apns-collapse-id", apns_collapse_id.value);
+        }
+        if let Some(apns_expiration) = options.apns_expiration {
+            request = request.header("apns-expiration", apns_expiration.to_string());
+        }
+        if let Some(apns_id) = options.apns_id {
+            request = request.header("apns-id", apns_id);
+        }
+        request
+    }
+
+    // MARK: - Provider token lifecycle
+
+    /// The current provider token, refreshed first if it is about to expire
+    async fn current_provider_token(&self) -> Result<String, ApnsError> {
+        let mut provider_token_state = self.provider_token_state.lock().await;
+        if provider_token_state.issued_at.elapsed() >= PROVIDER_TOKEN_MAX_AGE {
+            provider_token_state.token = self.signer.sign(nostr::Timestamp::now().as_u64())?;
+            provider_token_state.issued_at = Instant::now();
+        }
+        Ok(provider_token_state.token.clone())
+    }
+
+    async fn is_in_provider_token_backoff(&self) -> bool {
+        let provider_token_state = self.provider_token_state.lock().await;
+        provider_token_state
+            .backoff_until
+            .map(|backoff_until| Instant::now() < backoff_until)
+            .unwrap_or(false)
+    }
+
+    /// Pauses sending with exponential backoff, and refreshes the provider token if it was rejected,
+    /// but never more often than APNS allows
+    async fn back_off_provider_token(&self, should_refresh_token: bool) {
+        let mut provider_token_state = self.provider_token_state.lock().await;
+        let now = Instant::now();
+        if provider_token_state.backoff_until.map(|backoff_until| now < backoff_until).unwrap_or(false) {
+            // Other in-flight requests already triggered the backoff
+            return;
+        }
+        provider_token_state.backoff_until = Some(now + provider_token_state.backoff);
+        log::warn!(
+            "APNS rejected the provider token of {}, pausing sends for {:?}",
+            self.topic,
+            provider_token_state.backoff
+        );
+        provider_token_state.backoff = std::cmp::min(provider_token_state.backoff * 2, PROVIDER_TOKEN_MAX_BACKOFF);
+
+        if should_refresh_token && now.duration_since(provider_token_state.issued_at) >= PROVIDER_TOKEN_MIN_REFRESH_INTERVAL {
+            match self.signer.sign(nostr::Timestamp::now().as_u64()) {
+                Ok(token) => {
+                    provider_token_state.token = token;
+                    provider_token_state.issued_at = now;
+                    log::info!("Refreshed the APNS provider token of {}", self.topic);
+                }
+                Err(e) => log::error!("Failed to refresh the APNS provider token of {}: {}", self.topic, e),
+            }
+        }
+    }
+
+    async fn reset_provider_token_backoff(&self) {
+        let mut provider_token_state = self.provider_token_state.lock().await;
+        provider_token_state.backoff_until = None;
+        provider_token_state.backoff = PROVIDER_TOKEN_MIN_BACKOFF;
+    }
+}
+
+/// The APNS clients of all apps served by this instance.
+/// Devices registered without a tenant (or with an unknown one) use the default tenant, configured by the main APNS environment variables
+pub struct ApnsTenants {
+    default_tenant: ApnsTenant,
+    tenants: HashMap<String, ApnsTenant>,
+}
+
+impl ApnsTenants {
+    pub fn new(
+        default_tenant: ApnsTenant,
+        tenant_configs: HashMap<String, ApnsTenantConfig>,
+        connection: Arc<ApnsConnection>,
+    ) -> Result<Self, Box<dyn std::error::Error>> {
+        let mut tenants = HashMap::new();
+        for (tenant_id, config) in tenant_configs {
+            let tenant = ApnsTenant::new(
+                config.topic.clone(),
+                &config.team_id,
+                &config.key_id,
+                &config.key_path,
+                config.endpoint(),
+                connection.clone(),
+            )?;
+            log::info!("Loaded APNS tenant {} ({})", tenant_id, config.topic);
+            tenants.insert(tenant_id, tenant);
+        }
+        Ok(ApnsTenants { default_tenant, tenants })
+    }
+
+    pub fn contains(&self, tenant_id: &str) -> bool {
+        self.tenants.contains_key(tenant_id)
+    }
+
+    pub fn get(&self, tenant_id: Option<&str>) -> &ApnsTenant {
+        tenant_id
+            .and_then(|tenant_id| self.tenants.get(tenant_id))
+            .unwrap_or(&self.default_tenant)
+    }
+}
@@ -0,0 +1,35 @@
+use unicode_segmentation::UnicodeSegmentation;
+
+const ELLIPSIS: &str = "…";
+// Words starting with these prefixes are links that are not useful in a push notification body
+const STRIPPED_WORD_PREFIXES: [&str; 3] = ["http://", "https://", "nostr:"];
+
+/// Prepares note content for a push notification body:
+/// strips URLs and `nostr:` URIs, collapses whitespace, and truncates to `max_graphemes` user-perceived characters with an ellipsis
+pub fn sanitize_content(content: &str, max_graphemes: usize) -> String {
+    let collapsed = content
+        .split_whitespace()
+        .filter(|word| {
+            let lowercased_word = word.to_lowercase();
+            !STRIPPED_WORD_PREFIXES
+                .iter()
+                .any(|prefix| lowercased_word.starts_with(prefix))
+        })
+        .collect::<Vec<&str>>()
+        .join(" ");
+    truncate(&collapsed, max_graphemes)
+}
+
+/// Truncates the text to at most `max_graphemes` graphemes (including the ellipsis, if one is added)
+fn truncate(text: &str, max_graphemes: usize) -> String {
+    let graphemes: Vec<&str> = text.graphemes(true).collect();
+    if graphemes.len() <= max_graphemes {
+        return text.to_string();
+    }
+    if max_graphemes == 0 {
+        return "".to_string();
+    }
+    let mut truncated = graphemes[..max_graphemes - 1].concat().trim_end().to_string();
+    truncated.push_str(ELLIPSIS);
+    truncated
+}
@@ -0,0 +1,182 @@
+use super::public_address;
+use super::NotificationManager;
+use nostr_sdk::prelude::*;
+use std::collections::{HashMap, HashSet};
+use std::sync::Arc;
+use tokio::time::Duration;
+
+// How often the DM relay lists of registered users are refreshed
+const DM_RELAY_REFRESH_INTERVAL: Duration = Duration::from_secs(15 * 60);
+// Gift wraps are backdated by up to two days (NIP-59), so the subscriptions look back that far
+const GIFT_WRAP_LOOKBACK_SECONDS: u64 = 2 * 24 * 60 * 60;
+// The most DM relays subscribed to, keeping those with the most recipients, since the relay lists are untrusted input
+const MAX_DM_RELAYS: usize = 100;
+// The most recipients in a single subscription, since relays reject or truncate filters with too many of them
+const MAX_RECIPIENTS_PER_SUBSCRIPTION: usize = 500;
+
+/// Subscribes to the NIP-17 DM relays (kind 10050) of registered users for gift wraps addressed to them,
+/// so DM notifications work even when the gift wraps never reach the configured relay
+pub struct DmRelaySubscriber {
+    client: Client,
+    notification_manager: Arc<NotificationManager>,
+    // The subscriptions on each DM relay, one per chunk of recipients, so that only the chunks whose recipients changed are replaced
+    subscriptions: HashMap<String, Vec<RecipientSubscription>>,
+}
+
+/// A gift wrap subscription on a DM relay for a chunk of its recipients
+struct RecipientSubscription {
+    subscription_id: SubscriptionId,
+    recipients: Vec<PublicKey>,
+}
+
+impl DmRelaySubscriber {
+    // MARK: - Background job
+
+    pub async fn run(notification_manager: Arc<NotificationManager>) {
+        let client = Client::new(&Keys::generate());
+        tokio::spawn(Self::handle_gift_wraps(client.clone(), notification_manager.clone()));
+
+        let mut subscriber = DmRelaySubscriber {
+            client,
+            notification_manager,
+            subscriptions: HashMap::new(),
+        };
+        let mut interval = tokio::time::interval(DM_RELAY_REFRESH_INTERVAL);
+        loop {
+            interval.tick().await;
+            if let Err(e) = subscriber.refresh_subscriptions().await {
+                log::error!("Failed to refresh DM relay subscriptions: {}", e);
+            }
+        }
+    }
+
+    // MARK: - Subscriptions
+
+    /// Re-reads the DM relay lists of registered users and subscribes to each relay for the gift wraps of its users.
+    /// Subscriptions whose recipients did not change are kept open rather than replaced, so that the relays do not send
+    /// the last two days of gift wraps again on every refresh
+    async fn refresh_subscriptions(&mut self) -> Result<(), Box<dyn std::error::Error>> {
+        let registered_pubkeys = self.notification_manager.get_registered_pubkeys().await?;
+        let dm_relay_lists = self.notification_manager.fetch_dm_relay_lists(&registered_pubkeys).await;
+        let recipients_by_relay = Self::recipients_by_public_relay(dm_relay_lists).await;
+
+        // Drop relays that no registered user lists anymore
+        let stale_relay_urls: Vec<String> = self
+            .subscriptions
+            .keys()
+            .filter(|relay_url| !recipients_by_relay.contains_key(*relay_url))
+            .cloned()
+            .collect();
+        for relay_url in stale_relay_urls {
+            self.subscriptions.remove(&relay_url);
+            if let Err(e) = self.client.remove_relay(relay_url.as_str()).await {
+                log::warn!("Failed to remove DM relay {}: {}", relay_url, e);
+            }
+        }
+
+        for (relay_url, recipients) in recipients_by_relay {
+            if !self.subscriptions.contains_key(&relay_url) {
+                if let Err(e) = self.client.add_relay(relay_url.as_str()).await {
+                    log::warn!("Skipping invalid DM relay {}: {}", relay_url, e);
+                    continue;
+                }
+                let _ = self.client.connect_relay(relay_url.as_str()).await;
+            }
+            let mut recipients: Vec<PublicKey> = recipients.into_iter().collect();
+            recipients.sort_by_key(|recipient| recipient.to_hex());
+            let recipient_chunks: Vec<Vec<PublicKey>> = recipients
+                .chunks(MAX_RECIPIENTS_PER_SUBSCRIPTION)
+                .map(|recipient_chunk| recipient_chunk.to_vec())
+                .collect();
+            let relay_subscriptions = self.subscriptions.entry(relay_url.clone()).or_default();
+            for (index, recipient_chunk) in recipient_chunks.iter().enumerate() {
+                if relay_subscriptions.get(index).map_or(false, |subscription| subscription.recipients == *recipient_chunk) {
+                    continue;
+                }
+                let subscription_id = match relay_subscriptions.get(index) {
+                    Some(subscription) => subscription.subscription_id.clone(),
+                    None => SubscriptionId::generate(),
+                };
+                // DM relays are held to the same subscription limits as the relays we fetch from
+                let _relay_subscription_permit = match self.notification_manager.acquire_relay_subscription_permit(&relay_url).await {
+                    Some(relay_subscription_permit) => relay_subscription_permit,
+                    None => {
+                        log::warn!("No subscription slot on DM relay {} freed up in time, retrying on the next refresh", relay_url);
+                        break;
+                    }
+                };
+                let filter = Filter::new()
+                    .kind(Kind::GiftWrap)
+                    .pubkeys(recipient_chunk.clone())
+                    .since(Timestamp::now() - GIFT_WRAP_LOOKBACK_SECONDS);
+                if let Err(e) = self
+                    .client
+                    .subscribe_with_id_to(vec![relay_url.as_str()], subscription_id.clone(), vec![filter], None)
+                    .await
+                {
+                    // Later chunks would take this chunk's place, so they are left for the next refresh
+                    log::warn!("Failed to subscribe to DM relay {}: {}", relay_url, e);
+                    break;
+                }
+                let subscription = RecipientSubscription { subscription_id, recipients: recipient_chunk.clone() };
+                match relay_subscriptions.get_mut(index) {
+                    Some(existing_subscription) => *existing_subscription = subscription,
+                    None => relay_subscriptions.push(subscription),
+                }
+            }
+            // The relay has fewer recipients than before, so its last subscriptions are no longer needed
+            for subscription in relay_subscriptions.split_off(recipient_chunks.len().min(relay_subscriptions.len())) {
+                self.client.unsubscribe(subscription.subscription_id).await;
+            }
+        }
+        log::debug!("Subscribed to {} DM relays", self.subscriptions.len());
+        Ok(())
+    }
+
+    /// Groups the recipients by DM relay, keeping only relays on public hosts over wss and at most `MAX_DM_RELAYS` of them,
+    /// since the relay lists are untrusted input that must not make this server connect to its own network
+    async fn recipients_by_public_relay(dm_relay_lists: HashMap<PublicKey, Vec<String>>) -> HashMap<String, HashSet<PublicKey>> {
+        let mut recipients_by_relay: HashMap<String, HashSet<PublicKey>> = HashMap::new();
+        for (pubkey, relay_urls) in dm_relay_lists {
+            for relay_url in relay_urls {
+                recipients_by_relay.entry(relay_url).or_default().insert(pubkey);
+            }
+        }
+        let mut relays: Vec<(String, HashSet<PublicKey>)> = recipients_by_relay.into_iter().collect();
+        relays.sort_by(|a, b| b.1.len().cmp(&a.1.len()));
+
+        let mut public_recipients_by_relay: HashMap<String, HashSet<PublicKey>> = HashMap::new();
+        for (relay_url, recipients) in relays {
+            if public_recipients_by_relay.len() >= MAX_DM_RELAYS {
+                log::warn!("More than {} DM relays are listed, not subscribing to {}", MAX_DM_RELAYS, relay_url);
+                continue;
+            }
+            match public_address::resolve_public_host(&relay_url, &["wss"]).await {
+                Ok(url) => public_recipients_by_relay.entry(url.to_string()).or_default().extend(recipients),
+                Err(e) => log::debug!("Not subscribing to DM relay {}: {}", relay_url, e),
+            }
+        }
+        public_recipients_by_relay
+    }
+
+    // MARK: - Gift wrap handling
+
+    async fn handle_gift_wraps(client: Client, notification_manager: Arc<NotificationManager>) {
+        let mut notifications = client.notifications();
+        loop {
+            match notifications.recv().await {
+                Ok(RelayPoolNotification::Event { relay_url, event, .. }) if event.kind == Kind::GiftWrap => {
+                    // Gift wraps seen on several relays are only notified once, thanks to the notification claims
+                    if let Err(e) = notification_manager.send_notifications_if_needed(&event, Some(relay_url.as_str())).await {
+                        log::error!("Failed to send notifications for gift wrap {}: {}", event.id, e);
+                    }
+                }
+                Ok(_) => {}
+                Err(tokio::sync::broadcast::error::RecvError::Lagged(skipped)) => {
+                    log::warn!("Missed {} DM relay notifications", skipped);
+                }
+                Err(tokio::sync::broadcast::error::RecvError::Closed) => break,
+            }
+        }
+    }
+}
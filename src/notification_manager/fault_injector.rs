@@ -0,0 +1,81 @@
+use super::apns_connection::ApnsError;
+use rand::Rng;
+use std::collections::HashMap;
+
+/// The failures that can be injected to exercise the error paths end-to-end in staging
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Hash)]
+pub enum Fault {
+    // APNS answers a push with 503 ServiceUnavailable
+    ApnsFailure,
+    // A relay does not answer a fetch before the timeout
+    RelayTimeout,
+    // A database operation fails with SQLITE_BUSY
+    DbBusy,
+}
+
+impl Fault {
+    pub fn parse(s: &str) -> Option<Self> {
+        match s {
+            "apns_failure" => Some(Fault::ApnsFailure),
+            "relay_timeout" => Some(Fault::RelayTimeout),
+            "db_busy" => Some(Fault::DbBusy),
+            _ => None,
+        }
+    }
+}
+
+/// Randomly injects faults with configured probabilities. Injects nothing unless configured, which is the only sane setting in production
+#[derive(Debug, Clone, Default)]
+pub struct FaultInjector {
+    // The probability of injecting each fault, between 0 and 1
+    probabilities: HashMap<Fault, f64>,
+}
+
+impl FaultInjector {
+    pub fn new(probabilities: HashMap<Fault, f64>) -> Self {
+        let probabilities: HashMap<Fault, f64> = probabilities
+            .into_iter()
+            .map(|(fault, probability)| (fault, probability.clamp(0.0, 1.0)))
+            .filter(|(_, probability)| *probability > 0.0)
+            .collect();
+        for (fault, probability) in &probabilities {
+            log::warn!("Fault injection is enabled: {:?} with probability {}", fault, probability);
+        }
+        FaultInjector { probabilities }
+    }
+
+    /// Rolls the dice for a fault, returning whether it should be injected now
+    pub fn should_inject(&self, fault: Fault) -> bool {
+        let probability = match self.probabilities.get(&fault) {
+            Some(probability) => *probability,
+            None => return false,
+        };
+        let should_inject = rand::thread_rng().gen_bool(probability);
+        if should_inject {
+            log::info!("Injecting fault {:?}", fault);
+        }
+        should_inject
+    }
+
+    // MARK: - Injected errors
+
+    /// The APNS response to an injected push failure, which is retryable and keeps the device token
+    pub fn apns_failure() -> ApnsError {
+        ApnsError::Rejected(a2::Response {
+            error: Some(a2::ErrorBody {
+                reason: a2::ErrorReason::ServiceUnavailable,
+                timestamp: None,
+            }),
+            apns_id: None,
+            code: 503,
+        })
+    }
+
+    /// The error of an injected database failure, as SQLite reports a locked database
+    pub fn db_busy_error() -> rusqlite::Error {
+        rusqlite::Error::SqliteFailure(
+            rusqlite::ffi::Error::new(rusqlite::ffi::SQLITE_BUSY),
+            Some("Injected fault: database is locked".to_string()),
+        )
+    }
+}
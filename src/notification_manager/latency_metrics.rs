@@ -0,0 +1,132 @@
+use std::collections::BTreeMap;
+use std::sync::Mutex;
+use std::time::Duration;
+
+// The upper bounds of the histogram buckets, in seconds
+const BUCKET_BOUNDS_SECONDS: [f64; 12] = [0.005, 0.01, 0.025, 0.05, 0.1, 0.25, 0.5, 1.0, 2.5, 5.0, 10.0, 30.0];
+
+/// The stages an event goes through, from receipt to APNS acceptance
+#[derive(Debug, Clone, Copy, PartialEq, Eq, PartialOrd, Ord)]
+pub enum ProcessingPhase {
+    // Waiting in the ingestion queue
+    Queue,
+    // Finding the pubkeys to notify in the database
+    DbLookups,
+    // Fetching mute lists and contact lists from relays
+    RelayFetches,
+    // A single APNS request
+    Apns,
+    // From receipt to the end of processing
+    Total,
+}
+
+impl ProcessingPhase {
+    fn label(&self) -> &'static str {
+        match self {
+            ProcessingPhase::Queue => "queue",
+            ProcessingPhase::DbLookups => "db_lookups",
+            ProcessingPhase::RelayFetches => "relay_fetches",
+            ProcessingPhase::Apns => "apns",
+            ProcessingPhase::Total => "total",
+        }
+    }
+}
+
+/// Latency histograms of event processing, broken into phases, so that we can find which stage regresses under load.
+/// Also counts the events whose tags were truncated, since those are likely tag spam that would explain a regression
+#[derive(Default)]
+pub struct LatencyMetrics {
+    histograms: Mutex<BTreeMap<ProcessingPhase, Histogram>>,
+    // The number of events whose tags were not all processed, by tag name
+    truncated_tag_events: Mutex<BTreeMap<&'static str, u64>>,
+}
+
+/// The latency of a single phase, summarized for humans (e.g. by the benchmark)
+pub struct PhaseLatencySummary {
+    pub phase: &'static str,
+    pub count: u64,
+    pub mean: Duration,
+    // The upper bound of the histogram bucket holding the 95th percentile, or `None` if it is above the last bucket
+    pub p95_upper_bound: Option<Duration>,
+}
+
+#[derive(Default)]
+struct Histogram {
+    bucket_counts: [u64; BUCKET_BOUNDS_SECONDS.len()],
+    count: u64,
+    sum_seconds: f64,
+}
+
+impl LatencyMetrics {
+    pub fn observe(&self, phase: ProcessingPhase, duration: Duration) {
+        let seconds = duration.as_secs_f64();
+        let mut histograms = self.histograms.lock().unwrap_or_else(|poisoned| poisoned.into_inner());
+        let histogram = histograms.entry(phase).or_default();
+        for (bucket_count, bound) in histogram.bucket_counts.iter_mut().zip(BUCKET_BOUNDS_SECONDS) {
+            if seconds <= bound {
+                *bucket_count += 1;
+            }
+        }
+        histogram.count += 1;
+        histogram.sum_seconds += seconds;
+    }
+
+    pub fn count_tag_truncation(&self, tag_name: &'static str) {
+        let mut truncated_tag_events = self.truncated_tag_events.lock().unwrap_or_else(|poisoned| poisoned.into_inner());
+        *truncated_tag_events.entry(tag_name).or_default() += 1;
+    }
+
+    /// Summarizes the histograms of the phases that were observed at least once
+    pub fn summaries(&self) -> Vec<PhaseLatencySummary> {
+        let histograms = self.histograms.lock().unwrap_or_else(|poisoned| poisoned.into_inner());
+        histograms
+            .iter()
+            .filter(|(_, histogram)| histogram.count > 0)
+            .map(|(phase, histogram)| {
+                let p95_rank = (histogram.count as f64 * 0.95).ceil() as u64;
+                PhaseLatencySummary {
+                    phase: phase.label(),
+                    count: histogram.count,
+                    mean: Duration::from_secs_f64(histogram.sum_seconds / histogram.count as f64),
+                    p95_upper_bound: histogram
+                        .bucket_counts
+                        .iter()
+                        .zip(BUCKET_BOUNDS_SECONDS)
+                        .find(|(bucket_count, _)| **bucket_count >= p95_rank)
+                        .map(|(_, bound)| Duration::from_secs_f64(bound)),
+                }
+            })
+            .collect()
+    }
+
+    /// Renders the histograms and counters in the Prometheus text exposition format
+    pub fn render_prometheus(&self) -> String {
+        let histograms = self.histograms.lock().unwrap_or_else(|poisoned| poisoned.into_inner());
+        let mut output = String::new();
+        output.push_str("# HELP notepush_event_processing_seconds Time spent processing events, by phase\n");
+        output.push_str("# TYPE notepush_event_processing_seconds histogram\n");
+        for (phase, histogram) in histograms.iter() {
+            let label = phase.label();
+            for (bucket_count, bound) in histogram.bucket_counts.iter().zip(BUCKET_BOUNDS_SECONDS) {
+                output.push_str(&format!(
+                    "notepush_event_processing_seconds_bucket{{phase=\"{}\",le=\"{}\"}} {}\n",
+                    label, bound, bucket_count
+                ));
+            }
+            output.push_str(&format!(
+                "notepush_event_processing_seconds_bucket{{phase=\"{}\",le=\"+Inf\"}} {}\n",
+                label, histogram.count
+            ));
+            output.push_str(&format!("notepush_event_processing_seconds_sum{{phase=\"{}\"}} {}\n", label, histogram.sum_seconds));
+            output.push_str(&format!("notepush_event_processing_seconds_count{{phase=\"{}\"}} {}\n", label, histogram.count));
+        }
+        drop(histograms);
+        let truncated_tag_events = self.truncated_tag_events.lock().unwrap_or_else(|poisoned| poisoned.into_inner());
+        output.push_str("# HELP notepush_truncated_tag_events_total Events with more tags than are processed, by tag name\n");
+        output.push_str("# TYPE notepush_truncated_tag_events_total counter\n");
+        for (tag_name, count) in truncated_tag_events.iter() {
+            output.push_str(&format!("notepush_truncated_tag_events_total{{tag=\"{}\"}} {}\n", tag_name, count));
+        }
+        output
+    }
+}
@@ -0,0 +1,100 @@
+use super::apns_connection::{ApnsConnection, ApnsError, ApnsSigner};
+use serde::Serialize;
+use serde_json::{json, Value};
+use std::sync::Arc;
+use tokio::sync::Mutex;
+
+// Apple rejects provider tokens older than one hour, and throttles refreshes more frequent than every 20 minutes
+const PROVIDER_TOKEN_MAX_AGE_SECONDS: u64 = 50 * 60;
+
+/// Sends pushes to iOS Live Activities (`liveactivity` push type).
+///
+/// The `a2` payload builder cannot put `event`, `timestamp` and `content-state` into the `aps` dictionary,
+/// so Live Activity payloads are built here instead.
+pub struct LiveActivityClient {
+    connection: Arc<ApnsConnection>,
+    environment: a2::client::Endpoint,
+    topic: String,
+    signer: ApnsSigner,
+    // The current provider token (JWT) and the time it was issued at
+    provider_token: Mutex<Option<(String, u64)>>,
+}
+
+/// The type of update sent to a Live Activity
+#[derive(Serialize, Debug, Clone, Copy, PartialEq)]
+#[serde(rename_all = "lowercase")]
+pub enum LiveActivityEvent {
+    Update,
+    End,
+}
+
+impl LiveActivityClient {
+    // MARK: - Initialization
+
+    pub fn new(
+        connection: Arc<ApnsConnection>,
+        signer: ApnsSigner,
+        environment: a2::client::Endpoint,
+        app_topic: &str,
+    ) -> Self {
+        LiveActivityClient {
+            connection,
+            environment,
+            topic: format!("{}.push-type.liveactivity", app_topic),
+            signer,
+            provider_token: Mutex::new(None),
+        }
+    }
+
+    // MARK: - Sending
+
+    /// Sends an update (or the end) of a Live Activity with the given content state
+    pub async fn send(
+        &self,
+        activity_token: &str,
+        event: LiveActivityEvent,
+        content_state: Value,
+    ) -> Result<(), ApnsError> {
+        let payload = Self::build_payload(event, content_state);
+        let provider_token = self.get_provider_token().await?;
+        let request = self
+            .connection
+            .post(&self.environment, activity_token)
+            .header("authorization", format!("bearer {}", provider_token))
+            .header("apns-push-type", "liveactivity")
+            .header("apns-topic", &self.topic)
+            .header("apns-priority", "10")
+            .json(&payload);
+        ApnsConnection::send(request).await?;
+        Ok(())
+    }
+
+    fn build_payload(event: LiveActivityEvent, content_state: Value) -> Value {
+        let now = nostr::Timestamp::now().as_u64();
+        let mut aps = json!({
+            "timestamp": now,
+            "event": event,
+            "content-state": content_state,
+        });
+        if event == LiveActivityEvent::End {
+            // Let the activity disappear from the lock screen right away
+            aps["dismissal-date"] = json!(now);
+        }
+        json!({ "aps": aps })
+    }
+
+    // MARK: - Provider token
+
+    async fn get_provider_token(&self) -> Result<String, ApnsError> {
+        let now = nostr::Timestamp::now().as_u64();
+        let mut provider_token = self.provider_token.lock().await;
+        if let Some((token, issued_at)) = provider_token.as_ref() {
+            if now.saturating_sub(*issued_at) < PROVIDER_TOKEN_MAX_AGE_SECONDS {
+                return Ok(token.clone());
+            }
+        }
+        let token = self.signer.sign(now)?;
+        *provider_token = Some((token.clone(), now));
+        Ok(token)
+    }
+}
@@ -1,8 +1,31 @@
 pub mod nostr_network_helper;
 mod nostr_event_extensions;
 mod nostr_event_cache;
+mod content_formatter;
+mod notification_kind;
+pub mod push_payload;
+mod zap_receipt_verifier;
+mod public_address;
+pub mod apns_connection;
+mod live_activity_client;
+mod dm_relay_subscriber;
+pub mod apns_tenants;
+mod apns_provider;
+pub mod push_provider;
+mod send_rate_limiter;
+mod relay_fetch_limiter;
+pub mod latency_metrics;
+pub mod spam_filter;
 pub mod notification_manager;
+pub mod notification_templates;
+pub mod webhook_client;
+pub mod fault_injector;
 
 pub use nostr_network_helper::NostrNetworkHelper;
 use nostr_event_extensions::{ExtendedEvent, SqlStringConvertible};
 pub use notification_manager::NotificationManager;
+pub use notification_manager::NotificationManagerConfig;
+pub use notification_manager::RecipientShard;
+pub use notification_templates::NotificationTemplates;
+pub use spam_filter::SpamFilter;
+pub use dm_relay_subscriber::DmRelaySubscriber;
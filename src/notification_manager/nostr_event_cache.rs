@@ -1,11 +1,18 @@
 use crate::utils::time_delta::TimeDelta;
+use serde::Serialize;
 use tokio::time::Duration;
 use nostr_sdk::prelude::*;
-use std::collections::HashMap;
+use std::collections::{BTreeMap, HashMap};
 use std::sync::Arc;
 use log;
 
-use super::nostr_event_extensions::MaybeConvertibleToMuteList;
+use super::nostr_event_extensions::{MaybeConvertibleToMuteList, MaybeConvertibleToRelayList, RelayList};
+
+// The maximum number of cached profiles. Profiles are looked up for every notification author, so the least recently used ones are evicted
+const MAX_CACHED_PROFILES: usize = 10_000;
+// How long after they were cached expired mute and contact lists may still stand in for ones that cannot be refetched.
+// Older ones are dropped, since a list that old is likely to no longer reflect what the user wants
+const STALE_LIST_MAX_AGE: Duration = Duration::from_secs(7 * 24 * 60 * 60);
 
 struct CacheEntry {
     event: Option<Event>,   // `None` means the event does not exist as far as we know (It does NOT mean expired)
@@ -17,24 +24,44 @@ impl CacheEntry {
         let time_delta = TimeDelta::subtracting(nostr::Timestamp::now(), self.added_at);
         time_delta.negative || (time_delta.delta_abs_seconds > max_age.as_secs())
     }
+
+    fn status(&self, max_age: Duration) -> CacheEntryStatus {
+        CacheEntryStatus {
+            age_seconds: TimeDelta::subtracting(nostr::Timestamp::now(), self.added_at).delta_abs_seconds,
+            expired: self.is_expired(max_age),
+            found: self.event.is_some(),
+        }
+    }
 }
 
 pub struct Cache {
     entries: HashMap<EventId, Arc<CacheEntry>>,
     mute_lists: HashMap<PublicKey, Arc<CacheEntry>>,
     contact_lists: HashMap<PublicKey, Arc<CacheEntry>>,
+    // Profiles (kind 0) with the tick of their last use, and the pubkeys ordered by that tick for LRU eviction
+    profiles: HashMap<PublicKey, (Arc<CacheEntry>, u64)>,
+    profiles_by_last_use: BTreeMap<u64, PublicKey>,
+    profile_use_tick: u64,
+    relay_lists: HashMap<PublicKey, Arc<CacheEntry>>,
     max_age: Duration,
+    // Relay lists rarely change, so they are kept for longer than other events
+    relay_list_max_age: Duration,
 }
 
 impl Cache {
     // MARK: - Initialization
 
-    pub fn new(max_age: Duration) -> Self {
+    pub fn new(max_age: Duration, relay_list_max_age: Duration) -> Self {
         Cache {
             entries: HashMap::new(),
             mute_lists: HashMap::new(),
             contact_lists: HashMap::new(),
+            profiles: HashMap::new(),
+            profiles_by_last_use: BTreeMap::new(),
+            profile_use_tick: 0,
+            relay_lists: HashMap::new(),
             max_age,
+            relay_list_max_age,
         }
     }
 
@@ -44,8 +71,8 @@ impl Cache {
         if let Some(mute_list) = mute_list {
             self.add_event(mute_list);
         } else {
-            self.mute_lists.insert(
-                author.clone(),
+            self.replace_mute_list(
+                author,
                 Arc::new(CacheEntry {
                     event: None,
                     added_at: nostr::Timestamp::now(),
@@ -58,7 +85,21 @@ impl Cache {
         if let Some(contact_list) = contact_list {
             self.add_event(contact_list);
         } else {
-            self.contact_lists.insert(
+            self.replace_contact_list(
+                author,
+                Arc::new(CacheEntry {
+                    event: None,
+                    added_at: nostr::Timestamp::now(),
+                }),
+            );
+        }
+    }
+
+    pub fn add_optional_relay_list_with_author(&mut self, author: &PublicKey, relay_list: Option<Event>) {
+        if let Some(relay_list) = relay_list {
+            self.add_event(relay_list);
+        } else {
+            self.relay_lists.insert(
                 author.clone(),
                 Arc::new(CacheEntry {
                     event: None,
@@ -68,6 +109,20 @@ impl Cache {
         }
     }
 
+    pub fn add_optional_profile_with_author(&mut self, author: &PublicKey, profile: Option<Event>) {
+        if let Some(profile) = profile {
+            self.add_event(profile);
+        } else {
+            self.insert_profile(
+                author,
+                Arc::new(CacheEntry {
+                    event: None,
+                    added_at: nostr::Timestamp::now(),
+                }),
+            );
+        }
+    }
+
     pub fn add_event(&mut self, event: Event) {
         let entry = Arc::new(CacheEntry {
             event: Some(event.clone()),
@@ -77,25 +132,76 @@ impl Cache {
 
         match event.kind {
             Kind::MuteList => {
-                self.mute_lists.insert(event.pubkey.clone(), entry.clone());
+                self.replace_mute_list(&event.pubkey, entry.clone());
                 log::debug!("Added mute list to the cache. Event ID: {}", event.id.to_hex());
             }
             Kind::ContactList => {
-                self.contact_lists
-                    .insert(event.pubkey.clone(), entry.clone());
+                self.replace_contact_list(&event.pubkey, entry.clone());
                 log::debug!("Added contact list to the cache. Event ID: {}", event.id.to_hex());
             }
+            Kind::RelayList => {
+                self.relay_lists.insert(event.pubkey.clone(), entry.clone());
+                log::debug!("Added relay list to the cache. Event ID: {}", event.id.to_hex());
+            }
+            Kind::Metadata => {
+                // Profiles are bounded by their own LRU, so they are not kept in the unbounded event map
+                self.entries.remove(&event.id);
+                self.insert_profile(&event.pubkey, entry.clone());
+                log::debug!("Added profile to the cache. Event ID: {}", event.id.to_hex());
+            }
             _ => {
                 log::debug!("Added event to the cache. Event ID: {}", event.id.to_hex());
             }
         }
     }
 
+    fn replace_mute_list(&mut self, pubkey: &PublicKey, entry: Arc<CacheEntry>) {
+        Self::replace_list(&mut self.mute_lists, &mut self.entries, pubkey, entry);
+    }
+
+    fn replace_contact_list(&mut self, pubkey: &PublicKey, entry: Arc<CacheEntry>) {
+        Self::replace_list(&mut self.contact_lists, &mut self.entries, pubkey, entry);
+    }
+
+    /// Inserts a mute or contact list, dropping the (possibly expired) list it replaces from the event map
+    fn replace_list(
+        lists: &mut HashMap<PublicKey, Arc<CacheEntry>>,
+        entries: &mut HashMap<EventId, Arc<CacheEntry>>,
+        pubkey: &PublicKey,
+        entry: Arc<CacheEntry>,
+    ) {
+        let replaced_event_id = lists
+            .insert(pubkey.clone(), entry.clone())
+            .and_then(|replaced_entry| replaced_entry.event.as_ref().map(|event| event.id));
+        let event_id = entry.event.as_ref().map(|event| event.id);
+        if let Some(replaced_event_id) = replaced_event_id.filter(|replaced_event_id| Some(*replaced_event_id) != event_id) {
+            entries.remove(&replaced_event_id);
+        }
+    }
+
+    fn insert_profile(&mut self, pubkey: &PublicKey, entry: Arc<CacheEntry>) {
+        self.remove_profile(pubkey);
+        if self.profiles.len() >= MAX_CACHED_PROFILES {
+            if let Some((_, least_recently_used_pubkey)) = self.profiles_by_last_use.pop_first() {
+                self.profiles.remove(&least_recently_used_pubkey);
+            }
+        }
+        let tick = self.next_profile_use_tick();
+        self.profiles.insert(pubkey.clone(), (entry, tick));
+        self.profiles_by_last_use.insert(tick, pubkey.clone());
+    }
+
+    fn next_profile_use_tick(&mut self) -> u64 {
+        self.profile_use_tick += 1;
+        self.profile_use_tick
+    }
+
     // MARK: - Fetching items from the cache
 
+    /// Gets the mute list of a pubkey unless it is expired. Expired mute lists are kept until they are refetched,
+    /// so that they still apply when refetching fails (see `get_stale_mute_list`)
     pub fn get_mute_list(&mut self, pubkey: &PublicKey) -> Result<Option<MuteList>, CacheError> {
         if let Some(entry) = self.mute_lists.get(pubkey) {
-            let entry = entry.clone();  // Clone the Arc to avoid borrowing issues
             if !entry.is_expired(self.max_age) {
                 match &entry.event {
                     Some(event) => {
@@ -107,44 +213,124 @@ impl Cache {
                         return Ok(None);
                     }
                 }
-            } else {
-                log::debug!("Mute list for pubkey {} is expired, removing it from the cache", pubkey.to_hex());
-                self.mute_lists.remove(pubkey);
-                self.remove_event_from_all_maps(&entry.event);
             }
+            log::debug!("Mute list for pubkey {} is expired, keeping it until it is refetched", pubkey.to_hex());
         }
         log::debug!("Mute list for pubkey {} not found on cache", pubkey.to_hex());
         Err(CacheError::NotFound)
     }
 
+    /// Gets the mute list event of a pubkey even if it is expired, or `None` if nothing is cached or it is too old to use
+    pub fn get_stale_mute_list(&mut self, pubkey: &PublicKey) -> Option<Option<Event>> {
+        Self::get_stale_list(&mut self.mute_lists, &mut self.entries, pubkey)
+    }
+
+    /// Gets the contact list of a pubkey unless it is expired. Expired contact lists are kept until they are refetched,
+    /// so that they can stand in for the current one when refetching fails (see `get_stale_contact_list`)
     pub fn get_contact_list(&mut self, pubkey: &PublicKey) -> Result<Option<Event>, CacheError> {
         if let Some(entry) = self.contact_lists.get(pubkey) {
-            let entry = entry.clone();  // Clone the Arc to avoid borrowing issues
             if !entry.is_expired(self.max_age) {
                 return Ok(entry.event.clone());
+            }
+            log::debug!("Contact list for pubkey {} is expired, keeping it until it is refetched", pubkey.to_hex());
+        }
+        Err(CacheError::NotFound)
+    }
+
+    /// Gets the contact list of a pubkey even if it is expired, or `None` if nothing is cached or it is too old to use
+    pub fn get_stale_contact_list(&mut self, pubkey: &PublicKey) -> Option<Option<Event>> {
+        Self::get_stale_list(&mut self.contact_lists, &mut self.entries, pubkey)
+    }
+
+    /// Gets a mute or contact list even if it is expired, dropping it instead if it is older than `STALE_LIST_MAX_AGE`
+    fn get_stale_list(
+        lists: &mut HashMap<PublicKey, Arc<CacheEntry>>,
+        entries: &mut HashMap<EventId, Arc<CacheEntry>>,
+        pubkey: &PublicKey,
+    ) -> Option<Option<Event>> {
+        let entry = lists.get(pubkey)?;
+        if !entry.is_expired(STALE_LIST_MAX_AGE) {
+            return Some(entry.event.clone());
+        }
+        log::debug!("Expired list for pubkey {} is too old to use, dropping it", pubkey.to_hex());
+        if let Some(event_id) = lists.remove(pubkey).and_then(|entry| entry.event.as_ref().map(|event| event.id)) {
+            entries.remove(&event_id);
+        }
+        None
+    }
+
+    pub fn get_relay_list(&mut self, pubkey: &PublicKey) -> Result<Option<RelayList>, CacheError> {
+        if let Some(entry) = self.relay_lists.get(pubkey) {
+            let entry = entry.clone();  // Clone the Arc to avoid borrowing issues
+            if !entry.is_expired(self.relay_list_max_age) {
+                return Ok(entry.event.as_ref().and_then(|event| event.to_relay_list()));
+            } else {
+                log::debug!("Relay list for pubkey {} is expired, removing it from the cache", pubkey.to_hex());
+                self.relay_lists.remove(pubkey);
+                if let Some(event) = &entry.event {
+                    self.entries.remove(&event.id);
+                }
+            }
+        }
+        Err(CacheError::NotFound)
+    }
+
+    pub fn get_profile(&mut self, pubkey: &PublicKey) -> Result<Option<Metadata>, CacheError> {
+        if let Some((entry, last_use_tick)) = self.profiles.get(pubkey) {
+            let (entry, last_use_tick) = (entry.clone(), *last_use_tick);  // Clone the Arc to avoid borrowing issues
+            if !entry.is_expired(self.max_age) {
+                // Mark the profile as recently used
+                let tick = self.next_profile_use_tick();
+                self.profiles_by_last_use.remove(&last_use_tick);
+                self.profiles_by_last_use.insert(tick, pubkey.clone());
+                self.profiles.insert(pubkey.clone(), (entry.clone(), tick));
+                return Ok(entry.event.as_ref().and_then(|event| Metadata::from_json(&event.content).ok()));
             } else {
-                log::debug!("Contact list for pubkey {} is expired, removing it from the cache", pubkey.to_hex());
-                self.contact_lists.remove(pubkey);
-                self.remove_event_from_all_maps(&entry.event);
+                log::debug!("Profile for pubkey {} is expired, removing it from the cache", pubkey.to_hex());
+                self.remove_profile(pubkey);
             }
         }
         Err(CacheError::NotFound)
     }
 
+    // MARK: - Debugging
+
+    /// Describes what is cached about a pubkey. Unlike the getters, this does not evict expired entries, so that they can be reported
+    pub fn pubkey_cache_status(&self, pubkey: &PublicKey) -> PubkeyCacheStatus {
+        PubkeyCacheStatus {
+            mute_list: self.mute_lists.get(pubkey).map(|entry| entry.status(self.max_age)),
+            contact_list: self.contact_lists.get(pubkey).map(|entry| entry.status(self.max_age)),
+            relay_list: self.relay_lists.get(pubkey).map(|entry| entry.status(self.relay_list_max_age)),
+            profile: self.profiles.get(pubkey).map(|(entry, _)| entry.status(self.max_age)),
+        }
+    }
+
     // MARK: - Removing items from the cache
 
-    fn remove_event_from_all_maps(&mut self, event: &Option<Event>) {
-        if let Some(event) = event {
-            let event_id = event.id.clone();
-            let pubkey = event.pubkey.clone();
-            self.entries.remove(&event_id);
-            self.mute_lists.remove(&pubkey);
-            self.contact_lists.remove(&pubkey);
+    fn remove_profile(&mut self, pubkey: &PublicKey) {
+        if let Some((_, last_use_tick)) = self.profiles.remove(pubkey) {
+            self.profiles_by_last_use.remove(&last_use_tick);
         }
-        // We can't remove an event from all maps if the event does not exist
     }
 }
 
+/// The cache entries of a pubkey. `None` means that nothing is cached, so the next lookup fetches from the relays
+#[derive(Serialize, Debug)]
+pub struct PubkeyCacheStatus {
+    mute_list: Option<CacheEntryStatus>,
+    contact_list: Option<CacheEntryStatus>,
+    relay_list: Option<CacheEntryStatus>,
+    profile: Option<CacheEntryStatus>,
+}
+
+#[derive(Serialize, Debug)]
+pub struct CacheEntryStatus {
+    age_seconds: u64,
+    expired: bool,
+    // `false` if the relays answered that the pubkey has no such event
+    found: bool,
+}
+
 // Error type
 #[derive(Debug)]
 pub enum CacheError {
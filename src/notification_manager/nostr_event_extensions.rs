@@ -1,5 +1,5 @@
-use nostr::{self, key::PublicKey, nips::nip51::MuteList, Alphabet, SingleLetterTag, TagKind::SingleLetter};
-use nostr_sdk::{Kind, TagKind};
+use nostr::{self, key::PublicKey, nips::nip01::Coordinate, nips::nip51::MuteList, Alphabet, SingleLetterTag, TagKind::SingleLetter};
+use nostr_sdk::{JsonUtil, Kind, TagKind};
 
 /// Temporary scaffolding of old methods that have not been ported to use native Event methods
 pub trait ExtendedEvent {
@@ -12,11 +12,54 @@ pub trait ExtendedEvent {
     /// Retrieves a set of pubkeys relevant to the note
     fn relevant_pubkeys(&self) -> std::collections::HashSet<nostr::PublicKey>;
 
+    /// Retrieves a set of pubkeys referenced by the note's uppercase P tags (e.g. the zap sender in NIP-57 zap receipts)
+    fn uppercase_referenced_pubkeys(&self) -> std::collections::HashSet<nostr::PublicKey>;
+
     /// Retrieves a set of event IDs referenced by the note
     fn referenced_event_ids(&self) -> std::collections::HashSet<nostr::EventId>;
+
+    /// Retrieves the event ID of the root of the thread the note replies in (NIP-10)
+    fn root_event_id(&self) -> Option<nostr::EventId>;
+
+    /// Retrieves the event ID the note directly replies to (NIP-10), which is the root for top-level replies
+    fn reply_event_id(&self) -> Option<nostr::EventId>;
+
+    /// Retrieves a set of event IDs the note mentions without replying to them (NIP-10)
+    fn mentioned_event_ids(&self) -> std::collections::HashSet<nostr::EventId>;
+
+    /// Retrieves the coordinates of the addressable events referenced by the note (a tags), in tag order and without duplicates
+    fn referenced_coordinates(&self) -> Vec<Coordinate>;
     
     /// Retrieves a set of hashtags (t tags) referenced by the note
     fn referenced_hashtags(&self) -> std::collections::HashSet<String>;
+
+    /// Checks if the note carries a NIP-36 content warning
+    fn has_content_warning(&self) -> bool;
+
+    /// Retrieves the zap request embedded in a zap receipt, or the zap request itself if the note is one
+    fn zap_request(&self) -> Option<nostr::Event>;
+
+    /// Checks if the note is a zap (request or receipt) that the zapper asked to keep anonymous
+    fn is_anonymous_zap(&self) -> bool;
+
+    /// Retrieves the amount of a zap (request or receipt) in millisats, as requested by the zapper
+    fn zap_amount_msats(&self) -> Option<u64>;
+
+    /// Retrieves the payment hash of the bolt11 invoice a zap receipt was issued for, as hex
+    fn zap_payment_hash(&self) -> Option<String>;
+
+    /// Retrieves who sent a zap (request, receipt or private message). For zap receipts, this is the author of the embedded zap request
+    /// if its signature is valid, since only the zapper can sign it, and the uppercase P tag otherwise, since the lightning provider sets it
+    fn zap_sender(&self) -> Option<nostr::PublicKey>;
+
+    /// Retrieves who received a zap (request, receipt or private message)
+    fn zap_recipient(&self) -> Option<nostr::PublicKey>;
+
+    /// Retrieves who the note is from: the zap sender for zaps, whose author is a lightning provider, and the author otherwise
+    fn attributed_author(&self) -> nostr::PublicKey;
+
+    /// Retrieves the relay URLs hinted in the note's event, pubkey and address tags (NIP-10), in tag order and without duplicates
+    fn relay_hints(&self) -> Vec<String>;
 }
 
 // This is a wrapper around the Event type from strfry-policies, which adds some useful methods
@@ -41,13 +84,52 @@ impl ExtendedEvent for nostr::Event {
         pubkeys
     }
 
+    /// Retrieves a set of pubkeys referenced by the note's uppercase P tags (e.g. the zap sender in NIP-57 zap receipts)
+    fn uppercase_referenced_pubkeys(&self) -> std::collections::HashSet<nostr::PublicKey> {
+        self.get_tags_content(SingleLetter(SingleLetterTag::uppercase(Alphabet::P)))
+            .iter()
+            .filter_map(|tag| PublicKey::from_hex(tag).ok())
+            .collect()
+    }
+
     /// Retrieves a set of event IDs referenced by the note
     fn referenced_event_ids(&self) -> std::collections::HashSet<nostr::EventId> {
-        self.get_tag_content(SingleLetter(SingleLetterTag::lowercase(Alphabet::E)))
+        self.get_tags_content(SingleLetter(SingleLetterTag::lowercase(Alphabet::E)))
             .iter()
             .filter_map(|tag| nostr::EventId::from_hex(tag).ok())
             .collect()
     }
+
+    /// Retrieves the event ID of the root of the thread the note replies in (NIP-10)
+    fn root_event_id(&self) -> Option<nostr::EventId> {
+        thread_references(self).root
+    }
+
+    /// Retrieves the event ID the note directly replies to (NIP-10), which is the root for top-level replies
+    fn reply_event_id(&self) -> Option<nostr::EventId> {
+        let thread_references = thread_references(self);
+        thread_references.reply.or(thread_references.root)
+    }
+
+    /// Retrieves a set of event IDs the note mentions without replying to them (NIP-10)
+    fn mentioned_event_ids(&self) -> std::collections::HashSet<nostr::EventId> {
+        thread_references(self).mentions
+    }
+
+    /// Retrieves the coordinates of the addressable events referenced by the note (a tags), in tag order and without duplicates
+    fn referenced_coordinates(&self) -> Vec<Coordinate> {
+        let mut coordinates: Vec<Coordinate> = Vec::new();
+        for coordinate in self
+            .get_tags_content(SingleLetter(SingleLetterTag::lowercase(Alphabet::A)))
+            .iter()
+            .filter_map(|tag| tag.parse::<Coordinate>().ok())
+        {
+            if !coordinates.contains(&coordinate) {
+                coordinates.push(coordinate);
+            }
+        }
+        coordinates
+    }
     
     /// Retrieves a set of hashtags (t tags) referenced by the note
     fn referenced_hashtags(&self) -> std::collections::HashSet<String> {
@@ -56,6 +138,187 @@ impl ExtendedEvent for nostr::Event {
             .map(|tag| tag.to_string())
             .collect()
     }
+
+    /// Checks if the note carries a NIP-36 content warning
+    fn has_content_warning(&self) -> bool {
+        self.iter_tags().any(|tag| tag.kind() == TagKind::ContentWarning)
+    }
+
+    /// Retrieves the zap request embedded in a zap receipt, or the zap request itself if the note is one
+    fn zap_request(&self) -> Option<nostr::Event> {
+        match self.kind {
+            Kind::ZapRequest => Some(self.clone()),
+            Kind::ZapReceipt => {
+                let description = self.get_tag_content(TagKind::Description)?;
+                nostr::Event::from_json(description).ok()
+            }
+            _ => None,
+        }
+    }
+
+    /// Checks if the note is a zap (request or receipt) that the zapper asked to keep anonymous
+    fn is_anonymous_zap(&self) -> bool {
+        match self.zap_request() {
+            Some(zap_request) => zap_request.iter_tags().any(|tag| tag.kind() == TagKind::Anon),
+            None => false,
+        }
+    }
+
+    /// Retrieves the amount of a zap (request or receipt) in millisats, as requested by the zapper
+    fn zap_amount_msats(&self) -> Option<u64> {
+        self.zap_request()?
+            .get_tag_content(TagKind::Amount)?
+            .parse()
+            .ok()
+    }
+
+    /// Retrieves the payment hash of the bolt11 invoice a zap receipt was issued for, as hex
+    fn zap_payment_hash(&self) -> Option<String> {
+        bolt11_payment_hash(self.get_tag_content(TagKind::Bolt11)?)
+    }
+
+    /// Retrieves who sent a zap (request, receipt or private message). For zap receipts, this is the author of the embedded zap request
+    /// if its signature is valid, since only the zapper can sign it, and the uppercase P tag otherwise, since the lightning provider sets it
+    fn zap_sender(&self) -> Option<nostr::PublicKey> {
+        match self.kind {
+            Kind::ZapRequest | Kind::ZapPrivateMessage => Some(self.pubkey),
+            Kind::ZapReceipt => self
+                .zap_request()
+                .filter(|zap_request| zap_request.verify().is_ok())
+                .map(|zap_request| zap_request.pubkey)
+                .or_else(|| self.uppercase_referenced_pubkeys().into_iter().next()),
+            _ => None,
+        }
+    }
+
+    /// Retrieves who received a zap (request, receipt or private message)
+    fn zap_recipient(&self) -> Option<nostr::PublicKey> {
+        match self.kind {
+            Kind::ZapRequest | Kind::ZapPrivateMessage | Kind::ZapReceipt => self.referenced_pubkeys().into_iter().next(),
+            _ => None,
+        }
+    }
+
+    /// Retrieves who the note is from: the zap sender for zaps, whose author is a lightning provider, and the author otherwise
+    fn attributed_author(&self) -> nostr::PublicKey {
+        self.zap_sender().unwrap_or(self.pubkey)
+    }
+
+    /// Retrieves the relay URLs hinted in the note's event, pubkey and address tags (NIP-10), in tag order and without duplicates
+    fn relay_hints(&self) -> Vec<String> {
+        let mut relay_hints: Vec<String> = Vec::new();
+        for tag in self.iter_tags() {
+            let values = tag.as_vec();
+            let is_reference_tag = matches!(values.first().map(|name| name.as_str()), Some("e") | Some("p") | Some("a") | Some("q"));
+            let relay_hint = match values.get(2) {
+                Some(relay_hint) if is_reference_tag && (relay_hint.starts_with("wss://") || relay_hint.starts_with("ws://")) => relay_hint,
+                _ => continue,
+            };
+            if !relay_hints.contains(relay_hint) {
+                relay_hints.push(relay_hint.clone());
+            }
+        }
+        relay_hints
+    }
+}
+
+// MARK: - NIP-10 thread references
+
+/// The e tags of a note, classified by their role in the thread
+struct ThreadReferences {
+    root: Option<nostr::EventId>,
+    reply: Option<nostr::EventId>,
+    mentions: std::collections::HashSet<nostr::EventId>,
+}
+
+/// Classifies the e tags of a note by their markers, or by their position for notes using the deprecated positional scheme
+/// (the first tag is the root, the last one is the reply, and any in between are mentions)
+fn thread_references(event: &nostr::Event) -> ThreadReferences {
+    let e_tags: Vec<(nostr::EventId, Option<String>)> = event
+        .iter_tags()
+        .filter_map(|tag| {
+            let values = tag.as_vec();
+            if values.first().map(|name| name.as_str()) != Some("e") {
+                return None;
+            }
+            let event_id = nostr::EventId::from_hex(values.get(1)?).ok()?;
+            let marker = values.get(3).filter(|marker| !marker.is_empty()).cloned();
+            Some((event_id, marker))
+        })
+        .collect();
+    let mut thread_references = ThreadReferences {
+        root: None,
+        reply: None,
+        mentions: std::collections::HashSet::new(),
+    };
+
+    if e_tags.iter().any(|(_, marker)| marker.is_some()) {
+        for (event_id, marker) in e_tags {
+            match marker.as_deref() {
+                Some("root") => thread_references.root = Some(event_id),
+                Some("reply") => thread_references.reply = Some(event_id),
+                // Unmarked tags among marked ones are mentions, like those marked as such
+                _ => {
+                    thread_references.mentions.insert(event_id);
+                }
+            }
+        }
+        return thread_references;
+    }
+
+    let last_index = e_tags.len().saturating_sub(1);
+    for (index, (event_id, _)) in e_tags.into_iter().enumerate() {
+        if index == 0 {
+            thread_references.root = Some(event_id);
+        } else if index == last_index {
+            thread_references.reply = Some(event_id);
+        } else {
+            thread_references.mentions.insert(event_id);
+        }
+    }
+    thread_references
+}
+
+/// Decodes the payment hash tagged field (`p`) of a bolt11 invoice, without checking its checksum or signature
+fn bolt11_payment_hash(invoice: &str) -> Option<String> {
+    const BECH32_CHARSET: &str = "qpzry9x8gf2tvdw0s3jn54khce6mua7l";
+    const TIMESTAMP_LENGTH: usize = 7;
+    const CHECKSUM_LENGTH: usize = 6;
+    const PAYMENT_HASH_TYPE: u8 = 1;
+    const PAYMENT_HASH_LENGTH: usize = 52;
+
+    let invoice = invoice.to_ascii_lowercase();
+    let (_, data) = invoice.rsplit_once('1')?;
+    let data: Vec<u8> = data
+        .chars()
+        .map(|c| BECH32_CHARSET.find(c).map(|value| value as u8))
+        .collect::<Option<_>>()?;
+    let tagged_fields = data.get(TIMESTAMP_LENGTH..data.len().checked_sub(CHECKSUM_LENGTH)?)?;
+
+    let mut index = 0;
+    while index + 3 <= tagged_fields.len() {
+        let field_type = tagged_fields[index];
+        let field_length = tagged_fields[index + 1] as usize * 32 + tagged_fields[index + 2] as usize;
+        let field = tagged_fields.get(index + 3..index + 3 + field_length)?;
+        if field_type == PAYMENT_HASH_TYPE && field_length == PAYMENT_HASH_LENGTH {
+            // Regroups the 5-bit values into the 32 bytes of the hash, dropping the 4 bits of padding
+            let mut bytes = Vec::with_capacity(32);
+            let mut accumulator: u32 = 0;
+            let mut bit_count = 0;
+            for value in field {
+                accumulator = (accumulator << 5) | *value as u32;
+                bit_count += 5;
+                if bit_count >= 8 {
+                    bit_count -= 8;
+                    bytes.push((accumulator >> bit_count) as u8);
+                    accumulator &= (1 << bit_count) - 1;
+                }
+            }
+            return Some(bytes.iter().map(|byte| format!("{:02x}", byte)).collect());
+        }
+        index += 3 + field_length;
+    }
+    None
 }
 
 // MARK: - SQL String Convertible
@@ -87,6 +350,16 @@ impl SqlStringConvertible for nostr::PublicKey {
     }
 }
 
+impl SqlStringConvertible for Coordinate {
+    fn to_sql_string(&self) -> String {
+        self.to_string()
+    }
+
+    fn from_sql_string(s: String) -> Result<Self, Box<dyn std::error::Error>> {
+        s.parse::<Coordinate>().map_err(|e| e.into())
+    }
+}
+
 impl SqlStringConvertible for nostr::Timestamp {
     fn to_sql_string(&self) -> String {
         self.as_u64().to_string()
@@ -98,6 +371,42 @@ impl SqlStringConvertible for nostr::Timestamp {
     }
 }
 
+/// A NIP-65 relay list (kind 10002): where a user publishes their events (write) and where they read mentions of them (read)
+#[derive(Debug, Clone, Default, PartialEq)]
+pub struct RelayList {
+    pub read_relays: Vec<String>,
+    pub write_relays: Vec<String>,
+}
+
+pub trait MaybeConvertibleToRelayList {
+    fn to_relay_list(&self) -> Option<RelayList>;
+}
+
+impl MaybeConvertibleToRelayList for nostr::Event {
+    fn to_relay_list(&self) -> Option<RelayList> {
+        if self.kind != Kind::RelayList {
+            return None;
+        }
+        let mut relay_list = RelayList::default();
+        for tag in self.iter_tags() {
+            let values = tag.as_vec();
+            let relay_url = match (values.first().map(|name| name.as_str()), values.get(1)) {
+                (Some("r"), Some(relay_url)) => relay_url.clone(),
+                _ => continue,
+            };
+            // A relay without a marker is used for both reading and writing
+            let marker = values.get(2).map(|marker| marker.as_str());
+            if marker != Some("write") && !relay_list.read_relays.contains(&relay_url) {
+                relay_list.read_relays.push(relay_url.clone());
+            }
+            if marker != Some("read") && !relay_list.write_relays.contains(&relay_url) {
+                relay_list.write_relays.push(relay_url);
+            }
+        }
+        Some(relay_list)
+    }
+}
+
 pub trait MaybeConvertibleToMuteList {
     fn to_mute_list(&self) -> Option<MuteList>;
 }
@@ -115,3 +424,81 @@ impl MaybeConvertibleToMuteList for nostr::Event {
         })
     }
 }
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use nostr::{EventBuilder, Keys, Tag};
+
+    fn zap_request(zapper: &Keys, recipient: &PublicKey) -> nostr::Event {
+        EventBuilder::new(Kind::ZapRequest, "Great post", [Tag::public_key(*recipient)]).to_event(zapper).unwrap()
+    }
+
+    /// A zap receipt issued by a lightning provider, embedding the zap request and claiming the zap came from `claimed_sender`
+    fn zap_receipt(zap_request_json: &str, recipient: &PublicKey, claimed_sender: &PublicKey) -> nostr::Event {
+        let tags = [
+            Tag::public_key(*recipient),
+            Tag::parse(&["P".to_string(), claimed_sender.to_hex()]).unwrap(),
+            Tag::parse(&["description".to_string(), zap_request_json.to_string()]).unwrap(),
+        ];
+        EventBuilder::new(Kind::ZapReceipt, "", tags).to_event(&Keys::generate()).unwrap()
+    }
+
+    // MARK: - Zap senders
+
+    #[test]
+    fn zap_request_is_sent_by_its_author() {
+        let (zapper, recipient) = (Keys::generate(), Keys::generate().public_key());
+        let zap_request = zap_request(&zapper, &recipient);
+
+        assert_eq!(zap_request.zap_sender(), Some(zapper.public_key()));
+        assert_eq!(zap_request.zap_recipient(), Some(recipient));
+    }
+
+    #[test]
+    fn zap_receipt_is_sent_by_the_author_of_its_validly_signed_zap_request() {
+        let (zapper, recipient) = (Keys::generate(), Keys::generate().public_key());
+        let zap_request = zap_request(&zapper, &recipient);
+        // The P tag is set by the lightning provider, so it does not get to override the signed zap request
+        let zap_receipt = zap_receipt(&zap_request.as_json(), &recipient, &Keys::generate().public_key());
+
+        assert_eq!(zap_receipt.zap_sender(), Some(zapper.public_key()));
+        assert_eq!(zap_receipt.zap_recipient(), Some(recipient));
+    }
+
+    #[test]
+    fn zap_receipt_with_a_forged_zap_request_is_sent_by_its_uppercase_p_tag() {
+        let (zapper, impersonated, recipient) = (Keys::generate(), Keys::generate(), Keys::generate().public_key());
+        let mut forged_zap_request: serde_json::Value = serde_json::from_str(&zap_request(&zapper, &recipient).as_json()).unwrap();
+        forged_zap_request["pubkey"] = serde_json::json!(impersonated.public_key().to_hex());
+        let zap_receipt = zap_receipt(&forged_zap_request.to_string(), &recipient, &zapper.public_key());
+
+        assert_eq!(zap_receipt.zap_sender(), Some(zapper.public_key()));
+    }
+
+    #[test]
+    fn other_events_have_no_zap_sender() {
+        let note = EventBuilder::text_note("Hello", []).to_event(&Keys::generate()).unwrap();
+
+        assert_eq!(note.zap_sender(), None);
+    }
+
+    // MARK: - Attributed authors
+
+    #[test]
+    fn zap_receipt_is_attributed_to_the_zapper_instead_of_the_lightning_provider() {
+        let (zapper, recipient) = (Keys::generate(), Keys::generate().public_key());
+        let zap_receipt = zap_receipt(&zap_request(&zapper, &recipient).as_json(), &recipient, &zapper.public_key());
+
+        assert_ne!(zap_receipt.pubkey, zapper.public_key());
+        assert_eq!(zap_receipt.attributed_author(), zapper.public_key());
+    }
+
+    #[test]
+    fn other_events_are_attributed_to_their_author() {
+        let author = Keys::generate();
+        let note = EventBuilder::text_note("Hello", []).to_event(&author).unwrap();
+
+        assert_eq!(note.attributed_author(), author.public_key());
+    }
+}
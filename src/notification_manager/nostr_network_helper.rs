@@ -1,31 +1,143 @@
 use tokio::sync::Mutex;
-use super::nostr_event_extensions::MaybeConvertibleToMuteList;
+use super::nostr_event_extensions::{MaybeConvertibleToMuteList, MaybeConvertibleToRelayList, RelayList};
 use super::ExtendedEvent;
 use nostr_sdk::prelude::*;
-use super::nostr_event_cache::Cache;
-use tokio::time::{timeout, Duration};
+use super::nostr_event_cache::{Cache, PubkeyCacheStatus};
+use super::fault_injector::{Fault, FaultInjector};
+use super::relay_fetch_limiter::{RelayFetchLimiter, RelayFetchPermit};
+use std::sync::Arc;
+use tokio::time::{timeout_at, Duration, Instant};
+use std::collections::{HashMap, HashSet};
 
-const NOTE_FETCH_TIMEOUT: Duration = Duration::from_secs(5);
+const RELAY_HEALTH_CHECK_INTERVAL: Duration = Duration::from_secs(10);
+const RELAY_RECONNECT_MAX_BACKOFF: Duration = Duration::from_secs(5 * 60);
+// NIP-17 list of the relays a user wants to receive DMs on
+const DM_RELAY_LIST_KIND: u16 = 10050;
+// The most DM relays taken from each user's list, since NIP-17 asks for a few and the list is untrusted input
+const MAX_DM_RELAYS_PER_PUBKEY: usize = 3;
+// The most authors in a single fetch filter, since relays reject or truncate filters with too many of them
+const MAX_AUTHORS_PER_FILTER: usize = 500;
+// How long to wait before fetching an event again after all relays timed out, doubled on every consecutive timeout up to the maximum
+const FETCH_RETRY_MIN_BACKOFF: Duration = Duration::from_secs(30);
+const FETCH_RETRY_MAX_BACKOFF: Duration = Duration::from_secs(10 * 60);
 
 pub struct NostrNetworkHelper {
     client: Client,
     cache: Mutex<Cache>,
+    // The relays to fetch from, primary relay first, followed by the fallback relays in their configured order
+    relay_urls: Vec<String>,
+    relay_fetch_stats: Mutex<HashMap<String, RelayFetchStats>>,
+    // Events whose last fetch timed out on every relay, with when to retry and the current backoff.
+    // Timeouts are not negative-cached, since the relay may just have been slow
+    fetch_retry_backoffs: Mutex<HashMap<(PublicKey, Kind), (Instant, Duration)>>,
+    // How long to wait for a relay to answer a fetch
+    note_fetch_timeout: Duration,
+    // The `limit` of each fetch subscription filter
+    note_fetch_limit: usize,
+    // Caps the concurrency and rate of the fetch subscriptions to each relay
+    relay_fetch_limiter: RelayFetchLimiter,
+    fault_injector: Arc<FaultInjector>,
 }
 
 impl NostrNetworkHelper {
     // MARK: - Initialization
 
-    pub async fn new(relay_url: String, cache_max_age: Duration) -> Result<Self, Box<dyn std::error::Error>> {
+    pub async fn new(
+        relay_url: String,
+        fallback_relay_urls: Vec<String>,
+        cache_max_age: Duration,
+        relay_list_cache_max_age: Duration,
+        note_fetch_timeout: Duration,
+        note_fetch_limit: usize,
+        relay_fetch_max_concurrent_subscriptions: usize,
+        relay_fetch_subscriptions_per_second: u32,
+        relay_fetch_burst: u32,
+        fault_injector: Arc<FaultInjector>,
+    ) -> Result<Self, Box<dyn std::error::Error>> {
         let client = Client::new(&Keys::generate());
-        client.add_relay(relay_url.clone()).await?;
+        let mut relay_urls = vec![relay_url];
+        for fallback_relay_url in fallback_relay_urls {
+            if !relay_urls.contains(&fallback_relay_url) {
+                relay_urls.push(fallback_relay_url);
+            }
+        }
+        for relay_url in &relay_urls {
+            client.add_relay(relay_url.clone()).await?;
+        }
         client.connect().await;
         
+        tokio::spawn(Self::supervise_relay_connections(client.clone()));
+        
+        let relay_fetch_limiter = RelayFetchLimiter::new(
+            relay_fetch_max_concurrent_subscriptions,
+            relay_fetch_subscriptions_per_second,
+            relay_fetch_burst,
+        );
+
         Ok(NostrNetworkHelper { 
             client,
-            cache: Mutex::new(Cache::new(cache_max_age)),
+            cache: Mutex::new(Cache::new(cache_max_age, relay_list_cache_max_age)),
+            relay_urls,
+            relay_fetch_stats: Mutex::new(HashMap::new()),
+            fetch_retry_backoffs: Mutex::new(HashMap::new()),
+            note_fetch_timeout,
+            note_fetch_limit,
+            relay_fetch_limiter,
+            fault_injector,
         })
     }
 
+    // MARK: - Relay connection supervision
+
+    /// Periodically checks the relay connections, and reconnects with exponential backoff when they drop.
+    /// Without this, every mute/follow check would silently time out while the relay is unreachable.
+    async fn supervise_relay_connections(client: Client) {
+        let mut backoff = RELAY_HEALTH_CHECK_INTERVAL;
+        loop {
+            tokio::time::sleep(backoff).await;
+            let health = Self::relay_health_of_client(&client).await;
+            if health.is_healthy() {
+                if backoff != RELAY_HEALTH_CHECK_INTERVAL {
+                    log::info!("Relay connection restored ({}/{} relays connected)", health.connected_relays, health.total_relays);
+                }
+                backoff = RELAY_HEALTH_CHECK_INTERVAL;
+                continue;
+            }
+            log::warn!(
+                "No relays connected ({}/{} relays connected), attempting to reconnect. Next check in {:?}",
+                health.connected_relays,
+                health.total_relays,
+                backoff
+            );
+            client.connect().await;
+            backoff = std::cmp::min(backoff * 2, RELAY_RECONNECT_MAX_BACKOFF);
+        }
+    }
+
+    /// Gets the current health of the upstream relay connections
+    pub async fn relay_health(&self) -> RelayHealth {
+        Self::relay_health_of_client(&self.client).await
+    }
+
+    async fn relay_health_of_client(client: &Client) -> RelayHealth {
+        let relays = client.relays().await;
+        let mut connected_relays = 0;
+        for relay in relays.values() {
+            if relay.status().await == RelayStatus::Connected {
+                connected_relays += 1;
+            }
+        }
+        RelayHealth {
+            connected_relays,
+            total_relays: relays.len(),
+        }
+    }
+
+    /// Describes what is cached about a pubkey, for debugging mute and follow decisions
+    pub async fn cache_status(&self, pubkey: &PublicKey) -> PubkeyCacheStatus {
+        self.cache.lock().await.pubkey_cache_status(pubkey)
+    }
+
     // MARK: - Answering questions about a user
 
     pub async fn should_mute_notification_for_pubkey(
@@ -38,9 +150,12 @@ impl NostrNetworkHelper {
             event,
             pubkey
         );
+        // The author of a gift wrap is a throwaway key, and direct message contents are encrypted, so only some rules apply to them
+        let has_known_author = event.kind != Kind::GiftWrap;
+        let has_readable_content = event.kind != Kind::EncryptedDirectMessage && event.kind != Kind::GiftWrap;
         if let Some(mute_list) = self.get_public_mute_list(pubkey).await {
             for muted_public_key in mute_list.public_keys {
-                if event.pubkey == muted_public_key {
+                if has_known_author && event.pubkey == muted_public_key {
                     return true;
                 }
             }
@@ -61,7 +176,7 @@ impl NostrNetworkHelper {
                 }
             }
             for muted_word in mute_list.words {
-                if event
+                if has_readable_content && event
                     .content
                     .to_lowercase()
                     .contains(&muted_word.to_lowercase())
@@ -73,20 +188,35 @@ impl NostrNetworkHelper {
         false
     }
 
-    pub async fn does_pubkey_follow_pubkey(
+    /// Checks if a pubkey follows another, deciding with the policy when the contact list cannot be fetched
+    pub async fn follow_check(
         &self,
         source_pubkey: &PublicKey,
         target_pubkey: &PublicKey,
-    ) -> bool {
+        policy: FollowListUnavailablePolicy,
+    ) -> FollowCheck {
         log::debug!(
             "Checking if pubkey {:?} follows pubkey {:?}",
             source_pubkey,
             target_pubkey
         );
-        if let Some(contact_list) = self.get_contact_list(source_pubkey).await {
-            return contact_list.referenced_pubkeys().contains(target_pubkey);
-        }
-        false
+        let follows = |contact_list: Option<Event>| contact_list.map_or(false, |contact_list| contact_list.referenced_pubkeys().contains(target_pubkey));
+        let stale_contact_list = match self.lookup_contact_list(source_pubkey).await {
+            ContactListLookup::Current(contact_list) => {
+                return FollowCheck { follows: follows(contact_list), source: FollowListSource::Current };
+            }
+            ContactListLookup::Unavailable { stale_contact_list } => stale_contact_list,
+        };
+        let follow_check = match (policy, stale_contact_list) {
+            (FollowListUnavailablePolicy::UseStale, Some(stale_contact_list)) => {
+                FollowCheck { follows: follows(stale_contact_list), source: FollowListSource::Stale }
+            }
+            (FollowListUnavailablePolicy::FailOpen, _) => FollowCheck { follows: true, source: FollowListSource::FailOpen },
+            // Without a stale contact list to use, there is nothing better than failing closed
+            _ => FollowCheck { follows: false, source: FollowListSource::FailClosed },
+        };
+        log::info!("Contact list of {:?} is unavailable, follow check answered by {}", source_pubkey, follow_check.source.as_str());
+        follow_check
     }
 
     // MARK: - Getting specific event types with caching
@@ -100,64 +230,485 @@ impl NostrNetworkHelper {
         }   // Release the lock here for improved performance
         
         // We don't have an answer from the cache, so we need to fetch it
-        let mute_list_event = self.fetch_single_event(pubkey, Kind::MuteList).await;
+        let mute_list_event = self.fetch_single_event_with_backoff(pubkey, Kind::MuteList).await;
         let mut cache_mutex_guard = self.cache.lock().await;
-        cache_mutex_guard.add_optional_mute_list_with_author(pubkey, mute_list_event.clone());
-        mute_list_event?.to_mute_list()
+        match mute_list_event {
+            Some(mute_list_event) => {
+                cache_mutex_guard.add_optional_mute_list_with_author(pubkey, mute_list_event.clone());
+                mute_list_event?.to_mute_list()
+            }
+            // A relay that did not answer (or a fetch slot that did not free up in time) says nothing about the mute list,
+            // so the last one we know of still applies, unless it is too old
+            None => {
+                log::info!("Mute list of {:?} is unavailable, using the cached one if any", pubkey);
+                cache_mutex_guard.get_stale_mute_list(pubkey)??.to_mute_list()
+            }
+        }
     }
 
     pub async fn get_contact_list(&self, pubkey: &PublicKey) -> Option<Event> {
+        match self.lookup_contact_list(pubkey).await {
+            ContactListLookup::Current(contact_list) => contact_list,
+            ContactListLookup::Unavailable { .. } => None,
+        }
+    }
+
+    /// Gets the current contact list of a pubkey from the cache or the relays, or the stale one if it cannot be fetched
+    async fn lookup_contact_list(&self, pubkey: &PublicKey) -> ContactListLookup {
         {
             let mut cache_mutex_guard = self.cache.lock().await;
             if let Ok(optional_contact_list) = cache_mutex_guard.get_contact_list(pubkey) {
-                return optional_contact_list;
+                return ContactListLookup::Current(optional_contact_list);
             }
         }   // Release the lock here for improved performance
         
         // We don't have an answer from the cache, so we need to fetch it
-        let contact_list_event = self.fetch_single_event(pubkey, Kind::ContactList).await;
+        let contact_list_event = self.fetch_single_event_with_backoff(pubkey, Kind::ContactList).await;
         let mut cache_mutex_guard = self.cache.lock().await;
-        cache_mutex_guard.add_optional_contact_list_with_author(pubkey, contact_list_event.clone());
-        contact_list_event
+        match contact_list_event {
+            Some(contact_list_event) => {
+                cache_mutex_guard.add_optional_contact_list_with_author(pubkey, contact_list_event.clone());
+                ContactListLookup::Current(contact_list_event)
+            }
+            None => ContactListLookup::Unavailable {
+                stale_contact_list: cache_mutex_guard.get_stale_contact_list(pubkey),
+            },
+        }
+    }
+
+    /// Gets the NIP-65 relay list of a pubkey, i.e. its outbox (write) and inbox (read) relays
+    pub async fn get_relay_list(&self, pubkey: &PublicKey) -> Option<RelayList> {
+        {
+            let mut cache_mutex_guard = self.cache.lock().await;
+            if let Ok(optional_relay_list) = cache_mutex_guard.get_relay_list(pubkey) {
+                return optional_relay_list;
+            }
+        }   // Release the lock here for improved performance
+        
+        // We don't have an answer from the cache, so we need to fetch it
+        let relay_list_event = self.fetch_single_event_with_backoff(pubkey, Kind::RelayList).await?;
+        let mut cache_mutex_guard = self.cache.lock().await;
+        cache_mutex_guard.add_optional_relay_list_with_author(pubkey, relay_list_event.clone());
+        relay_list_event?.to_relay_list()
+    }
+
+    pub async fn get_metadata(&self, pubkey: &PublicKey) -> Option<Metadata> {
+        {
+            let mut cache_mutex_guard = self.cache.lock().await;
+            if let Ok(optional_metadata) = cache_mutex_guard.get_profile(pubkey) {
+                return optional_metadata;
+            }
+        }   // Release the lock here for improved performance
+        
+        // We don't have an answer from the cache, so we need to fetch it
+        let metadata_event = self.fetch_single_event_with_backoff(pubkey, Kind::Metadata).await?;
+        let mut cache_mutex_guard = self.cache.lock().await;
+        cache_mutex_guard.add_optional_profile_with_author(pubkey, metadata_event.clone());
+        Metadata::from_json(&metadata_event?.content).ok()
+    }
+
+    // MARK: - Batched fetching
+
+    /// Fetches the mute lists and contact lists of many pubkeys with a single subscription, and adds them to the cache.
+    /// Pubkeys whose lists are not found here are left out of the cache, so that the single event fetches can still fall back to other relays.
+    pub async fn prefetch_lists(&self, pubkeys: &HashSet<PublicKey>) {
+        let pubkeys_to_fetch: Vec<PublicKey> = {
+            let mut cache_mutex_guard = self.cache.lock().await;
+            pubkeys
+                .iter()
+                .filter(|pubkey| {
+                    cache_mutex_guard.get_mute_list(pubkey).is_err()
+                        || cache_mutex_guard.get_contact_list(pubkey).is_err()
+                })
+                .cloned()
+                .collect()
+        };   // Release the lock here for improved performance
+        if pubkeys_to_fetch.is_empty() {
+            return;
+        }
+        let newest_events = self
+            .fetch_newest_events_by_author(vec![Kind::MuteList, Kind::ContactList], &pubkeys_to_fetch)
+            .await;
+
+        log::debug!("Prefetched {} lists for {} pubkeys", newest_events.len(), pubkeys_to_fetch.len());
+        let mut cache_mutex_guard = self.cache.lock().await;
+        for (_, event) in newest_events {
+            cache_mutex_guard.add_event(event);
+        }
+    }
+
+    /// Caches the mute lists, contact lists, relay lists and profiles of these pubkeys as not found
+    pub async fn assume_no_events_for_pubkeys(&self, pubkeys: &HashSet<PublicKey>) {
+        let mut cache_mutex_guard = self.cache.lock().await;
+        for pubkey in pubkeys {
+            cache_mutex_guard.add_optional_mute_list_with_author(pubkey, None);
+            cache_mutex_guard.add_optional_contact_list_with_author(pubkey, None);
+            cache_mutex_guard.add_optional_relay_list_with_author(pubkey, None);
+            cache_mutex_guard.add_optional_profile_with_author(pubkey, None);
+        }
+    }
+
+    /// Fetches the NIP-17 DM relay lists (kind 10050) of many pubkeys with a single subscription.
+    /// Pubkeys without a DM relay list are left out of the result
+    pub async fn fetch_dm_relay_lists(&self, pubkeys: &HashSet<PublicKey>) -> HashMap<PublicKey, Vec<String>> {
+        let pubkeys: Vec<PublicKey> = pubkeys.iter().cloned().collect();
+        self.fetch_newest_events_by_author(vec![Kind::from(DM_RELAY_LIST_KIND)], &pubkeys)
+            .await
+            .into_iter()
+            .map(|((pubkey, _), event)| {
+                let relay_urls = event
+                    .get_tags_content(TagKind::Relay)
+                    .into_iter()
+                    .take(MAX_DM_RELAYS_PER_PUBKEY)
+                    .map(|relay_url| relay_url.to_string())
+                    .collect();
+                (pubkey, relay_urls)
+            })
+            .collect()
+    }
+
+    /// Fetches the newest event of each of the given kinds for each author, with a subscription to the best performing relay per chunk of authors
+    async fn fetch_newest_events_by_author(&self, kinds: Vec<Kind>, authors: &[PublicKey]) -> HashMap<(PublicKey, Kind), Event> {
+        let mut newest_events: HashMap<(PublicKey, Kind), Event> = HashMap::new();
+        for authors_chunk in authors.chunks(MAX_AUTHORS_PER_FILTER) {
+            newest_events.extend(self.fetch_newest_events_by_author_chunk(kinds.clone(), authors_chunk).await);
+        }
+        newest_events
+    }
+
+    async fn fetch_newest_events_by_author_chunk(&self, kinds: Vec<Kind>, authors: &[PublicKey]) -> HashMap<(PublicKey, Kind), Event> {
+        let mut newest_events: HashMap<(PublicKey, Kind), Event> = HashMap::new();
+        if authors.is_empty() {
+            return newest_events;
+        }
+        let relay_url = match self.relay_urls_by_success_rate().await.into_iter().next() {
+            Some(relay_url) => relay_url,
+            None => return newest_events,
+        };
+
+        // These kinds are replaceable, so there is one event per author and kind
+        let subscription_filter = Filter::new()
+            .limit(authors.len() * kinds.len())
+            .kinds(kinds)
+            .authors(authors.to_vec());
+
+        let _relay_fetch_permit = match self.relay_fetch_limiter.acquire(&relay_url, self.note_fetch_timeout).await {
+            Some(relay_fetch_permit) => relay_fetch_permit,
+            None => return newest_events,
+        };
+        let mut notifications = self.client.notifications();
+        let this_subscription_id = match self
+            .client
+            .subscribe_to(vec![relay_url.as_str()], Vec::from([subscription_filter]), None)
+            .await
+        {
+            Ok(subscription_id) => subscription_id,
+            Err(e) => {
+                log::warn!("Failed to subscribe to relay {}: {}", relay_url, e);
+                return newest_events;
+            }
+        };
+
+        let deadline = Instant::now() + self.note_fetch_timeout;
+        while let Ok(result) = timeout_at(deadline, notifications.recv()).await {
+            match result {
+                Ok(RelayPoolNotification::Event { subscription_id, event, .. }) if subscription_id == this_subscription_id => {
+                    if !authors.contains(&event.pubkey) {
+                        continue;
+                    }
+                    let key = (event.pubkey, event.kind);
+                    let is_newer = newest_events
+                        .get(&key)
+                        .map(|existing| event.created_at > existing.created_at)
+                        .unwrap_or(true);
+                    if is_newer {
+                        newest_events.insert(key, (*event).clone());
+                    }
+                }
+                Ok(RelayPoolNotification::Message { message: RelayMessage::EndOfStoredEvents(subscription_id), .. }) if subscription_id == this_subscription_id => {
+                    break;
+                }
+                Ok(_) => {}
+                // Missing some notifications of the busy pool does not mean ours are gone, so keep listening until the deadline
+                Err(tokio::sync::broadcast::error::RecvError::Lagged(skipped)) => {
+                    log::debug!("Lagged behind the relay pool notifications, skipped {}", skipped);
+                }
+                Err(tokio::sync::broadcast::error::RecvError::Closed) => break,
+            }
+        }
+        self.client.unsubscribe(this_subscription_id).await;
+        newest_events
     }
 
     // MARK: - Lower level fetching functions
 
-    async fn fetch_single_event(&self, author: &PublicKey, kind: Kind) -> Option<Event> {
+    /// Fetches a single event, unless its last fetch timed out and it is still backing off.
+    /// Returns `None` if no relay answered (so the result must not be cached), or `Some(None)` if the relays answered that there is no such event
+    async fn fetch_single_event_with_backoff(&self, author: &PublicKey, kind: Kind) -> Option<Option<Event>> {
+        let key = (author.clone(), kind);
+        let previous_backoff = match self.fetch_retry_backoffs.lock().await.get(&key) {
+            Some((retry_at, _)) if Instant::now() < *retry_at => {
+                log::debug!("Fetch of event of kind {:?} for pubkey {:?} is backing off after a timeout", kind, author);
+                return None;
+            }
+            Some((_, backoff)) => Some(*backoff),
+            None => None,
+        };
+        match self.fetch_single_event(author, kind).await {
+            FetchOutcome::Found(event) => {
+                self.fetch_retry_backoffs.lock().await.remove(&key);
+                Some(Some(event))
+            }
+            FetchOutcome::NotFound => {
+                self.fetch_retry_backoffs.lock().await.remove(&key);
+                Some(None)
+            }
+            // Our own limiter is not the relays' fault, so there is no backing off
+            FetchOutcome::RateLimited => None,
+            FetchOutcome::TimedOut => {
+                let backoff = previous_backoff
+                    .map(|backoff| (backoff * 2).min(FETCH_RETRY_MAX_BACKOFF))
+                    .unwrap_or(FETCH_RETRY_MIN_BACKOFF);
+                log::info!("Fetch of event of kind {:?} for pubkey {:?} timed out on all relays, retrying in {:?}", kind, author, backoff);
+                self.fetch_retry_backoffs.lock().await.insert(key, (Instant::now() + backoff, backoff));
+                None
+            }
+        }
+    }
+
+    /// Fetches a single event, trying each relay in turn until one of them has it.
+    /// Relays with a better track record are tried first, with the configured order breaking ties.
+    /// The event is only reported as not found if at least one relay answered, otherwise the fetch timed out.
+    async fn fetch_single_event(&self, author: &PublicKey, kind: Kind) -> FetchOutcome {
+        let (mut any_relay_answered, mut any_relay_rate_limited) = (false, false);
+        for relay_url in self.relay_urls_by_success_rate().await {
+            let outcome = self.fetch_single_event_from_relay(&relay_url, author, kind).await;
+            // The relay was not asked, so this says nothing about its track record. Not having the event is not the relay's failure either
+            if !matches!(outcome, FetchOutcome::RateLimited) {
+                self.record_relay_fetch_result(&relay_url, !matches!(outcome, FetchOutcome::TimedOut)).await;
+            }
+            match outcome {
+                FetchOutcome::Found(event) => return FetchOutcome::Found(event),
+                FetchOutcome::NotFound => any_relay_answered = true,
+                FetchOutcome::RateLimited => any_relay_rate_limited = true,
+                FetchOutcome::TimedOut => {}
+            }
+            log::debug!("Event of kind {:?} for pubkey {:?} not found on {}, trying the next relay", kind, author, relay_url);
+        }
+        if !any_relay_answered && any_relay_rate_limited {
+            return FetchOutcome::RateLimited;
+        }
+        if !any_relay_answered {
+            return FetchOutcome::TimedOut;
+        }
+        log::info!("Event of kind {:?} not found for pubkey {:?}", kind, author);
+        FetchOutcome::NotFound
+    }
+
+    async fn fetch_single_event_from_relay(&self, relay_url: &str, author: &PublicKey, kind: Kind) -> FetchOutcome {
+        if self.fault_injector.should_inject(Fault::RelayTimeout) {
+            tokio::time::sleep(self.note_fetch_timeout).await;
+            return FetchOutcome::TimedOut;
+        }
         let subscription_filter = Filter::new()
             .kinds(vec![kind])
             .authors(vec![author.clone()])
-            .limit(1);
+            .limit(self.note_fetch_limit);
         
+        let _relay_fetch_permit = match self.relay_fetch_limiter.acquire(relay_url, self.note_fetch_timeout).await {
+            Some(relay_fetch_permit) => relay_fetch_permit,
+            None => return FetchOutcome::RateLimited,
+        };
         let mut notifications = self.client.notifications();
-        let this_subscription_id = self
+        let this_subscription_id = match self
             .client
-            .subscribe(Vec::from([subscription_filter]), None)
-            .await;
+            .subscribe_to(vec![relay_url], Vec::from([subscription_filter]), None)
+            .await
+        {
+            Ok(subscription_id) => subscription_id,
+            Err(e) => {
+                log::warn!("Failed to subscribe to relay {}: {}", relay_url, e);
+                return FetchOutcome::TimedOut;
+            }
+        };
 
+        // Replaceable events may be returned in any order, so collect everything until EOSE and keep the newest
         let mut event: Option<Event> = None;
+        let mut received_end_of_stored_events = false;
+        let deadline = Instant::now() + self.note_fetch_timeout;
         
-        while let Ok(result) = timeout(NOTE_FETCH_TIMEOUT, notifications.recv()).await {
-            if let Ok(notification) = result {
-                if let RelayPoolNotification::Event {
+        while let Ok(result) = timeout_at(deadline, notifications.recv()).await {
+            match result {
+                Ok(RelayPoolNotification::Event {
                     subscription_id,
                     event: event_option,
                     ..
-                } = notification
-                {
-                    if this_subscription_id == subscription_id && event_option.kind == kind {
+                }) if subscription_id == this_subscription_id && event_option.kind == kind => {
+                    let is_newer = event
+                        .as_ref()
+                        .map(|current| event_option.created_at > current.created_at)
+                        .unwrap_or(true);
+                    if is_newer {
                         event = Some((*event_option).clone());
-                        break;
                     }
                 }
+                Ok(RelayPoolNotification::Message { message: RelayMessage::EndOfStoredEvents(subscription_id), .. }) if subscription_id == this_subscription_id => {
+                    received_end_of_stored_events = true;
+                    break;
+                }
+                Ok(_) => {}
+                // Missing some notifications of the busy pool does not mean ours are gone, so keep listening until the deadline
+                Err(tokio::sync::broadcast::error::RecvError::Lagged(skipped)) => {
+                    log::debug!("Lagged behind the relay pool notifications, skipped {}", skipped);
+                }
+                Err(tokio::sync::broadcast::error::RecvError::Closed) => break,
             }
         }
 
-        if event.is_none() {
-            log::info!("Event of kind {:?} not found for pubkey {:?}", kind, author);
+        self.client.unsubscribe(this_subscription_id).await;
+        match (event, received_end_of_stored_events) {
+            // An event that arrived before the deadline is good, even if EOSE did not
+            (Some(event), _) => FetchOutcome::Found(event),
+            (None, true) => FetchOutcome::NotFound,
+            (None, false) => FetchOutcome::TimedOut,
         }
+    }
 
-        self.client.unsubscribe(this_subscription_id).await;
-        event
+    // MARK: - Relay fetch statistics
+
+    async fn relay_urls_by_success_rate(&self) -> Vec<String> {
+        let relay_fetch_stats = self.relay_fetch_stats.lock().await;
+        let mut relay_urls = self.relay_urls.clone();
+        // `sort_by` is stable, so relays with the same success rate keep their configured order
+        relay_urls.sort_by(|a, b| {
+            let a_rate = relay_fetch_stats.get(a).map(|stats| stats.success_rate()).unwrap_or(1.0);
+            let b_rate = relay_fetch_stats.get(b).map(|stats| stats.success_rate()).unwrap_or(1.0);
+            b_rate.partial_cmp(&a_rate).unwrap_or(std::cmp::Ordering::Equal)
+        });
+        relay_urls
+    }
+
+    async fn record_relay_fetch_result(&self, relay_url: &str, success: bool) {
+        let mut relay_fetch_stats = self.relay_fetch_stats.lock().await;
+        let stats = relay_fetch_stats.entry(relay_url.to_string()).or_default();
+        stats.attempts += 1;
+        if success {
+            stats.successes += 1;
+        }
+    }
+
+    /// Waits until a subscription to the relay is allowed by the relay fetch limits, for subscriptions opened outside of this helper
+    /// (e.g. to DM relays). Returns `None` if that takes longer than the fetch timeout
+    pub async fn acquire_relay_subscription_permit(&self, relay_url: &str) -> Option<RelayFetchPermit> {
+        self.relay_fetch_limiter.acquire(relay_url, self.note_fetch_timeout).await
+    }
+
+    /// Renders the per-relay fetch subscription metrics in the Prometheus text format
+    pub fn render_relay_fetch_metrics(&self) -> String {
+        self.relay_fetch_limiter.render_prometheus()
+    }
+
+    /// Gets a snapshot of the fetch statistics of each relay
+    pub async fn relay_fetch_stats(&self) -> HashMap<String, RelayFetchStats> {
+        self.relay_fetch_stats.lock().await.clone()
+    }
+}
+
+// MARK: - Helper types
+
+/// The outcome of fetching an event from relays
+enum FetchOutcome {
+    Found(Event),
+    // The relay answered (EOSE) without the event
+    NotFound,
+    // The relay did not answer in time, or could not be subscribed to
+    TimedOut,
+    // No fetch slot for the relay freed up in time, so it was not asked
+    RateLimited,
+}
+
+/// The outcome of looking up a contact list
+enum ContactListLookup {
+    // From the cache or the relays. `None` means the pubkey has no contact list
+    Current(Option<Event>),
+    // The relays did not answer, with the expired cached contact list if there is one that is not too old
+    Unavailable { stale_contact_list: Option<Option<Event>> },
+}
+
+/// What to do when a follow check needs a contact list that cannot be fetched
+#[derive(Debug, Clone, Copy, PartialEq)]
+pub enum FollowListUnavailablePolicy {
+    // Treat the pubkey as followed, e.g. notify even if only notifications from follows are wanted
+    FailOpen,
+    // Treat the pubkey as not followed
+    FailClosed,
+    // Use the expired cached contact list if there is one that is not too old, and fail closed otherwise
+    UseStale,
+}
+
+impl FollowListUnavailablePolicy {
+    pub fn parse(value: &str) -> Option<Self> {
+        match value {
+            "fail_open" => Some(FollowListUnavailablePolicy::FailOpen),
+            "fail_closed" => Some(FollowListUnavailablePolicy::FailClosed),
+            "use_stale" => Some(FollowListUnavailablePolicy::UseStale),
+            _ => None,
+        }
+    }
+}
+
+/// The answer to a follow check, and where it came from
+pub struct FollowCheck {
+    pub follows: bool,
+    pub source: FollowListSource,
+}
+
+#[derive(Debug, Clone, Copy, PartialEq)]
+pub enum FollowListSource {
+    // A contact list that is not expired, or the knowledge that there is none
+    Current,
+    // An expired contact list, since the current one could not be fetched
+    Stale,
+    // No contact list could be fetched, and the policy said to fail open
+    FailOpen,
+    // No contact list could be fetched, and the policy said (or left no choice but) to fail closed
+    FailClosed,
+}
+
+impl FollowListSource {
+    pub fn as_str(&self) -> &'static str {
+        match self {
+            FollowListSource::Current => "current_contact_list",
+            FollowListSource::Stale => "stale_contact_list",
+            FollowListSource::FailOpen => "fail_open",
+            FollowListSource::FailClosed => "fail_closed",
+        }
+    }
+}
+
+pub struct RelayHealth {
+    pub connected_relays: usize,
+    pub total_relays: usize,
+}
+
+impl RelayHealth {
+    pub fn is_healthy(&self) -> bool {
+        self.connected_relays > 0
+    }
+}
+
+#[derive(Clone, Default, Debug)]
+pub struct RelayFetchStats {
+    pub attempts: u64,
+    // The attempts the relay answered, with or without the event
+    pub successes: u64,
+}
+
+impl RelayFetchStats {
+    pub fn success_rate(&self) -> f64 {
+        if self.attempts == 0 {
+            return 1.0;
+        }
+        self.successes as f64 / self.attempts as f64
     }
 }
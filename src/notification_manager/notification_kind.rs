@@ -0,0 +1,177 @@
+use nostr::{Event, Kind};
+use serde::{Deserialize, Serialize};
+
+use super::content_formatter::sanitize_content;
+use super::notification_manager::UserNotificationSettings;
+use super::ExtendedEvent;
+
+/// What a notification is about, classified from its event.
+/// Kind-dependent policy (which kinds are supported, which setting controls them, and their wording) lives here, so that it does not drift apart.
+#[derive(Serialize, Deserialize, Debug, Clone, Copy, PartialEq)]
+#[serde(rename_all = "snake_case")]
+pub enum NotificationKind {
+    // A text note that replies to another note
+    Reply,
+    // A text note that mentions the recipient without replying to anything
+    Mention,
+    // A NIP-04 direct message
+    DirectMessage,
+    // A NIP-17 direct message. Its sender and contents are hidden until the app unwraps it
+    GiftWrap,
+    Repost,
+    Reaction,
+    ZapPrivateMessage,
+    ZapReceipt,
+}
+
+impl NotificationKind {
+    // MARK: - Classification
+
+    /// Every kind of notification, in the order clients list them
+    pub const ALL: [NotificationKind; 8] = [
+        NotificationKind::Reply,
+        NotificationKind::Mention,
+        NotificationKind::DirectMessage,
+        NotificationKind::GiftWrap,
+        NotificationKind::Repost,
+        NotificationKind::Reaction,
+        NotificationKind::ZapPrivateMessage,
+        NotificationKind::ZapReceipt,
+    ];
+
+    /// Classifies the event, returning `None` if its kind does not trigger notifications
+    pub fn from_event(event: &Event) -> Option<Self> {
+        match event.kind {
+            // Notes that only quote or mention other notes are not replies (NIP-10)
+            Kind::TextNote if event.reply_event_id().is_none() => Some(NotificationKind::Mention),
+            Kind::TextNote => Some(NotificationKind::Reply),
+            Kind::EncryptedDirectMessage => Some(NotificationKind::DirectMessage),
+            Kind::GiftWrap => Some(NotificationKind::GiftWrap),
+            Kind::Repost | Kind::GenericRepost => Some(NotificationKind::Repost),
+            Kind::Reaction => Some(NotificationKind::Reaction),
+            Kind::ZapPrivateMessage => Some(NotificationKind::ZapPrivateMessage),
+            Kind::ZapReceipt => Some(NotificationKind::ZapReceipt),
+            _ => None,
+        }
+    }
+
+    // MARK: - Policy
+
+    /// Checks if the recipient's settings allow this kind of notification
+    pub fn is_enabled(&self, settings: &UserNotificationSettings) -> bool {
+        match self {
+            // Replies are controlled by the mention setting until clients expose a separate one
+            NotificationKind::Reply | NotificationKind::Mention => settings.mention_notifications_enabled,
+            NotificationKind::DirectMessage | NotificationKind::GiftWrap => settings.dm_notifications_enabled,
+            NotificationKind::Repost => settings.repost_notifications_enabled,
+            NotificationKind::Reaction => settings.reaction_notifications_enabled,
+            NotificationKind::ZapPrivateMessage | NotificationKind::ZapReceipt => settings.zap_notifications_enabled,
+        }
+    }
+
+    /// The event kinds that are classified as this kind of notification
+    pub fn event_kinds(&self) -> Vec<Kind> {
+        match self {
+            NotificationKind::Reply | NotificationKind::Mention => vec![Kind::TextNote],
+            NotificationKind::DirectMessage => vec![Kind::EncryptedDirectMessage],
+            NotificationKind::GiftWrap => vec![Kind::GiftWrap],
+            NotificationKind::Repost => vec![Kind::Repost, Kind::GenericRepost],
+            NotificationKind::Reaction => vec![Kind::Reaction],
+            NotificationKind::ZapPrivateMessage => vec![Kind::ZapPrivateMessage],
+            NotificationKind::ZapReceipt => vec![Kind::ZapReceipt],
+        }
+    }
+
+    /// The name of the setting checked by `is_enabled`, for clients to link each kind to its toggle
+    pub fn setting_name(&self) -> &'static str {
+        match self {
+            NotificationKind::Reply | NotificationKind::Mention => "mention_notifications_enabled",
+            NotificationKind::DirectMessage | NotificationKind::GiftWrap => "dm_notifications_enabled",
+            NotificationKind::Repost => "repost_notifications_enabled",
+            NotificationKind::Reaction => "reaction_notifications_enabled",
+            NotificationKind::ZapPrivateMessage | NotificationKind::ZapReceipt => "zap_notifications_enabled",
+        }
+    }
+
+    /// Whether this kind can be held back until the recipient's delivery window opens. DMs and zaps are always delivered right away
+    pub fn is_deferrable(&self) -> bool {
+        match self {
+            NotificationKind::Reply | NotificationKind::Mention | NotificationKind::Repost | NotificationKind::Reaction => true,
+            NotificationKind::DirectMessage | NotificationKind::GiftWrap | NotificationKind::ZapPrivateMessage | NotificationKind::ZapReceipt => false,
+        }
+    }
+
+    /// Whether the event author is the real sender. The author of a gift wrap is a throwaway key
+    pub fn has_known_author(&self) -> bool {
+        *self != NotificationKind::GiftWrap
+    }
+
+    // MARK: - Persistence
+
+    pub fn as_sql_str(&self) -> &'static str {
+        match self {
+            NotificationKind::Reply => "reply",
+            NotificationKind::Mention => "mention",
+            NotificationKind::DirectMessage => "direct_message",
+            NotificationKind::GiftWrap => "gift_wrap",
+            NotificationKind::Repost => "repost",
+            NotificationKind::Reaction => "reaction",
+            NotificationKind::ZapPrivateMessage => "zap_private_message",
+            NotificationKind::ZapReceipt => "zap_receipt",
+        }
+    }
+
+    pub fn from_sql_str(value: &str) -> Option<Self> {
+        NotificationKind::ALL.into_iter().find(|notification_kind| notification_kind.as_sql_str() == value)
+    }
+
+    // MARK: - Formatting
+
+    /// The key of this kind in the operator's notification templates
+    pub fn template_key(&self) -> &'static str {
+        match self {
+            NotificationKind::Reply | NotificationKind::Mention => "text_note",
+            NotificationKind::DirectMessage | NotificationKind::GiftWrap => "direct_message",
+            NotificationKind::Repost => "repost",
+            NotificationKind::Reaction => "reaction",
+            NotificationKind::ZapPrivateMessage => "zap_private_message",
+            NotificationKind::ZapReceipt => "zap_receipt",
+        }
+    }
+
+    /// How a number of notifications of this kind is called in a digest, e.g. "3 reactions"
+    pub fn plural_noun(&self) -> &'static str {
+        match self {
+            NotificationKind::Reply => "replies",
+            NotificationKind::Mention => "mentions",
+            NotificationKind::DirectMessage | NotificationKind::GiftWrap => "direct messages",
+            NotificationKind::Repost => "reposts",
+            NotificationKind::Reaction => "reactions",
+            NotificationKind::ZapPrivateMessage | NotificationKind::ZapReceipt => "zaps",
+        }
+    }
+
+    /// The built-in title and body. These are just fallbacks, since the client handles formatting
+    pub fn default_title_and_body(&self, event: &Event, push_body_max_length: usize) -> (String, String) {
+        match self {
+            NotificationKind::Reply => ("New reply".to_string(), sanitize_content(&event.content, push_body_max_length)),
+            NotificationKind::Mention => ("New mention".to_string(), sanitize_content(&event.content, push_body_max_length)),
+            NotificationKind::DirectMessage | NotificationKind::GiftWrap => ("New direct message".to_string(), "Contents are encrypted".to_string()),
+            NotificationKind::Repost => ("Someone reposted".to_string(), sanitize_content(&event.content, push_body_max_length)),
+            NotificationKind::Reaction => {
+                let formatted_text = match event.content.as_str() {
+                    "" => "❤️",
+                    "+" => "❤️",
+                    "-" => "👎",
+                    content => content,
+                };
+                ("New reaction".to_string(), sanitize_content(formatted_text, push_body_max_length))
+            }
+            NotificationKind::ZapPrivateMessage => ("New zap private message".to_string(), "Contents are encrypted".to_string()),
+            NotificationKind::ZapReceipt => match (event.is_anonymous_zap(), event.zap_amount_msats()) {
+                (true, Some(amount_msats)) => (format!("Someone zapped you {} sats", amount_msats / 1000), "".to_string()),
+                _ => ("Someone zapped you".to_string(), "".to_string()),
+            },
+        }
+    }
+}
@@ -1,33 +1,218 @@
-use a2::{Client, ClientConfig, DefaultNotificationBuilder, NotificationBuilder};
+use futures::StreamExt;
 use log;
 use nostr::event::EventId;
 use nostr::key::PublicKey;
 use nostr::types::Timestamp;
+use nostr::nips::nip01::Coordinate;
 use nostr_sdk::JsonUtil;
 use nostr_sdk::Kind;
 use rusqlite;
 use rusqlite::params;
+use rusqlite::OptionalExtension;
+use rusqlite::TransactionBehavior;
 use serde::Deserialize;
 use serde::Serialize;
 use tokio::sync::Mutex;
-use std::collections::HashSet;
+use std::collections::{HashMap, HashSet};
 use tokio;
 
-use super::nostr_network_helper::NostrNetworkHelper;
+use super::nostr_network_helper::{FollowCheck, FollowListUnavailablePolicy, NostrNetworkHelper, RelayHealth};
+use super::nostr_event_cache::PubkeyCacheStatus;
+use super::webhook_client::{Webhook, WebhookClient, WebhookError};
+use super::notification_templates::{NotificationTemplate, NotificationTemplates};
+use super::notification_kind::NotificationKind;
+use super::push_payload;
+use super::push_payload::EventInclusion;
+use super::zap_receipt_verifier::ZapReceiptVerifier;
+use super::live_activity_client::{LiveActivityClient, LiveActivityEvent};
+use super::spam_filter::SpamFilter;
+use super::apns_connection::{ApnsConnection, ApnsSigner};
+use super::apns_tenants::{ApnsTenant, ApnsTenantConfig, ApnsTenants};
+use super::apns_provider::ApnsProvider;
+use super::push_provider::{PushAlert, PushMessage, PushPriority, PushProvider, TokenType};
+use super::send_rate_limiter::SendRateLimiter;
+use super::relay_fetch_limiter::RelayFetchPermit;
+use super::latency_metrics::{LatencyMetrics, PhaseLatencySummary, ProcessingPhase};
+use super::fault_injector::{Fault, FaultInjector};
 use super::ExtendedEvent;
 use super::SqlStringConvertible;
 use nostr::Event;
 use r2d2;
 use r2d2_sqlite::SqliteConnectionManager;
-use std::fs::File;
+
+// APNS device tokens are 32 bytes, hex-encoded by the client
+const APNS_DEVICE_TOKEN_LENGTH: usize = 64;
+// Live Activity tokens are longer than device tokens, and their length is not documented, so only bound it loosely
+const MAX_LIVE_ACTIVITY_TOKEN_LENGTH: usize = 512;
+// The maximum number of recipients whose mute/contact lists are fetched at the same time for a single event
+const MAX_CONCURRENT_RECIPIENT_CHECKS: usize = 16;
+// The maximum number of pubkeys notified about a referenced event (e.g. a thread root) that are considered subscribed to replies, most recent first
+const MAX_SUBSCRIBERS_PER_REFERENCED_EVENT: i64 = 1000;
+// The maximum number of device tokens with failed deliveries listed in the delivery stats
+const MAX_FAILING_DEVICE_TOKENS_IN_STATS: i64 = 20;
+// The maximum number of recent delivery attempts listed in the debug info of a user
+const MAX_DELIVERIES_IN_USER_DEBUG_INFO: i64 = 20;
+// How often the delivery analytics are aggregated into the daily summaries
+const DELIVERY_ANALYTICS_INTERVAL: std::time::Duration = std::time::Duration::from_secs(60 * 60);
+const SECONDS_PER_DAY: u64 = 24 * 60 * 60;
+const DEVICE_PURGE_INTERVAL: std::time::Duration = std::time::Duration::from_secs(60 * 60);
+// How often the row caps of the notifications and deliveries tables are enforced, and how many rows are evicted per statement
+const ROW_CAP_INTERVAL: std::time::Duration = std::time::Duration::from_secs(60 * 60);
+const ROW_EVICTION_BATCH_SIZE: i64 = 10_000;
+// How often devices are checked for a due weekly summary, and how far apart the summaries of a device are
+const WEEKLY_SUMMARY_INTERVAL: std::time::Duration = std::time::Duration::from_secs(60 * 60);
+const SECONDS_PER_WEEK: u64 = 7 * SECONDS_PER_DAY;
+// How often the notifications held back outside of delivery windows are checked for devices whose window has opened
+const DEFERRED_DIGEST_INTERVAL: std::time::Duration = std::time::Duration::from_secs(5 * 60);
+// How long the notifications in a digest being sent stay claimed. Older claims are from a sender that crashed mid-send, and are taken over
+const DEFERRED_DIGEST_CLAIM_TIMEOUT_SECONDS: u64 = 10 * 60;
+const MINUTES_PER_DAY: i64 = 24 * 60;
+// The maximum number of hashtags a pubkey can subscribe to
+pub const MAX_HASHTAG_SUBSCRIPTIONS: usize = 50;
+// The maximum number of notifications a pubkey gets for each subscribed hashtag within the rate cap window, so that trending hashtags do not flood them
+const MAX_NOTIFICATIONS_PER_HASHTAG: usize = 5;
+const HASHTAG_RATE_CAP_WINDOW_SECONDS: u64 = 60 * 60;
+// How often the hashtag notifications that left the rate cap window are forgotten
+const HASHTAG_RATE_CAP_PRUNE_INTERVAL: std::time::Duration = std::time::Duration::from_secs(10 * 60);
+// The relevance score of the first text note from an author to a recipient, above every relationship but a mutual follow
+const NEW_CONVERSATION_RELEVANCE_SCORE: f64 = 0.9;
+// How long after a zap notification its counterpart (zap private message or zap receipt) is considered a duplicate
+const ZAP_DEDUP_WINDOW_SECONDS: u64 = 5 * 60;
+// How long after startup pending notifications are first re-driven. Only those claimed at least this long ago are, so that the ones
+// other instances sharing the database are still sending are left alone
+const PENDING_NOTIFICATION_REDRIVE_DELAY: std::time::Duration = std::time::Duration::from_secs(60);
+// How often pending notifications are re-driven after that, e.g. those left pending by a retryable failure
+const PENDING_NOTIFICATION_REDRIVE_INTERVAL: std::time::Duration = std::time::Duration::from_secs(5 * 60);
+// How many times a notification is attempted (the first send included) before it is given up on, so that a recipient that keeps failing
+// in a retryable way (e.g. a webhook that is always down) is not retried until the event gets too old
+const MAX_NOTIFICATION_ATTEMPTS: i64 = 5;
+// How long the flood guard holds an event for approval before discarding it, since notifying about it later would no longer be timely
+const HELD_EVENT_MAX_AGE_SECONDS: u64 = 24 * 60 * 60;
+const HELD_EVENT_EXPIRY_INTERVAL: std::time::Duration = std::time::Duration::from_secs(60 * 60);
+// The maximum size of an APNS payload for regular remote notifications
+const MAX_APNS_PAYLOAD_SIZE: usize = 4096;
+
+// The error of a database operation. It is sent back from the blocking thread the operation runs on
+type DatabaseError = Box<dyn std::error::Error + Send + Sync>;
 
 // MARK: - NotificationManager
 
 pub struct NotificationManager {
-    db: Mutex<r2d2::Pool<SqliteConnectionManager>>,
-    apns_topic: String,
-    apns_client: Mutex<Client>,
+    // The pool is shared by all tasks, and queries run on blocking threads with a connection each
+    db: r2d2::Pool<SqliteConnectionManager>,
+    // The transports pushes are delivered through, selected by the token type of each device
+    push_providers: HashMap<TokenType, Box<dyn PushProvider>>,
+    latency_metrics: LatencyMetrics,
+    // Shared with the network helper, so that one configuration covers every injected fault
+    fault_injector: std::sync::Arc<FaultInjector>,
     nostr_network_helper: NostrNetworkHelper,
+    recipient_shard: RecipientShard,
+    webhook_client: WebhookClient,
+    notification_templates: NotificationTemplates,
+    // The maximum length of the note content shown in a notification body, in graphemes
+    push_body_max_length: usize,
+    zap_receipt_verifier: ZapReceiptVerifier,
+    // Recently notified zaps, keyed by recipient and zapped event, used to avoid notifying the same zap twice
+    recent_zap_notifications: Mutex<std::collections::HashMap<(PublicKey, Option<EventId>), Vec<RecentZap>>>,
+    // The slots of the per-hashtag rate cap taken by recent hashtag notifications (when, and for which event), keyed by recipient and hashtag.
+    // Kept in memory only, so the cap starts over on restart, and instances sharing a shard each enforce it on their own
+    recent_hashtag_notifications: Mutex<HashMap<(PublicKey, String), Vec<(Timestamp, EventId)>>>,
+    live_activity_client: LiveActivityClient,
+    // Event kinds that wake the app with a silent (content-available only) push instead of showing a notification
+    silent_push_kinds: HashSet<Kind>,
+    // How much of the event the pushes of each kind carry. Kinds without a policy carry the full event
+    event_inclusion_policies: HashMap<Kind, EventInclusion>,
+    // The accepted age range of events, in seconds. A negative minimum tolerates events from the future
+    event_max_age_seconds: u64,
+    event_min_age_seconds: Option<i64>,
+    spam_filter: SpamFilter,
+    // The maximum number of `p` and `e` tags of an event that are processed, so that tag spam cannot cause a huge fan-out
+    max_processed_pubkey_tags: usize,
+    max_processed_event_tags: usize,
+    // Events that would notify more pubkeys than the threshold are degraded or held for approval. 0 disables it
+    flood_guard_threshold: usize,
+    flood_guard_mode: FloodGuardMode,
+    // How "only from following" checks decide when the recipient's contact list cannot be fetched
+    follow_list_unavailable_policy: FollowListUnavailablePolicy,
+    // The number of reports a recipient must file against an author to stop being notified about them. 0 disables it
+    report_suppression_threshold: usize,
+    // How long a report counts towards the threshold
+    report_max_age: std::time::Duration,
+    // Hashtags (lowercase) that mark an event as sensitive content
+    sensitive_hashtags: HashSet<String>,
+    // How long removed devices are kept (disabled) before being purged, so that re-registering them restores their settings
+    device_removal_grace_period: std::time::Duration,
+    // The maximum number of rows kept in the notifications and deliveries tables, as a safety net against filling the disk
+    notifications_max_rows: Option<u64>,
+    deliveries_max_rows: Option<u64>,
+}
+
+/// The settings of the notification manager, named so that settings of the same type cannot be swapped by accident
+pub struct NotificationManagerConfig {
+    // The primary relay, and the relays tried after it when fetching events
+    pub relay_url: String,
+    pub fallback_relay_urls: Vec<String>,
+    // The credentials and app of the default APNS tenant
+    pub apns_private_key_path: String,
+    pub apns_private_key_id: String,
+    pub apns_team_id: String,
+    pub apns_environment: a2::client::Endpoint,
+    pub apns_topic: String,
+    // A PEM bundle of additional CA roots to trust for APNS connections, and whether the built-in roots are trusted as well
+    pub apns_extra_ca_roots_path: Option<String>,
+    pub apns_use_built_in_ca_roots: bool,
+    // An APNS host to use instead of Apple's (e.g. a mock server for testing), and the port to connect to (443, or 2197 where 443 is blocked)
+    pub apns_host: Option<String>,
+    pub apns_port: u16,
+    // How long fetched events are cached, with relay lists kept for longer
+    pub cache_max_age: std::time::Duration,
+    pub relay_list_cache_max_age: std::time::Duration,
+    // How long to wait for a relay to answer a fetch, the `limit` of fetch filters, and the rate and concurrency caps of fetches
+    pub note_fetch_timeout: std::time::Duration,
+    pub note_fetch_limit: usize,
+    pub relay_fetch_max_concurrent_subscriptions: usize,
+    pub relay_fetch_subscriptions_per_second: u32,
+    pub relay_fetch_burst: u32,
+    // The pubkeys this instance notifies, when recipients are spread across several instances
+    pub recipient_shard: RecipientShard,
+    pub notification_templates: NotificationTemplates,
+    // The maximum length of the note content shown in a notification body, in graphemes
+    pub push_body_max_length: usize,
+    // Event kinds that wake the app with a silent push instead of showing a notification
+    pub silent_push_kinds: HashSet<Kind>,
+    // How much of the event the pushes of each kind carry
+    pub event_inclusion_policies: HashMap<Kind, EventInclusion>,
+    // The accepted age range of events, in seconds. A negative minimum tolerates events from the future
+    pub event_max_age_seconds: u64,
+    pub event_min_age_seconds: Option<i64>,
+    pub spam_filter: SpamFilter,
+    // The maximum number of `p` and `e` tags of an event that are processed
+    pub max_processed_pubkey_tags: usize,
+    pub max_processed_event_tags: usize,
+    // Events that would notify more pubkeys than the threshold are degraded or held for approval. 0 disables it
+    pub flood_guard_threshold: usize,
+    pub flood_guard_mode: FloodGuardMode,
+    // How follow checks decide when the recipient's contact list cannot be fetched
+    pub follow_list_unavailable_policy: FollowListUnavailablePolicy,
+    // The number of reports against an author that stops notifying the reporter about them (0 disables it), and how long reports count
+    pub report_suppression_threshold: usize,
+    pub report_max_age: std::time::Duration,
+    // Hashtags (lowercase) that mark an event as sensitive content
+    pub sensitive_hashtags: HashSet<String>,
+    // How long removed devices are kept (disabled) before being purged
+    pub device_removal_grace_period: std::time::Duration,
+    // The maximum number of rows kept in the notifications and deliveries tables
+    pub notifications_max_rows: Option<u64>,
+    pub deliveries_max_rows: Option<u64>,
+    // The keys webhook payloads are signed with, if any
+    pub webhook_signing_keys: Option<nostr::Keys>,
+    // The APNS credentials of additional apps, keyed by tenant ID
+    pub apns_tenant_configs: HashMap<String, ApnsTenantConfig>,
+    // The concurrency and rate caps of APNS sends
+    pub apns_max_in_flight_sends: usize,
+    pub apns_sends_per_second: u32,
+    pub apns_send_burst: u32,
+    pub fault_injector: FaultInjector,
 }
 
 impl NotificationManager {
@@ -35,34 +220,191 @@ impl NotificationManager {
 
     pub async fn new(
         db: r2d2::Pool<SqliteConnectionManager>,
-        relay_url: String,
-        apns_private_key_path: String,
-        apns_private_key_id: String,
-        apns_team_id: String,
-        apns_environment: a2::client::Endpoint,
-        apns_topic: String,
-        cache_max_age: std::time::Duration,
+        config: NotificationManagerConfig,
     ) -> Result<Self, Box<dyn std::error::Error>> {
+        let NotificationManagerConfig {
+            relay_url,
+            fallback_relay_urls,
+            apns_private_key_path,
+            apns_private_key_id,
+            apns_team_id,
+            apns_environment,
+            apns_topic,
+            apns_extra_ca_roots_path,
+            apns_use_built_in_ca_roots,
+            apns_host,
+            apns_port,
+            cache_max_age,
+            relay_list_cache_max_age,
+            note_fetch_timeout,
+            note_fetch_limit,
+            relay_fetch_max_concurrent_subscriptions,
+            relay_fetch_subscriptions_per_second,
+            relay_fetch_burst,
+            recipient_shard,
+            notification_templates,
+            push_body_max_length,
+            silent_push_kinds,
+            event_inclusion_policies,
+            event_max_age_seconds,
+            event_min_age_seconds,
+            spam_filter,
+            max_processed_pubkey_tags,
+            max_processed_event_tags,
+            flood_guard_threshold,
+            flood_guard_mode,
+            follow_list_unavailable_policy,
+            report_suppression_threshold,
+            report_max_age,
+            sensitive_hashtags,
+            device_removal_grace_period,
+            notifications_max_rows,
+            deliveries_max_rows,
+            webhook_signing_keys,
+            apns_tenant_configs,
+            apns_max_in_flight_sends,
+            apns_sends_per_second,
+            apns_send_burst,
+            fault_injector,
+        } = config;
+        let fault_injector = std::sync::Arc::new(fault_injector);
         let connection = db.get()?;
         Self::setup_database(&connection)?;
 
-        let mut file = File::open(&apns_private_key_path)?;
+        let apns_connection = std::sync::Arc::new(ApnsConnection::new(
+            apns_extra_ca_roots_path.as_deref(),
+            apns_use_built_in_ca_roots,
+            apns_host,
+            apns_port,
+        )?);
 
-        let client = Client::token(
-            &mut file,
-            &apns_private_key_id,
+        let live_activity_client = LiveActivityClient::new(
+            apns_connection.clone(),
+            ApnsSigner::load(&apns_private_key_path, apns_private_key_id.clone(), apns_team_id.clone())?,
+            apns_environment.clone(),
+            &apns_topic,
+        );
+
+        let default_apns_tenant = ApnsTenant::new(
+            apns_topic,
             &apns_team_id,
-            ClientConfig::new(apns_environment.clone()),
+            &apns_private_key_id,
+            &apns_private_key_path,
+            apns_environment.clone(),
+            apns_connection.clone(),
         )?;
 
         Ok(Self {
-            apns_topic,
-            apns_client: Mutex::new(client),
-            db: Mutex::new(db),
-            nostr_network_helper: NostrNetworkHelper::new(relay_url.clone(), cache_max_age).await?,
+            push_providers: HashMap::from([(
+                TokenType::Apns,
+                Box::new(ApnsProvider::new(
+                    ApnsTenants::new(default_apns_tenant, apns_tenant_configs, apns_connection)?,
+                    SendRateLimiter::new(apns_max_in_flight_sends, apns_sends_per_second, apns_send_burst),
+                    fault_injector.clone(),
+                )) as Box<dyn PushProvider>,
+            )]),
+            latency_metrics: LatencyMetrics::default(),
+            fault_injector: fault_injector.clone(),
+            db,
+            nostr_network_helper: NostrNetworkHelper::new(
+                relay_url.clone(),
+                fallback_relay_urls,
+                cache_max_age,
+                relay_list_cache_max_age,
+                note_fetch_timeout,
+                note_fetch_limit,
+                relay_fetch_max_concurrent_subscriptions,
+                relay_fetch_subscriptions_per_second,
+                relay_fetch_burst,
+                fault_injector.clone(),
+            ).await?,
+            recipient_shard,
+            webhook_client: WebhookClient::new(webhook_signing_keys)?,
+            notification_templates,
+            push_body_max_length,
+            zap_receipt_verifier: ZapReceiptVerifier::new(cache_max_age)?,
+            recent_zap_notifications: Mutex::new(std::collections::HashMap::new()),
+            recent_hashtag_notifications: Mutex::new(HashMap::new()),
+            live_activity_client,
+            silent_push_kinds,
+            event_inclusion_policies,
+            event_max_age_seconds,
+            event_min_age_seconds,
+            spam_filter,
+            max_processed_pubkey_tags,
+            max_processed_event_tags,
+            flood_guard_threshold,
+            flood_guard_mode,
+            follow_list_unavailable_policy,
+            report_suppression_threshold,
+            report_max_age,
+            sensitive_hashtags,
+            device_removal_grace_period,
+            notifications_max_rows,
+            deliveries_max_rows,
         })
     }
 
+    // MARK: - Health
+
+    pub async fn relay_health(&self) -> RelayHealth {
+        self.nostr_network_helper.relay_health().await
+    }
+
+    // MARK: - Metrics
+
+    /// Records how long a phase of processing an event took
+    pub fn record_processing_latency(&self, phase: ProcessingPhase, duration: std::time::Duration) {
+        self.latency_metrics.observe(phase, duration);
+    }
+
+    /// The pubkeys tagged in the event (in tag order, up to the processing cap) and its author
+    fn capped_relevant_pubkeys(&self, event: &Event) -> HashSet<PublicKey> {
+        let mut pubkeys: HashSet<PublicKey> = event.public_keys().take(self.max_processed_pubkey_tags).cloned().collect();
+        if event.public_keys().nth(self.max_processed_pubkey_tags).is_some() {
+            log::warn!("Event {} has more than {} pubkey tags, only processing the first ones", event.id, self.max_processed_pubkey_tags);
+            self.latency_metrics.count_tag_truncation("p");
+        }
+        pubkeys.insert(event.pubkey);
+        pubkeys
+    }
+
+    /// The event IDs referenced by the event, up to the processing cap
+    fn capped_referenced_event_ids(&self, event: &Event) -> HashSet<EventId> {
+        let referenced_event_ids = event.referenced_event_ids();
+        if referenced_event_ids.len() > self.max_processed_event_tags {
+            log::warn!("Event {} has more than {} event tags, only processing some of them", event.id, self.max_processed_event_tags);
+            self.latency_metrics.count_tag_truncation("e");
+        }
+        referenced_event_ids.into_iter().take(self.max_processed_event_tags).collect()
+    }
+
+    /// The coordinates of the addressable events referenced by the event, up to the processing cap
+    fn capped_referenced_coordinates(&self, event: &Event) -> Vec<Coordinate> {
+        let referenced_coordinates = event.referenced_coordinates();
+        if referenced_coordinates.len() > self.max_processed_event_tags {
+            log::warn!("Event {} has more than {} address tags, only processing the first ones", event.id, self.max_processed_event_tags);
+            self.latency_metrics.count_tag_truncation("a");
+        }
+        referenced_coordinates.into_iter().take(self.max_processed_event_tags).collect()
+    }
+
+    /// Renders the processing latency histograms and the relay fetch metrics in the Prometheus text format
+    pub fn render_metrics(&self) -> String {
+        self.latency_metrics.render_prometheus() + &self.nostr_network_helper.render_relay_fetch_metrics()
+    }
+
+    /// Summarizes the processing latency of each phase observed so far
+    pub fn latency_summaries(&self) -> Vec<PhaseLatencySummary> {
+        self.latency_metrics.summaries()
+    }
+
+    /// Caches the lists and profiles of these pubkeys as not existing, so that processing their events never waits on relays.
+    /// Used by the benchmark, which measures this server rather than the relays
+    pub async fn assume_no_relay_data_for_pubkeys(&self, pubkeys: &HashSet<PublicKey>) {
+        self.nostr_network_helper.assume_no_events_for_pubkeys(pubkeys).await
+    }
+
     // MARK: - Database setup operations
 
     pub fn setup_database(db: &rusqlite::Connection) -> Result<(), rusqlite::Error> {
@@ -98,8 +440,31 @@ impl NotificationManager {
         )?;
 
         Self::add_column_if_not_exists(&db, "notifications", "sent_at", "INTEGER", None)?;
+        
+        // Covers the notification status lookup of an event and the (most recent) subscribers of the events it references, so it never touches the table
+        db.execute("DROP INDEX IF EXISTS notification_event_id_pubkey_received_index", [])?;
+        db.execute(
+            "CREATE INDEX IF NOT EXISTS notification_event_id_sent_at_index ON notifications (event_id, sent_at, pubkey, received_notification)",
+            [],
+        )?;
+        
+        // Used for counting the notifications of a pubkey (NIP-45 COUNT)
+        db.execute(
+            "CREATE INDEX IF NOT EXISTS notification_pubkey_sent_at_index ON notifications (pubkey, sent_at)",
+            [],
+        )?;
         Self::add_column_if_not_exists(&db, "user_info", "added_at", "INTEGER", None)?;
         
+        // Two-phase delivery marking: a notification is claimed as pending with its event (JSON) and reason, and only marked as received
+        // once it was delivered, so that the pending ones can be re-driven after a crash
+        Self::add_column_if_not_exists(&db, "notifications", "pending_event", "TEXT", None)?;
+        Self::add_column_if_not_exists(&db, "notifications", "pending_reason", "TEXT", None)?;
+        Self::add_column_if_not_exists(&db, "notifications", "pending_attempts", "INTEGER", Some("0"))?;
+        db.execute(
+            "CREATE INDEX IF NOT EXISTS notification_pending_sent_at_index ON notifications (sent_at) WHERE pending_event IS NOT NULL",
+            [],
+        )?;
+        
         // Notification settings migration (https://github.com/damus-io/damus/issues/2360)
         
         Self::add_column_if_not_exists(&db, "user_info", "zap_notifications_enabled", "BOOLEAN", Some("true"))?;
@@ -108,10 +473,454 @@ impl NotificationManager {
         Self::add_column_if_not_exists(&db, "user_info", "reaction_notifications_enabled", "BOOLEAN", Some("true"))?;
         Self::add_column_if_not_exists(&db, "user_info", "dm_notifications_enabled", "BOOLEAN", Some("true"))?;
         Self::add_column_if_not_exists(&db, "user_info", "only_notifications_from_following_enabled", "BOOLEAN", Some("false"))?;
+        Self::add_column_if_not_exists(&db, "user_info", "strangers_to_requests_folder_enabled", "BOOLEAN", Some("false"))?;
+        Self::add_column_if_not_exists(&db, "user_info", "dm_only_from_following_enabled", "BOOLEAN", Some("false"))?;
+        Self::add_column_if_not_exists(&db, "user_info", "sensitive_content_filter", "TEXT", Some("'show'"))?;
+        Self::add_column_if_not_exists(&db, "user_info", "mention_min_proof_of_work", "INTEGER", Some("0"))?;
+        Self::add_column_if_not_exists(&db, "user_info", "weekly_summary_enabled", "BOOLEAN", Some("false"))?;
+        // JSON-encoded `DeliveryWindow`. NULL means that every kind is delivered right away
+        Self::add_column_if_not_exists(&db, "user_info", "delivery_window", "TEXT", None)?;
+        
+        // Notifications held back outside of a device's delivery window, sent as a digest once it opens
+        
+        db.execute(
+            "CREATE TABLE IF NOT EXISTS deferred_notifications (
+                pubkey TEXT NOT NULL,
+                device_token TEXT NOT NULL,
+                event_id TEXT NOT NULL,
+                notification_kind TEXT NOT NULL,
+                deferred_at INTEGER NOT NULL,
+                PRIMARY KEY (pubkey, device_token, event_id)
+            )",
+            [],
+        )?;
+        // The digest sender that claimed the notifications and when, and how many times they were claimed, so that they are only removed once
+        // the digest was delivered, and given up on if it keeps failing
+        Self::add_column_if_not_exists(&db, "deferred_notifications", "claim_id", "TEXT", None)?;
+        Self::add_column_if_not_exists(&db, "deferred_notifications", "claimed_at", "INTEGER", None)?;
+        Self::add_column_if_not_exists(&db, "deferred_notifications", "attempts", "INTEGER", Some("0"))?;
+        db.execute(
+            "CREATE INDEX IF NOT EXISTS deferred_notification_claim_id_index ON deferred_notifications (claim_id) WHERE claim_id IS NOT NULL",
+            [],
+        )?;
+        
+        // Live Activities
+        
+        db.execute(
+            "CREATE TABLE IF NOT EXISTS live_activity_tokens (
+                id TEXT PRIMARY KEY,
+                pubkey TEXT,
+                activity_token TEXT,
+                event_id TEXT,
+                added_at INTEGER
+            )",
+            [],
+        )?;
+
+        db.execute(
+            "CREATE INDEX IF NOT EXISTS live_activity_tokens_event_id_index ON live_activity_tokens (event_id)",
+            [],
+        )?;
+
+        // Claims of the Live Activity updates sent for zap receipts, so that each one is sent once across restarts and instances
+        db.execute(
+            "CREATE TABLE IF NOT EXISTS live_activity_updates (
+                id TEXT PRIMARY KEY,
+                sent_at INTEGER NOT NULL
+            )",
+            [],
+        )?;
+
+        db.execute(
+            "CREATE INDEX IF NOT EXISTS live_activity_updates_sent_at_index ON live_activity_updates (sent_at)",
+            [],
+        )?;
+        
+        // Delivery tracking
+        
+        db.execute(
+            "CREATE TABLE IF NOT EXISTS deliveries (
+                id INTEGER PRIMARY KEY AUTOINCREMENT,
+                event_id TEXT,
+                pubkey TEXT,
+                device_token TEXT,
+                apns_id TEXT,
+                status INTEGER,
+                reason TEXT,
+                success BOOLEAN,
+                sent_at INTEGER
+            )",
+            [],
+        )?;
+
+        db.execute(
+            "CREATE INDEX IF NOT EXISTS deliveries_sent_at_index ON deliveries (sent_at)",
+            [],
+        )?;
+
+        // Used for listing the recent deliveries of a pubkey in its debug info
+        db.execute(
+            "CREATE INDEX IF NOT EXISTS deliveries_pubkey_sent_at_index ON deliveries (pubkey, sent_at)",
+            [],
+        )?;
+
+        db.execute(
+            "CREATE INDEX IF NOT EXISTS deliveries_device_token_index ON deliveries (device_token)",
+            [],
+        )?;
+        
+        Self::add_column_if_not_exists(&db, "deliveries", "topic", "TEXT", None)?;
+        Self::add_column_if_not_exists(&db, "deliveries", "latency_ms", "INTEGER", None)?;
+
+        db.execute(
+            "CREATE TABLE IF NOT EXISTS delivery_daily_summaries (
+                day INTEGER,
+                topic TEXT,
+                total INTEGER,
+                successes INTEGER,
+                p95_latency_ms INTEGER,
+                failure_reasons TEXT,
+                PRIMARY KEY (day, topic)
+            )",
+            [],
+        )?;
+        
+        // Webhook transport migration
+        
+        Self::add_column_if_not_exists(&db, "user_info", "webhook_url", "TEXT", None)?;
+        Self::add_column_if_not_exists(&db, "user_info", "webhook_secret", "TEXT", None)?;
+        
+        // Device metadata migration
+        
+        Self::add_column_if_not_exists(&db, "user_info", "locale", "TEXT", None)?;
+        Self::add_column_if_not_exists(&db, "user_info", "app_version", "TEXT", None)?;
+        Self::add_column_if_not_exists(&db, "user_info", "os_version", "TEXT", None)?;
+        
+        // Multi-tenant APNS migration
+        
+        Self::add_column_if_not_exists(&db, "user_info", "apns_tenant", "TEXT", None)?;
+        
+        // Push provider migration. Devices registered before it are APNS devices
+        
+        Self::add_column_if_not_exists(&db, "user_info", "token_type", "TEXT", Some("'apns'"))?;
+        
+        // Push payload versioning migration. Devices registered before it get the legacy layout
+        
+        Self::add_column_if_not_exists(&db, "user_info", "payload_version", "INTEGER", None)?;
+        
+        // Soft-delete migration. Removed devices are disabled (non-NULL `deleted_at`) and purged after a grace period
+        
+        Self::add_column_if_not_exists(&db, "user_info", "deleted_at", "INTEGER", None)?;
+        // Accounts switched away from on a device are disabled the same way, but never purged, so that switching back restores them
+        Self::add_column_if_not_exists(&db, "user_info", "switched_away", "BOOLEAN", Some("false"))?;
+        
+        // The last successful send to each device, so that clients can detect broken pushes
+        
+        Self::add_column_if_not_exists(&db, "user_info", "last_notified_at", "INTEGER", None)?;
+        
+        // Weekly summaries. The kind and zap amount of each notification are kept to summarize them
+        
+        Self::add_column_if_not_exists(&db, "notifications", "kind", "INTEGER", None)?;
+        Self::add_column_if_not_exists(&db, "notifications", "zap_amount_msats", "INTEGER", None)?;
+        Self::add_column_if_not_exists(&db, "user_info", "weekly_summary_sent_at", "INTEGER", None)?;
+        
+        // The author of each notification, used to tell whether an author interacts with a recipient for the first time
+        
+        Self::add_column_if_not_exists(&db, "notifications", "author", "TEXT", None)?;
+        db.execute(
+            "CREATE INDEX IF NOT EXISTS notification_pubkey_author_index ON notifications (pubkey, author)",
+            [],
+        )?;
+        
+        // Linked accounts. Other identities of the device's user, whose events should not notify the device
+        
+        db.execute(
+            "CREATE TABLE IF NOT EXISTS linked_pubkeys (
+                device_token TEXT,
+                pubkey TEXT,
+                PRIMARY KEY (device_token, pubkey)
+            )",
+            [],
+        )?;
+        
+        // NIP-56 reports filed by registered users, used to suppress notifications from the authors they report
+        
+        db.execute(
+            "CREATE TABLE IF NOT EXISTS reports (
+                id TEXT PRIMARY KEY,
+                reporter TEXT,
+                reported_pubkey TEXT,
+                created_at INTEGER
+            )",
+            [],
+        )?;
+
+        db.execute(
+            "CREATE INDEX IF NOT EXISTS reports_reporter_reported_pubkey_index ON reports (reporter, reported_pubkey)",
+            [],
+        )?;
+        
+        // Hashtag subscriptions
+        
+        db.execute(
+            "CREATE TABLE IF NOT EXISTS hashtag_subscriptions (
+                pubkey TEXT,
+                hashtag TEXT,
+                scope TEXT,
+                PRIMARY KEY (pubkey, hashtag)
+            )",
+            [],
+        )?;
+
+        db.execute(
+            "CREATE INDEX IF NOT EXISTS hashtag_subscriptions_hashtag_index ON hashtag_subscriptions (hashtag)",
+            [],
+        )?;
+        
+        // Events held back by the flood guard until an operator approves them
+        
+        db.execute(
+            "CREATE TABLE IF NOT EXISTS held_events (
+                event_id TEXT PRIMARY KEY,
+                event TEXT,
+                source_relay_url TEXT,
+                recipient_count INTEGER,
+                held_at INTEGER
+            )",
+            [],
+        )?;
+        
+        // Audit log of admin API requests and destructive user actions. Append-only, so that it can be trusted after the fact
+        
+        db.execute(
+            "CREATE TABLE IF NOT EXISTS audit_log (
+                id INTEGER PRIMARY KEY AUTOINCREMENT,
+                actor_pubkey TEXT NOT NULL,
+                method TEXT NOT NULL,
+                path TEXT NOT NULL,
+                status INTEGER NOT NULL,
+                request_id TEXT NOT NULL,
+                created_at INTEGER NOT NULL
+            )",
+            [],
+        )?;
+        
+        db.execute(
+            "CREATE INDEX IF NOT EXISTS audit_log_actor_pubkey_index ON audit_log (actor_pubkey, id)",
+            [],
+        )?;
+        
+        db.execute(
+            "CREATE TRIGGER IF NOT EXISTS audit_log_no_update BEFORE UPDATE ON audit_log
+            BEGIN SELECT RAISE(ABORT, 'audit_log is append-only'); END",
+            [],
+        )?;
+        
+        db.execute(
+            "CREATE TRIGGER IF NOT EXISTS audit_log_no_delete BEFORE DELETE ON audit_log
+            BEGIN SELECT RAISE(ABORT, 'audit_log is append-only'); END",
+            [],
+        )?;
+        
+        // Addressable events. The first addressable event (a tag) a notification references, so that the participants
+        // of threads on long-form articles or live events can be found like those of threads on regular notes
+        
+        Self::add_column_if_not_exists(&db, "notifications", "coordinate", "TEXT", None)?;
+        db.execute(
+            "CREATE INDEX IF NOT EXISTS notification_coordinate_sent_at_index ON notifications (coordinate, sent_at)",
+            [],
+        )?;
+        
+        // Uniqueness migration. The string-concatenated IDs do not prevent duplicates from older schemas, so dedupe before adding the constraints
+        
+        Self::add_unique_index_if_not_exists(&db, "user_info", "user_info_pubkey_device_token_unique", &["pubkey", "device_token"])?;
+        Self::add_unique_index_if_not_exists(&db, "notifications", "notification_event_id_pubkey_unique", &["event_id", "pubkey"])?;
 
         Ok(())
     }
 
+    /// Adds a unique index on the given columns, first deleting duplicate rows (keeping the oldest one of each group)
+    fn add_unique_index_if_not_exists(
+        db: &rusqlite::Connection,
+        table_name: &str,
+        index_name: &str,
+        column_names: &[&str],
+    ) -> Result<(), rusqlite::Error> {
+        let index_exists: bool = db.query_row(
+            "SELECT EXISTS (SELECT 1 FROM sqlite_master WHERE type = 'index' AND name = ?)",
+            [index_name],
+            |row| row.get(0),
+        )?;
+        if index_exists {
+            return Ok(());
+        }
+        
+        // Dedupe and index in one transaction, so that other instances sharing the database cannot insert duplicates in between
+        let transaction = db.unchecked_transaction()?;
+        let columns = column_names.join(", ");
+        let deleted_rows = transaction.execute(
+            &format!(
+                "DELETE FROM {} WHERE rowid NOT IN (SELECT MIN(rowid) FROM {} GROUP BY {})",
+                table_name, table_name, columns
+            ),
+            [],
+        )?;
+        if deleted_rows > 0 {
+            log::info!("Deleted {} duplicate rows from {} before adding a unique index on ({})", deleted_rows, table_name, columns);
+        }
+        transaction.execute(
+            &format!("CREATE UNIQUE INDEX IF NOT EXISTS {} ON {} ({})", index_name, table_name, columns),
+            [],
+        )?;
+        transaction.commit()
+    }
+
+    // MARK: - Database maintenance
+
+    /// Copies the database to `destination_path` with SQLite's online backup API.
+    /// The copy is consistent, and the backup yields to writers between steps, so the service keeps running meanwhile
+    pub async fn backup_database(
+        &self,
+        destination_path: std::path::PathBuf,
+    ) -> Result<(), Box<dyn std::error::Error>> {
+        self.with_connection(move |connection| {
+            connection.backup(rusqlite::DatabaseName::Main, destination_path, None)?;
+            Ok(())
+        })
+        .await
+    }
+
+    /// Moves the contents of the write-ahead log into the database file. Has no effect unless the database is in WAL mode
+    pub async fn checkpoint_wal(
+        &self,
+        mode: WalCheckpointMode,
+    ) -> Result<WalCheckpointResult, Box<dyn std::error::Error>> {
+        let query = format!("PRAGMA wal_checkpoint({})", mode.as_sql());
+        self.with_connection(move |connection| {
+            let result = connection.query_row(&query, [], |row| {
+                Ok(WalCheckpointResult {
+                    busy: row.get::<_, i64>(0)? != 0,
+                    wal_pages: row.get(1)?,
+                    checkpointed_pages: row.get(2)?,
+                })
+            })?;
+            Ok(result)
+        })
+        .await
+    }
+
+    /// Periodically evicts the oldest rows of the tables that are above their row cap, and expires old Live Activity update claims and reports.
+    /// Runs forever, so it should be spawned as a task
+    pub async fn run_row_cap_job(notification_manager: std::sync::Arc<Self>) {
+        let mut interval = tokio::time::interval(ROW_CAP_INTERVAL);
+        loop {
+            interval.tick().await;
+            for (table, max_rows) in [("notifications", notification_manager.notifications_max_rows), ("deliveries", notification_manager.deliveries_max_rows)] {
+                let max_rows = match max_rows {
+                    Some(max_rows) => max_rows,
+                    None => continue,
+                };
+                match notification_manager.evict_rows_above_cap(table, max_rows).await {
+                    Ok(0) => {}
+                    Ok(evicted_rows) => log::warn!("Evicted the {} oldest rows of {}, which was above its cap of {} rows", evicted_rows, table, max_rows),
+                    Err(e) => log::error!("Failed to enforce the row cap of {}: {}", table, e),
+                }
+            }
+            if let Err(e) = notification_manager.expire_live_activity_update_claims().await {
+                log::error!("Failed to expire Live Activity update claims: {}", e);
+            }
+            if let Err(e) = notification_manager.expire_reports().await {
+                log::error!("Failed to expire reports: {}", e);
+            }
+        }
+    }
+
+    /// Deletes the oldest rows (in insertion order) of a table until it has at most `max_rows` rows, returning how many were deleted.
+    /// Rows are deleted in batches, so that writers are not blocked for long.
+    /// Notifications that are pending, or recent enough for their events to still be processed, are never evicted, since they are
+    /// what keeps the same notification from being sent twice. The table is left above its cap if needed
+    async fn evict_rows_above_cap(&self, table: &'static str, max_rows: u64) -> Result<usize, Box<dyn std::error::Error>> {
+        let dedup_cutoff = Timestamp::now().as_u64().saturating_sub(self.event_max_age_seconds) as i64;
+        let eviction_condition = match table {
+            "notifications" => format!("AND pending_event IS NULL AND sent_at < {}", dedup_cutoff),
+            _ => String::new(),
+        };
+        self.with_connection(move |connection| {
+            // Rowids grow with insertion, so the rows above the cap are found from the newest one without counting the table.
+            // Gaps left by rows deleted otherwise (e.g. by retention) can only leave the table below its cap
+            let newest_rowid: Option<i64> = connection.query_row(&format!("SELECT MAX(rowid) FROM {}", table), [], |row| row.get(0))?;
+            let newest_evicted_rowid = match newest_rowid {
+                Some(newest_rowid) => newest_rowid.saturating_sub(max_rows as i64),
+                None => return Ok(0),
+            };
+            let mut evicted_rows = 0;
+            loop {
+                let deleted_rows = connection.execute(
+                    &format!(
+                        "DELETE FROM {table} WHERE rowid IN (SELECT rowid FROM {table} WHERE rowid <= ? {condition} ORDER BY rowid LIMIT ?)",
+                        table = table,
+                        condition = eviction_condition,
+                    ),
+                    [newest_evicted_rowid, ROW_EVICTION_BATCH_SIZE],
+                )?;
+                if deleted_rows == 0 {
+                    break;
+                }
+                evicted_rows += deleted_rows;
+            }
+            Ok(evicted_rows)
+        })
+        .await
+    }
+
+    // MARK: - Capabilities
+
+    /// Describes what this server build supports, so that clients can build their settings screens from it instead of hard-coding it
+    pub fn capabilities(&self) -> ServerCapabilities {
+        ServerCapabilities {
+            settings_version: USER_NOTIFICATION_SETTINGS_VERSION,
+            payload_versions: (push_payload::LEGACY_PAYLOAD_VERSION..=push_payload::LATEST_PAYLOAD_VERSION).collect(),
+            notification_kinds: NotificationKind::ALL
+                .iter()
+                .map(|notification_kind| NotificationKindCapability {
+                    kind: *notification_kind,
+                    event_kinds: notification_kind.event_kinds().iter().map(|kind| kind.as_u16()).collect(),
+                    setting: notification_kind.setting_name(),
+                    silent: notification_kind.event_kinds().iter().all(|kind| self.is_silent_push_kind(*kind)),
+                })
+                .collect(),
+            digests: vec![
+                DigestCapability { digest: "weekly_summary", setting: "weekly_summary_enabled" },
+                DigestCapability { digest: "deferred_digest", setting: "delivery_window" },
+            ],
+            sounds: Vec::new(),
+            sensitive_content_filters: vec![SensitiveContentFilter::Show, SensitiveContentFilter::Blank, SensitiveContentFilter::Suppress],
+            max_hashtag_subscriptions: MAX_HASHTAG_SUBSCRIPTIONS,
+            hashtag_scopes: vec![HashtagScope::Following, HashtagScope::Global],
+        }
+    }
+
+    // MARK: - Database access
+
+    /// Runs a database operation with a connection from the pool on a blocking thread,
+    /// so that queries run concurrently (up to the pool size) without blocking the async runtime
+    async fn with_connection<T, F>(&self, operation: F) -> Result<T, Box<dyn std::error::Error>>
+    where
+        F: FnOnce(&mut rusqlite::Connection) -> Result<T, DatabaseError> + Send + 'static,
+        T: Send + 'static,
+    {
+        if self.fault_injector.should_inject(Fault::DbBusy) {
+            return Err(Box::new(FaultInjector::db_busy_error()));
+        }
+        let db = self.db.clone();
+        let result = tokio::task::spawn_blocking(move || {
+            let mut connection = db.get()?;
+            operation(&mut connection)
+        })
+        .await?;
+        result.map_err(|error| error as Box<dyn std::error::Error>)
+    }
+
     fn add_column_if_not_exists(
         db: &rusqlite::Connection,
         table_name: &str,
@@ -141,75 +950,650 @@ impl NotificationManager {
 
     // MARK: - Business logic
 
+    /// Sends the notifications for an event, if any. `source_relay_url` is the relay the event was seen on, if it did not come in directly
     pub async fn send_notifications_if_needed(
         &self,
         event: &Event,
+        source_relay_url: Option<&str>,
     ) -> Result<(), Box<dyn std::error::Error>> {
         log::debug!(
             "Checking if notifications need to be sent for event: {}",
             event.id
         );
-        let one_week_ago = nostr::Timestamp::now() - 7 * 24 * 60 * 60;
-        if event.created_at < one_week_ago {
-            log::debug!("Event is older than a week, not sending notifications");
+        let event_age = nostr::Timestamp::now().as_u64() as i64 - event.created_at.as_u64() as i64;
+        if event_age > self.event_max_age_seconds as i64 {
+            log::debug!("Event is older than {} seconds, not sending notifications", self.event_max_age_seconds);
             return Ok(());
         }
+        if let Some(event_min_age_seconds) = self.event_min_age_seconds {
+            if event_age < event_min_age_seconds {
+                log::debug!("Event is younger than {} seconds, not sending notifications", event_min_age_seconds);
+                return Ok(());
+            }
+        }
+        
+        if event.kind == Kind::Reporting {
+            return self.save_report_if_relevant(event).await;
+        }
         
-        if !Self::is_event_kind_supported(event.kind) {
+        if NotificationKind::from_event(event).is_none() && !self.is_silent_push_kind(event.kind) {
             log::debug!("Event kind is not supported, not sending notifications");
             return Ok(());
         }
+        
+        if let Some(reason) = self.spam_filter.rejection_reason(event) {
+            log::debug!("Event was rejected by the spam filter ({}), not sending notifications", reason);
+            return Ok(());
+        }
+        
+        // Verifying a zap receipt fetches the recipient's LNURL pay endpoint, so it is only done for zaps someone here would be told about
+        if event.kind == Kind::ZapReceipt && !self.has_zap_receipt_audience(event).await? {
+            log::debug!("Zap receipt has no registered recipient or Live Activity, not sending notifications");
+            return Ok(());
+        }
 
-        let pubkeys_to_notify = self.pubkeys_to_notify_for_event(event).await?;
+        if event.kind == Kind::ZapReceipt && !self.zap_receipt_verifier.is_zap_receipt_valid(event, &self.nostr_network_helper).await {
+            log::debug!("Zap receipt was not issued by the recipient's lightning provider, not sending notifications");
+            return Ok(());
+        }
 
-        log::debug!(
-            "Sending notifications to {} pubkeys",
-            pubkeys_to_notify.len()
-        );
+        // Only verified zaps are remembered, so that a forged receipt cannot suppress the notification of the real one
+        if self.is_duplicate_zap_notification(event).await {
+            log::debug!("Zap event is a duplicate of a recently notified zap, not sending notifications");
+            return Ok(());
+        }
 
-        for pubkey in pubkeys_to_notify {
-            self.send_event_notifications_to_pubkey(event, &pubkey)
-                .await?;
+        if event.kind == Kind::ZapReceipt {
+            self.send_live_activity_updates_for_zap(event).await?;
+        }
+
+        let pubkeys_to_notify = self.pubkeys_to_notify_for_event(event).await?;
+
+        // A viral event must not turn into an accidental mass blast
+        let is_mass_notification = self.flood_guard_threshold > 0 && pubkeys_to_notify.len() > self.flood_guard_threshold;
+        if is_mass_notification && self.flood_guard_mode == FloodGuardMode::Hold {
+            log::warn!(
+                "Event {} would notify {} pubkeys, more than the flood guard threshold of {}, holding it for approval",
+                event.id,
+                pubkeys_to_notify.len(),
+                self.flood_guard_threshold
+            );
+            for (pubkey, _) in pubkeys_to_notify.iter().filter(|(_, reason)| **reason == NotificationReason::Hashtag) {
+                self.release_hashtag_notification_slot(pubkey, event).await;
+            }
+            return self.hold_event(event, source_relay_url, pubkeys_to_notify.len()).await;
+        }
+        if is_mass_notification {
+            log::warn!(
+                "Event {} would notify {} pubkeys, more than the flood guard threshold of {}, sending low-priority collapsible pushes",
+                event.id,
+                pubkeys_to_notify.len(),
+                self.flood_guard_threshold
+            );
+        }
+
+        self.notify_pubkeys(event, pubkeys_to_notify, source_relay_url, is_mass_notification).await
+    }
+
+    async fn notify_pubkeys(
+        &self,
+        event: &Event,
+        pubkeys_to_notify: HashMap<PublicKey, NotificationReason>,
+        source_relay_url: Option<&str>,
+        is_mass_notification: bool,
+    ) -> Result<(), Box<dyn std::error::Error>> {
+        log::debug!(
+            "Sending notifications to {} pubkeys",
+            pubkeys_to_notify.len()
+        );
+
+        // Tells the client where to fetch context (e.g. parent notes and profiles) from
+        let mut relay_hints: Vec<String> = source_relay_url.map(|url| url.to_string()).into_iter().collect();
+        for relay_hint in event.relay_hints() {
+            if !relay_hints.contains(&relay_hint) {
+                relay_hints.push(relay_hint);
+            }
+        }
+
+        for (pubkey, reason) in pubkeys_to_notify {
+            // Claim the notification before sending it, so that only one instance sends it when several of them ingest the same event
+            match self.claim_notification(event, &pubkey, reason).await {
+                Ok(true) => {}
+                Ok(false) => {
+                    log::debug!("Notification for event {} to pubkey {} was already claimed, skipping", event.id, pubkey);
+                    self.release_hashtag_notification_slot_unless_delivered(&pubkey, event, reason, PubkeyDelivery::NotDelivered).await;
+                    continue;
+                }
+                Err(e) => {
+                    log::error!("Failed to claim notification for event {} to pubkey {}: {}", event.id, pubkey, e);
+                    self.release_hashtag_notification_slot_unless_delivered(&pubkey, event, reason, PubkeyDelivery::Retryable).await;
+                    continue;
+                }
+            }
+            // One recipient failing must not keep the others from being notified
+            let delivery = match self
+                .send_event_notifications_to_pubkey(event, &pubkey, reason, &relay_hints, is_mass_notification)
+                .await
             {
-                let db_mutex_guard = self.db.lock().await;
-                db_mutex_guard.get()?.execute(
-                    "INSERT OR REPLACE INTO notifications (id, event_id, pubkey, received_notification, sent_at)
-                    VALUES (?, ?, ?, ?, ?)",
-                    params![
-                        format!("{}:{}", event.id, pubkey),
-                        event.id.to_sql_string(),
-                        pubkey.to_sql_string(),
-                        true,
-                        nostr::Timestamp::now().to_sql_string(),
-                    ],
-                )?;
+                Ok(delivery) => delivery,
+                Err(e) => {
+                    log::error!("Failed to notify pubkey {} about event {}: {}", pubkey, event.id, e);
+                    PubkeyDelivery::Retryable
+                }
+            };
+            self.release_hashtag_notification_slot_unless_delivered(&pubkey, event, reason, delivery).await;
+            self.settle_notification(event, &pubkey, delivery).await;
+        }
+        Ok(())
+    }
+
+    // MARK: - Pending notifications
+
+    /// Re-drives the notifications that were claimed but never finished, e.g. because the server crashed mid-send or the send failed
+    /// in a retryable way, and expires the ones claimed longer ago than the maximum event age, which are no longer worth sending.
+    /// Waits for a while after startup first, so that other instances sharing the database can finish the ones they are sending.
+    /// Delivery is at least once, so a recipient with several devices may get a push again on a device it already reached.
+    /// Runs forever, so it should be spawned as a task at startup
+    pub async fn run_pending_notification_redrive_job(notification_manager: std::sync::Arc<Self>) {
+        tokio::time::sleep(PENDING_NOTIFICATION_REDRIVE_DELAY).await;
+        let mut interval = tokio::time::interval(PENDING_NOTIFICATION_REDRIVE_INTERVAL);
+        loop {
+            interval.tick().await;
+            notification_manager.recover_pending_notifications().await;
+        }
+    }
+
+    async fn recover_pending_notifications(&self) {
+        let now = Timestamp::now().as_u64();
+        let claimed_before = now.saturating_sub(PENDING_NOTIFICATION_REDRIVE_DELAY.as_secs()) as i64;
+        let expiry_cutoff = now.saturating_sub(self.event_max_age_seconds) as i64;
+        let expired_count = match self.expire_pending_notifications(expiry_cutoff).await {
+            Ok(expired_count) => expired_count,
+            Err(e) => {
+                log::error!("Failed to expire pending notifications: {}", e);
+                0
+            }
+        };
+        match self.redrive_pending_notifications(expiry_cutoff, claimed_before).await {
+            Ok(redriven_count) if redriven_count + expired_count > 0 => {
+                log::info!("Recovered {} pending notifications, expired {} older ones", redriven_count, expired_count)
+            }
+            Ok(_) => {}
+            Err(e) => log::error!("Failed to re-drive pending notifications: {}", e),
+        }
+    }
+
+    /// Finishes the pending notifications claimed before the cutoff without sending them, returning how many there were
+    async fn expire_pending_notifications(&self, claimed_before: i64) -> Result<usize, Box<dyn std::error::Error>> {
+        self.with_connection(move |connection| {
+            let expired_rows = Self::expire_pending_notification_rows(connection, claimed_before)?;
+            Ok(expired_rows)
+        })
+        .await
+    }
+
+    fn expire_pending_notification_rows(connection: &rusqlite::Connection, claimed_before: i64) -> Result<usize, rusqlite::Error> {
+        connection.execute(
+            "UPDATE notifications SET pending_event = NULL, pending_reason = NULL WHERE pending_event IS NOT NULL AND sent_at < ?",
+            [claimed_before],
+        )
+    }
+
+    /// Re-drives the pending notifications claimed between the two timestamps, returning how many were re-driven
+    async fn redrive_pending_notifications(&self, claimed_after: i64, claimed_before: i64) -> Result<usize, Box<dyn std::error::Error>> {
+        let pending_notifications = self
+            .with_connection(move |connection| {
+                let pending_notifications = Self::pending_notification_rows(connection, claimed_after, claimed_before)?;
+                Ok(pending_notifications)
+            })
+            .await?;
+        let mut redriven_count = 0;
+        for PendingNotification { id, pubkey, event, reason, claimed_at, attempts } in pending_notifications {
+            if attempts >= MAX_NOTIFICATION_ATTEMPTS {
+                log::warn!("Pending notification {} failed {} times, giving up on it", id, attempts);
+                self.finish_notification_by_id(id, false).await?;
+                continue;
             }
+            let parsed_notification = match (PublicKey::from_sql_string(pubkey), Event::from_json(event)) {
+                (Ok(pubkey), Ok(event)) => Some((pubkey, event)),
+                _ => None,
+            };
+            let (pubkey, event) = match parsed_notification {
+                Some(parsed_notification) => parsed_notification,
+                None => {
+                    log::warn!("Pending notification {} cannot be parsed, dropping it", id);
+                    self.finish_notification_by_id(id, false).await?;
+                    continue;
+                }
+            };
+            // Another instance may have taken it in the meantime
+            if !self.take_pending_notification(id, claimed_at).await? {
+                continue;
+            }
+            // Pushes about events that went stale while the server was down are no longer worth sending
+            let event_age = Timestamp::now().as_u64() as i64 - event.created_at.as_u64() as i64;
+            if !self.recipient_shard.contains(&pubkey) || event_age > self.event_max_age_seconds as i64 {
+                self.finish_notification(&event, &pubkey, false).await?;
+                continue;
+            }
+            let reason = reason.as_deref().and_then(NotificationReason::parse).unwrap_or(NotificationReason::Mention);
+            log::debug!("Re-driving pending notification for event {} to pubkey {}", event.id, pubkey);
+            let delivery = match self
+                .send_event_notifications_to_pubkey(&event, &pubkey, reason, &event.relay_hints(), false)
+                .await
+            {
+                Ok(delivery) => delivery,
+                Err(e) => {
+                    log::error!("Failed to re-drive notification for event {} to pubkey {}: {}", event.id, pubkey, e);
+                    PubkeyDelivery::Retryable
+                }
+            };
+            self.settle_notification(&event, &pubkey, delivery).await;
+            redriven_count += 1;
+        }
+        Ok(redriven_count)
+    }
+
+    /// The pending notifications claimed between the two timestamps, oldest first
+    fn pending_notification_rows(
+        connection: &rusqlite::Connection,
+        claimed_after: i64,
+        claimed_before: i64,
+    ) -> Result<Vec<PendingNotification>, rusqlite::Error> {
+        let mut stmt = connection.prepare(
+            "SELECT id, pubkey, pending_event, pending_reason, sent_at, pending_attempts FROM notifications
+            WHERE pending_event IS NOT NULL AND sent_at >= ? AND sent_at <= ?
+            ORDER BY sent_at",
+        )?;
+        let pending_notifications = stmt
+            .query_map([claimed_after, claimed_before], |row| {
+                Ok(PendingNotification {
+                    id: row.get(0)?,
+                    pubkey: row.get(1)?,
+                    event: row.get(2)?,
+                    reason: row.get(3)?,
+                    claimed_at: row.get(4)?,
+                    attempts: row.get(5)?,
+                })
+            })?
+            .filter_map(|r| r.ok())
+            .collect();
+        Ok(pending_notifications)
+    }
+
+    /// Finishes the notification after an attempt to send it. Retryable failures leave it pending instead, so that it is re-driven later
+    async fn settle_notification(&self, event: &Event, pubkey: &PublicKey, delivery: PubkeyDelivery) {
+        let id = Self::notification_id(event, pubkey);
+        let settle_result = self
+            .with_connection(move |connection| {
+                let is_finished = Self::settle_notification_row(connection, &id, delivery)?;
+                Ok(is_finished)
+            })
+            .await;
+        match settle_result {
+            Ok(true) => {}
+            Ok(false) => log::info!("Notification for event {} to pubkey {} failed in a retryable way, leaving it pending", event.id, pubkey),
+            Err(e) => log::error!("Failed to finish notification for event {} to pubkey {}: {}", event.id, pubkey, e),
         }
+    }
+
+    /// Finishes the notification unless the delivery failed in a retryable way, returning whether it was finished
+    fn settle_notification_row(connection: &rusqlite::Connection, id: &str, delivery: PubkeyDelivery) -> Result<bool, rusqlite::Error> {
+        if delivery == PubkeyDelivery::Retryable {
+            return Ok(false);
+        }
+        Self::finish_notification_row(connection, id, delivery == PubkeyDelivery::Delivered)?;
+        Ok(true)
+    }
+
+    /// Atomically takes a pending notification for re-driving by refreshing its claim time and counting the attempt,
+    /// returning `false` if it was taken already
+    async fn take_pending_notification(&self, id: String, claimed_at: i64) -> Result<bool, Box<dyn std::error::Error>> {
+        self.with_connection(move |connection| {
+            let is_taken = Self::take_pending_notification_row(connection, &id, claimed_at, Timestamp::now())?;
+            Ok(is_taken)
+        })
+        .await
+    }
+
+    fn take_pending_notification_row(connection: &rusqlite::Connection, id: &str, claimed_at: i64, now: Timestamp) -> Result<bool, rusqlite::Error> {
+        let updated_rows = connection.execute(
+            "UPDATE notifications SET sent_at = ?, pending_attempts = pending_attempts + 1 WHERE id = ? AND sent_at = ? AND pending_event IS NOT NULL",
+            params![now.to_sql_string(), id, claimed_at],
+        )?;
+        Ok(updated_rows > 0)
+    }
+
+    /// Marks a claimed notification as finished, and as received if it was delivered to at least one device
+    async fn finish_notification(&self, event: &Event, pubkey: &PublicKey, was_delivered: bool) -> Result<(), Box<dyn std::error::Error>> {
+        self.finish_notification_by_id(Self::notification_id(event, pubkey), was_delivered).await
+    }
+
+    async fn finish_notification_by_id(&self, id: String, was_delivered: bool) -> Result<(), Box<dyn std::error::Error>> {
+        self.with_connection(move |connection| {
+            Self::finish_notification_row(connection, &id, was_delivered)?;
+            Ok(())
+        })
+        .await
+    }
+
+    fn finish_notification_row(connection: &rusqlite::Connection, id: &str, was_delivered: bool) -> Result<(), rusqlite::Error> {
+        connection.execute(
+            "UPDATE notifications SET received_notification = ?, pending_event = NULL, pending_reason = NULL WHERE id = ?",
+            params![was_delivered, id],
+        )?;
         Ok(())
     }
-    
-    fn is_event_kind_supported(event_kind: nostr::Kind) -> bool {
-        match event_kind {
-            nostr_sdk::Kind::TextNote => true,
-            nostr_sdk::Kind::EncryptedDirectMessage => true,
-            nostr_sdk::Kind::Repost => true,
-            nostr_sdk::Kind::GenericRepost => true,
-            nostr_sdk::Kind::Reaction => true,
-            nostr_sdk::Kind::ZapPrivateMessage => true,
-            nostr_sdk::Kind::ZapRequest => false,
-            nostr_sdk::Kind::ZapReceipt => true,
-            _ => false,
+
+    // MARK: - Flood guard
+
+    async fn hold_event(
+        &self,
+        event: &Event,
+        source_relay_url: Option<&str>,
+        recipient_count: usize,
+    ) -> Result<(), Box<dyn std::error::Error>> {
+        let (event_id, event_json) = (event.id.to_sql_string(), event.as_json());
+        let source_relay_url = source_relay_url.map(str::to_string);
+        self.with_connection(move |connection| {
+            connection.execute(
+                "INSERT INTO held_events (event_id, event, source_relay_url, recipient_count, held_at) VALUES (?, ?, ?, ?, ?)
+                ON CONFLICT DO NOTHING",
+                params![
+                    event_id,
+                    event_json,
+                    source_relay_url,
+                    recipient_count as i64,
+                    Timestamp::now().to_sql_string(),
+                ],
+            )?;
+            Ok(())
+        })
+        .await
+    }
+
+    /// Lists the events held back by the flood guard, oldest first
+    pub async fn get_held_events(&self) -> Result<Vec<HeldEvent>, Box<dyn std::error::Error>> {
+        let rows: Vec<(String, i64, i64)> = self.with_connection(|connection| {
+            let mut stmt = connection.prepare("SELECT event, recipient_count, held_at FROM held_events ORDER BY held_at")?;
+            let rows = stmt
+                .query_map([], |row| Ok((row.get(0)?, row.get(1)?, row.get(2)?)))?
+                .filter_map(|r| r.ok())
+                .collect();
+            Ok(rows)
+        })
+        .await?;
+        let held_events = rows
+            .into_iter()
+            .filter_map(|(event, recipient_count, held_at)| {
+                let event = Event::from_json(event).ok()?;
+                Some(HeldEvent {
+                    event_id: event.id.to_hex(),
+                    kind: event.kind.as_u16(),
+                    author: event.pubkey.to_hex(),
+                    recipient_count: recipient_count as u64,
+                    held_at: held_at as u64,
+                })
+            })
+            .collect();
+        Ok(held_events)
+    }
+
+    /// Sends the notifications of a held event, as approved by an operator.
+    /// The recipients are found again, since they may have changed while it was held. Returns `None` if the event is not held
+    pub async fn approve_held_event(&self, event_id: &EventId) -> Result<Option<usize>, Box<dyn std::error::Error>> {
+        let (event, source_relay_url) = match self.take_held_event(event_id).await? {
+            Some(held_event) => held_event,
+            None => return Ok(None),
+        };
+        let pubkeys_to_notify = self.pubkeys_to_notify_for_event(&event).await?;
+        let recipient_count = pubkeys_to_notify.len();
+        log::info!("Held event {} was approved, notifying {} pubkeys", event.id, recipient_count);
+        self.notify_pubkeys(&event, pubkeys_to_notify, source_relay_url.as_deref(), false).await?;
+        Ok(Some(recipient_count))
+    }
+
+    /// Drops a held event without notifying anyone, returning `false` if it is not held
+    pub async fn discard_held_event(&self, event_id: &EventId) -> Result<bool, Box<dyn std::error::Error>> {
+        Ok(self.take_held_event(event_id).await?.is_some())
+    }
+
+    /// Periodically discards the held events that were not approved in time. Runs forever, so it should be spawned as a task
+    pub async fn run_held_event_expiry_job(notification_manager: std::sync::Arc<Self>) {
+        let mut interval = tokio::time::interval(HELD_EVENT_EXPIRY_INTERVAL);
+        loop {
+            interval.tick().await;
+            match notification_manager.expire_held_events().await {
+                Ok(0) => {}
+                Ok(expired_events) => log::warn!("Discarded {} held events that were not approved within {} seconds", expired_events, HELD_EVENT_MAX_AGE_SECONDS),
+                Err(e) => log::error!("Failed to expire held events: {}", e),
+            }
+        }
+    }
+
+    async fn expire_held_events(&self) -> Result<usize, Box<dyn std::error::Error>> {
+        let cutoff = Timestamp::now().as_u64().saturating_sub(HELD_EVENT_MAX_AGE_SECONDS) as i64;
+        self.with_connection(move |connection| {
+            let expired_events = connection.execute("DELETE FROM held_events WHERE held_at < ?", [cutoff])?;
+            Ok(expired_events)
+        })
+        .await
+    }
+
+    /// Removes a held event, returning it with its source relay
+    async fn take_held_event(&self, event_id: &EventId) -> Result<Option<(Event, Option<String>)>, Box<dyn std::error::Error>> {
+        let event_id = event_id.to_sql_string();
+        let held_event: Option<(String, Option<String>)> = self.with_connection(move |connection| {
+            let held_event = connection
+                .query_row(
+                    "DELETE FROM held_events WHERE event_id = ? RETURNING event, source_relay_url",
+                    [event_id],
+                    |row| Ok((row.get(0)?, row.get(1)?)),
+                )
+                .optional()?;
+            Ok(held_event)
+        })
+        .await?;
+        match held_event {
+            Some((event, source_relay_url)) => Ok(Some((Event::from_json(event)?, source_relay_url))),
+            None => Ok(None),
+        }
+    }
+
+    /// Stores a NIP-56 report if it was filed by a registered user of this shard, so that the reported authors stop notifying them
+    async fn save_report_if_relevant(&self, event: &Event) -> Result<(), Box<dyn std::error::Error>> {
+        if self.report_suppression_threshold == 0 || !self.recipient_shard.contains(&event.pubkey) || !self.is_pubkey_registered(&event.pubkey).await? {
+            return Ok(());
+        }
+        // The reported pubkey is the first `p` tag, any other ones are incidental
+        let reported_pubkey = match event.public_keys().next() {
+            Some(pubkey) if *pubkey != event.pubkey => *pubkey,
+            _ => return Ok(()),
+        };
+        log::debug!("Saving report by {} against {}", event.pubkey, reported_pubkey);
+        // Reports dated in the future would otherwise count for longer than the maximum report age
+        let created_at = std::cmp::min(event.created_at, Timestamp::now());
+        let (event_id, reporter, created_at) = (event.id.to_sql_string(), event.pubkey.to_sql_string(), created_at.to_sql_string());
+        self.with_connection(move |connection| {
+            connection.execute(
+                "INSERT INTO reports (id, reporter, reported_pubkey, created_at) VALUES (?, ?, ?, ?)
+                ON CONFLICT DO NOTHING",
+                params![event_id, reporter, reported_pubkey.to_sql_string(), created_at],
+            )?;
+            Ok(())
+        })
+        .await
+    }
+
+    /// Checks if the recipient recently reported the author of the event often enough to stop being notified about them.
+    /// Authors the recipient follows are never suppressed, since reporting one of their notes is not a wish to stop hearing from them
+    async fn has_pubkey_reported_author(&self, pubkey: &PublicKey, event: &Event) -> bool {
+        // The author of a gift wrap is a throwaway key
+        let has_known_author = NotificationKind::from_event(event).map_or(true, |kind| kind.has_known_author());
+        if self.report_suppression_threshold == 0 || !has_known_author {
+            return false;
+        }
+        let has_enough_reports = match self.count_recent_reports(pubkey, &event.pubkey).await {
+            Ok(report_count) => report_count >= self.report_suppression_threshold,
+            Err(e) => {
+                log::warn!("Failed to count the reports by {} against {}: {}", pubkey, event.pubkey, e);
+                false
+            }
+        };
+        has_enough_reports && !self.does_pubkey_follow_pubkey(pubkey, &event.pubkey).await
+    }
+
+    /// Counts the reports the reporter filed against the pubkey within the maximum report age
+    async fn count_recent_reports(&self, reporter: &PublicKey, reported_pubkey: &PublicKey) -> Result<usize, Box<dyn std::error::Error>> {
+        let (reporter, reported_pubkey) = (reporter.to_sql_string(), reported_pubkey.to_sql_string());
+        let cutoff = Timestamp::now().as_u64().saturating_sub(self.report_max_age.as_secs()) as i64;
+        let report_count: i64 = self.with_connection(move |connection| {
+            let report_count = connection.query_row(
+                "SELECT COUNT(*) FROM reports WHERE reporter = ? AND reported_pubkey = ? AND created_at >= ?",
+                params![reporter, reported_pubkey, cutoff],
+                |row| row.get(0),
+            )?;
+            Ok(report_count)
+        })
+        .await?;
+        Ok(report_count as usize)
+    }
+
+    /// Deletes the reports older than the maximum report age, which no longer count
+    async fn expire_reports(&self) -> Result<usize, Box<dyn std::error::Error>> {
+        let cutoff = Timestamp::now().as_u64().saturating_sub(self.report_max_age.as_secs()) as i64;
+        self.with_connection(move |connection| {
+            let expired_rows = connection.execute("DELETE FROM reports WHERE created_at < ?", [cutoff])?;
+            Ok(expired_rows)
+        })
+        .await
+    }
+
+    /// Checks if this zap was already notified, either as a receipt with the same payment hash or through its counterpart
+    /// (a zap private message and its zap receipt describe the same zap). Counterparts are correlated by recipient and zapped event
+    /// within a short time window, and each notified zap is paired with at most one counterpart, so that two zaps of the same profile
+    /// or event are both notified.
+    async fn is_duplicate_zap_notification(&self, event: &Event) -> bool {
+        let correlation_key = match Self::zap_correlation_key(event) {
+            Some(key) => key,
+            None => return false,
+        };
+        let payment_hash = match event.kind {
+            Kind::ZapReceipt => event.zap_payment_hash(),
+            _ => None,
+        };
+        let now = nostr::Timestamp::now();
+        let mut recent_zaps = self.recent_zap_notifications.lock().await;
+        recent_zaps.retain(|_, zaps| {
+            zaps.retain(|zap| now.as_u64().saturating_sub(zap.seen_at.as_u64()) <= ZAP_DEDUP_WINDOW_SECONDS);
+            !zaps.is_empty()
+        });
+        let zaps = recent_zaps.entry(correlation_key).or_default();
+        if payment_hash.is_some() && zaps.iter().any(|zap| zap.payment_hash == payment_hash) {
+            return true;
         }
+        let counterpart = zaps.iter_mut().find(|zap| zap.kind != event.kind && !zap.is_paired);
+        let is_duplicate = match counterpart {
+            Some(counterpart) => {
+                counterpart.is_paired = true;
+                true
+            }
+            None => false,
+        };
+        zaps.push(RecentZap {
+            kind: event.kind,
+            payment_hash,
+            seen_at: now,
+            is_paired: is_duplicate,
+        });
+        is_duplicate
+    }
+
+    /// The recipient and zapped event of a zap. For zap receipts, these are taken from the embedded zap request
+    fn zap_correlation_key(event: &Event) -> Option<(PublicKey, Option<EventId>)> {
+        let zap_event = match event.kind {
+            Kind::ZapPrivateMessage => event.clone(),
+            Kind::ZapReceipt => event.zap_request()?,
+            _ => return None,
+        };
+        let recipient = zap_event.zap_recipient()?;
+        let zapped_event_id = zap_event.referenced_event_ids().into_iter().next();
+        Some((recipient, zapped_event_id))
+    }
+
+    fn notification_id(event: &Event, pubkey: &PublicKey) -> String {
+        format!("{}:{}", event.id, pubkey)
+    }
+
+    /// Atomically claims the notification of an event to a pubkey, as pending until `finish_notification` is called.
+    /// Returns `false` if the notification was already claimed (e.g. by another instance sharing the same database)
+    async fn claim_notification(
+        &self,
+        event: &Event,
+        pubkey: &PublicKey,
+        reason: NotificationReason,
+    ) -> Result<bool, Box<dyn std::error::Error>> {
+        let (event, pubkey) = (event.clone(), *pubkey);
+        self.with_connection(move |connection| {
+            let is_claimed = Self::insert_pending_notification(connection, &event, &pubkey, reason, Timestamp::now())?;
+            Ok(is_claimed)
+        })
+        .await
+    }
+
+    fn insert_pending_notification(
+        connection: &rusqlite::Connection,
+        event: &Event,
+        pubkey: &PublicKey,
+        reason: NotificationReason,
+        now: Timestamp,
+    ) -> Result<bool, rusqlite::Error> {
+        let inserted_rows = connection.execute(
+            "INSERT INTO notifications (id, event_id, pubkey, received_notification, sent_at, kind, zap_amount_msats, author, coordinate, pending_event, pending_reason, pending_attempts)
+            VALUES (?, ?, ?, ?, ?, ?, ?, ?, ?, ?, ?, 1)
+            ON CONFLICT DO NOTHING",
+            params![
+                Self::notification_id(event, pubkey),
+                event.id.to_sql_string(),
+                pubkey.to_sql_string(),
+                false,
+                now.to_sql_string(),
+                event.kind.as_u16(),
+                event.zap_amount_msats().map(|msats| msats as i64),
+                event.pubkey.to_sql_string(),
+                event.referenced_coordinates().first().map(|coordinate| coordinate.to_sql_string()),
+                event.as_json(),
+                reason.as_str(),
+            ],
+        )?;
+        Ok(inserted_rows > 0)
+    }
+    
+    /// Checks if events of this kind should wake the app silently instead of showing a notification
+    fn is_silent_push_kind(&self, event_kind: nostr::Kind) -> bool {
+        self.silent_push_kinds.contains(&event_kind)
     }
 
+    /// Works out who to notify about the event, and why
     async fn pubkeys_to_notify_for_event(
         &self,
         event: &Event,
-    ) -> Result<HashSet<nostr::PublicKey>, Box<dyn std::error::Error>> {
+    ) -> Result<HashMap<nostr::PublicKey, NotificationReason>, Box<dyn std::error::Error>> {
+        let db_lookups_started_at = std::time::Instant::now();
         let notification_status = self.get_notification_status(event).await?;
-        let relevant_pubkeys = self.pubkeys_relevant_to_event(event).await?;
+        // Silent pushes sync the author's own devices (e.g. after a contact list or settings change), never the pubkeys it tags
+        let is_silent_push = self.is_silent_push_kind(event.kind);
+        let mentioned_pubkeys = match is_silent_push {
+            true => HashSet::from([event.pubkey]),
+            false => self.capped_relevant_pubkeys(event),
+        };
+        let mut relevant_pubkeys = mentioned_pubkeys.clone();
+        if !is_silent_push {
+            relevant_pubkeys.extend(notification_status.pubkeys_subscribed_to_referenced_events());
+        }
         let mut relevant_pubkeys_that_are_registered = HashSet::new();
-        for pubkey in relevant_pubkeys {
+        // Only handle recipients that belong to this instance's shard, other instances take care of the rest
+        for pubkey in relevant_pubkeys.into_iter().filter(|pubkey| self.recipient_shard.contains(pubkey)) {
             if self.is_pubkey_registered(&pubkey).await? {
                 relevant_pubkeys_that_are_registered.insert(pubkey);
             }
@@ -218,277 +1602,2408 @@ impl NotificationManager {
             notification_status.pubkeys_that_received_notification();
         let relevant_pubkeys_yet_to_receive: HashSet<PublicKey> = relevant_pubkeys_that_are_registered
             .difference(&pubkeys_that_received_notification)
-            .filter(|&x| *x != event.pubkey)
+            .filter(|&x| is_silent_push || *x != event.pubkey)
             .cloned()
             .collect();
+        self.record_processing_latency(ProcessingPhase::DbLookups, db_lookups_started_at.elapsed());
+        let relay_fetches_started_at = std::time::Instant::now();
         
 
-        let mut pubkeys_to_notify = HashSet::new();
-        for pubkey in relevant_pubkeys_yet_to_receive {
-            let should_mute: bool = {
-                self.nostr_network_helper
-                    .should_mute_notification_for_pubkey(event, &pubkey)
-                    .await
-            };
-            if !should_mute {
-                pubkeys_to_notify.insert(pubkey);
+        // Warm up the cache with a single batched fetch, instead of one subscription per recipient and list kind
+        self.nostr_network_helper.prefetch_lists(&relevant_pubkeys_yet_to_receive).await;
+
+        // Check all recipients concurrently (bounded), so that one slow relay fetch does not hold up everyone else
+        let mut pubkeys_to_notify: HashMap<PublicKey, NotificationReason> = futures::stream::iter(relevant_pubkeys_yet_to_receive)
+            .map(|pubkey| async move {
+                // Fetch the contact list alongside the mute list if a setting needs it, so that the follow check later on is answered from the cache
+                let prefetch_contact_list = async {
+                    if self.uses_follow_list(&pubkey).await {
+                        self.nostr_network_helper.get_contact_list(&pubkey).await;
+                    }
+                };
+                let (should_mute, _, has_reported_author) = tokio::join!(
+                    self.nostr_network_helper.should_mute_notification_for_pubkey(event, &pubkey),
+                    prefetch_contact_list,
+                    self.has_pubkey_reported_author(&pubkey, event),
+                );
+                (pubkey, should_mute || has_reported_author)
+            })
+            .buffer_unordered(MAX_CONCURRENT_RECIPIENT_CHECKS)
+            .filter_map(|(pubkey, should_mute)| {
+                // Being mentioned directly takes precedence over participating in the thread
+                let reason = if is_silent_push {
+                    NotificationReason::OwnEvent
+                } else if mentioned_pubkeys.contains(&pubkey) {
+                    NotificationReason::Mention
+                } else {
+                    NotificationReason::Thread
+                };
+                async move {
+                    if should_mute { None } else { Some((pubkey, reason)) }
+                }
+            })
+            .collect()
+            .await;
+        if event.kind == Kind::TextNote && !is_silent_push {
+            // Being involved in the event takes precedence over following one of its hashtags, and takes no hashtag rate cap slot
+            let mut excluded_pubkeys = pubkeys_that_received_notification;
+            excluded_pubkeys.extend(pubkeys_to_notify.keys().cloned());
+            for pubkey in self.hashtag_pubkeys_to_notify(event, &excluded_pubkeys).await? {
+                pubkeys_to_notify.insert(pubkey, NotificationReason::Hashtag);
             }
         }
+        self.record_processing_latency(ProcessingPhase::RelayFetches, relay_fetches_started_at.elapsed());
         Ok(pubkeys_to_notify)
     }
 
-    async fn pubkeys_relevant_to_event(
+    /// Checks if the settings of any of the pubkey's devices depend on whom they follow. Assumes they do if the settings cannot be read
+    async fn uses_follow_list(&self, pubkey: &PublicKey) -> bool {
+        let device_tokens = match self.get_user_device_tokens(pubkey).await {
+            Ok(device_tokens) => device_tokens,
+            Err(_) => return true,
+        };
+        for device_token in device_tokens {
+            match self.get_user_notification_settings(pubkey, device_token).await {
+                Ok(settings) if !settings.uses_follow_list() => {}
+                _ => return true,
+            }
+        }
+        false
+    }
+
+    /// Finds the registered subscribers of the event's hashtags that should be notified about it,
+    /// honoring the scope of their subscriptions, their mute lists and the per-hashtag rate cap.
+    /// Each of them takes a rate cap slot, which must be released if they end up not being notified
+    async fn hashtag_pubkeys_to_notify(
         &self,
         event: &Event,
+        excluded_pubkeys: &HashSet<PublicKey>,
     ) -> Result<HashSet<PublicKey>, Box<dyn std::error::Error>> {
-        let mut relevant_pubkeys = event.relevant_pubkeys();
-        let referenced_event_ids = event.referenced_event_ids();
-        for referenced_event_id in referenced_event_ids {
-            let pubkeys_relevant_to_referenced_event =
-                self.pubkeys_subscribed_to_event_id(&referenced_event_id).await?;
-            relevant_pubkeys.extend(pubkeys_relevant_to_referenced_event);
+        let hashtags: HashSet<String> = event.referenced_hashtags().iter().map(|hashtag| hashtag.to_lowercase()).collect();
+        let mut pubkeys = HashSet::new();
+        if hashtags.is_empty() {
+            return Ok(pubkeys);
+        }
+        for (pubkey, hashtag, scope) in self.get_hashtag_subscribers(&hashtags).await? {
+            let author = event.attributed_author();
+            if pubkey == author || pubkeys.contains(&pubkey) || excluded_pubkeys.contains(&pubkey) || !self.recipient_shard.contains(&pubkey) {
+                continue;
+            }
+            if scope == HashtagScope::Following && !self.does_pubkey_follow_pubkey(&pubkey, &author).await {
+                continue;
+            }
+            if self.nostr_network_helper.should_mute_notification_for_pubkey(event, &pubkey).await || self.has_pubkey_reported_author(&pubkey, event).await {
+                continue;
+            }
+            if !self.reserve_hashtag_notification_slot(&pubkey, &hashtag, event).await {
+                log::debug!("Pubkey {} reached the rate cap of hashtag '{}', not notifying", pubkey, hashtag);
+                continue;
+            }
+            pubkeys.insert(pubkey);
         }
-        Ok(relevant_pubkeys)
+        Ok(pubkeys)
+    }
+
+    /// Takes a slot of the hashtag's rate cap for notifying the pubkey about the event, if the pubkey got fewer hashtag notifications
+    /// than the cap allows recently. Checked and taken under one lock, so that events processed concurrently cannot exceed the cap
+    async fn reserve_hashtag_notification_slot(&self, pubkey: &PublicKey, hashtag: &str, event: &Event) -> bool {
+        let mut recent_notifications = self.recent_hashtag_notifications.lock().await;
+        if Self::recent_hashtag_notification_count(&recent_notifications, pubkey, hashtag) >= MAX_NOTIFICATIONS_PER_HASHTAG {
+            return false;
+        }
+        recent_notifications.entry((*pubkey, hashtag.to_string())).or_default().push((Timestamp::now(), event.id));
+        true
     }
 
-    async fn pubkeys_subscribed_to_event_id(
+    /// Gives back the hashtag rate cap slot the pubkey took for the event, unless the hashtag notification went out
+    async fn release_hashtag_notification_slot_unless_delivered(
         &self,
-        event_id: &EventId,
-    ) -> Result<HashSet<PublicKey>, Box<dyn std::error::Error>> {
-        let db_mutex_guard = self.db.lock().await;
-        let connection = db_mutex_guard.get()?;
-        let mut stmt = connection.prepare("SELECT pubkey FROM notifications WHERE event_id = ?")?;
-        let pubkeys = stmt
-            .query_map([event_id.to_sql_string()], |row| row.get(0))?
-            .filter_map(|r| r.ok())
-            .filter_map(|r: String| PublicKey::from_sql_string(r).ok())
-            .collect();
-        Ok(pubkeys)
+        pubkey: &PublicKey,
+        event: &Event,
+        reason: NotificationReason,
+        delivery: PubkeyDelivery,
+    ) {
+        if reason == NotificationReason::Hashtag && delivery != PubkeyDelivery::Delivered {
+            self.release_hashtag_notification_slot(pubkey, event).await;
+        }
+    }
+
+    async fn release_hashtag_notification_slot(&self, pubkey: &PublicKey, event: &Event) {
+        let mut recent_notifications = self.recent_hashtag_notifications.lock().await;
+        for hashtag in event.referenced_hashtags().iter().map(|hashtag| hashtag.to_lowercase()) {
+            if let Some(slots) = recent_notifications.get_mut(&(*pubkey, hashtag)) {
+                slots.retain(|(_, event_id)| *event_id != event.id);
+            }
+        }
+    }
+
+    fn recent_hashtag_notification_count(
+        recent_notifications: &HashMap<(PublicKey, String), Vec<(Timestamp, EventId)>>,
+        pubkey: &PublicKey,
+        hashtag: &str,
+    ) -> usize {
+        let now = Timestamp::now();
+        recent_notifications
+            .get(&(*pubkey, hashtag.to_string()))
+            .map_or(0, |slots| {
+                slots
+                    .iter()
+                    .filter(|(taken_at, _)| now.as_u64().saturating_sub(taken_at.as_u64()) <= HASHTAG_RATE_CAP_WINDOW_SECONDS)
+                    .count()
+            })
+    }
+
+    /// Periodically forgets the hashtag notifications that left the rate cap window. Runs forever, so it should be spawned as a task
+    pub async fn run_hashtag_rate_cap_prune_job(notification_manager: std::sync::Arc<Self>) {
+        let mut interval = tokio::time::interval(HASHTAG_RATE_CAP_PRUNE_INTERVAL);
+        loop {
+            interval.tick().await;
+            let now = Timestamp::now();
+            let mut recent_notifications = notification_manager.recent_hashtag_notifications.lock().await;
+            recent_notifications.retain(|_, slots| {
+                slots.retain(|(taken_at, _)| now.as_u64().saturating_sub(taken_at.as_u64()) <= HASHTAG_RATE_CAP_WINDOW_SECONDS);
+                !slots.is_empty()
+            });
+        }
     }
 
     async fn send_event_notifications_to_pubkey(
         &self,
         event: &Event,
         pubkey: &PublicKey,
-    ) -> Result<(), Box<dyn std::error::Error>> {
+        reason: NotificationReason,
+        relay_hints: &[String],
+        is_mass_notification: bool,
+    ) -> Result<PubkeyDelivery, Box<dyn std::error::Error>> {
+        let (mut was_delivered, mut is_retryable) = (false, false);
         let user_device_tokens = self.get_user_device_tokens(pubkey).await?;
+        if user_device_tokens.is_empty() {
+            return Ok(PubkeyDelivery::NotDelivered);
+        }
+        let author_context = self.author_context(event, pubkey).await?;
         for device_token in user_device_tokens {
-            if !self.user_wants_notification(pubkey, device_token.clone(), event).await? {
+            // Users running several accounts on one device should not be notified about their own alts' events
+            if self.is_pubkey_linked_to_device(&event.pubkey, &device_token).await? {
                 continue;
             }
-            self.send_event_notification_to_device_token(event, &device_token)
-                .await?;
+            match self.settings_decision(pubkey, device_token.clone(), event).await? {
+                SettingsDecision::Deliver => {}
+                // The device learns about it from the digest sent when its delivery window opens, so it counts as delivered
+                SettingsDecision::Defer(notification_kind) => {
+                    match self.defer_notification(pubkey, &device_token, event, notification_kind).await {
+                        Ok(()) => was_delivered = true,
+                        Err(e) => {
+                            log::error!("Failed to defer notification about event {} to device token '{}': {}", event.id, device_token, e);
+                            is_retryable = true;
+                        }
+                    }
+                    continue;
+                }
+                _ => continue,
+            }
+            match self
+                .send_event_notification_to_device_token(event, pubkey, &device_token, reason, &author_context, relay_hints, is_mass_notification, false)
+                .await
+            {
+                Ok(device_notification) => {
+                    was_delivered |= device_notification.was_delivered;
+                    is_retryable |= device_notification.is_retryable;
+                }
+                Err(e) => {
+                    log::error!("Failed to notify device token '{}' about event {}: {}", device_token, event.id, e);
+                    is_retryable = true;
+                }
+            }
         }
-        Ok(())
+        // Once any device got it, retrying would notify that device again
+        Ok(match (was_delivered, is_retryable) {
+            (true, _) => PubkeyDelivery::Delivered,
+            (false, true) => PubkeyDelivery::Retryable,
+            (false, false) => PubkeyDelivery::NotDelivered,
+        })
     }
     
-    async fn user_wants_notification(
+    /// Decides what the recipient's settings for the device say about the event, without acting on it
+    async fn settings_decision(
         &self,
         pubkey: &PublicKey,
         device_token: String,
         event: &Event,
-    ) -> Result<bool, Box<dyn std::error::Error>> {
+    ) -> Result<SettingsDecision, Box<dyn std::error::Error>> {
         let notification_preferences = self.get_user_notification_settings(pubkey, device_token).await?;
-        if notification_preferences.only_notifications_from_following_enabled {
-            if !self.nostr_network_helper.does_pubkey_follow_pubkey(pubkey, &event.author()).await {
-                return Ok(false);
+        if notification_preferences.sensitive_content_filter == SensitiveContentFilter::Suppress && self.is_sensitive_content(event) {
+            return Ok(SettingsDecision::SensitiveContentSuppressed);
+        }
+        let notification_kind = NotificationKind::from_event(event);
+        // The real sender of a gift wrap is unknown until the app unwraps it
+        let has_known_author = notification_kind.map_or(true, |kind| kind.has_known_author());
+        if notification_preferences.only_notifications_from_following_enabled && has_known_author {
+            if !self.settings_follow_check(pubkey, event).await.follows {
+                return Ok(SettingsDecision::AuthorNotFollowed);
             }
         }
-        match event.kind {
-            Kind::TextNote => Ok(notification_preferences.mention_notifications_enabled),   // TODO: Not 100% accurate
-            Kind::EncryptedDirectMessage => Ok(notification_preferences.dm_notifications_enabled),
-            Kind::Repost => Ok(notification_preferences.repost_notifications_enabled),
-            Kind::GenericRepost => Ok(notification_preferences.repost_notifications_enabled),
-            Kind::Reaction => Ok(notification_preferences.reaction_notifications_enabled),
-            Kind::ZapPrivateMessage => Ok(notification_preferences.zap_notifications_enabled),
-            Kind::ZapRequest => Ok(notification_preferences.zap_notifications_enabled),
-            Kind::ZapReceipt => Ok(notification_preferences.zap_notifications_enabled),
-            _ => Ok(false),
+        // The sender of a NIP-17 DM is only known to the app, so this only applies to NIP-04 DMs
+        if notification_preferences.dm_only_from_following_enabled && notification_kind == Some(NotificationKind::DirectMessage) {
+            if !self.settings_follow_check(pubkey, event).await.follows {
+                return Ok(SettingsDecision::DmAuthorNotFollowed);
+            }
+        }
+        if event.kind == Kind::TextNote && notification_preferences.mention_min_proof_of_work > 0 && !event.check_pow(notification_preferences.mention_min_proof_of_work) {
+            // Only strangers have to put in the work, so that follows are never held to it
+            if !self.settings_follow_check(pubkey, event).await.follows {
+                return Ok(SettingsDecision::InsufficientProofOfWork);
+            }
+        }
+        let notification_kind = match notification_kind {
+            Some(notification_kind) => notification_kind,
+            // Silent pushes only wake the app to sync, so they are not subject to notification preferences
+            None if self.is_silent_push_kind(event.kind) => return Ok(SettingsDecision::Deliver),
+            None => return Ok(SettingsDecision::UnsupportedKind),
+        };
+        if !notification_kind.is_enabled(&notification_preferences) {
+            return Ok(SettingsDecision::KindDisabled);
+        }
+        // Kinds held back outside of the delivery window go into the digest sent when it opens
+        if let Some(delivery_window) = &notification_preferences.delivery_window {
+            if delivery_window.defers(notification_kind) && !delivery_window.is_open_at(Timestamp::now()) {
+                return Ok(SettingsDecision::Defer(notification_kind));
+            }
         }
+        Ok(SettingsDecision::Deliver)
     }
     
-    async fn is_pubkey_token_pair_registered(
+    async fn defer_notification(
         &self,
         pubkey: &PublicKey,
         device_token: &str,
-    ) -> Result<bool, Box<dyn std::error::Error>> {
-        let current_device_tokens = self.get_user_device_tokens(pubkey).await?;
-        Ok(current_device_tokens.contains(&device_token.to_string()))
+        event: &Event,
+        notification_kind: NotificationKind,
+    ) -> Result<(), Box<dyn std::error::Error>> {
+        let (pubkey, device_token, event_id) = (pubkey.to_sql_string(), device_token.to_string(), event.id.to_sql_string());
+        self.with_connection(move |connection| {
+            connection.execute(
+                "INSERT OR IGNORE INTO deferred_notifications (pubkey, device_token, event_id, notification_kind, deferred_at) VALUES (?, ?, ?, ?, ?)",
+                params![pubkey, device_token, event_id, notification_kind.as_sql_str(), Timestamp::now().to_sql_string()],
+            )?;
+            Ok(())
+        })
+        .await
     }
     
-    async fn is_pubkey_registered(
-        &self,
-        pubkey: &PublicKey,
-    ) -> Result<bool, Box<dyn std::error::Error>> {
-        Ok(!self.get_user_device_tokens(pubkey).await?.is_empty())
+    /// Checks if the event carries a content warning or one of the configured sensitive hashtags
+    fn is_sensitive_content(&self, event: &Event) -> bool {
+        event.has_content_warning()
+            || event.referenced_hashtags().iter().any(|hashtag| self.sensitive_hashtags.contains(&hashtag.to_lowercase()))
+    }
+    
+    /// Checks if a pubkey follows another, with the configured policy deciding when the contact list cannot be fetched
+    async fn does_pubkey_follow_pubkey(&self, source_pubkey: &PublicKey, target_pubkey: &PublicKey) -> bool {
+        self.nostr_network_helper
+            .follow_check(source_pubkey, target_pubkey, self.follow_list_unavailable_policy)
+            .await
+            .follows
     }
 
-    async fn get_user_device_tokens(
+    /// Works out how the author of an event relates to the recipient of its notification, from the (cached) contact lists
+    async fn relationship_between(&self, recipient: &PublicKey, author: &PublicKey) -> Relationship {
+        let (recipient_follows_author, author_follows_recipient) = tokio::join!(
+            self.does_pubkey_follow_pubkey(recipient, author),
+            self.does_pubkey_follow_pubkey(author, recipient),
+        );
+        match (recipient_follows_author, author_follows_recipient) {
+            (true, true) => Relationship::MutualFollow,
+            (true, false) => Relationship::Following,
+            (false, true) => Relationship::Follower,
+            (false, false) => Relationship::Stranger,
+        }
+    }
+    
+    /// Works out the recipient's relationship and history with the event's author, once for all of their devices
+    async fn author_context(
         &self,
+        event: &Event,
         pubkey: &PublicKey,
-    ) -> Result<Vec<String>, Box<dyn std::error::Error>> {
-        let db_mutex_guard = self.db.lock().await;
-        let connection = db_mutex_guard.get()?;
-        let mut stmt = connection.prepare("SELECT device_token FROM user_info WHERE pubkey = ?")?;
-        let device_tokens = stmt
-            .query_map([pubkey.to_sql_string()], |row| row.get(0))?
-            .filter_map(|r| r.ok())
-            .collect();
-        Ok(device_tokens)
+    ) -> Result<AuthorContext, Box<dyn std::error::Error>> {
+        let notification_kind = NotificationKind::from_event(event);
+        if self.is_silent_push_kind(event.kind) || !notification_kind.map_or(false, |kind| kind.has_known_author()) {
+            return Ok(AuthorContext { relationship: None, is_new_conversation: false });
+        }
+        // Zaps are scored by who sent them, not by their lightning provider
+        let relationship = self.relationship_between(pubkey, &event.attributed_author()).await;
+        let is_text_note = matches!(notification_kind, Some(NotificationKind::Reply | NotificationKind::Mention));
+        let is_new_conversation = is_text_note && self.is_new_conversation(event, pubkey).await?;
+        Ok(AuthorContext { relationship: Some(relationship), is_new_conversation })
     }
 
-    async fn get_notification_status(
+    /// Checks if the event is the first one from its author that the recipient was notified about.
+    /// Notifications recorded before their author was are not attributable, so a recipient with any of them has no known first contact
+    async fn is_new_conversation(
         &self,
         event: &Event,
-    ) -> Result<NotificationStatus, Box<dyn std::error::Error>> {
-        let db_mutex_guard = self.db.lock().await;
-        let connection = db_mutex_guard.get()?;
-        let mut stmt = connection.prepare(
-            "SELECT pubkey, received_notification FROM notifications WHERE event_id = ?",
-        )?;
-        let rows: std::collections::HashMap<PublicKey, bool> = stmt
-            .query_map([event.id.to_sql_string()], |row| {
-                Ok((row.get(0)?, row.get(1)?))
-            })?
-            .filter_map(|r: Result<(String, bool), rusqlite::Error>| r.ok())
-            .filter_map(|r: (String, bool)| {
-                let pubkey = PublicKey::from_sql_string(r.0).ok()?;
-                let received_notification = r.1;
-                Some((pubkey, received_notification))
-            })
-            .collect();
-
-        let mut status_info = std::collections::HashMap::new();
-        for row in rows {
-            let (pubkey, received_notification) = row;
-            status_info.insert(pubkey, received_notification);
+        pubkey: &PublicKey,
+    ) -> Result<bool, Box<dyn std::error::Error>> {
+        let (pubkey, author, event_id) = (pubkey.to_sql_string(), event.pubkey.to_sql_string(), event.id.to_sql_string());
+        let (has_previous_interaction, has_unattributed_history) = self.with_connection(move |connection| {
+            let has_previous_interaction = connection
+                .query_row(
+                    "SELECT 1 FROM notifications WHERE pubkey = ? AND author = ? AND event_id != ? LIMIT 1",
+                    params![pubkey, author, event_id],
+                    |_| Ok(()),
+                )
+                .optional()?
+                .is_some();
+            let has_unattributed_history = connection
+                .query_row(
+                    "SELECT 1 FROM notifications WHERE pubkey = ? AND author IS NULL LIMIT 1",
+                    params![pubkey],
+                    |_| Ok(()),
+                )
+                .optional()?
+                .is_some();
+            Ok((has_previous_interaction, has_unattributed_history))
+        })
+        .await?;
+        Ok(!has_previous_interaction && !has_unattributed_history)
+    }
+    
+    async fn is_pubkey_token_pair_registered(
+        &self,
+        pubkey: &PublicKey,
+        device_token: &str,
+    ) -> Result<bool, Box<dyn std::error::Error>> {
+        let current_device_tokens = self.get_user_device_tokens(pubkey).await?;
+        Ok(current_device_tokens.contains(&device_token.to_string()))
+    }
+    
+    /// Gets the registered pubkeys that belong to this instance's shard
+    pub async fn get_registered_pubkeys(&self) -> Result<HashSet<PublicKey>, Box<dyn std::error::Error>> {
+        let pubkeys: Vec<String> = self.with_connection(|connection| {
+            let mut stmt = connection.prepare("SELECT DISTINCT pubkey FROM user_info WHERE deleted_at IS NULL")?;
+            let pubkeys = stmt
+                .query_map([], |row| row.get(0))?
+                .filter_map(|r| r.ok())
+                .collect();
+            Ok(pubkeys)
+        })
+        .await?;
+        let pubkeys = pubkeys
+            .into_iter()
+            .filter_map(|r| PublicKey::from_sql_string(r).ok())
+            .filter(|pubkey| self.recipient_shard.contains(pubkey))
+            .collect();
+        Ok(pubkeys)
+    }
+    
+    /// Fetches the NIP-17 DM relay lists of the given pubkeys
+    pub async fn fetch_dm_relay_lists(&self, pubkeys: &HashSet<PublicKey>) -> std::collections::HashMap<PublicKey, Vec<String>> {
+        self.nostr_network_helper.fetch_dm_relay_lists(pubkeys).await
+    }
+    
+    /// Waits until a subscription to the relay is allowed by the relay fetch limits, or returns `None` if that takes too long
+    pub async fn acquire_relay_subscription_permit(&self, relay_url: &str) -> Option<RelayFetchPermit> {
+        self.nostr_network_helper.acquire_relay_subscription_permit(relay_url).await
+    }
+    
+    async fn is_pubkey_registered(
+        &self,
+        pubkey: &PublicKey,
+    ) -> Result<bool, Box<dyn std::error::Error>> {
+        Ok(!self.get_user_device_tokens(pubkey).await?.is_empty())
+    }
+
+    async fn get_user_device_tokens(
+        &self,
+        pubkey: &PublicKey,
+    ) -> Result<Vec<String>, Box<dyn std::error::Error>> {
+        let pubkey = pubkey.to_sql_string();
+        self.with_connection(move |connection| {
+            let mut stmt = connection.prepare("SELECT device_token FROM user_info WHERE pubkey = ? AND deleted_at IS NULL")?;
+            let device_tokens = stmt
+                .query_map([pubkey], |row| row.get(0))?
+                .filter_map(|r| r.ok())
+                .collect();
+            Ok(device_tokens)
+        })
+        .await
+    }
+
+    /// Gets, in a single query, who was already notified about the event and who is subscribed to the events it references
+    /// (i.e. was notified about them, such as the participants of a thread). For referenced addressable events, the subscribers
+    /// are those notified about other events in the same thread, since addressable events are not notified themselves
+    async fn get_notification_status(
+        &self,
+        event: &Event,
+    ) -> Result<NotificationStatus, Box<dyn std::error::Error>> {
+        let referenced_event_ids: Vec<String> = self
+            .capped_referenced_event_ids(event)
+            .iter()
+            .map(|event_id| event_id.to_sql_string())
+            .collect();
+        let referenced_coordinates: Vec<String> = self
+            .capped_referenced_coordinates(event)
+            .iter()
+            .map(|coordinate| coordinate.to_sql_string())
+            .collect();
+        let event_id = event.id.to_sql_string();
+        // A bounded subquery per referenced event, so that a viral referenced event only yields its most recent subscribers.
+        // The last column tells the rows of subscribers apart, since the event can reference itself, in which case its rows count for both
+        let mut subqueries = vec!["SELECT pubkey, received_notification, false FROM notifications WHERE event_id = ?".to_string()];
+        for _ in &referenced_event_ids {
+            subqueries.push("SELECT * FROM (SELECT pubkey, received_notification, true FROM notifications WHERE event_id = ? ORDER BY sent_at DESC LIMIT ?)".to_string());
+        }
+        for _ in &referenced_coordinates {
+            subqueries.push("SELECT * FROM (SELECT pubkey, received_notification, true FROM notifications WHERE coordinate = ? ORDER BY sent_at DESC LIMIT ?)".to_string());
+        }
+        let query = subqueries.join(" UNION ALL ");
+
+        let rows: Vec<(String, bool, bool)> = self
+            .with_connection(move |connection| {
+                let mut query_parameters: Vec<&dyn rusqlite::ToSql> = vec![&event_id];
+                for referenced_id in referenced_event_ids.iter().chain(referenced_coordinates.iter()) {
+                    query_parameters.push(referenced_id);
+                    query_parameters.push(&MAX_SUBSCRIBERS_PER_REFERENCED_EVENT);
+                }
+                let mut stmt = connection.prepare(&query)?;
+                let rows = stmt
+                    .query_map(query_parameters.as_slice(), |row| {
+                        Ok((row.get(0)?, row.get(1)?, row.get(2)?))
+                    })?
+                    .filter_map(|r| r.ok())
+                    .collect();
+                Ok(rows)
+            })
+            .await?;
+
+        let mut status_info = std::collections::HashMap::new();
+        let mut subscribed_pubkeys = HashSet::new();
+        for (pubkey, received_notification, is_subscription) in rows {
+            let pubkey = match PublicKey::from_sql_string(pubkey) {
+                Ok(pubkey) => pubkey,
+                Err(_) => continue,
+            };
+            if is_subscription {
+                subscribed_pubkeys.insert(pubkey);
+            } else {
+                status_info.insert(pubkey, received_notification);
+            }
         }
 
-        Ok(NotificationStatus { status_info })
+        Ok(NotificationStatus { status_info, subscribed_pubkeys })
     }
 
+    /// Builds the push for a device and sends it, unless `dry_run` is set. Returns the size of the payload in bytes
     async fn send_event_notification_to_device_token(
         &self,
         event: &Event,
+        pubkey: &PublicKey,
         device_token: &str,
-    ) -> Result<(), Box<dyn std::error::Error>> {
-        let (title, subtitle, body) = self.format_notification_message(event);
-
-        log::debug!("Sending notification to device token: {}", device_token);
+        reason: NotificationReason,
+        author_context: &AuthorContext,
+        relay_hints: &[String],
+        is_mass_notification: bool,
+        dry_run: bool,
+    ) -> Result<DeviceNotification, Box<dyn std::error::Error>> {
+        let payload_version = self.get_device_payload_version(pubkey, device_token).await?;
+        let event_inclusion = self.event_inclusion_policies.get(&event.kind).copied().unwrap_or(EventInclusion::Full);
+        let mut payload_data = push_payload::payload_data(event, reason, relay_hints, payload_version, event_inclusion)?;
+        let locale = self.get_device_locale(pubkey, device_token).await?;
+        let (title, subtitle, mut body) = self.format_notification_message(event, locale.as_deref());
+        let notification_settings = self.get_user_notification_settings(pubkey, device_token.to_string()).await?;
+        if notification_settings.sensitive_content_filter == SensitiveContentFilter::Blank && self.is_sensitive_content(event) {
+            // Lets the notification service extension know not to render the content either
+            body = "Sensitive content".to_string();
+            payload_data.push(("sensitive_content", serde_json::Value::Bool(true)));
+        }
+        if let Some(webhook) = self.get_device_webhook(pubkey, device_token).await? {
+            let webhook_payload = Self::webhook_payload((title, subtitle, body), payload_data);
+            let payload_size = webhook_payload.to_string().len();
+            if dry_run {
+                return Ok(DeviceNotification { payload_size, was_delivered: false, is_retryable: false });
+            }
+            let webhook_result = self.send_event_notification_to_webhook(&webhook, webhook_payload).await;
+            let was_delivered = webhook_result.is_ok();
+            if was_delivered {
+                if let Err(e) = self.set_device_last_notified_at(pubkey, device_token).await {
+                    log::error!("Failed to update when device token '{}' was last notified: {}", device_token, e);
+                }
+            }
+            let is_retryable = webhook_result.err().map_or(false, |e| e.is_retryable());
+            return Ok(DeviceNotification { payload_size, was_delivered, is_retryable });
+        }
 
-        let mut payload = DefaultNotificationBuilder::new()
-            .set_title(&title)
-            .set_subtitle(&subtitle)
-            .set_body(&body)
-            .set_mutable_content()
-            .set_content_available()
-            .build(device_token, Default::default());
+        log::debug!("Building notification for device token: {}", device_token);
 
-        payload.options.apns_topic = Some(self.apns_topic.as_str());
-        payload.data.insert("nostr_event", serde_json::Value::String(event.try_as_json()?));
-        
+        let is_silent_push = self.is_silent_push_kind(event.kind);
+        let token_type = self.get_device_token_type(pubkey, device_token).await?;
+        let push_provider = self.push_provider(token_type)?;
+        let mut push_message = PushMessage {
+            token: device_token.to_string(),
+            tenant_id: self.get_device_apns_tenant(pubkey, device_token).await?,
+            alert: (!is_silent_push).then(|| PushAlert { title, subtitle: Some(subtitle), body }),
+            is_processed_by_app: true,
+            priority: None,
+            collapse_id: None,
+            data: payload_data,
+        };
+        if let Some(relationship) = author_context.relationship {
+            // Providers cannot set APNS's `relevance-score` or `thread-id`, so the relationship is passed along for the notification service extension to apply
+            push_message.priority = Some(relationship.push_priority());
+            let mut relevance_score = relationship.relevance_score();
+            push_message.data.push(("relationship", serde_json::json!(relationship)));
+            // Someone new reaching out is worth surfacing right away, whoever they are
+            if author_context.is_new_conversation {
+                push_message.priority = Some(PushPriority::High);
+                push_message.data.push(("new_conversation", serde_json::Value::Bool(true)));
+                relevance_score = relevance_score.max(NEW_CONVERSATION_RELEVANCE_SCORE);
+            }
+            push_message.data.push(("relevance_score", serde_json::json!(relevance_score)));
+            if relationship == Relationship::Stranger && notification_settings.strangers_to_requests_folder_enabled {
+                push_message.data.push(("requests_folder", serde_json::Value::Bool(true)));
+            }
+        }
+        if is_mass_notification {
+            // Demoted, and collapsed into a single notification on the device in case it gets several pushes about the event
+            push_message.priority = Some(PushPriority::Normal);
+            push_message.collapse_id = Some(event.id.to_hex());
+        }
+        let payload_size = push_provider.payload_size(&push_message)?;
+        if dry_run {
+            return Ok(DeviceNotification { payload_size, was_delivered: false, is_retryable: false });
+        }
 
-        let apns_client_mutex_guard = self.apns_client.lock().await;
+        let send_started_at = std::time::Instant::now();
+        let push_receipt = match push_provider.send(&push_message).await {
+            Some(push_receipt) => push_receipt,
+            // The provider is backing off, so the push can be sent once it recovers
+            None => return Ok(DeviceNotification { payload_size, was_delivered: false, is_retryable: true }),
+        };
+        self.record_processing_latency(ProcessingPhase::Apns, send_started_at.elapsed());
+        let delivery_outcome = DeliveryOutcome {
+            apns_id: push_receipt.message_id.clone(),
+            status: push_receipt.status,
+            reason: push_receipt.reason.clone(),
+            success: push_receipt.success,
+            latency_ms: send_started_at.elapsed().as_millis() as i64,
+        };
+        // The push already went out, so failing to record it must not make it look undelivered (and get it sent again)
+        if let Err(e) = self.record_delivery(event, pubkey, device_token, &push_receipt.destination, &delivery_outcome).await {
+            log::error!("Failed to record delivery of event {} to device token '{}': {}", event.id, device_token, e);
+        }
         
-        match apns_client_mutex_guard.send(payload).await {
-            Ok(_response) => {},
-            Err(e) => log::error!("Failed to send notification to device token '{}': {}", device_token, e),
+        if push_receipt.is_token_unusable {
+            log::info!("{} reports device token '{}' is no longer valid, pruning it", push_provider.name(), device_token);
+            if let Err(e) = self.prune_device_token(device_token).await {
+                log::error!("Failed to prune device token '{}': {}", device_token, e);
+            }
+            return Ok(DeviceNotification { payload_size, was_delivered: false, is_retryable: false });
+        }
+        if !push_receipt.success {
+            log::error!(
+                "Failed to send notification to device token '{}': {}",
+                device_token,
+                push_receipt.reason.as_deref().unwrap_or("unknown error")
+            );
+            // Transport errors, throttling and server errors may go away, while other rejections (e.g. a bad payload) will not
+            let is_retryable = push_receipt.status.map_or(true, |status| status == 429 || status >= 500);
+            return Ok(DeviceNotification { payload_size, was_delivered: false, is_retryable });
+        }
+        if let Err(e) = self.set_device_last_notified_at(pubkey, device_token).await {
+            log::error!("Failed to update when device token '{}' was last notified: {}", device_token, e);
         }
 
         log::info!("Notification sent to device token: {}", device_token);
 
+        Ok(DeviceNotification { payload_size, was_delivered: true, is_retryable: false })
+    }
+
+    // MARK: - Diagnostics
+
+    /// Runs an event through the notification pipeline for one recipient without sending, claiming, deferring or recording anything,
+    /// tracing every decision along the way. Answers "why didn't I get a push?" support cases
+    pub async fn trace_notification(
+        &self,
+        event: &Event,
+        recipient: &PublicKey,
+    ) -> Result<NotificationTrace, Box<dyn std::error::Error>> {
+        let mut steps = Vec::new();
+
+        // The event itself
+        let event_age = nostr::Timestamp::now().as_u64() as i64 - event.created_at.as_u64() as i64;
+        let is_recent_enough = event_age <= self.event_max_age_seconds as i64;
+        let is_old_enough = self.event_min_age_seconds.map_or(true, |event_min_age_seconds| event_age >= event_min_age_seconds);
+        steps.push(TraceStep::new("event_age", is_recent_enough && is_old_enough, Some(format!("{} seconds old", event_age))));
+        let notification_kind = NotificationKind::from_event(event);
+        let is_supported_kind = notification_kind.is_some() || self.is_silent_push_kind(event.kind);
+        steps.push(TraceStep::new("supported_kind", is_supported_kind, Some(format!("kind {}", event.kind.as_u16()))));
+        let spam_rejection_reason = self.spam_filter.rejection_reason(event);
+        steps.push(TraceStep::new("spam_filter", spam_rejection_reason.is_none(), spam_rejection_reason));
+        if event.kind == Kind::ZapReceipt {
+            // Traces never fetch the LNURL pay endpoint, so that they cannot be used to make this server send requests
+            let (is_zap_receipt_valid, detail) = match self.zap_receipt_verifier.cached_zap_receipt_validity(event).await {
+                Some(is_zap_receipt_valid) => (is_zap_receipt_valid, None),
+                None => (true, Some("not verified, the recipient's lightning provider is not cached".to_string())),
+            };
+            steps.push(TraceStep::new("zap_receipt_valid", is_zap_receipt_valid, detail));
+        }
+
+        // The recipient
+        steps.push(TraceStep::new("recipient_shard", self.recipient_shard.contains(recipient), None));
+        steps.push(TraceStep::new("registered", self.is_pubkey_registered(recipient).await?, None));
+        let is_silent_push = self.is_silent_push_kind(event.kind);
+        // Silent pushes are only ever sent to the author's own devices
+        steps.push(match is_silent_push {
+            true => TraceStep::new("author", event.pubkey == *recipient, None),
+            false => TraceStep::new("not_author", event.pubkey != *recipient, None),
+        });
+        let notification_status = self.get_notification_status(event).await?;
+        let reason = if is_silent_push {
+            (event.pubkey == *recipient).then_some(NotificationReason::OwnEvent)
+        } else if self.capped_relevant_pubkeys(event).contains(recipient) {
+            Some(NotificationReason::Mention)
+        } else if notification_status.pubkeys_subscribed_to_referenced_events().contains(recipient) {
+            Some(NotificationReason::Thread)
+        } else if event.kind == Kind::TextNote && self.is_subscribed_to_event_hashtags(recipient, event).await? {
+            Some(NotificationReason::Hashtag)
+        } else {
+            None
+        };
+        steps.push(TraceStep::new("relevant", reason.is_some(), reason.map(|reason| serde_json::json!(reason).as_str().unwrap_or_default().to_string())));
+        let was_already_notified = notification_status.pubkeys_that_received_notification().contains(recipient);
+        steps.push(TraceStep::new("not_already_notified", !was_already_notified, None));
+        let should_mute = self.nostr_network_helper.should_mute_notification_for_pubkey(event, recipient).await;
+        steps.push(TraceStep::new("not_muted", !should_mute, None));
+        let has_reported_author = self.has_pubkey_reported_author(recipient, event).await;
+        steps.push(TraceStep::new("author_not_reported", !has_reported_author, None));
+
+        // Each of the recipient's devices
+        let author_context = self.author_context(event, recipient).await?;
+        let mut devices = Vec::new();
+        for device_token in self.get_user_device_tokens(recipient).await? {
+            let mut device_steps = Vec::new();
+            let is_linked_to_author = self.is_pubkey_linked_to_device(&event.pubkey, &device_token).await?;
+            device_steps.push(TraceStep::new("author_not_linked_to_device", !is_linked_to_author, None));
+            let notification_preferences = self.get_user_notification_settings(recipient, device_token.clone()).await?;
+            if notification_preferences.uses_follow_list() {
+                let follow_check = self.settings_follow_check(recipient, event).await;
+                device_steps.push(TraceStep::new("follow_list", follow_check.follows, Some(follow_check.source.as_str().to_string())));
+            }
+            let settings_decision = self.settings_decision(recipient, device_token.clone(), event).await?;
+            device_steps.push(TraceStep::new("settings", settings_decision == SettingsDecision::Deliver, Some(settings_decision.as_str().to_string())));
+            let relay_hints = event.relay_hints();
+            let payload_size_step = match self
+                .send_event_notification_to_device_token(
+                    event,
+                    recipient,
+                    &device_token,
+                    reason.unwrap_or(NotificationReason::Mention),
+                    &author_context,
+                    &relay_hints,
+                    false,
+                    true,
+                )
+                .await
+            {
+                Ok(DeviceNotification { payload_size, .. }) => {
+                    TraceStep::new("payload_size", payload_size <= MAX_APNS_PAYLOAD_SIZE, Some(format!("{} bytes", payload_size)))
+                }
+                Err(e) => TraceStep::new("payload_size", false, Some(format!("Failed to build the payload: {}", e))),
+            };
+            device_steps.push(payload_size_step);
+            devices.push(DeviceTrace { device_token, steps: device_steps });
+        }
+
+        let would_notify = steps.iter().all(|step| step.passed)
+            && devices.iter().any(|device| device.steps.iter().all(|step| step.passed));
+        Ok(NotificationTrace {
+            event_id: event.id.to_hex(),
+            recipient: recipient.to_hex(),
+            would_notify,
+            steps,
+            devices,
+        })
+    }
+
+    /// Checks if the pubkey follows the author of the event for the notification settings that depend on it,
+    /// applying the configured policy when the pubkey's contact list cannot be fetched
+    async fn settings_follow_check(&self, pubkey: &PublicKey, event: &Event) -> FollowCheck {
+        let follow_check = self
+            .nostr_network_helper
+            .follow_check(pubkey, &event.attributed_author(), self.follow_list_unavailable_policy)
+            .await;
+        log::debug!(
+            "Follow check of {} for event {}: follows = {}, answered by {}",
+            pubkey.to_hex(),
+            event.id.to_hex(),
+            follow_check.follows,
+            follow_check.source.as_str()
+        );
+        follow_check
+    }
+
+    /// Checks if the pubkey subscribed to one of the event's hashtags, within the scope of the subscription. The rate cap is not checked
+    async fn is_subscribed_to_event_hashtags(&self, pubkey: &PublicKey, event: &Event) -> Result<bool, Box<dyn std::error::Error>> {
+        let hashtags: HashSet<String> = event.referenced_hashtags().iter().map(|hashtag| hashtag.to_lowercase()).collect();
+        if hashtags.is_empty() {
+            return Ok(false);
+        }
+        for (subscriber, _, scope) in self.get_hashtag_subscribers(&hashtags).await? {
+            if subscriber != *pubkey {
+                continue;
+            }
+            if scope == HashtagScope::Global || self.does_pubkey_follow_pubkey(pubkey, &event.attributed_author()).await {
+                return Ok(true);
+            }
+        }
+        Ok(false)
+    }
+
+    // MARK: - Delivery tracking
+
+    async fn record_delivery(
+        &self,
+        event: &Event,
+        pubkey: &PublicKey,
+        device_token: &str,
+        topic: &str,
+        delivery_outcome: &DeliveryOutcome,
+    ) -> Result<(), Box<dyn std::error::Error>> {
+        let (event_id, pubkey, device_token, topic) = (event.id.to_sql_string(), pubkey.to_sql_string(), device_token.to_string(), topic.to_string());
+        let delivery_outcome = delivery_outcome.clone();
+        self.with_connection(move |connection| {
+            connection.execute(
+                "INSERT INTO deliveries (event_id, pubkey, device_token, apns_id, status, reason, success, sent_at, topic, latency_ms)
+                VALUES (?, ?, ?, ?, ?, ?, ?, ?, ?, ?)",
+                params![
+                    event_id,
+                    pubkey,
+                    device_token,
+                    delivery_outcome.apns_id,
+                    delivery_outcome.status,
+                    delivery_outcome.reason,
+                    delivery_outcome.success,
+                    nostr::Timestamp::now().to_sql_string(),
+                    topic,
+                    delivery_outcome.latency_ms,
+                ],
+            )?;
+            Ok(())
+        })
+        .await
+    }
+
+    /// Gets delivery statistics for deliveries made since the given time
+    pub async fn get_delivery_stats(
+        &self,
+        since: Timestamp,
+    ) -> Result<DeliveryStats, Box<dyn std::error::Error>> {
+        self.with_connection(move |connection| {
+            let (total, successes): (i64, i64) = connection.query_row(
+                "SELECT COUNT(*), COALESCE(SUM(success), 0) FROM deliveries WHERE sent_at >= ?",
+                [since.to_sql_string()],
+                |row| Ok((row.get(0)?, row.get(1)?)),
+            )?;
+
+            let mut stmt = connection.prepare(
+                "SELECT COALESCE(reason, 'Unknown'), COUNT(*) FROM deliveries WHERE sent_at >= ? AND NOT success GROUP BY 1 ORDER BY 2 DESC",
+            )?;
+            let failure_reasons = stmt
+                .query_map([since.to_sql_string()], |row| Ok((row.get(0)?, row.get(1)?)))?
+                .filter_map(|r| r.ok())
+                .collect();
+
+            let mut stmt = connection.prepare(
+                "SELECT device_token, COUNT(*), SUM(NOT success) AS failures FROM deliveries
+                WHERE sent_at >= ? GROUP BY device_token HAVING failures > 0 ORDER BY failures DESC LIMIT ?",
+            )?;
+            let failing_device_tokens = stmt
+                .query_map(params![since.to_sql_string(), MAX_FAILING_DEVICE_TOKENS_IN_STATS], |row| {
+                    Ok(DeviceTokenDeliveryStats {
+                        device_token: row.get(0)?,
+                        attempts: row.get(1)?,
+                        failures: row.get(2)?,
+                    })
+                })?
+                .filter_map(|r| r.ok())
+                .collect();
+
+            Ok(DeliveryStats {
+                since: since.as_u64(),
+                total,
+                successes,
+                failures: total - successes,
+                failure_reasons,
+                failing_device_tokens,
+            })
+        })
+        .await
+    }
+
+    // MARK: - Delivery analytics
+
+    /// Periodically aggregates the deliveries into daily summaries. Runs forever, so it should be spawned as a task
+    pub async fn run_delivery_analytics_job(notification_manager: std::sync::Arc<Self>) {
+        let mut interval = tokio::time::interval(DELIVERY_ANALYTICS_INTERVAL);
+        loop {
+            interval.tick().await;
+            if let Err(e) = notification_manager.aggregate_delivery_analytics().await {
+                log::error!("Failed to aggregate delivery analytics: {}", e);
+            }
+        }
+    }
+
+    /// Aggregates the deliveries of today and yesterday (which may have received late deliveries) into daily summaries
+    async fn aggregate_delivery_analytics(&self) -> Result<(), Box<dyn std::error::Error>> {
+        let today = nostr::Timestamp::now().as_u64() / SECONDS_PER_DAY * SECONDS_PER_DAY;
+        for day in [today - SECONDS_PER_DAY, today] {
+            self.with_connection(move |connection| Self::aggregate_delivery_analytics_of_day(connection, day)).await?;
+        }
         Ok(())
     }
 
-    fn format_notification_message(&self, event: &Event) -> (String, String, String) {
-        // NOTE: This is simple because the client will handle formatting. These are just fallbacks.
-        let (title, body) = match event.kind {
-            nostr_sdk::Kind::TextNote => ("New activity".to_string(), event.content.clone()),
-            nostr_sdk::Kind::EncryptedDirectMessage => ("New direct message".to_string(), "Contents are encrypted".to_string()),
-            nostr_sdk::Kind::Repost => ("Someone reposted".to_string(), event.content.clone()),
-            nostr_sdk::Kind::Reaction => {
-                let content_text = event.content.clone();
-                let formatted_text = match content_text.as_str() {
-                    "" => "❤️",
-                    "+" => "❤️",
-                    "-" => "👎",
-                    _ => content_text.as_str(),
-                };
-                ("New reaction".to_string(), formatted_text.to_string())
-            },
-            nostr_sdk::Kind::ZapPrivateMessage => ("New zap private message".to_string(), "Contents are encrypted".to_string()),
-            nostr_sdk::Kind::ZapReceipt => ("Someone zapped you".to_string(), "".to_string()),
-            _ => ("New activity".to_string(), "".to_string()),
+    /// Aggregates the deliveries of a day into a summary per topic
+    fn aggregate_delivery_analytics_of_day(connection: &rusqlite::Connection, day: u64) -> Result<(), DatabaseError> {
+        let mut stmt = connection.prepare(
+            "SELECT COALESCE(topic, ''), success, latency_ms, reason FROM deliveries WHERE sent_at >= ? AND sent_at < ?",
+        )?;
+        let rows: Vec<(String, bool, Option<i64>, Option<String>)> = stmt
+            .query_map(params![day as i64, (day + SECONDS_PER_DAY) as i64], |row| {
+                Ok((row.get(0)?, row.get(1)?, row.get(2)?, row.get(3)?))
+            })?
+            .filter_map(|r| r.ok())
+            .collect();
+
+        let mut summaries: std::collections::HashMap<String, DeliveryDailySummary> = std::collections::HashMap::new();
+        let mut latencies: std::collections::HashMap<String, Vec<i64>> = std::collections::HashMap::new();
+        for (topic, success, latency_ms, reason) in rows {
+            let summary = summaries.entry(topic.clone()).or_insert_with(|| DeliveryDailySummary {
+                day,
+                topic: topic.clone(),
+                ..Default::default()
+            });
+            summary.total += 1;
+            if success {
+                summary.successes += 1;
+            } else {
+                *summary.failure_reasons.entry(reason.unwrap_or("Unknown".to_string())).or_insert(0) += 1;
+            }
+            if let Some(latency_ms) = latency_ms {
+                latencies.entry(topic).or_default().push(latency_ms);
+            }
+        }
+
+        for (topic, mut summary) in summaries {
+            if let Some(topic_latencies) = latencies.get_mut(&topic) {
+                topic_latencies.sort_unstable();
+                let p95_index = (topic_latencies.len() * 95).div_ceil(100).saturating_sub(1);
+                summary.p95_latency_ms = topic_latencies.get(p95_index).cloned();
+            }
+            connection.execute(
+                "INSERT OR REPLACE INTO delivery_daily_summaries (day, topic, total, successes, p95_latency_ms, failure_reasons)
+                VALUES (?, ?, ?, ?, ?, ?)",
+                params![
+                    summary.day as i64,
+                    summary.topic,
+                    summary.total,
+                    summary.successes,
+                    summary.p95_latency_ms,
+                    serde_json::to_string(&summary.failure_reasons)?,
+                ],
+            )?;
+        }
+        Ok(())
+    }
+
+    /// Gets the daily delivery summaries since the given time, newest first
+    pub async fn get_delivery_daily_summaries(
+        &self,
+        since: Timestamp,
+    ) -> Result<Vec<DeliveryDailySummary>, Box<dyn std::error::Error>> {
+        self.with_connection(move |connection| {
+            let mut stmt = connection.prepare(
+                "SELECT day, topic, total, successes, p95_latency_ms, failure_reasons FROM delivery_daily_summaries
+                WHERE day >= ? ORDER BY day DESC, topic",
+            )?;
+            let summaries = stmt
+                .query_map([since.as_u64() as i64], |row| {
+                    let failure_reasons: String = row.get(5)?;
+                    Ok(DeliveryDailySummary {
+                        day: row.get::<_, i64>(0)? as u64,
+                        topic: row.get(1)?,
+                        total: row.get(2)?,
+                        successes: row.get(3)?,
+                        p95_latency_ms: row.get(4)?,
+                        failure_reasons: serde_json::from_str(&failure_reasons).unwrap_or_default(),
+                    })
+                })?
+                .filter_map(|r| r.ok())
+                .collect();
+            Ok(summaries)
+        })
+        .await
+    }
+
+    // MARK: - Weekly summaries
+
+    /// Periodically sends the weekly summaries that are due. Runs forever, so it should be spawned as a task
+    pub async fn run_weekly_summary_job(notification_manager: std::sync::Arc<Self>) {
+        let mut interval = tokio::time::interval(WEEKLY_SUMMARY_INTERVAL);
+        loop {
+            interval.tick().await;
+            if let Err(e) = notification_manager.send_due_weekly_summaries().await {
+                log::error!("Failed to send weekly summaries: {}", e);
+            }
+        }
+    }
+
+    async fn send_due_weekly_summaries(&self) -> Result<(), Box<dyn std::error::Error>> {
+        let now = Timestamp::now();
+        let week_ago = now.as_u64().saturating_sub(SECONDS_PER_WEEK);
+        let due_devices: Vec<(String, String, Option<i64>)> = self.with_connection(move |connection| {
+            let mut stmt = connection.prepare(
+                "SELECT pubkey, device_token, weekly_summary_sent_at FROM user_info
+                WHERE weekly_summary_enabled = 1 AND deleted_at IS NULL AND (weekly_summary_sent_at IS NULL OR weekly_summary_sent_at <= ?)",
+            )?;
+            let due_devices = stmt
+                .query_map([week_ago as i64], |row| Ok((row.get(0)?, row.get(1)?, row.get(2)?)))?
+                .filter_map(|r| r.ok())
+                .collect();
+            Ok(due_devices)
+        })
+        .await?;
+        for (pubkey, device_token, weekly_summary_sent_at) in due_devices {
+            let pubkey = match PublicKey::from_sql_string(pubkey) {
+                Ok(pubkey) if self.recipient_shard.contains(&pubkey) => pubkey,
+                _ => continue,
+            };
+            // One failing device must not hold up the summaries of all the others
+            if let Err(e) = self.send_weekly_summary(&pubkey, &device_token, weekly_summary_sent_at.is_none(), now).await {
+                log::error!("Failed to send weekly summary to device token '{}': {}", device_token, e);
+            }
+        }
+        Ok(())
+    }
+
+    /// Sends the weekly summary of a device that is due for one. It is only marked as sent once it went out (or there was nothing
+    /// to send), so that a failed summary is tried again at the next interval
+    async fn send_weekly_summary(
+        &self,
+        pubkey: &PublicKey,
+        device_token: &str,
+        is_first_week: bool,
+        now: Timestamp,
+    ) -> Result<(), Box<dyn std::error::Error>> {
+        // Devices that just opted in start their first week now, instead of getting a summary right away
+        if is_first_week {
+            return self.set_device_weekly_summary_sent_at(pubkey, device_token, now).await;
+        }
+        let week_ago = Timestamp::from(now.as_u64().saturating_sub(SECONDS_PER_WEEK));
+        let summary = self.get_weekly_summary(pubkey, week_ago).await?;
+        // A summary of nothing is just noise
+        if summary.is_empty() {
+            return self.set_device_weekly_summary_sent_at(pubkey, device_token, now).await;
+        }
+        let locale = self.get_device_locale(pubkey, device_token).await?;
+        let message = self.format_weekly_summary_message(&summary, locale.as_deref());
+        let payload_data = vec![("weekly_summary", serde_json::json!(summary))];
+        if self.send_summary_to_device_token(pubkey, device_token, message, payload_data).await? {
+            self.set_device_weekly_summary_sent_at(pubkey, device_token, now).await?;
+        }
+        Ok(())
+    }
+
+    async fn set_device_weekly_summary_sent_at(
+        &self,
+        pubkey: &PublicKey,
+        device_token: &str,
+        sent_at: Timestamp,
+    ) -> Result<(), Box<dyn std::error::Error>> {
+        let (pubkey, device_token) = (pubkey.to_sql_string(), device_token.to_string());
+        self.with_connection(move |connection| {
+            connection.execute(
+                "UPDATE user_info SET weekly_summary_sent_at = ? WHERE pubkey = ? AND device_token = ?",
+                params![sent_at.to_sql_string(), pubkey, device_token],
+            )?;
+            Ok(())
+        })
+        .await
+    }
+
+    /// Summarizes the notifications sent to a pubkey since the given time
+    async fn get_weekly_summary(
+        &self,
+        pubkey: &PublicKey,
+        since: Timestamp,
+    ) -> Result<WeeklySummary, Box<dyn std::error::Error>> {
+        let pubkey = pubkey.to_sql_string();
+        self.with_connection(move |connection| {
+            let summary = connection.query_row(
+                "SELECT
+                    COALESCE(SUM(kind = ?), 0),
+                    COALESCE(SUM(kind = ?), 0),
+                    COALESCE(SUM(CASE WHEN kind = ? THEN zap_amount_msats ELSE 0 END), 0)
+                FROM notifications WHERE pubkey = ? AND sent_at >= ? AND received_notification = 1",
+                params![
+                    Kind::TextNote.as_u16(),
+                    Kind::ZapReceipt.as_u16(),
+                    Kind::ZapReceipt.as_u16(),
+                    pubkey,
+                    since.as_u64() as i64,
+                ],
+                |row| Ok(WeeklySummary {
+                    mention_count: row.get::<_, i64>(0)? as u64,
+                    zap_count: row.get::<_, i64>(1)? as u64,
+                    zap_amount_msats: row.get::<_, i64>(2)? as u64,
+                }),
+            )?;
+            Ok(summary)
+        })
+        .await
+    }
+
+    /// Sends a non-urgent summary push (a weekly summary or a deferred digest), to the device's webhook if it has one.
+    /// Returns whether it was delivered
+    async fn send_summary_to_device_token(
+        &self,
+        pubkey: &PublicKey,
+        device_token: &str,
+        (title, body): (String, String),
+        payload_data: Vec<(&'static str, serde_json::Value)>,
+    ) -> Result<bool, Box<dyn std::error::Error>> {
+        if let Some(webhook) = self.get_device_webhook(pubkey, device_token).await? {
+            let webhook_payload = Self::webhook_payload((title, "".to_string(), body), payload_data);
+            let was_delivered = self.send_event_notification_to_webhook(&webhook, webhook_payload).await.is_ok();
+            if was_delivered {
+                self.set_device_last_notified_at(pubkey, device_token).await?;
+            }
+            return Ok(was_delivered);
+        }
+
+        let token_type = self.get_device_token_type(pubkey, device_token).await?;
+        let push_message = PushMessage {
+            token: device_token.to_string(),
+            tenant_id: self.get_device_apns_tenant(pubkey, device_token).await?,
+            alert: Some(PushAlert { title, subtitle: None, body }),
+            is_processed_by_app: false,
+            // A summary is not urgent
+            priority: Some(PushPriority::Normal),
+            collapse_id: None,
+            data: payload_data,
+        };
+        let push_provider = self.push_provider(token_type)?;
+        match push_provider.send(&push_message).await {
+            Some(push_receipt) if push_receipt.success => {
+                log::info!("Summary sent to device token: {}", device_token);
+                self.set_device_last_notified_at(pubkey, device_token).await?;
+                Ok(true)
+            }
+            Some(push_receipt) => {
+                log::error!(
+                    "Failed to send summary to device token '{}': {}",
+                    device_token,
+                    push_receipt.reason.as_deref().unwrap_or("unknown error")
+                );
+                Ok(false)
+            }
+            None => {
+                log::warn!("Not sending summary to device token '{}', the push provider is backing off", device_token);
+                Ok(false)
+            }
+        }
+    }
+
+    fn format_weekly_summary_message(&self, summary: &WeeklySummary, locale: Option<&str>) -> (String, String) {
+        let title = "Your week on Nostr".to_string();
+        let body = format!(
+            "You had {} mentions and {} zaps totaling {} sats",
+            summary.mention_count,
+            summary.zap_count,
+            summary.zap_amount_msats / 1000
+        );
+        let template = match self.notification_templates.get(locale, "weekly_summary") {
+            Some(template) => template,
+            None => return (title, body),
         };
-        (title, "".to_string(), body)
+        let variables = std::collections::HashMap::from([
+            ("mention_count", summary.mention_count.to_string()),
+            ("zap_count", summary.zap_count.to_string()),
+            ("amount_sats", (summary.zap_amount_msats / 1000).to_string()),
+        ]);
+        let render = |text: &Option<String>, fallback: String| match text {
+            Some(text) => NotificationTemplate::render(text, &variables),
+            None => fallback,
+        };
+        (render(&template.title, title), render(&template.body, body))
     }
-    
-    // MARK: - User device info and settings
-    
-    pub async fn save_user_device_info_if_not_present(
+
+    // MARK: - Deferred digests
+
+    /// Periodically sends the digests of the notifications held back outside of delivery windows that have opened since. Runs forever, so it should be spawned as a task
+    pub async fn run_deferred_digest_job(notification_manager: std::sync::Arc<Self>) {
+        let mut interval = tokio::time::interval(DEFERRED_DIGEST_INTERVAL);
+        loop {
+            interval.tick().await;
+            if let Err(e) = notification_manager.send_due_deferred_digests().await {
+                log::error!("Failed to send deferred digests: {}", e);
+            }
+        }
+    }
+
+    async fn send_due_deferred_digests(&self) -> Result<(), Box<dyn std::error::Error>> {
+        let now = Timestamp::now();
+        let deferring_devices: Vec<(String, String)> = self.with_connection(|connection| {
+            let mut stmt = connection.prepare("SELECT DISTINCT pubkey, device_token FROM deferred_notifications")?;
+            let deferring_devices = stmt
+                .query_map([], |row| Ok((row.get(0)?, row.get(1)?)))?
+                .filter_map(|r| r.ok())
+                .collect();
+            Ok(deferring_devices)
+        })
+        .await?;
+        for (pubkey, device_token) in deferring_devices {
+            let pubkey = match PublicKey::from_sql_string(pubkey) {
+                Ok(pubkey) if self.recipient_shard.contains(&pubkey) => pubkey,
+                _ => continue,
+            };
+            // One failing device must not hold up the digests of all the others
+            if let Err(e) = self.send_deferred_digest_if_due(&pubkey, &device_token, now).await {
+                log::error!("Failed to send deferred digest to device token '{}': {}", device_token, e);
+            }
+        }
+        Ok(())
+    }
+
+    async fn send_deferred_digest_if_due(
         &self,
-        pubkey: nostr::PublicKey,
+        pubkey: &PublicKey,
         device_token: &str,
+        now: Timestamp,
     ) -> Result<(), Box<dyn std::error::Error>> {
-        if self.is_pubkey_token_pair_registered(&pubkey, &device_token).await? {
+        // Devices that removed their window since get their digest right away
+        let settings = self.get_user_notification_settings(pubkey, device_token.to_string()).await?;
+        if settings.delivery_window.is_some_and(|delivery_window| !delivery_window.is_open_at(now)) {
             return Ok(());
         }
-        self.save_user_device_info(pubkey, device_token).await
+        // Claimed rather than removed, so that the notifications stay around for the next interval if the digest is not delivered
+        let claim_id = uuid::Uuid::new_v4().to_string();
+        let digest = self.claim_deferred_digest(pubkey, device_token, &claim_id, now).await?;
+        if digest.is_empty() {
+            return Ok(());
+        }
+        let was_delivered = if self.is_pubkey_token_pair_registered(pubkey, device_token).await? {
+            let locale = self.get_device_locale(pubkey, device_token).await?;
+            let message = self.format_deferred_digest_message(&digest, locale.as_deref());
+            let payload_data = vec![("deferred_digest", serde_json::json!(digest))];
+            match self.send_summary_to_device_token(pubkey, device_token, message, payload_data).await {
+                Ok(was_delivered) => was_delivered,
+                Err(e) => {
+                    log::error!("Failed to send deferred digest to device token '{}': {}", device_token, e);
+                    false
+                }
+            }
+        } else {
+            // Not worth keeping for a device that is gone
+            true
+        };
+        self.with_connection(move |connection| {
+            Self::settle_deferred_notifications(connection, &claim_id, was_delivered)?;
+            Ok(())
+        })
+        .await
     }
 
-    pub async fn save_user_device_info(
+    /// Claims the notifications held back for a device that nobody else is sending a digest of, returning how many there were of each kind
+    async fn claim_deferred_digest(
+        &self,
+        pubkey: &PublicKey,
+        device_token: &str,
+        claim_id: &str,
+        now: Timestamp,
+    ) -> Result<DeferredDigest, Box<dyn std::error::Error>> {
+        let (pubkey, device_token, claim_id) = (pubkey.to_sql_string(), device_token.to_string(), claim_id.to_string());
+        let notification_kinds = self
+            .with_connection(move |connection| {
+                let notification_kinds = Self::claim_deferred_notifications(connection, &pubkey, &device_token, &claim_id, now)?;
+                Ok(notification_kinds)
+            })
+            .await?;
+        Ok(Self::deferred_digest(&notification_kinds))
+    }
+
+    /// Claims the unclaimed (or abandoned) notifications held back for a device, returning their kinds.
+    /// The ones that were already claimed too many times are given up on instead
+    fn claim_deferred_notifications(
+        connection: &mut rusqlite::Connection,
+        pubkey: &str,
+        device_token: &str,
+        claim_id: &str,
+        now: Timestamp,
+    ) -> Result<Vec<String>, rusqlite::Error> {
+        let abandoned_before = now.as_u64().saturating_sub(DEFERRED_DIGEST_CLAIM_TIMEOUT_SECONDS) as i64;
+        let transaction = connection.transaction_with_behavior(TransactionBehavior::Immediate)?;
+        transaction.execute(
+            "DELETE FROM deferred_notifications
+            WHERE pubkey = ? AND device_token = ? AND (claim_id IS NULL OR claimed_at < ?) AND attempts >= ?",
+            params![pubkey, device_token, abandoned_before, MAX_NOTIFICATION_ATTEMPTS],
+        )?;
+        let notification_kinds = transaction
+            .prepare(
+                "UPDATE deferred_notifications SET claim_id = ?, claimed_at = ?, attempts = attempts + 1
+                WHERE pubkey = ? AND device_token = ? AND (claim_id IS NULL OR claimed_at < ?)
+                RETURNING notification_kind",
+            )?
+            .query_map(params![claim_id, now.to_sql_string(), pubkey, device_token, abandoned_before], |row| row.get(0))?
+            .filter_map(|r| r.ok())
+            .collect();
+        transaction.commit()?;
+        Ok(notification_kinds)
+    }
+
+    /// Removes the claimed notifications once their digest was delivered, or gives them back for the next interval if it was not
+    fn settle_deferred_notifications(connection: &rusqlite::Connection, claim_id: &str, was_delivered: bool) -> Result<(), rusqlite::Error> {
+        if was_delivered {
+            connection.execute("DELETE FROM deferred_notifications WHERE claim_id = ?", [claim_id])?;
+        } else {
+            connection.execute("UPDATE deferred_notifications SET claim_id = NULL, claimed_at = NULL WHERE claim_id = ?", [claim_id])?;
+        }
+        Ok(())
+    }
+
+    /// Counts how many of the notifications there were of each kind
+    fn deferred_digest(notification_kinds: &[String]) -> DeferredDigest {
+        let counts = NotificationKind::ALL
+            .into_iter()
+            .map(|notification_kind| DeferredKindCount {
+                kind: notification_kind,
+                count: notification_kinds.iter().filter(|stored_kind| NotificationKind::from_sql_str(stored_kind) == Some(notification_kind)).count() as u64,
+            })
+            .filter(|kind_count| kind_count.count > 0)
+            .collect();
+        DeferredDigest { counts }
+    }
+
+    fn format_deferred_digest_message(&self, digest: &DeferredDigest, locale: Option<&str>) -> (String, String) {
+        let title = "While you were away".to_string();
+        let summary = digest
+            .counts
+            .iter()
+            .map(|kind_count| format!("{} {}", kind_count.count, kind_count.kind.plural_noun()))
+            .collect::<Vec<String>>()
+            .join(", ");
+        let body = format!("You have {}", summary);
+        let template = match self.notification_templates.get(locale, "deferred_digest") {
+            Some(template) => template,
+            None => return (title, body),
+        };
+        let variables = std::collections::HashMap::from([
+            ("count", digest.total_count().to_string()),
+            ("summary", summary),
+        ]);
+        let render = |text: &Option<String>, fallback: String| match text {
+            Some(text) => NotificationTemplate::render(text, &variables),
+            None => fallback,
+        };
+        (render(&template.title, title), render(&template.body, body))
+    }
+
+    fn webhook_payload(
+        (title, subtitle, body): (String, String, String),
+        payload_data: Vec<(&'static str, serde_json::Value)>,
+    ) -> serde_json::Value {
+        let mut payload = serde_json::json!({
+            "title": title,
+            "subtitle": subtitle,
+            "body": body,
+        });
+        for (key, value) in payload_data {
+            payload[key] = value;
+        }
+        payload
+    }
+
+    async fn send_event_notification_to_webhook(&self, webhook: &Webhook, payload: serde_json::Value) -> Result<(), WebhookError> {
+        log::debug!("Sending notification to webhook: {}", webhook.url);
+
+        match self.webhook_client.send(webhook, &payload).await {
+            Ok(()) => {
+                log::info!("Notification sent to webhook: {}", webhook.url);
+                Ok(())
+            }
+            Err(e) => {
+                log::error!("Failed to send notification to webhook '{}': {}", webhook.url, e);
+                Err(e)
+            }
+        }
+    }
+
+    fn format_notification_message(&self, event: &Event, locale: Option<&str>) -> (String, String, String) {
+        let notification_kind = NotificationKind::from_event(event);
+        let (kind_key, (title, body)) = match notification_kind {
+            Some(notification_kind) => (
+                notification_kind.template_key(),
+                notification_kind.default_title_and_body(event, self.push_body_max_length),
+            ),
+            None => ("other", ("New activity".to_string(), "".to_string())),
+        };
+        
+        // Apply the operator's templates on top of the built-in text, if there are any for this kind
+        let template = match self.notification_templates.get(locale, kind_key) {
+            Some(template) => template,
+            None => return (title, "".to_string(), body),
+        };
+        let variables = std::collections::HashMap::from([
+            ("content", body.clone()),
+            // Anonymous zaps must not reveal who sent them
+            ("author", if event.is_anonymous_zap() { "".to_string() } else { event.attributed_author().to_hex() }),
+            ("amount_sats", event.zap_amount_msats().map(|msats| (msats / 1000).to_string()).unwrap_or_default()),
+        ]);
+        let render = |text: &Option<String>, fallback: String| match text {
+            Some(text) => NotificationTemplate::render(text, &variables),
+            None => fallback,
+        };
+        (
+            render(&template.title, title),
+            render(&template.subtitle, "".to_string()),
+            render(&template.body, body.clone()),
+        )
+    }
+    
+    // MARK: - Audit log
+
+    pub async fn record_audit_log_entry(
+        &self,
+        actor_pubkey: &PublicKey,
+        method: &str,
+        path: &str,
+        status: u16,
+        request_id: &str,
+    ) -> Result<(), Box<dyn std::error::Error>> {
+        let (actor_pubkey, method, path, request_id) = (actor_pubkey.to_sql_string(), method.to_string(), path.to_string(), request_id.to_string());
+        self.with_connection(move |connection| {
+            connection.execute(
+                "INSERT INTO audit_log (actor_pubkey, method, path, status, request_id, created_at) VALUES (?, ?, ?, ?, ?, ?)",
+                params![actor_pubkey, method, path, status, request_id, Timestamp::now().to_sql_string()],
+            )?;
+            Ok(())
+        })
+        .await
+    }
+
+    /// Gets the most recent audit log entries, newest first. `before` is the `id` of the last entry of the previous page
+    pub async fn get_audit_log(
+        &self,
+        actor_pubkey: Option<PublicKey>,
+        before: Option<i64>,
+        limit: usize,
+    ) -> Result<Vec<AuditLogEntry>, Box<dyn std::error::Error>> {
+        let actor_pubkey = actor_pubkey.map(|actor_pubkey| actor_pubkey.to_sql_string());
+        self.with_connection(move |connection| {
+            let mut stmt = connection.prepare(
+                "SELECT id, actor_pubkey, method, path, status, request_id, created_at FROM audit_log
+                WHERE (?1 IS NULL OR actor_pubkey = ?1) AND (?2 IS NULL OR id < ?2)
+                ORDER BY id DESC LIMIT ?3",
+            )?;
+            let entries = stmt
+                .query_map(params![actor_pubkey, before, limit as i64], |row| {
+                    Ok(AuditLogEntry {
+                        id: row.get(0)?,
+                        actor_pubkey: row.get(1)?,
+                        method: row.get(2)?,
+                        path: row.get(3)?,
+                        status: row.get(4)?,
+                        request_id: row.get(5)?,
+                        created_at: row.get::<_, i64>(6)? as u64,
+                    })
+                })?
+                .filter_map(|r| r.ok())
+                .collect();
+            Ok(entries)
+        })
+        .await
+    }
+
+    // MARK: - Notification history
+
+    /// Counts the notifications sent to a pubkey within any of the given `(since, until)` time ranges (both bounds inclusive and optional).
+    /// Clients use this with the time they last read their notifications to get an unread count.
+    pub async fn count_notifications(
+        &self,
+        pubkey: &PublicKey,
+        time_ranges: &[(Option<Timestamp>, Option<Timestamp>)],
+    ) -> Result<usize, Box<dyn std::error::Error>> {
+        let mut time_range_conditions: Vec<String> = Vec::new();
+        let mut parameters: Vec<i64> = Vec::new();
+        for (since, until) in time_ranges {
+            time_range_conditions.push("(COALESCE(sent_at, 0) >= ? AND COALESCE(sent_at, 0) <= ?)".to_string());
+            parameters.push(since.map(|since| since.as_u64() as i64).unwrap_or(0));
+            parameters.push(until.map(|until| until.as_u64() as i64).unwrap_or(i64::MAX));
+        }
+        if time_range_conditions.is_empty() {
+            time_range_conditions.push("1".to_string());
+        }
+        
+        let query = format!(
+            "SELECT COUNT(*) FROM notifications WHERE pubkey = ? AND received_notification = 1 AND ({})",
+            time_range_conditions.join(" OR ")
+        );
+        let pubkey_string = pubkey.to_sql_string();
+        let count: i64 = self.with_connection(move |connection| {
+            let mut query_parameters: Vec<&dyn rusqlite::ToSql> = vec![];
+            query_parameters.push(&pubkey_string);
+            for parameter in &parameters {
+                query_parameters.push(parameter);
+            }
+            let count = connection.query_row(&query, query_parameters.as_slice(), |row| row.get(0))?;
+            Ok(count)
+        })
+        .await?;
+        Ok(count as usize)
+    }
+
+    // MARK: - Live Activities
+
+    /// Checks if the Live Activity token has the expected shape. These tokens are hex encoded, but longer than device tokens
+    pub fn is_live_activity_token_valid(activity_token: &str) -> bool {
+        activity_token.len() >= APNS_DEVICE_TOKEN_LENGTH
+            && activity_token.len() <= MAX_LIVE_ACTIVITY_TOKEN_LENGTH
+            && activity_token.len() % 2 == 0
+            && activity_token.chars().all(|c| c.is_ascii_hexdigit())
+    }
+
+    /// Registers a Live Activity token that tracks a given event (e.g. a live zap raiser)
+    pub async fn save_live_activity_token(
+        &self,
+        pubkey: &PublicKey,
+        activity_token: &str,
+        event_id: &EventId,
+    ) -> Result<(), Box<dyn std::error::Error>> {
+        let (pubkey, activity_token, event_id) = (pubkey.to_sql_string(), activity_token.to_string(), event_id.to_sql_string());
+        self.with_connection(move |connection| {
+            connection.execute(
+                "INSERT OR REPLACE INTO live_activity_tokens (id, pubkey, activity_token, event_id, added_at) VALUES (?, ?, ?, ?, ?)",
+                params![
+                    format!("{}:{}", pubkey, activity_token),
+                    pubkey,
+                    activity_token,
+                    event_id,
+                    Timestamp::now().to_sql_string(),
+                ],
+            )?;
+            Ok(())
+        })
+        .await
+    }
+
+    /// Ends the Live Activity on the device and forgets its token
+    pub async fn end_live_activity(
+        &self,
+        pubkey: &PublicKey,
+        activity_token: &str,
+    ) -> Result<(), Box<dyn std::error::Error>> {
+        if let Err(e) = self.live_activity_client.send(activity_token, LiveActivityEvent::End, serde_json::json!({})).await {
+            log::warn!("Failed to end Live Activity '{}': {}", activity_token, e);
+        }
+        let (pubkey, activity_token) = (pubkey.to_sql_string(), activity_token.to_string());
+        self.with_connection(move |connection| {
+            connection.execute(
+                "DELETE FROM live_activity_tokens WHERE pubkey = ? AND activity_token = ?",
+                params![pubkey, activity_token],
+            )?;
+            Ok(())
+        })
+        .await
+    }
+
+    async fn get_live_activity_tokens(
+        &self,
+        event_id: &EventId,
+    ) -> Result<Vec<String>, Box<dyn std::error::Error>> {
+        let event_id = event_id.to_sql_string();
+        self.with_connection(move |connection| {
+            let mut stmt = connection.prepare("SELECT activity_token FROM live_activity_tokens WHERE event_id = ?")?;
+            let activity_tokens = stmt
+                .query_map([event_id], |row| row.get(0))?
+                .filter_map(|r| r.ok())
+                .collect();
+            Ok(activity_tokens)
+        })
+        .await
+    }
+
+    /// Checks if the zap receipt would notify a registered recipient on this shard, or update a Live Activity
+    async fn has_zap_receipt_audience(&self, zap_receipt: &Event) -> Result<bool, Box<dyn std::error::Error>> {
+        if let Some(recipient) = zap_receipt.zap_recipient() {
+            if self.recipient_shard.contains(&recipient) && self.is_pubkey_registered(&recipient).await? {
+                return Ok(true);
+            }
+        }
+        for zapped_event_id in zap_receipt.referenced_event_ids() {
+            if !self.get_live_activity_tokens(&zapped_event_id).await?.is_empty() {
+                return Ok(true);
+            }
+        }
+        Ok(false)
+    }
+
+    /// Updates the Live Activities tracking the zapped event with the new zap
+    async fn send_live_activity_updates_for_zap(
+        &self,
+        zap_receipt: &Event,
+    ) -> Result<(), Box<dyn std::error::Error>> {
+        let content_state = serde_json::json!({
+            "zap_receipt_id": zap_receipt.id.to_hex(),
+            "zap_amount_msats": zap_receipt.zap_amount_msats(),
+        });
+        for zapped_event_id in zap_receipt.referenced_event_ids() {
+            for activity_token in self.get_live_activity_tokens(&zapped_event_id).await? {
+                if !self.claim_live_activity_update(zap_receipt, &activity_token).await? {
+                    log::debug!("Live Activity update for zap receipt {} was already sent to activity token '{}'", zap_receipt.id, activity_token);
+                    continue;
+                }
+                match self.live_activity_client.send(&activity_token, LiveActivityEvent::Update, content_state.clone()).await {
+                    Ok(_) => log::info!("Live Activity update sent to activity token: {}", activity_token),
+                    Err(e) => log::error!("Failed to send Live Activity update to activity token '{}': {}", activity_token, e),
+                }
+            }
+        }
+        Ok(())
+    }
+    
+    /// Atomically claims the update of a Live Activity about a zap receipt, like `claim_notification` does for pushes.
+    /// Returns `false` if it was already claimed (e.g. because the receipt came in from another relay)
+    async fn claim_live_activity_update(&self, zap_receipt: &Event, activity_token: &str) -> Result<bool, Box<dyn std::error::Error>> {
+        let id = format!("{}:{}", zap_receipt.id, activity_token);
+        let inserted_rows = self.with_connection(move |connection| {
+            let inserted_rows = connection.execute(
+                "INSERT INTO live_activity_updates (id, sent_at) VALUES (?, ?) ON CONFLICT DO NOTHING",
+                params![id, Timestamp::now().to_sql_string()],
+            )?;
+            Ok(inserted_rows)
+        })
+        .await?;
+        Ok(inserted_rows > 0)
+    }
+
+    /// Deletes the Live Activity update claims older than the maximum event age, since their zap receipts are no longer processed
+    async fn expire_live_activity_update_claims(&self) -> Result<usize, Box<dyn std::error::Error>> {
+        let cutoff = Timestamp::now().as_u64().saturating_sub(self.event_max_age_seconds) as i64;
+        self.with_connection(move |connection| {
+            let expired_rows = connection.execute("DELETE FROM live_activity_updates WHERE sent_at < ?", [cutoff])?;
+            Ok(expired_rows)
+        })
+        .await
+    }
+    
+    // MARK: - User device info and settings
+    
+    /// Checks if the device token has the shape its push provider expects before we accept it.
+    /// Without a token type, the token only has to be valid for one of the configured providers
+    pub fn is_device_token_valid(&self, device_token: &str, token_type: Option<TokenType>) -> bool {
+        match token_type {
+            Some(token_type) => self.push_provider(token_type).map_or(false, |push_provider| push_provider.validate_token(device_token)),
+            None => self.push_providers.values().any(|push_provider| push_provider.validate_token(device_token)),
+        }
+    }
+    
+    /// Registers the device token for the pubkey if it is not registered yet.
+    /// Returns `true` if a new registration was created, `false` if it already existed.
+    /// Existing registrations are left untouched, preserving `added_at` and notification settings.
+    pub async fn save_user_device_info_if_not_present(
+        &self,
+        pubkey: nostr::PublicKey,
+        device_token: &str,
+    ) -> Result<bool, Box<dyn std::error::Error>> {
+        if self.is_pubkey_token_pair_registered(&pubkey, &device_token).await? {
+            return Ok(false);
+        }
+        self.save_user_device_info(pubkey, device_token).await
+    }
+
+    /// Saves the device token for the pubkey, returning `true` if a new row was inserted
+    pub async fn save_user_device_info(
+        &self,
+        pubkey: nostr::PublicKey,
+        device_token: &str,
+    ) -> Result<bool, Box<dyn std::error::Error>> {
+        let device_token = device_token.to_string();
+        self.with_connection(move |connection| Ok(Self::insert_user_device_info(connection, &pubkey, &device_token)?)).await
+    }
+
+    fn insert_user_device_info(
+        connection: &rusqlite::Connection,
+        pubkey: &PublicKey,
+        device_token: &str,
+    ) -> Result<bool, rusqlite::Error> {
+        let current_time_unix = Timestamp::now();
+        // Re-registering a removed device within the grace period restores it with its previous settings
+        let restored_rows = connection.execute(
+            "UPDATE user_info SET deleted_at = NULL, switched_away = false WHERE pubkey = ? AND device_token = ? AND deleted_at IS NOT NULL",
+            params![pubkey.to_sql_string(), device_token],
+        )?;
+        if restored_rows > 0 {
+            return Ok(true);
+        }
+        // `ON CONFLICT DO NOTHING` keeps an existing row (and its settings) intact if there is a concurrent registration
+        let inserted_rows = connection.execute(
+            "INSERT INTO user_info (id, pubkey, device_token, added_at) VALUES (?, ?, ?, ?)
+            ON CONFLICT DO NOTHING",
+            params![
+                format!("{}:{}", pubkey.to_sql_string(), device_token), 
+                pubkey.to_sql_string(),
+                device_token,
+                current_time_unix.to_sql_string()
+            ],
+        )?;
+        Ok(inserted_rows > 0)
+    }
+
+    /// Registers several devices of a pubkey (e.g. when restoring from a backup) in a single transaction, so that either all or none of them are saved.
+    /// Returns, for each registration, whether it created a new device and the negotiated payload version
+    pub async fn save_user_device_registrations(
+        &self,
+        pubkey: &PublicKey,
+        registrations: &[DeviceRegistration],
+    ) -> Result<Vec<(bool, u32)>, Box<dyn std::error::Error>> {
+        let (pubkey, registrations) = (*pubkey, registrations.to_vec());
+        self.with_connection(move |connection| {
+            let transaction = connection.transaction_with_behavior(TransactionBehavior::Immediate)?;
+            let mut results = Vec::new();
+            for registration in &registrations {
+                let device_token = registration.device_token.as_str();
+                let created = Self::insert_user_device_info(&transaction, &pubkey, device_token)?;
+                let token_type = registration.token_type.unwrap_or(TokenType::Apns);
+                transaction.execute(
+                    "UPDATE user_info SET token_type = ? WHERE pubkey = ? AND device_token = ?",
+                    params![token_type.as_str(), pubkey.to_sql_string(), device_token],
+                )?;
+                let payload_version = push_payload::negotiate_payload_version(registration.payload_version);
+                transaction.execute(
+                    "UPDATE user_info SET payload_version = ? WHERE pubkey = ? AND device_token = ?",
+                    params![payload_version, pubkey.to_sql_string(), device_token],
+                )?;
+                if !registration.metadata.is_empty() {
+                    Self::update_device_metadata(&transaction, &pubkey, device_token, &registration.metadata)?;
+                }
+                if let Some(settings) = &registration.settings {
+                    Self::update_user_notification_settings(&transaction, &pubkey, device_token, settings)?;
+                }
+                results.push((created, payload_version));
+            }
+            transaction.commit()?;
+            Ok(results)
+        })
+        .await
+    }
+
+    /// Disables the device for the pubkey, which stops notifications immediately.
+    /// The row (and its settings) is only purged after the grace period, in case the removal was accidental
+    pub async fn remove_user_device_info(
+        &self,
+        pubkey: nostr::PublicKey,
+        device_token: &str,
+    ) -> Result<(), Box<dyn std::error::Error>> {
+        let current_time_unix = Timestamp::now();
+        let device_token = device_token.to_string();
+        self.with_connection(move |connection| {
+            let transaction = connection.transaction_with_behavior(TransactionBehavior::Immediate)?;
+            transaction.execute(
+                "UPDATE user_info SET deleted_at = ?, switched_away = false WHERE pubkey = ? AND device_token = ? AND (deleted_at IS NULL OR switched_away)",
+                params![current_time_unix.to_sql_string(), pubkey.to_sql_string(), device_token],
+            )?;
+            // A removed device must not get a digest of what was held back for it, even if it is registered again
+            transaction.execute(
+                "DELETE FROM deferred_notifications WHERE pubkey = ? AND device_token = ?",
+                params![pubkey.to_sql_string(), device_token],
+            )?;
+            transaction.commit()?;
+            Ok(())
+        })
+        .await
+    }
+
+    /// Makes the pubkey the only account notified on the device, as when the user switches accounts in the app.
+    /// The other accounts on the device are disabled in the same transaction, keeping their settings for when the user switches back.
+    /// Returns the pubkeys that were switched away from, or `None` if the pubkey is not registered on the device (switched away from counts),
+    /// since knowing a device token must not be enough to silence its other accounts
+    pub async fn switch_device_account(
+        &self,
+        pubkey: &PublicKey,
+        device_token: &str,
+    ) -> Result<Option<Vec<PublicKey>>, Box<dyn std::error::Error>> {
+        let current_time_unix = Timestamp::now();
+        let (pubkey, device_token) = (*pubkey, device_token.to_string());
+        let switched_away_pubkeys: Option<Vec<String>> = self.with_connection(move |connection| {
+            // Immediate, so that concurrent switches wait for each other instead of failing to upgrade their locks
+            let transaction = connection.transaction_with_behavior(TransactionBehavior::Immediate)?;
+            let is_registered = transaction
+                .query_row(
+                    "SELECT 1 FROM user_info WHERE pubkey = ? AND device_token = ? AND (deleted_at IS NULL OR switched_away)",
+                    params![pubkey.to_sql_string(), device_token],
+                    |_| Ok(()),
+                )
+                .optional()?
+                .is_some();
+            if !is_registered {
+                return Ok(None);
+            }
+            let switched_away_pubkeys = {
+                let mut stmt = transaction.prepare(
+                    "UPDATE user_info SET deleted_at = ?, switched_away = true WHERE device_token = ? AND pubkey != ? AND deleted_at IS NULL
+                    RETURNING pubkey",
+                )?;
+                let switched_away_pubkeys = stmt
+                    .query_map(params![current_time_unix.to_sql_string(), device_token, pubkey.to_sql_string()], |row| row.get(0))?
+                    .filter_map(|r| r.ok())
+                    .collect();
+                switched_away_pubkeys
+            };
+            Self::insert_user_device_info(&transaction, &pubkey, &device_token)?;
+            transaction.commit()?;
+            Ok(Some(switched_away_pubkeys))
+        })
+        .await?;
+        Ok(switched_away_pubkeys.map(|switched_away_pubkeys| {
+            switched_away_pubkeys.into_iter().filter_map(|r| PublicKey::from_sql_string(r).ok()).collect()
+        }))
+    }
+
+    /// Periodically purges devices removed longer ago than the grace period. Runs forever, so it should be spawned as a task
+    pub async fn run_device_purge_job(notification_manager: std::sync::Arc<Self>) {
+        let mut interval = tokio::time::interval(DEVICE_PURGE_INTERVAL);
+        loop {
+            interval.tick().await;
+            match notification_manager.purge_removed_devices().await {
+                Ok(0) => {}
+                Ok(purged_devices) => log::info!("Purged {} removed devices", purged_devices),
+                Err(e) => log::error!("Failed to purge removed devices: {}", e),
+            }
+        }
+    }
+
+    async fn purge_removed_devices(&self) -> Result<usize, Box<dyn std::error::Error>> {
+        let cutoff = Timestamp::now().as_u64().saturating_sub(self.device_removal_grace_period.as_secs());
+        self.with_connection(move |connection| {
+            let transaction = connection.transaction_with_behavior(TransactionBehavior::Immediate)?;
+            let purged_devices = transaction.execute(
+                "DELETE FROM user_info WHERE deleted_at IS NOT NULL AND deleted_at <= ? AND NOT switched_away",
+                params![cutoff as i64],
+            )?;
+            // Also catches devices removed some other way, e.g. unbound from a pubkey
+            transaction.execute(
+                "DELETE FROM deferred_notifications WHERE NOT EXISTS (
+                    SELECT 1 FROM user_info WHERE user_info.pubkey = deferred_notifications.pubkey AND user_info.device_token = deferred_notifications.device_token
+                )",
+                [],
+            )?;
+            transaction.commit()?;
+            Ok(purged_devices)
+        })
+        .await
+    }
+    
+    /// Sets (or clears) the webhook that notifications to this device are delivered to instead of APNS
+    pub async fn set_device_webhook(
+        &self,
+        pubkey: &PublicKey,
+        device_token: &str,
+        webhook: Option<&Webhook>,
+    ) -> Result<(), Box<dyn std::error::Error>> {
+        let (pubkey, device_token) = (pubkey.to_sql_string(), device_token.to_string());
+        let (webhook_url, webhook_secret) = (webhook.map(|webhook| webhook.url.clone()), webhook.map(|webhook| webhook.secret.clone()));
+        self.with_connection(move |connection| {
+            connection.execute(
+                "UPDATE user_info SET webhook_url = ?, webhook_secret = ? WHERE pubkey = ? AND device_token = ?",
+                params![webhook_url, webhook_secret, pubkey, device_token],
+            )?;
+            Ok(())
+        })
+        .await
+    }
+
+    /// Checks if an APNS tenant with this ID is configured
+    pub fn is_apns_tenant_known(&self, tenant_id: &str) -> bool {
+        self.push_providers.get(&TokenType::Apns).map_or(false, |push_provider| push_provider.has_tenant(tenant_id))
+    }
+    
+    /// Replaces the push provider that delivers to tokens of this type, e.g. with a mock one for benchmarking
+    pub fn replace_push_provider(&mut self, token_type: TokenType, push_provider: Box<dyn PushProvider>) {
+        self.push_providers.insert(token_type, push_provider);
+    }
+    
+    /// The push provider that delivers to tokens of this type
+    fn push_provider(&self, token_type: TokenType) -> Result<&dyn PushProvider, Box<dyn std::error::Error>> {
+        match self.push_providers.get(&token_type) {
+            Some(push_provider) => Ok(push_provider.as_ref()),
+            None => Err(format!("No push provider is configured for {} tokens", token_type.as_str()).into()),
+        }
+    }
+    
+    /// Sets the type of the device token, which decides the push provider notifications to this device are sent through
+    pub async fn set_device_token_type(
+        &self,
+        pubkey: &PublicKey,
+        device_token: &str,
+        token_type: TokenType,
+    ) -> Result<(), Box<dyn std::error::Error>> {
+        let (pubkey, device_token) = (pubkey.to_sql_string(), device_token.to_string());
+        self.with_connection(move |connection| {
+            connection.execute(
+                "UPDATE user_info SET token_type = ? WHERE pubkey = ? AND device_token = ?",
+                params![token_type.as_str(), pubkey, device_token],
+            )?;
+            Ok(())
+        })
+        .await
+    }
+    
+    async fn get_device_token_type(
+        &self,
+        pubkey: &PublicKey,
+        device_token: &str,
+    ) -> Result<TokenType, Box<dyn std::error::Error>> {
+        let (pubkey, device_token) = (pubkey.to_sql_string(), device_token.to_string());
+        let token_type = self.with_connection(move |connection| {
+            let mut stmt = connection.prepare(
+                "SELECT token_type FROM user_info WHERE pubkey = ? AND device_token = ?",
+            )?;
+            let token_type = stmt
+                .query_map(params![pubkey, device_token], |row| row.get::<_, Option<String>>(0))?
+                .filter_map(|r| r.ok())
+                .next()
+                .flatten();
+            Ok(token_type)
+        })
+        .await?;
+        Ok(token_type.as_deref().and_then(TokenType::parse).unwrap_or(TokenType::Apns))
+    }
+    
+    /// Sets the APNS tenant (app) whose credentials are used to send notifications to this device
+    pub async fn set_device_apns_tenant(
+        &self,
+        pubkey: &PublicKey,
+        device_token: &str,
+        tenant_id: &str,
+    ) -> Result<(), Box<dyn std::error::Error>> {
+        let (pubkey, device_token, tenant_id) = (pubkey.to_sql_string(), device_token.to_string(), tenant_id.to_string());
+        self.with_connection(move |connection| {
+            connection.execute(
+                "UPDATE user_info SET apns_tenant = ? WHERE pubkey = ? AND device_token = ?",
+                params![tenant_id, pubkey, device_token],
+            )?;
+            Ok(())
+        })
+        .await
+    }
+    
+    /// Sets the push payload layout this device's app build understands, as negotiated at registration
+    pub async fn set_device_payload_version(
+        &self,
+        pubkey: &PublicKey,
+        device_token: &str,
+        payload_version: u32,
+    ) -> Result<(), Box<dyn std::error::Error>> {
+        let (pubkey, device_token) = (pubkey.to_sql_string(), device_token.to_string());
+        self.with_connection(move |connection| {
+            connection.execute(
+                "UPDATE user_info SET payload_version = ? WHERE pubkey = ? AND device_token = ?",
+                params![payload_version, pubkey, device_token],
+            )?;
+            Ok(())
+        })
+        .await
+    }
+    
+    async fn get_device_payload_version(
+        &self,
+        pubkey: &PublicKey,
+        device_token: &str,
+    ) -> Result<u32, Box<dyn std::error::Error>> {
+        let (pubkey, device_token) = (pubkey.to_sql_string(), device_token.to_string());
+        let payload_version = self.with_connection(move |connection| {
+            let mut stmt = connection.prepare(
+                "SELECT payload_version FROM user_info WHERE pubkey = ? AND device_token = ?",
+            )?;
+            let payload_version = stmt
+                .query_map(params![pubkey, device_token], |row| row.get::<_, Option<u32>>(0))?
+                .filter_map(|r| r.ok())
+                .next()
+                .flatten();
+            Ok(payload_version)
+        })
+        .await?;
+        Ok(payload_version.unwrap_or(push_payload::LEGACY_PAYLOAD_VERSION))
+    }
+    
+    async fn get_device_apns_tenant(
+        &self,
+        pubkey: &PublicKey,
+        device_token: &str,
+    ) -> Result<Option<String>, Box<dyn std::error::Error>> {
+        let (pubkey, device_token) = (pubkey.to_sql_string(), device_token.to_string());
+        let tenant_id = self.with_connection(move |connection| {
+            let mut stmt = connection.prepare(
+                "SELECT apns_tenant FROM user_info WHERE pubkey = ? AND device_token = ?",
+            )?;
+            let tenant_id = stmt
+                .query_map(params![pubkey, device_token], |row| row.get::<_, Option<String>>(0))?
+                .filter_map(|r| r.ok())
+                .next()
+                .flatten();
+            Ok(tenant_id)
+        })
+        .await?;
+        Ok(tenant_id)
+    }
+
+    /// Saves the metadata reported by the device. Fields that were not reported keep their previous value
+    pub async fn save_device_metadata(
+        &self,
+        pubkey: &PublicKey,
+        device_token: &str,
+        metadata: &DeviceMetadata,
+    ) -> Result<(), Box<dyn std::error::Error>> {
+        let (pubkey, device_token, metadata) = (*pubkey, device_token.to_string(), metadata.clone());
+        self.with_connection(move |connection| Ok(Self::update_device_metadata(connection, &pubkey, &device_token, &metadata)?)).await
+    }
+
+    fn update_device_metadata(
+        connection: &rusqlite::Connection,
+        pubkey: &PublicKey,
+        device_token: &str,
+        metadata: &DeviceMetadata,
+    ) -> Result<(), rusqlite::Error> {
+        connection.execute(
+            "UPDATE user_info SET locale = COALESCE(?, locale), app_version = COALESCE(?, app_version), os_version = COALESCE(?, os_version) WHERE pubkey = ? AND device_token = ?",
+            params![
+                metadata.locale,
+                metadata.app_version,
+                metadata.os_version,
+                pubkey.to_sql_string(),
+                device_token,
+            ],
+        )?;
+        Ok(())
+    }
+
+    async fn get_device_locale(
+        &self,
+        pubkey: &PublicKey,
+        device_token: &str,
+    ) -> Result<Option<String>, Box<dyn std::error::Error>> {
+        let (pubkey, device_token) = (pubkey.to_sql_string(), device_token.to_string());
+        let locale = self.with_connection(move |connection| {
+            let mut stmt = connection.prepare(
+                "SELECT locale FROM user_info WHERE pubkey = ? AND device_token = ?",
+            )?;
+            let locale = stmt
+                .query_map(params![pubkey, device_token], |row| row.get::<_, Option<String>>(0))?
+                .filter_map(|r| r.ok())
+                .next()
+                .flatten();
+            Ok(locale)
+        })
+        .await?;
+        Ok(locale)
+    }
+
+    async fn get_device_webhook(
+        &self,
+        pubkey: &PublicKey,
+        device_token: &str,
+    ) -> Result<Option<Webhook>, Box<dyn std::error::Error>> {
+        let (pubkey, device_token) = (pubkey.to_sql_string(), device_token.to_string());
+        self.with_connection(move |connection| {
+            let mut stmt = connection.prepare(
+                "SELECT webhook_url, webhook_secret FROM user_info WHERE pubkey = ? AND device_token = ?",
+            )?;
+            let webhook = stmt
+                .query_map(params![pubkey, device_token], |row| {
+                    Ok((row.get::<_, Option<String>>(0)?, row.get::<_, Option<String>>(1)?))
+                })?
+                .filter_map(|r| r.ok())
+                .find_map(|(url, secret)| Some(Webhook { url: url?, secret: secret? }));
+            Ok(webhook)
+        })
+        .await
+    }
+
+    /// Removes a device token from every pubkey it is bound to (e.g. after APNS reports it as unregistered)
+    pub async fn prune_device_token(
+        &self,
+        device_token: &str,
+    ) -> Result<(), Box<dyn std::error::Error>> {
+        let device_token = device_token.to_string();
+        self.with_connection(move |connection| {
+            let transaction = connection.transaction_with_behavior(TransactionBehavior::Immediate)?;
+            transaction.execute(
+                "DELETE FROM user_info WHERE device_token = ?",
+                params![device_token],
+            )?;
+            transaction.execute(
+                "DELETE FROM deferred_notifications WHERE device_token = ?",
+                params![device_token],
+            )?;
+            transaction.commit()?;
+            Ok(())
+        })
+        .await
+    }
+
+    async fn set_device_last_notified_at(
+        &self,
+        pubkey: &PublicKey,
+        device_token: &str,
+    ) -> Result<(), Box<dyn std::error::Error>> {
+        let (pubkey, device_token) = (pubkey.to_sql_string(), device_token.to_string());
+        self.with_connection(move |connection| {
+            connection.execute(
+                "UPDATE user_info SET last_notified_at = ? WHERE pubkey = ? AND device_token = ?",
+                params![Timestamp::now().to_sql_string(), pubkey, device_token],
+            )?;
+            Ok(())
+        })
+        .await
+    }
+
+    /// Gets when each pubkey bound to a device token last had a notification successfully sent to it, if ever
+    pub async fn get_device_last_notified_at(
+        &self,
+        device_token: &str,
+    ) -> Result<HashMap<PublicKey, Option<Timestamp>>, Box<dyn std::error::Error>> {
+        let device_token = device_token.to_string();
+        let rows: Vec<(String, Option<i64>)> = self.with_connection(move |connection| {
+            let mut stmt = connection.prepare("SELECT pubkey, last_notified_at FROM user_info WHERE device_token = ? AND deleted_at IS NULL")?;
+            let rows = stmt
+                .query_map([device_token], |row| Ok((row.get(0)?, row.get(1)?)))?
+                .filter_map(|r| r.ok())
+                .collect();
+            Ok(rows)
+        })
+        .await?;
+        let last_notified_at = rows
+            .into_iter()
+            .filter_map(|(pubkey, last_notified_at)| {
+                let pubkey = PublicKey::from_sql_string(pubkey).ok()?;
+                Some((pubkey, last_notified_at.map(|seconds| Timestamp::from(seconds as u64))))
+            })
+            .collect();
+        Ok(last_notified_at)
+    }
+
+    /// Gets all pubkeys currently bound to a device token (e.g. multiple accounts on one phone)
+    pub async fn get_device_token_pubkeys(
+        &self,
+        device_token: &str,
+    ) -> Result<HashSet<PublicKey>, Box<dyn std::error::Error>> {
+        let device_token = device_token.to_string();
+        self.with_connection(move |connection| {
+            let mut stmt = connection.prepare("SELECT pubkey FROM user_info WHERE device_token = ? AND deleted_at IS NULL")?;
+            let pubkeys = stmt
+                .query_map([device_token], |row| row.get(0))?
+                .filter_map(|r| r.ok())
+                .filter_map(|r: String| PublicKey::from_sql_string(r).ok())
+                .collect();
+            Ok(pubkeys)
+        })
+        .await
+    }
+
+    /// Atomically unbinds pubkeys from a device token, like removing the device for each of them, returning the pubkeys that remain bound
+    pub async fn unbind_device_token_pubkeys(
+        &self,
+        device_token: &str,
+        pubkeys: &HashSet<PublicKey>,
+    ) -> Result<HashSet<PublicKey>, Box<dyn std::error::Error>> {
+        let current_time_unix = Timestamp::now();
+        let (device_token, pubkeys) = (device_token.to_string(), pubkeys.clone());
+        self.with_connection(move |connection| {
+            let transaction = connection.transaction_with_behavior(TransactionBehavior::Immediate)?;
+            for pubkey in &pubkeys {
+                // Not switched away, so that the rows are purged once the grace period is over
+                transaction.execute(
+                    "UPDATE user_info SET deleted_at = ?, switched_away = false WHERE pubkey = ? AND device_token = ? AND (deleted_at IS NULL OR switched_away)",
+                    params![current_time_unix.to_sql_string(), pubkey.to_sql_string(), device_token],
+                )?;
+                transaction.execute(
+                    "DELETE FROM deferred_notifications WHERE pubkey = ? AND device_token = ?",
+                    params![pubkey.to_sql_string(), device_token],
+                )?;
+            }
+            let remaining_pubkeys = transaction
+                .prepare("SELECT pubkey FROM user_info WHERE device_token = ? AND deleted_at IS NULL")?
+                .query_map([&device_token], |row| row.get(0))?
+                .filter_map(|r| r.ok())
+                .filter_map(|r: String| PublicKey::from_sql_string(r).ok())
+                .collect();
+            transaction.commit()?;
+            Ok(remaining_pubkeys)
+        })
+        .await
+    }
+    
+    /// Gets the pubkeys linked to a device token, i.e. the other identities of the person using it
+    pub async fn get_device_linked_pubkeys(
+        &self,
+        device_token: &str,
+    ) -> Result<HashSet<PublicKey>, Box<dyn std::error::Error>> {
+        let device_token = device_token.to_string();
+        self.with_connection(move |connection| {
+            let mut stmt = connection.prepare("SELECT pubkey FROM linked_pubkeys WHERE device_token = ?")?;
+            let pubkeys = stmt
+                .query_map([device_token], |row| row.get(0))?
+                .filter_map(|r| r.ok())
+                .filter_map(|r: String| PublicKey::from_sql_string(r).ok())
+                .collect();
+            Ok(pubkeys)
+        })
+        .await
+    }
+
+    /// Atomically replaces the set of pubkeys linked to a device token
+    pub async fn replace_device_linked_pubkeys(
+        &self,
+        device_token: &str,
+        pubkeys: &HashSet<PublicKey>,
+    ) -> Result<(), Box<dyn std::error::Error>> {
+        let (device_token, pubkeys) = (device_token.to_string(), pubkeys.clone());
+        self.with_connection(move |connection| {
+            let transaction = connection.transaction_with_behavior(TransactionBehavior::Immediate)?;
+            transaction.execute("DELETE FROM linked_pubkeys WHERE device_token = ?", [&device_token])?;
+            for pubkey in &pubkeys {
+                transaction.execute(
+                    "INSERT INTO linked_pubkeys (device_token, pubkey) VALUES (?, ?)",
+                    params![device_token, pubkey.to_sql_string()],
+                )?;
+            }
+            transaction.commit()?;
+            Ok(())
+        })
+        .await
+    }
+
+    // MARK: - Account overview
+
+    /// Gets the registered devices of a pubkey with their metadata, settings and linked pubkeys
+    pub async fn get_account_devices(
+        &self,
+        pubkey: &PublicKey,
+    ) -> Result<Vec<AccountDevice>, Box<dyn std::error::Error>> {
+        let pubkey_string = pubkey.to_sql_string();
+        let rows: Vec<(String, Option<i64>, Option<i64>, Option<u32>, DeviceMetadata)> = self.with_connection(move |connection| {
+            let mut stmt = connection.prepare(
+                "SELECT device_token, added_at, last_notified_at, payload_version, locale, app_version, os_version FROM user_info
+                WHERE pubkey = ? AND deleted_at IS NULL ORDER BY added_at",
+            )?;
+            let rows = stmt
+                .query_map([pubkey_string], |row| {
+                    Ok((row.get(0)?, row.get(1)?, row.get(2)?, row.get(3)?, DeviceMetadata {
+                        locale: row.get(4)?,
+                        app_version: row.get(5)?,
+                        os_version: row.get(6)?,
+                    }))
+                })?
+                .filter_map(|r| r.ok())
+                .collect();
+            Ok(rows)
+        })
+        .await?;
+        
+        let mut devices = Vec::new();
+        for (device_token, added_at, last_notified_at, payload_version, metadata) in rows {
+            let settings = self.get_user_notification_settings(pubkey, device_token.clone()).await?;
+            let linked_pubkeys = self.get_device_linked_pubkeys(&device_token).await?.iter().map(|pubkey| pubkey.to_hex()).collect();
+            devices.push(AccountDevice {
+                device_token,
+                added_at: added_at.map(|added_at| added_at as u64),
+                last_notified_at: last_notified_at.map(|last_notified_at| last_notified_at as u64),
+                payload_version: payload_version.unwrap_or(push_payload::LEGACY_PAYLOAD_VERSION),
+                metadata,
+                settings,
+                linked_pubkeys,
+            });
+        }
+        Ok(devices)
+    }
+
+    /// Gathers what goes into the notification decisions for a pubkey: its cached lists, its devices and its recent delivery attempts
+    pub async fn get_user_debug_info(
+        &self,
+        pubkey: &PublicKey,
+    ) -> Result<UserDebugInfo, Box<dyn std::error::Error>> {
+        let cache = self.nostr_network_helper.cache_status(pubkey).await;
+        let devices = self.get_account_devices(pubkey).await?;
+        let pubkey_string = pubkey.to_sql_string();
+        let recent_deliveries = self.with_connection(move |connection| {
+            let mut stmt = connection.prepare(
+                "SELECT event_id, device_token, apns_id, status, reason, success, sent_at FROM deliveries
+                WHERE pubkey = ? ORDER BY sent_at DESC LIMIT ?",
+            )?;
+            let recent_deliveries = stmt
+                .query_map(params![pubkey_string, MAX_DELIVERIES_IN_USER_DEBUG_INFO], |row| {
+                    Ok(DeliveryAttempt {
+                        event_id: row.get(0)?,
+                        device_token: row.get(1)?,
+                        apns_id: row.get(2)?,
+                        status: row.get(3)?,
+                        reason: row.get(4)?,
+                        success: row.get(5)?,
+                        sent_at: row.get::<_, i64>(6)? as u64,
+                    })
+                })?
+                .filter_map(|r| r.ok())
+                .collect();
+            Ok(recent_deliveries)
+        })
+        .await?;
+        Ok(UserDebugInfo {
+            pubkey: pubkey.to_hex(),
+            cache,
+            devices,
+            recent_deliveries,
+        })
+    }
+
+    // MARK: - Hashtag subscriptions
+
+    /// Gets the subscribers of any of the given (lowercase) hashtags that have a registered device, with the hashtag and scope of each subscription
+    async fn get_hashtag_subscribers(
+        &self,
+        hashtags: &HashSet<String>,
+    ) -> Result<Vec<(PublicKey, String, HashtagScope)>, Box<dyn std::error::Error>> {
+        let hashtags = hashtags.clone();
+        let rows: Vec<(String, String, Option<String>)> = self.with_connection(move |connection| {
+            let mut stmt = connection.prepare(
+                "SELECT pubkey, hashtag, scope FROM hashtag_subscriptions
+                WHERE hashtag = ? AND EXISTS (SELECT 1 FROM user_info WHERE user_info.pubkey = hashtag_subscriptions.pubkey AND user_info.deleted_at IS NULL)",
+            )?;
+            let mut rows = Vec::new();
+            for hashtag in &hashtags {
+                rows.extend(stmt.query_map([hashtag], |row| Ok((row.get(0)?, row.get(1)?, row.get(2)?)))?.filter_map(|r| r.ok()));
+            }
+            Ok(rows)
+        })
+        .await?;
+        let mut subscribers = Vec::new();
+        for (pubkey, hashtag, scope) in rows {
+            let pubkey = match PublicKey::from_sql_string(pubkey) {
+                Ok(pubkey) => pubkey,
+                Err(_) => continue,
+            };
+            let scope = scope.as_deref().and_then(HashtagScope::from_sql_str).unwrap_or_default();
+            subscribers.push((pubkey, hashtag, scope));
+        }
+        Ok(subscribers)
+    }
+
+    pub async fn get_hashtag_subscriptions(
+        &self,
+        pubkey: &PublicKey,
+    ) -> Result<Vec<HashtagSubscription>, Box<dyn std::error::Error>> {
+        let pubkey = pubkey.to_sql_string();
+        self.with_connection(move |connection| {
+            let mut stmt = connection.prepare("SELECT hashtag, scope FROM hashtag_subscriptions WHERE pubkey = ? ORDER BY hashtag")?;
+            let subscriptions = stmt
+                .query_map([pubkey], |row| Ok((row.get::<_, String>(0)?, row.get::<_, Option<String>>(1)?)))?
+                .filter_map(|r| r.ok())
+                .map(|(hashtag, scope)| HashtagSubscription {
+                    hashtag,
+                    scope: scope.as_deref().and_then(HashtagScope::from_sql_str).unwrap_or_default(),
+                })
+                .collect();
+            Ok(subscriptions)
+        })
+        .await
+    }
+
+    /// Atomically replaces the hashtags a pubkey is subscribed to. Hashtags are normalized to lowercase without the leading `#`
+    pub async fn replace_hashtag_subscriptions(
         &self,
-        pubkey: nostr::PublicKey,
-        device_token: &str,
+        pubkey: &PublicKey,
+        subscriptions: &[HashtagSubscription],
     ) -> Result<(), Box<dyn std::error::Error>> {
-        let current_time_unix = Timestamp::now();
-        let db_mutex_guard = self.db.lock().await;
-        db_mutex_guard.get()?.execute(
-            "INSERT OR REPLACE INTO user_info (id, pubkey, device_token, added_at) VALUES (?, ?, ?, ?)",
-            params![
-                format!("{}:{}", pubkey.to_sql_string(), device_token), 
-                pubkey.to_sql_string(),
-                device_token,
-                current_time_unix.to_sql_string()
-            ],
-        )?;
-        Ok(())
+        let (pubkey, subscriptions) = (pubkey.to_sql_string(), subscriptions.to_vec());
+        self.with_connection(move |connection| {
+            let transaction = connection.transaction_with_behavior(TransactionBehavior::Immediate)?;
+            transaction.execute("DELETE FROM hashtag_subscriptions WHERE pubkey = ?", [&pubkey])?;
+            for subscription in &subscriptions {
+                transaction.execute(
+                    "INSERT INTO hashtag_subscriptions (pubkey, hashtag, scope) VALUES (?, ?, ?)
+                    ON CONFLICT (pubkey, hashtag) DO UPDATE SET scope = excluded.scope",
+                    params![pubkey, subscription.normalized_hashtag(), subscription.scope.as_sql_str()],
+                )?;
+            }
+            transaction.commit()?;
+            Ok(())
+        })
+        .await
     }
 
-    pub async fn remove_user_device_info(
+    async fn is_pubkey_linked_to_device(
         &self,
-        pubkey: nostr::PublicKey,
+        pubkey: &PublicKey,
         device_token: &str,
-    ) -> Result<(), Box<dyn std::error::Error>> {
-        let db_mutex_guard = self.db.lock().await;
-        db_mutex_guard.get()?.execute(
-            "DELETE FROM user_info WHERE pubkey = ? AND device_token = ?",
-            params![pubkey.to_sql_string(), device_token],
-        )?;
-        Ok(())
+    ) -> Result<bool, Box<dyn std::error::Error>> {
+        let (pubkey, device_token) = (pubkey.to_sql_string(), device_token.to_string());
+        self.with_connection(move |connection| {
+            let mut stmt = connection.prepare("SELECT 1 FROM linked_pubkeys WHERE device_token = ? AND pubkey = ?")?;
+            Ok(stmt.exists(params![device_token, pubkey])?)
+        })
+        .await
     }
     
+    /// Gets the notification settings of a device.
+    /// Falls back to (and persists) the default settings if the device has no settings stored, instead of failing
     pub async fn get_user_notification_settings(
         &self,
         pubkey: &PublicKey,
         device_token: String,
     ) -> Result<UserNotificationSettings, Box<dyn std::error::Error>> {
-        let db_mutex_guard = self.db.lock().await;
-        let connection = db_mutex_guard.get()?;
-        let mut stmt = connection.prepare(
-            "SELECT zap_notifications_enabled, mention_notifications_enabled, repost_notifications_enabled, reaction_notifications_enabled, dm_notifications_enabled, only_notifications_from_following_enabled FROM user_info WHERE pubkey = ? AND device_token = ?",
-        )?;
-        let settings = stmt
-            .query_row([pubkey.to_sql_string(), device_token], |row| {
-                Ok(UserNotificationSettings {
-                    zap_notifications_enabled: row.get(0)?,
-                    mention_notifications_enabled: row.get(1)?,
-                    repost_notifications_enabled: row.get(2)?,
-                    reaction_notifications_enabled: row.get(3)?,
-                    dm_notifications_enabled: row.get(4)?,
-                    only_notifications_from_following_enabled: row.get(5)?,
-                })
-            })?;
+        let stored_settings = {
+            let (pubkey, device_token) = (pubkey.to_sql_string(), device_token.clone());
+            self.with_connection(move |connection| {
+                let mut stmt = connection.prepare(
+                    "SELECT zap_notifications_enabled, mention_notifications_enabled, repost_notifications_enabled, reaction_notifications_enabled, dm_notifications_enabled, only_notifications_from_following_enabled, strangers_to_requests_folder_enabled, dm_only_from_following_enabled, weekly_summary_enabled, sensitive_content_filter, mention_min_proof_of_work, delivery_window FROM user_info WHERE pubkey = ? AND device_token = ?",
+                )?;
+                let stored_settings: Option<([Option<bool>; 9], Option<String>, Option<u8>, Option<String>)> = stmt
+                    .query_row([pubkey, device_token], |row| {
+                        Ok(([row.get(0)?, row.get(1)?, row.get(2)?, row.get(3)?, row.get(4)?, row.get(5)?, row.get(6)?, row.get(7)?, row.get(8)?], row.get(9)?, row.get(10)?, row.get(11)?))
+                    })
+                    .optional()?;
+                Ok(stored_settings)
+            })
+            .await?
+        };
+        
+        let (stored_settings, stored_sensitive_content_filter, stored_mention_min_proof_of_work, stored_delivery_window) = match stored_settings {
+            Some(stored_settings) => stored_settings,
+            None => {
+                log::debug!("No settings stored for device token {}, using the defaults", device_token);
+                return Ok(UserNotificationSettings::default());
+            }
+        };
+        let defaults = UserNotificationSettings::default();
+        let settings = UserNotificationSettings {
+            settings_version: USER_NOTIFICATION_SETTINGS_VERSION,
+            zap_notifications_enabled: stored_settings[0].unwrap_or(defaults.zap_notifications_enabled),
+            mention_notifications_enabled: stored_settings[1].unwrap_or(defaults.mention_notifications_enabled),
+            repost_notifications_enabled: stored_settings[2].unwrap_or(defaults.repost_notifications_enabled),
+            reaction_notifications_enabled: stored_settings[3].unwrap_or(defaults.reaction_notifications_enabled),
+            dm_notifications_enabled: stored_settings[4].unwrap_or(defaults.dm_notifications_enabled),
+            only_notifications_from_following_enabled: stored_settings[5].unwrap_or(defaults.only_notifications_from_following_enabled),
+            strangers_to_requests_folder_enabled: stored_settings[6].unwrap_or(defaults.strangers_to_requests_folder_enabled),
+            dm_only_from_following_enabled: stored_settings[7].unwrap_or(defaults.dm_only_from_following_enabled),
+            weekly_summary_enabled: stored_settings[8].unwrap_or(defaults.weekly_summary_enabled),
+            sensitive_content_filter: stored_sensitive_content_filter
+                .as_deref()
+                .and_then(SensitiveContentFilter::from_sql_str)
+                .unwrap_or(defaults.sensitive_content_filter),
+            mention_min_proof_of_work: stored_mention_min_proof_of_work.unwrap_or(defaults.mention_min_proof_of_work),
+            // No window is a valid setting, so a missing one does not make the settings incomplete
+            delivery_window: stored_delivery_window.and_then(|delivery_window| serde_json::from_str(&delivery_window).ok()),
+        };
+        if stored_settings.iter().any(|setting| setting.is_none()) || stored_sensitive_content_filter.is_none() || stored_mention_min_proof_of_work.is_none() {
+            log::debug!("Incomplete settings stored for device token {}, persisting the defaults", device_token);
+            self.save_user_notification_settings(pubkey, device_token, settings.clone()).await?;
+        }
         
         Ok(settings)
     }
@@ -499,10 +4014,18 @@ impl NotificationManager {
         device_token: String,
         settings: UserNotificationSettings,
     ) -> Result<(), Box<dyn std::error::Error>> {
-        let db_mutex_guard = self.db.lock().await;
-        let connection = db_mutex_guard.get()?;
+        let pubkey = *pubkey;
+        self.with_connection(move |connection| Ok(Self::update_user_notification_settings(connection, &pubkey, &device_token, &settings)?)).await
+    }
+
+    fn update_user_notification_settings(
+        connection: &rusqlite::Connection,
+        pubkey: &PublicKey,
+        device_token: &str,
+        settings: &UserNotificationSettings,
+    ) -> Result<(), rusqlite::Error> {
         connection.execute(
-            "UPDATE user_info SET zap_notifications_enabled = ?, mention_notifications_enabled = ?, repost_notifications_enabled = ?, reaction_notifications_enabled = ?, dm_notifications_enabled = ?, only_notifications_from_following_enabled = ? WHERE pubkey = ? AND device_token = ?",
+            "UPDATE user_info SET zap_notifications_enabled = ?, mention_notifications_enabled = ?, repost_notifications_enabled = ?, reaction_notifications_enabled = ?, dm_notifications_enabled = ?, only_notifications_from_following_enabled = ?, strangers_to_requests_folder_enabled = ?, dm_only_from_following_enabled = ?, weekly_summary_enabled = ?, sensitive_content_filter = ?, mention_min_proof_of_work = ?, delivery_window = ? WHERE pubkey = ? AND device_token = ?",
             params![
                 settings.zap_notifications_enabled,
                 settings.mention_notifications_enabled,
@@ -510,6 +4033,12 @@ impl NotificationManager {
                 settings.reaction_notifications_enabled,
                 settings.dm_notifications_enabled,
                 settings.only_notifications_from_following_enabled,
+                settings.strangers_to_requests_folder_enabled,
+                settings.dm_only_from_following_enabled,
+                settings.weekly_summary_enabled,
+                settings.sensitive_content_filter.as_sql_str(),
+                settings.mention_min_proof_of_work,
+                settings.delivery_window.as_ref().and_then(|delivery_window| serde_json::to_string(delivery_window).ok()),
                 pubkey.to_sql_string(),
                 device_token,
             ],
@@ -518,21 +4047,625 @@ impl NotificationManager {
     }
 }
 
-#[derive(Serialize, Deserialize, Debug)]
+/// The version of the settings schema, bumped whenever settings are added or their meaning changes
+const USER_NOTIFICATION_SETTINGS_VERSION: u32 = 7;
+
+/// The notification settings of a device.
+/// Missing fields fall back to their defaults and unknown fields are ignored, so that clients that are older or newer than the server can interoperate.
+#[derive(Serialize, Deserialize, Debug, Clone)]
+#[serde(default)]
 pub struct UserNotificationSettings {
-    zap_notifications_enabled: bool,
-    mention_notifications_enabled: bool,
-    repost_notifications_enabled: bool,
-    reaction_notifications_enabled: bool,
-    dm_notifications_enabled: bool,
-    only_notifications_from_following_enabled: bool
+    // Always reports the server's schema version, regardless of what the client sent
+    #[serde(skip_deserializing)]
+    settings_version: u32,
+    pub zap_notifications_enabled: bool,
+    pub mention_notifications_enabled: bool,
+    pub repost_notifications_enabled: bool,
+    pub reaction_notifications_enabled: bool,
+    pub dm_notifications_enabled: bool,
+    pub only_notifications_from_following_enabled: bool,
+    // Notifications from strangers (neither following nor followed) are flagged for a separate "requests" folder
+    pub strangers_to_requests_folder_enabled: bool,
+    // Only notify about NIP-04 DMs from pubkeys the recipient follows
+    pub dm_only_from_following_enabled: bool,
+    // Opt-in weekly push summarizing the mentions and zaps of the past week
+    pub weekly_summary_enabled: bool,
+    pub sensitive_content_filter: SensitiveContentFilter,
+    // The minimum NIP-13 proof-of-work difficulty of text notes from pubkeys the recipient does not follow. 0 disables it
+    pub mention_min_proof_of_work: u8,
+    // Holds back some kinds outside of a daily window. `None` delivers every kind right away
+    pub delivery_window: Option<DeliveryWindow>,
+}
+
+impl Default for UserNotificationSettings {
+    // Keep in sync with the column defaults in `setup_database`
+    fn default() -> Self {
+        UserNotificationSettings {
+            settings_version: USER_NOTIFICATION_SETTINGS_VERSION,
+            zap_notifications_enabled: true,
+            mention_notifications_enabled: true,
+            repost_notifications_enabled: true,
+            reaction_notifications_enabled: true,
+            dm_notifications_enabled: true,
+            only_notifications_from_following_enabled: false,
+            strangers_to_requests_folder_enabled: false,
+            dm_only_from_following_enabled: false,
+            weekly_summary_enabled: false,
+            sensitive_content_filter: SensitiveContentFilter::Show,
+            mention_min_proof_of_work: 0,
+            delivery_window: None,
+        }
+    }
+}
+
+impl UserNotificationSettings {
+    /// Whether some of the settings depend on whom the user follows
+    fn uses_follow_list(&self) -> bool {
+        self.only_notifications_from_following_enabled || self.dm_only_from_following_enabled || self.mention_min_proof_of_work > 0
+    }
+}
+
+/// A daily window outside of which some kinds of notifications are held back, to be delivered as a digest once it opens
+#[derive(Serialize, Deserialize, Debug, Clone, PartialEq)]
+pub struct DeliveryWindow {
+    // Minutes after local midnight. A window that ends before it starts spans midnight, and one that ends when it starts is always open
+    pub start_minute: u16,
+    pub end_minute: u16,
+    // The offset of the device's local time from UTC
+    #[serde(default)]
+    pub utc_offset_minutes: i16,
+    // The kinds held back outside of the window. Kinds that are not deferrable (DMs and zaps) are always delivered right away
+    pub kinds: Vec<NotificationKind>,
+}
+
+impl DeliveryWindow {
+    pub fn defers(&self, notification_kind: NotificationKind) -> bool {
+        notification_kind.is_deferrable() && self.kinds.contains(&notification_kind)
+    }
+
+    pub fn is_open_at(&self, timestamp: Timestamp) -> bool {
+        let local_minute = (timestamp.as_u64() as i64 / 60 + self.utc_offset_minutes as i64).rem_euclid(MINUTES_PER_DAY);
+        let (start_minute, end_minute) = (self.start_minute as i64 % MINUTES_PER_DAY, self.end_minute as i64 % MINUTES_PER_DAY);
+        if start_minute <= end_minute {
+            start_minute == end_minute || (start_minute..end_minute).contains(&local_minute)
+        } else {
+            local_minute >= start_minute || local_minute < end_minute
+        }
+    }
+}
+
+/// What to do with notifications about sensitive content (events with a content warning or a sensitive hashtag)
+#[derive(Serialize, Deserialize, Debug, Clone, Copy, PartialEq)]
+#[serde(rename_all = "snake_case")]
+pub enum SensitiveContentFilter {
+    Show,
+    // Notify, but without showing the content
+    Blank,
+    // Do not notify at all
+    Suppress,
+}
+
+impl SensitiveContentFilter {
+    fn as_sql_str(&self) -> &'static str {
+        match self {
+            SensitiveContentFilter::Show => "show",
+            SensitiveContentFilter::Blank => "blank",
+            SensitiveContentFilter::Suppress => "suppress",
+        }
+    }
+
+    fn from_sql_str(value: &str) -> Option<Self> {
+        match value {
+            "show" => Some(SensitiveContentFilter::Show),
+            "blank" => Some(SensitiveContentFilter::Blank),
+            "suppress" => Some(SensitiveContentFilter::Suppress),
+            _ => None,
+        }
+    }
+}
+
+/// What the flood guard does with an event that would notify more pubkeys than its threshold
+#[derive(Debug, Clone, Copy, PartialEq)]
+pub enum FloodGuardMode {
+    // Send low-priority pushes with a collapse ID
+    Degrade,
+    // Hold the event until an operator approves or discards it
+    Hold,
+}
+
+impl FloodGuardMode {
+    pub fn parse(value: &str) -> Option<Self> {
+        match value {
+            "degrade" => Some(FloodGuardMode::Degrade),
+            "hold" => Some(FloodGuardMode::Hold),
+            _ => None,
+        }
+    }
+}
+
+/// An event held back by the flood guard
+#[derive(Serialize, Debug)]
+pub struct HeldEvent {
+    event_id: String,
+    kind: u16,
+    author: String,
+    // The number of pubkeys it would have notified when it was held
+    recipient_count: u64,
+    held_at: u64,
+}
+
+/// An admin API request or destructive user action
+#[derive(Serialize, Debug)]
+pub struct AuditLogEntry {
+    id: i64,
+    actor_pubkey: String,
+    method: String,
+    path: String,
+    status: u16,
+    // The `X-Request-Id` of the request, or the one generated for it
+    request_id: String,
+    created_at: u64,
+}
+
+/// A hashtag a pubkey wants to be notified about
+#[derive(Serialize, Deserialize, Debug, Clone)]
+pub struct HashtagSubscription {
+    pub hashtag: String,
+    #[serde(default)]
+    pub scope: HashtagScope,
+}
+
+impl HashtagSubscription {
+    pub fn normalized_hashtag(&self) -> String {
+        self.hashtag.trim().trim_start_matches('#').to_lowercase()
+    }
+}
+
+/// Whose events with a subscribed hashtag trigger notifications
+#[derive(Serialize, Deserialize, Debug, Clone, Copy, PartialEq, Default)]
+#[serde(rename_all = "snake_case")]
+pub enum HashtagScope {
+    // Only the pubkeys the subscriber follows
+    #[default]
+    Following,
+    // Anyone
+    Global,
+}
+
+impl HashtagScope {
+    fn as_sql_str(&self) -> &'static str {
+        match self {
+            HashtagScope::Following => "following",
+            HashtagScope::Global => "global",
+        }
+    }
+
+    fn from_sql_str(value: &str) -> Option<Self> {
+        match value {
+            "following" => Some(HashtagScope::Following),
+            "global" => Some(HashtagScope::Global),
+            _ => None,
+        }
+    }
+}
+
+/// Why a pubkey is notified about an event
+#[derive(Serialize, Debug, Clone, Copy, PartialEq)]
+#[serde(rename_all = "snake_case")]
+pub enum NotificationReason {
+    // The event tags the pubkey directly (e.g. a mention, reply, reaction, zap or DM)
+    Mention,
+    // The pubkey was notified about an event this one references, i.e. it participates in the thread
+    Thread,
+    // The pubkey subscribed to one of the event's hashtags
+    Hashtag,
+    // The event is the pubkey's own and of a silent push kind, so that their other devices sync it
+    OwnEvent,
+}
+
+impl NotificationReason {
+    fn as_str(&self) -> &'static str {
+        match self {
+            NotificationReason::Mention => "mention",
+            NotificationReason::Thread => "thread",
+            NotificationReason::Hashtag => "hashtag",
+            NotificationReason::OwnEvent => "own_event",
+        }
+    }
+
+    fn parse(value: &str) -> Option<Self> {
+        match value {
+            "mention" => Some(NotificationReason::Mention),
+            "thread" => Some(NotificationReason::Thread),
+            "hashtag" => Some(NotificationReason::Hashtag),
+            "own_event" => Some(NotificationReason::OwnEvent),
+            _ => None,
+        }
+    }
+}
+
+/// A recently notified zap, remembered to recognize its counterpart or a repeated receipt
+struct RecentZap {
+    kind: Kind,
+    // The payment hash of the receipt's invoice. `None` for zap private messages
+    payment_hash: Option<String>,
+    seen_at: Timestamp,
+    // Whether its counterpart was already seen, so that it is not paired with another zap
+    is_paired: bool,
+}
+
+/// The recipient's relationship and history with the author of an event, which are the same for all of their devices
+struct AuthorContext {
+    // `None` if the author is not known (e.g. gift wraps) or the push is silent
+    relationship: Option<Relationship>,
+    // The event is the first one from its author the recipient is notified about
+    is_new_conversation: bool,
+}
+
+/// The result of notifying one device about an event
+struct DeviceNotification {
+    // The size of the payload, for checking it against the APNS limit
+    payload_size: usize,
+    // Whether APNS or the webhook accepted the notification
+    was_delivered: bool,
+    // Whether it failed in a way that may succeed if sent again later
+    is_retryable: bool,
+}
+
+/// A notification that was claimed but not finished yet, as stored
+struct PendingNotification {
+    id: String,
+    pubkey: String,
+    // The event as JSON
+    event: String,
+    reason: Option<String>,
+    claimed_at: i64,
+    attempts: i64,
+}
+
+/// The result of notifying all devices of a pubkey about an event
+#[derive(Debug, Clone, Copy, PartialEq)]
+enum PubkeyDelivery {
+    // At least one device accepted it
+    Delivered,
+    // No device accepted it, and sending it again would not change that (e.g. the settings filtered it out)
+    NotDelivered,
+    // No device accepted it, but at least one failed in a way that may succeed later
+    Retryable,
+}
+
+/// What the recipient's settings for a device say about a notification
+#[derive(Debug, Clone, Copy, PartialEq)]
+enum SettingsDecision {
+    Deliver,
+    // Held back until the delivery window opens
+    Defer(NotificationKind),
+    SensitiveContentSuppressed,
+    AuthorNotFollowed,
+    DmAuthorNotFollowed,
+    InsufficientProofOfWork,
+    KindDisabled,
+    UnsupportedKind,
+}
+
+impl SettingsDecision {
+    fn as_str(&self) -> &'static str {
+        match self {
+            SettingsDecision::Deliver => "deliver",
+            SettingsDecision::Defer(_) => "defer_until_delivery_window",
+            SettingsDecision::SensitiveContentSuppressed => "sensitive_content_suppressed",
+            SettingsDecision::AuthorNotFollowed => "author_not_followed",
+            SettingsDecision::DmAuthorNotFollowed => "dm_author_not_followed",
+            SettingsDecision::InsufficientProofOfWork => "insufficient_proof_of_work",
+            SettingsDecision::KindDisabled => "kind_disabled",
+            SettingsDecision::UnsupportedKind => "unsupported_kind",
+        }
+    }
+}
+
+/// The decisions the notification pipeline would make about an event for one recipient
+#[derive(Serialize, Debug)]
+pub struct NotificationTrace {
+    event_id: String,
+    recipient: String,
+    // Whether every step passed, on at least one device
+    would_notify: bool,
+    steps: Vec<TraceStep>,
+    devices: Vec<DeviceTrace>,
+}
+
+#[derive(Serialize, Debug)]
+struct DeviceTrace {
+    device_token: String,
+    steps: Vec<TraceStep>,
+}
+
+#[derive(Serialize, Debug)]
+struct TraceStep {
+    check: &'static str,
+    passed: bool,
+    #[serde(skip_serializing_if = "Option::is_none")]
+    detail: Option<String>,
+}
+
+impl TraceStep {
+    fn new(check: &'static str, passed: bool, detail: Option<String>) -> Self {
+        TraceStep { check, passed, detail }
+    }
+}
+
+/// How the author of an event relates to the recipient of its notification, in the follow graph
+#[derive(Serialize, Debug, Clone, Copy, PartialEq)]
+#[serde(rename_all = "snake_case")]
+pub enum Relationship {
+    MutualFollow,
+    // The recipient follows the author
+    Following,
+    // The author follows the recipient
+    Follower,
+    Stranger,
+}
+
+impl Relationship {
+    /// The relevance score for the notification summary (0 to 1), higher for closer relationships
+    pub fn relevance_score(&self) -> f64 {
+        match self {
+            Relationship::MutualFollow => 1.0,
+            Relationship::Following => 0.75,
+            Relationship::Follower => 0.5,
+            Relationship::Stranger => 0.25,
+        }
+    }
+
+    /// Notifications from strangers are not urgent, so the push provider may batch them to save power
+    pub fn push_priority(&self) -> PushPriority {
+        match self {
+            Relationship::Stranger => PushPriority::Normal,
+            _ => PushPriority::High,
+        }
+    }
+}
+
+/// Information about the device and app, reported by the client at registration
+#[derive(Serialize, Deserialize, Debug, Default, Clone)]
+pub struct DeviceMetadata {
+    pub locale: Option<String>,
+    pub app_version: Option<String>,
+    pub os_version: Option<String>,
+}
+
+/// A registered device of an account, with everything its settings screen shows
+#[derive(Serialize, Debug)]
+pub struct AccountDevice {
+    device_token: String,
+    added_at: Option<u64>,
+    last_notified_at: Option<u64>,
+    payload_version: u32,
+    #[serde(flatten)]
+    metadata: DeviceMetadata,
+    settings: UserNotificationSettings,
+    // The pubkeys whose events do not notify this device
+    linked_pubkeys: Vec<String>,
+}
+
+/// What goes into the notification decisions for a pubkey, for debugging
+#[derive(Serialize, Debug)]
+pub struct UserDebugInfo {
+    pubkey: String,
+    cache: PubkeyCacheStatus,
+    devices: Vec<AccountDevice>,
+    // The most recent first
+    recent_deliveries: Vec<DeliveryAttempt>,
+}
+
+/// A push sent to APNS, and how APNS answered
+#[derive(Serialize, Debug)]
+struct DeliveryAttempt {
+    event_id: String,
+    device_token: String,
+    apns_id: Option<String>,
+    status: Option<u16>,
+    reason: Option<String>,
+    success: bool,
+    sent_at: u64,
+}
+
+/// One device of a batch registration
+#[derive(Deserialize, Debug, Clone)]
+pub struct DeviceRegistration {
+    pub device_token: String,
+    // APNS unless set
+    #[serde(default)]
+    pub token_type: Option<TokenType>,
+    // The settings to restore. Existing settings are kept if unset
+    #[serde(default)]
+    pub settings: Option<UserNotificationSettings>,
+    #[serde(default)]
+    pub payload_version: Option<u32>,
+    #[serde(flatten)]
+    pub metadata: DeviceMetadata,
+}
+
+impl DeviceMetadata {
+    pub fn is_empty(&self) -> bool {
+        self.locale.is_none() && self.app_version.is_none() && self.os_version.is_none()
+    }
+}
+
+/// What this server build supports
+#[derive(Serialize, Debug)]
+pub struct ServerCapabilities {
+    settings_version: u32,
+    // The push payload layouts a device can ask for at registration
+    payload_versions: Vec<u32>,
+    notification_kinds: Vec<NotificationKindCapability>,
+    digests: Vec<DigestCapability>,
+    // Custom notification sounds. None are offered yet, so pushes use the app's default alert
+    sounds: Vec<String>,
+    sensitive_content_filters: Vec<SensitiveContentFilter>,
+    max_hashtag_subscriptions: usize,
+    hashtag_scopes: Vec<HashtagScope>,
+}
+
+#[derive(Serialize, Debug)]
+struct NotificationKindCapability {
+    kind: NotificationKind,
+    event_kinds: Vec<u16>,
+    // The setting that enables this kind of notification
+    setting: &'static str,
+    // Whether this kind wakes the app with a silent push instead of showing a notification
+    silent: bool,
+}
+
+/// A periodic summary a device can opt into
+#[derive(Serialize, Debug)]
+struct DigestCapability {
+    digest: &'static str,
+    setting: &'static str,
+}
+
+#[derive(Clone)]
+struct DeliveryOutcome {
+    apns_id: Option<String>,
+    status: Option<u16>,
+    reason: Option<String>,
+    success: bool,
+    latency_ms: i64,
+}
+
+#[derive(Serialize, Debug)]
+pub struct DeliveryStats {
+    since: u64,
+    total: i64,
+    successes: i64,
+    failures: i64,
+    failure_reasons: std::collections::HashMap<String, i64>,
+    failing_device_tokens: Vec<DeviceTokenDeliveryStats>,
+}
+
+#[derive(Serialize, Debug)]
+pub struct DeviceTokenDeliveryStats {
+    device_token: String,
+    attempts: i64,
+    failures: i64,
+}
+
+#[derive(Serialize, Debug, Default)]
+pub struct DeliveryDailySummary {
+    day: u64,
+    topic: String,
+    total: i64,
+    successes: i64,
+    p95_latency_ms: Option<i64>,
+    failure_reasons: std::collections::HashMap<String, i64>,
+}
+
+/// The notifications a pubkey received over the past week
+#[derive(Serialize, Debug)]
+struct WeeklySummary {
+    mention_count: u64,
+    zap_count: u64,
+    zap_amount_msats: u64,
+}
+
+impl WeeklySummary {
+    fn is_empty(&self) -> bool {
+        self.mention_count == 0 && self.zap_count == 0
+    }
+}
+
+/// The notifications held back outside of a device's delivery window
+#[derive(Serialize, Debug)]
+struct DeferredDigest {
+    counts: Vec<DeferredKindCount>,
+}
+
+#[derive(Serialize, Debug)]
+struct DeferredKindCount {
+    kind: NotificationKind,
+    count: u64,
+}
+
+impl DeferredDigest {
+    fn is_empty(&self) -> bool {
+        self.counts.is_empty()
+    }
+
+    fn total_count(&self) -> u64 {
+        self.counts.iter().map(|kind_count| kind_count.count).sum()
+    }
+}
+
+/// How hard a WAL checkpoint tries, see https://www.sqlite.org/pragma.html#pragma_wal_checkpoint
+#[derive(Deserialize, Debug, Clone, Copy, Default)]
+#[serde(rename_all = "snake_case")]
+pub enum WalCheckpointMode {
+    // Checkpoints as much as possible without waiting for readers or writers
+    #[default]
+    Passive,
+    // Waits for writers, then checkpoints everything
+    Full,
+    // Like `Full`, and also waits for readers so that the next writer restarts the log from the beginning
+    Restart,
+    // Like `Restart`, and also truncates the log file to zero bytes
+    Truncate,
+}
+
+impl WalCheckpointMode {
+    fn as_sql(&self) -> &'static str {
+        match self {
+            WalCheckpointMode::Passive => "PASSIVE",
+            WalCheckpointMode::Full => "FULL",
+            WalCheckpointMode::Restart => "RESTART",
+            WalCheckpointMode::Truncate => "TRUNCATE",
+        }
+    }
+}
+
+#[derive(Serialize, Debug)]
+pub struct WalCheckpointResult {
+    // Whether the checkpoint could not complete because of other connections
+    busy: bool,
+    // The number of pages in the log, and how many of them were moved into the database file (-1 if not in WAL mode)
+    wal_pages: i64,
+    checkpointed_pages: i64,
+}
+
+/// A deterministic partition of recipient pubkeys, so that large deployments can split the work across instances
+pub struct RecipientShard {
+    count: u64,
+    index: u64,
+}
+
+impl RecipientShard {
+    pub fn new(count: u64, index: u64) -> Option<Self> {
+        if count == 0 || index >= count {
+            return None;
+        }
+        Some(RecipientShard { count, index })
+    }
+
+    /// Checks if the pubkey belongs to this shard.
+    /// Pubkeys are uniformly distributed, so their leading bytes can be used directly as a stable hash across instances.
+    pub fn contains(&self, pubkey: &PublicKey) -> bool {
+        let bytes = pubkey.to_bytes();
+        let mut leading_bytes = [0u8; 8];
+        leading_bytes.copy_from_slice(&bytes[..8]);
+        u64::from_be_bytes(leading_bytes) % self.count == self.index
+    }
 }
 
 struct NotificationStatus {
     status_info: std::collections::HashMap<PublicKey, bool>,
+    // The pubkeys that were notified about any of the events referenced by the event
+    subscribed_pubkeys: HashSet<PublicKey>,
 }
 
 impl NotificationStatus {
+    fn pubkeys_subscribed_to_referenced_events(&self) -> HashSet<PublicKey> {
+        self.subscribed_pubkeys.clone()
+    }
+
     fn pubkeys_that_received_notification(&self) -> HashSet<PublicKey> {
         self.status_info
             .iter()
@@ -541,3 +4674,228 @@ impl NotificationStatus {
             .collect()
     }
 }
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use nostr::{EventBuilder, Keys};
+
+    fn test_database() -> rusqlite::Connection {
+        let connection = rusqlite::Connection::open_in_memory().unwrap();
+        NotificationManager::setup_database(&connection).unwrap();
+        connection
+    }
+
+    fn text_note() -> Event {
+        EventBuilder::text_note("Hello", []).to_event(&Keys::generate()).unwrap()
+    }
+
+    fn is_pending(connection: &rusqlite::Connection, id: &str) -> bool {
+        connection
+            .query_row("SELECT pending_event IS NOT NULL FROM notifications WHERE id = ?", [id], |row| row.get(0))
+            .unwrap()
+    }
+
+    fn received_notification(connection: &rusqlite::Connection, id: &str) -> bool {
+        connection
+            .query_row("SELECT received_notification FROM notifications WHERE id = ?", [id], |row| row.get(0))
+            .unwrap()
+    }
+
+    // MARK: - Pending notifications
+
+    #[test]
+    fn notification_is_claimed_once() {
+        let connection = test_database();
+        let (event, pubkey) = (text_note(), Keys::generate().public_key());
+        let now = Timestamp::from(1_000);
+
+        assert!(NotificationManager::insert_pending_notification(&connection, &event, &pubkey, NotificationReason::Mention, now).unwrap());
+        assert!(!NotificationManager::insert_pending_notification(&connection, &event, &pubkey, NotificationReason::Mention, now).unwrap());
+    }
+
+    #[test]
+    fn notification_claimed_before_a_crash_is_redriven_once() {
+        let connection = test_database();
+        let (event, pubkey) = (text_note(), Keys::generate().public_key());
+        NotificationManager::insert_pending_notification(&connection, &event, &pubkey, NotificationReason::Reply, Timestamp::from(1_000)).unwrap();
+
+        let pending_notifications = NotificationManager::pending_notification_rows(&connection, 0, 2_000).unwrap();
+        assert_eq!(pending_notifications.len(), 1);
+        let pending_notification = &pending_notifications[0];
+        assert_eq!(pending_notification.id, NotificationManager::notification_id(&event, &pubkey));
+        assert_eq!(pending_notification.pubkey, pubkey.to_hex());
+        assert_eq!(Event::from_json(&pending_notification.event).unwrap().id, event.id);
+        assert_eq!(pending_notification.reason.as_deref(), Some("reply"));
+        assert_eq!((pending_notification.claimed_at, pending_notification.attempts), (1_000, 1));
+
+        // Only one of the instances re-driving it takes it
+        let id = &pending_notification.id;
+        assert!(NotificationManager::take_pending_notification_row(&connection, id, 1_000, Timestamp::from(3_000)).unwrap());
+        assert!(!NotificationManager::take_pending_notification_row(&connection, id, 1_000, Timestamp::from(3_000)).unwrap());
+
+        let pending_notifications = NotificationManager::pending_notification_rows(&connection, 0, 4_000).unwrap();
+        assert_eq!((pending_notifications[0].claimed_at, pending_notifications[0].attempts), (3_000, 2));
+    }
+
+    #[test]
+    fn notifications_are_only_redriven_when_claimed_in_the_window() {
+        let connection = test_database();
+        let (event, pubkey) = (text_note(), Keys::generate().public_key());
+        NotificationManager::insert_pending_notification(&connection, &event, &pubkey, NotificationReason::Mention, Timestamp::from(1_000)).unwrap();
+
+        // Claimed too recently, so another instance may still be sending it
+        assert!(NotificationManager::pending_notification_rows(&connection, 0, 999).unwrap().is_empty());
+        // Claimed before the expiry cutoff
+        assert!(NotificationManager::pending_notification_rows(&connection, 1_001, 2_000).unwrap().is_empty());
+    }
+
+    #[test]
+    fn notifications_claimed_before_the_expiry_cutoff_are_finished_without_sending() {
+        let connection = test_database();
+        let pubkey = Keys::generate().public_key();
+        let (old_event, new_event) = (text_note(), text_note());
+        NotificationManager::insert_pending_notification(&connection, &old_event, &pubkey, NotificationReason::Mention, Timestamp::from(1_000)).unwrap();
+        NotificationManager::insert_pending_notification(&connection, &new_event, &pubkey, NotificationReason::Mention, Timestamp::from(2_000)).unwrap();
+
+        assert_eq!(NotificationManager::expire_pending_notification_rows(&connection, 1_500).unwrap(), 1);
+
+        let old_id = NotificationManager::notification_id(&old_event, &pubkey);
+        assert!(!is_pending(&connection, &old_id));
+        assert!(!received_notification(&connection, &old_id));
+        assert!(is_pending(&connection, &NotificationManager::notification_id(&new_event, &pubkey)));
+    }
+
+    #[test]
+    fn retryable_failures_leave_the_notification_pending() {
+        let connection = test_database();
+        let (event, pubkey) = (text_note(), Keys::generate().public_key());
+        let id = NotificationManager::notification_id(&event, &pubkey);
+        NotificationManager::insert_pending_notification(&connection, &event, &pubkey, NotificationReason::Mention, Timestamp::from(1_000)).unwrap();
+
+        assert!(!NotificationManager::settle_notification_row(&connection, &id, PubkeyDelivery::Retryable).unwrap());
+        assert!(is_pending(&connection, &id));
+    }
+
+    #[test]
+    fn permanent_failures_finish_the_notification_as_not_received() {
+        let connection = test_database();
+        let (event, pubkey) = (text_note(), Keys::generate().public_key());
+        let id = NotificationManager::notification_id(&event, &pubkey);
+        NotificationManager::insert_pending_notification(&connection, &event, &pubkey, NotificationReason::Mention, Timestamp::from(1_000)).unwrap();
+
+        assert!(NotificationManager::settle_notification_row(&connection, &id, PubkeyDelivery::NotDelivered).unwrap());
+        assert!(!is_pending(&connection, &id));
+        assert!(!received_notification(&connection, &id));
+    }
+
+    #[test]
+    fn deliveries_finish_the_notification_as_received() {
+        let connection = test_database();
+        let (event, pubkey) = (text_note(), Keys::generate().public_key());
+        let id = NotificationManager::notification_id(&event, &pubkey);
+        NotificationManager::insert_pending_notification(&connection, &event, &pubkey, NotificationReason::Mention, Timestamp::from(1_000)).unwrap();
+
+        assert!(NotificationManager::settle_notification_row(&connection, &id, PubkeyDelivery::Delivered).unwrap());
+        assert!(!is_pending(&connection, &id));
+        assert!(received_notification(&connection, &id));
+    }
+
+    // MARK: - Deferred digests
+
+    fn defer_notification(connection: &rusqlite::Connection, event_id: &str, notification_kind: NotificationKind) {
+        connection
+            .execute(
+                "INSERT INTO deferred_notifications (pubkey, device_token, event_id, notification_kind, deferred_at) VALUES ('pubkey', 'token', ?, ?, 0)",
+                params![event_id, notification_kind.as_sql_str()],
+            )
+            .unwrap();
+    }
+
+    fn claim_deferred_notifications(connection: &mut rusqlite::Connection, claim_id: &str, now: u64) -> Vec<String> {
+        NotificationManager::claim_deferred_notifications(connection, "pubkey", "token", claim_id, Timestamp::from(now)).unwrap()
+    }
+
+    #[test]
+    fn deferred_digest_counts_the_notifications_of_each_kind() {
+        let notification_kinds = [NotificationKind::Reaction, NotificationKind::Mention, NotificationKind::Reaction]
+            .map(|notification_kind| notification_kind.as_sql_str().to_string());
+
+        let digest = NotificationManager::deferred_digest(&notification_kinds);
+
+        let count_of = |kind: NotificationKind| digest.counts.iter().find(|kind_count| kind_count.kind == kind).map(|kind_count| kind_count.count);
+        assert_eq!(count_of(NotificationKind::Reaction), Some(2));
+        assert_eq!(count_of(NotificationKind::Mention), Some(1));
+        assert_eq!(digest.counts.len(), 2);
+    }
+
+    #[test]
+    fn deferred_notifications_are_kept_until_their_digest_is_delivered() {
+        let mut connection = test_database();
+        defer_notification(&connection, "a", NotificationKind::Mention);
+        defer_notification(&connection, "b", NotificationKind::Reaction);
+
+        assert_eq!(claim_deferred_notifications(&mut connection, "first", 1_000).len(), 2);
+        // Another sender does not get them while they are claimed
+        assert!(claim_deferred_notifications(&mut connection, "second", 1_001).is_empty());
+
+        NotificationManager::settle_deferred_notifications(&connection, "first", false).unwrap();
+        assert_eq!(claim_deferred_notifications(&mut connection, "third", 1_002).len(), 2);
+
+        NotificationManager::settle_deferred_notifications(&connection, "third", true).unwrap();
+        assert!(claim_deferred_notifications(&mut connection, "fourth", 1_003).is_empty());
+    }
+
+    #[test]
+    fn abandoned_deferred_digest_claims_are_taken_over() {
+        let mut connection = test_database();
+        defer_notification(&connection, "a", NotificationKind::Mention);
+
+        assert_eq!(claim_deferred_notifications(&mut connection, "crashed", 1_000).len(), 1);
+        assert!(claim_deferred_notifications(&mut connection, "second", 1_000 + DEFERRED_DIGEST_CLAIM_TIMEOUT_SECONDS).is_empty());
+        assert_eq!(claim_deferred_notifications(&mut connection, "third", 1_001 + DEFERRED_DIGEST_CLAIM_TIMEOUT_SECONDS).len(), 1);
+    }
+
+    #[test]
+    fn deferred_notifications_are_given_up_on_after_too_many_attempts() {
+        let mut connection = test_database();
+        defer_notification(&connection, "a", NotificationKind::Mention);
+
+        for attempt in 0..MAX_NOTIFICATION_ATTEMPTS {
+            let claim_id = format!("attempt-{}", attempt);
+            assert_eq!(claim_deferred_notifications(&mut connection, &claim_id, 1_000).len(), 1);
+            NotificationManager::settle_deferred_notifications(&connection, &claim_id, false).unwrap();
+        }
+
+        assert!(claim_deferred_notifications(&mut connection, "last", 1_000).is_empty());
+        let remaining_count: i64 = connection.query_row("SELECT COUNT(*) FROM deferred_notifications", [], |row| row.get(0)).unwrap();
+        assert_eq!(remaining_count, 0);
+    }
+
+    // MARK: - Recipient shards
+
+    #[test]
+    fn recipient_shard_rejects_invalid_partitions() {
+        assert!(RecipientShard::new(0, 0).is_none());
+        assert!(RecipientShard::new(2, 2).is_none());
+        assert!(RecipientShard::new(1, 0).is_some());
+    }
+
+    #[test]
+    fn every_pubkey_belongs_to_exactly_one_shard() {
+        let shards: Vec<RecipientShard> = (0..4).map(|index| RecipientShard::new(4, index).unwrap()).collect();
+        for _ in 0..100 {
+            let pubkey = Keys::generate().public_key();
+            assert_eq!(shards.iter().filter(|shard| shard.contains(&pubkey)).count(), 1);
+        }
+    }
+
+    #[test]
+    fn recipient_shard_uses_the_leading_bytes_of_the_pubkey() {
+        // The x coordinate of the secp256k1 generator, whose leading bytes are 0x79be667ef9dcbbac
+        let pubkey = PublicKey::from_hex("79be667ef9dcbbac55a06295ce870b07029bfcdb2dce28d959f2815b16f81798").unwrap();
+        assert!(RecipientShard::new(3, 2).unwrap().contains(&pubkey));
+        assert!(RecipientShard::new(4, 0).unwrap().contains(&pubkey));
+        assert!(!RecipientShard::new(4, 1).unwrap().contains(&pubkey));
+    }
+}
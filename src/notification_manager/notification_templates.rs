@@ -0,0 +1,146 @@
+use serde::Deserialize;
+use std::collections::HashMap;
+
+// The locale used when there is no template for the device's locale
+const DEFAULT_LOCALE: &str = "default";
+
+/// Operator-provided notification wording, keyed by locale and then by notification kind.
+///
+/// Example TOML file:
+/// ```toml
+/// [default.reaction]
+/// title = "New reaction"
+/// body = "{content}"
+///
+/// [de.reaction]
+/// title = "Neue Reaktion"
+/// ```
+///
+/// Any field left out of a template falls back to the built-in text.
+#[derive(Deserialize, Default, Debug)]
+pub struct NotificationTemplates {
+    #[serde(flatten)]
+    locales: HashMap<String, HashMap<String, NotificationTemplate>>,
+}
+
+#[derive(Deserialize, Default, Debug, Clone)]
+pub struct NotificationTemplate {
+    pub title: Option<String>,
+    pub subtitle: Option<String>,
+    pub body: Option<String>,
+}
+
+impl NotificationTemplates {
+    // MARK: - Initialization
+
+    pub fn load(path: &str) -> Result<Self, Box<dyn std::error::Error>> {
+        let contents = std::fs::read_to_string(path)?;
+        Ok(toml::from_str(&contents)?)
+    }
+
+    // MARK: - Template lookup
+
+    /// Finds the template for a kind, trying the exact locale (e.g. `pt-BR`), then its language (`pt`), then the default locale
+    pub fn get(&self, locale: Option<&str>, kind_key: &str) -> Option<&NotificationTemplate> {
+        let mut candidate_locales: Vec<String> = Vec::new();
+        if let Some(locale) = locale {
+            candidate_locales.push(locale.to_string());
+            if let Some(language) = locale.split(|c| c == '-' || c == '_').next() {
+                candidate_locales.push(language.to_string());
+            }
+        }
+        candidate_locales.push(DEFAULT_LOCALE.to_string());
+
+        candidate_locales
+            .iter()
+            .find_map(|locale| self.locales.get(locale)?.get(kind_key))
+    }
+}
+
+impl NotificationTemplate {
+    /// Replaces `{name}` placeholders in the template text with the given variables, in a single pass over the template,
+    /// so that placeholders inside the values (e.g. note content written by anyone) are left as they are.
+    /// Unknown placeholders are kept verbatim
+    pub fn render(text: &str, variables: &HashMap<&str, String>) -> String {
+        let mut rendered = String::with_capacity(text.len());
+        let mut remaining = text;
+        while let Some(start) = remaining.find('{') {
+            rendered.push_str(&remaining[..start]);
+            let placeholder = &remaining[start..];
+            let value = placeholder
+                .find('}')
+                .and_then(|end| Some((variables.get(&placeholder[1..end])?, end)));
+            match value {
+                Some((value, end)) => {
+                    rendered.push_str(value);
+                    remaining = &placeholder[end + 1..];
+                }
+                None => {
+                    rendered.push('{');
+                    remaining = &placeholder[1..];
+                }
+            }
+        }
+        rendered.push_str(remaining);
+        rendered
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn variables() -> HashMap<&'static str, String> {
+        HashMap::from([("name", "alice".to_string()), ("content", "{name} says hi".to_string())])
+    }
+
+    // MARK: - Rendering
+
+    #[test]
+    fn placeholders_are_replaced_with_their_variables() {
+        assert_eq!(NotificationTemplate::render("{name} reacted: {content}!", &variables()), "alice reacted: {name} says hi!");
+    }
+
+    #[test]
+    fn placeholders_inside_values_are_not_rendered() {
+        assert_eq!(NotificationTemplate::render("{content}", &variables()), "{name} says hi");
+    }
+
+    #[test]
+    fn unknown_and_unclosed_placeholders_are_kept() {
+        assert_eq!(NotificationTemplate::render("{unknown} {name} {", &variables()), "{unknown} alice {");
+        assert_eq!(NotificationTemplate::render("{{name}}", &variables()), "{alice}");
+    }
+
+    #[test]
+    fn text_without_placeholders_is_unchanged() {
+        assert_eq!(NotificationTemplate::render("New reaction", &variables()), "New reaction");
+    }
+
+    // MARK: - Template lookup
+
+    #[test]
+    fn templates_fall_back_from_the_locale_to_its_language_to_the_default() {
+        let templates: NotificationTemplates = toml::from_str(
+            r#"
+            [default.reaction]
+            title = "default"
+
+            [pt.reaction]
+            title = "pt"
+
+            [pt-BR.mention]
+            title = "pt-BR"
+            "#,
+        )
+        .unwrap();
+        let title = |locale, kind_key| templates.get(locale, kind_key).and_then(|template| template.title.clone());
+
+        assert_eq!(title(Some("pt-BR"), "mention").as_deref(), Some("pt-BR"));
+        assert_eq!(title(Some("pt-BR"), "reaction").as_deref(), Some("pt"));
+        assert_eq!(title(Some("pt_PT"), "reaction").as_deref(), Some("pt"));
+        assert_eq!(title(Some("de"), "reaction").as_deref(), Some("default"));
+        assert_eq!(title(None, "reaction").as_deref(), Some("default"));
+        assert_eq!(title(None, "mention"), None);
+    }
+}
@@ -0,0 +1,131 @@
+//! Guards the requests we make to URLs taken from untrusted input (profile metadata, webhooks, relay lists),
+//! so that they cannot reach this server's own network (SSRF), e.g. `127.0.0.1`, `169.254.169.254` or RFC 1918 hosts
+
+use reqwest::dns::{Addrs, Name, Resolve, Resolving};
+use std::net::{IpAddr, SocketAddr};
+use std::sync::Arc;
+
+// The most redirects followed for a request to a URL taken from untrusted input
+const MAX_REDIRECTS: usize = 5;
+
+// MARK: - Checks
+
+/// Checks if the address is reachable on the public internet, rather than loopback, private, link-local or otherwise reserved
+pub fn is_public_ip(ip: &IpAddr) -> bool {
+    match ip {
+        IpAddr::V4(ipv4) => {
+            let octets = ipv4.octets();
+            !(ipv4.is_loopback()
+                || ipv4.is_private()
+                || ipv4.is_link_local()
+                || ipv4.is_unspecified()
+                || ipv4.is_broadcast()
+                || ipv4.is_documentation()
+                || ipv4.is_multicast()
+                // Carrier-grade NAT (100.64.0.0/10)
+                || (octets[0] == 100 && (octets[1] & 0xc0) == 64)
+                // "This network" (0.0.0.0/8) and reserved (240.0.0.0/4)
+                || octets[0] == 0
+                || octets[0] >= 240)
+        }
+        IpAddr::V6(ipv6) => {
+            if let Some(ipv4) = ipv6.to_ipv4_mapped() {
+                return is_public_ip(&IpAddr::V4(ipv4));
+            }
+            let first_segment = ipv6.segments()[0];
+            !(ipv6.is_loopback()
+                || ipv6.is_unspecified()
+                || ipv6.is_multicast()
+                // Unique local (fc00::/7)
+                || (first_segment & 0xfe00) == 0xfc00
+                // Link-local (fe80::/10)
+                || (first_segment & 0xffc0) == 0xfe80)
+        }
+    }
+}
+
+/// Checks that the URL uses one of the schemes and that its host is not a non-public IP address or `localhost`.
+/// Host names are not resolved here, see `resolve_public_host`
+pub fn check_url(url: &str, allowed_schemes: &[&str]) -> Result<nostr::Url, String> {
+    let url = nostr::Url::parse(url).map_err(|_| "not a valid URL".to_string())?;
+    if !allowed_schemes.contains(&url.scheme()) {
+        return Err(format!("the URL must use {}", allowed_schemes.join(" or ")));
+    }
+    let host = match url.host_str() {
+        Some(host) if !host.is_empty() => host.trim_start_matches('[').trim_end_matches(']'),
+        _ => return Err("the URL has no host".to_string()),
+    };
+    let is_private = match host.parse::<IpAddr>() {
+        Ok(ip) => !is_public_ip(&ip),
+        Err(_) => is_localhost(host),
+    };
+    if is_private {
+        return Err("the URL must not point to a private address".to_string());
+    }
+    Ok(url)
+}
+
+/// Checks the URL like `check_url`, and that every address its host resolves to is public
+pub async fn resolve_public_host(url: &str, allowed_schemes: &[&str]) -> Result<nostr::Url, String> {
+    let url = check_url(url, allowed_schemes)?;
+    let (host, port) = match (url.host_str(), url.port_or_known_default()) {
+        (Some(host), Some(port)) => (host.trim_start_matches('[').trim_end_matches(']').to_string(), port),
+        _ => return Err("the URL has no host".to_string()),
+    };
+    let addresses: Vec<SocketAddr> = tokio::net::lookup_host((host.as_str(), port))
+        .await
+        .map_err(|e| format!("the URL's host cannot be resolved: {}", e))?
+        .collect();
+    if addresses.is_empty() {
+        return Err("the URL's host cannot be resolved".to_string());
+    }
+    if addresses.iter().any(|address| !is_public_ip(&address.ip())) {
+        return Err("the URL must not point to a private address".to_string());
+    }
+    Ok(url)
+}
+
+fn is_localhost(domain: &str) -> bool {
+    let domain = domain.trim_end_matches('.').to_ascii_lowercase();
+    domain == "localhost" || domain.ends_with(".localhost")
+}
+
+// MARK: - HTTP clients
+
+/// A client builder whose requests only ever connect to public addresses: host names are resolved with `PublicOnlyResolver`,
+/// which also covers DNS rebinding since it runs on every connection, and redirects must stay on public HTTPS URLs
+pub fn public_only_client_builder() -> reqwest::ClientBuilder {
+    reqwest::Client::builder()
+        .dns_resolver(Arc::new(PublicOnlyResolver))
+        .redirect(reqwest::redirect::Policy::custom(|attempt| {
+            if attempt.previous().len() >= MAX_REDIRECTS {
+                attempt.error("too many redirects")
+            } else if let Err(e) = check_url(attempt.url().as_str(), &["https"]) {
+                attempt.error(format!("refusing to follow redirect: {}", e))
+            } else {
+                attempt.follow()
+            }
+        }))
+}
+
+/// Resolves host names with the system resolver, failing if any of the addresses is not public
+struct PublicOnlyResolver;
+
+impl Resolve for PublicOnlyResolver {
+    fn resolve(&self, name: Name) -> Resolving {
+        Box::pin(Self::resolve_public_addresses(name.as_str().to_string()))
+    }
+}
+
+impl PublicOnlyResolver {
+    async fn resolve_public_addresses(host: String) -> Result<Addrs, Box<dyn std::error::Error + Send + Sync>> {
+        if is_localhost(&host) {
+            return Err(format!("refusing to connect to {}, it is not a public host", host).into());
+        }
+        let addresses: Vec<SocketAddr> = tokio::net::lookup_host((host.as_str(), 0)).await?.collect();
+        if addresses.iter().any(|address| !is_public_ip(&address.ip())) {
+            return Err(format!("refusing to connect to {}, it resolves to a private address", host).into());
+        }
+        Ok(Box::new(addresses.into_iter()))
+    }
+}
@@ -0,0 +1,181 @@
+use base64::prelude::*;
+use nostr::nips::nip19::{Nip19Event, Nip19Profile, ToBech32};
+use nostr::Event;
+use nostr_sdk::JsonUtil;
+use serde::Serialize;
+use serde_json::{json, Value};
+
+use super::notification_kind::NotificationKind;
+use super::notification_manager::NotificationReason;
+use super::ExtendedEvent;
+
+/// The flat layout with a `nostr_event` JSON string, understood by every app build. Used for devices that did not ask for a version
+pub const LEGACY_PAYLOAD_VERSION: u32 = 1;
+/// The `notepush` envelope with typed fields
+pub const ENVELOPE_PAYLOAD_VERSION: u32 = 2;
+/// The envelope, with large events zstd-compressed into `event_zstd` instead of `event`
+pub const LATEST_PAYLOAD_VERSION: u32 = 3;
+// Events whose JSON is at least this large are compressed for devices that understand it. Smaller ones barely shrink, and are simpler to read as is
+const MIN_COMPRESSED_EVENT_SIZE: usize = 1024;
+// Favors speed, since every push is compressed on the hot path
+const EVENT_COMPRESSION_LEVEL: i32 = 3;
+// The maximum number of relay hints embedded in the `nevent` and `nprofile` identifiers, to keep the payload well under the APNS size limit
+const MAX_RELAY_HINTS_IN_BECH32_ENTITIES: usize = 3;
+
+/// The payload version used for a device asking for `requested_version` at registration: the newest one this server supports, up to the requested one
+pub fn negotiate_payload_version(requested_version: Option<u32>) -> u32 {
+    requested_version
+        .unwrap_or(LEGACY_PAYLOAD_VERSION)
+        .clamp(LEGACY_PAYLOAD_VERSION, LATEST_PAYLOAD_VERSION)
+}
+
+/// How much of the event a push carries, configured per kind (e.g. to keep DMs out of push payloads)
+#[derive(Debug, Clone, Copy, PartialEq)]
+pub enum EventInclusion {
+    // The full event JSON
+    Full,
+    // Only the event ID and its `nevent`, so that the app fetches the event itself
+    IdOnly,
+    // Nothing about the event but its ID, which the payload version 2 envelope always carries
+    None,
+}
+
+impl EventInclusion {
+    pub fn parse(value: &str) -> Option<Self> {
+        match value {
+            "full" => Some(EventInclusion::Full),
+            "id_only" => Some(EventInclusion::IdOnly),
+            "none" => Some(EventInclusion::None),
+            _ => None,
+        }
+    }
+}
+
+/// The envelope sent under the `notepush` key from payload version 2 on. New fields can be added freely, removing or changing one needs a new version
+#[derive(Serialize, Debug)]
+struct PushPayloadEnvelope {
+    payload_version: u32,
+    // `None` for silent pushes of kinds that never show a notification
+    kind: Option<NotificationKind>,
+    reason: NotificationReason,
+    event_id: String,
+    // Left out for anonymous zaps, since the zap receipt embeds the zap request and would reveal the zapper
+    event: Option<Event>,
+    // From payload version 3 on, large events are sent here instead of in `event`, as base64 of the zstd-compressed event JSON
+    #[serde(skip_serializing_if = "Option::is_none")]
+    event_zstd: Option<String>,
+    nevent: Option<String>,
+    author_nprofile: Option<String>,
+    relay_hints: Vec<String>,
+    zap: Option<ZapPayload>,
+}
+
+#[derive(Serialize, Debug)]
+struct ZapPayload {
+    anonymous: bool,
+    amount_msats: Option<u64>,
+}
+
+/// Builds the custom data sent along with the notification, in the layout of the given payload version
+pub fn payload_data(
+    event: &Event,
+    reason: NotificationReason,
+    relay_hints: &[String],
+    payload_version: u32,
+    event_inclusion: EventInclusion,
+) -> Result<Vec<(&'static str, Value)>, Box<dyn std::error::Error>> {
+    let (nevent, author_nprofile) = match event_inclusion {
+        EventInclusion::None => (None, None),
+        EventInclusion::Full | EventInclusion::IdOnly => bech32_entities(event, relay_hints),
+    };
+    let includes_event = event_inclusion == EventInclusion::Full && !event.is_anonymous_zap();
+    if payload_version >= ENVELOPE_PAYLOAD_VERSION {
+        let notification_kind = NotificationKind::from_event(event);
+        let is_zap = matches!(notification_kind, Some(NotificationKind::ZapPrivateMessage) | Some(NotificationKind::ZapReceipt));
+        let event_zstd = match includes_event && payload_version >= 3 {
+            true => compressed_event(event)?,
+            false => None,
+        };
+        let envelope = PushPayloadEnvelope {
+            payload_version,
+            kind: notification_kind,
+            reason,
+            event_id: event.id.to_hex(),
+            event: (includes_event && event_zstd.is_none()).then(|| event.clone()),
+            event_zstd,
+            nevent,
+            author_nprofile,
+            relay_hints: relay_hints.to_vec(),
+            zap: is_zap.then(|| ZapPayload {
+                anonymous: event.is_anonymous_zap(),
+                amount_msats: event.zap_amount_msats(),
+            }),
+        };
+        return Ok(vec![("notepush", serde_json::to_value(envelope)?)]);
+    }
+
+    // Lets the client route and render the notification according to why it was sent
+    let mut payload_data = vec![("reason", json!(reason))];
+    if let Some(nevent) = nevent {
+        payload_data.push(("nevent", Value::String(nevent)));
+    }
+    if let Some(author_nprofile) = author_nprofile {
+        payload_data.push(("author_nprofile", Value::String(author_nprofile)));
+    }
+    payload_data.push(("relay_hints", json!(relay_hints)));
+    if event.is_anonymous_zap() {
+        // The zap receipt embeds the zap request, so only send what the client needs without revealing the zapper
+        payload_data.extend([
+            ("anonymous_zap", Value::Bool(true)),
+            ("nostr_event_id", Value::String(event.id.to_hex())),
+            ("zap_amount_msats", json!(event.zap_amount_msats())),
+        ]);
+        return Ok(payload_data);
+    }
+    match event_inclusion {
+        EventInclusion::Full => payload_data.push(("nostr_event", Value::String(event.try_as_json()?))),
+        EventInclusion::IdOnly => payload_data.push(("nostr_event_id", Value::String(event.id.to_hex()))),
+        EventInclusion::None => {}
+    }
+    Ok(payload_data)
+}
+
+/// The event JSON compressed with zstd and encoded as base64, or `None` if the event is too small to be worth compressing
+fn compressed_event(event: &Event) -> Result<Option<String>, Box<dyn std::error::Error>> {
+    let event_json = event.try_as_json()?;
+    if event_json.len() < MIN_COMPRESSED_EVENT_SIZE {
+        return Ok(None);
+    }
+    let compressed_event_json = zstd::stream::encode_all(event_json.as_bytes(), EVENT_COMPRESSION_LEVEL)?;
+    Ok(Some(BASE64_STANDARD.encode(compressed_event_json)))
+}
+
+/// The NIP-19 `nevent` of the event and `nprofile` of its author, so that the notification service extension can deep-link without bech32 encoding on-device
+fn bech32_entities(event: &Event, relay_hints: &[String]) -> (Option<String>, Option<String>) {
+    let relays: Vec<String> = relay_hints.iter().take(MAX_RELAY_HINTS_IN_BECH32_ENTITIES).cloned().collect();
+    // The author of a gift wrap is a throwaway key, and the author of a zap receipt is the zapper's lightning provider
+    let has_meaningful_author = NotificationKind::from_event(event)
+        .map_or(true, |kind| kind.has_known_author() && kind != NotificationKind::ZapReceipt);
+    let mut nevent = Nip19Event::new(event.id, relays.clone()).kind(event.kind);
+    if has_meaningful_author {
+        nevent = nevent.author(event.pubkey);
+    }
+    let nevent = match nevent.to_bech32() {
+        Ok(nevent) => Some(nevent),
+        Err(e) => {
+            log::warn!("Failed to encode the nevent of event {}: {}", event.id, e);
+            None
+        }
+    };
+    if !has_meaningful_author {
+        return (nevent, None);
+    }
+    let author_nprofile = match Nip19Profile::new(event.pubkey, relays).to_bech32() {
+        Ok(nprofile) => Some(nprofile),
+        Err(e) => {
+            log::warn!("Failed to encode the nprofile of pubkey {}: {}", event.pubkey, e);
+            None
+        }
+    };
+    (nevent, author_nprofile)
+}
@@ -0,0 +1,88 @@
+use futures::future::BoxFuture;
+use serde::{Deserialize, Serialize};
+
+/// The kind of a device token, which decides the push provider notifications to it are sent through
+#[derive(Serialize, Deserialize, Debug, Clone, Copy, PartialEq, Eq, Hash)]
+#[serde(rename_all = "snake_case")]
+pub enum TokenType {
+    Apns,
+}
+
+impl TokenType {
+    pub fn parse(s: &str) -> Option<Self> {
+        match s {
+            "apns" => Some(TokenType::Apns),
+            _ => None,
+        }
+    }
+
+    pub fn as_str(&self) -> &'static str {
+        match self {
+            TokenType::Apns => "apns",
+        }
+    }
+}
+
+/// A transport that delivers pushes to devices (e.g. APNS), so that the notification pipeline does not depend on any of them
+pub trait PushProvider: Send + Sync {
+    /// A short name for logs
+    fn name(&self) -> &'static str;
+
+    /// Checks if the token has the shape this provider expects, before we accept it
+    fn validate_token(&self, token: &str) -> bool;
+
+    /// Checks if the provider is configured for this tenant (app)
+    fn has_tenant(&self, _tenant_id: &str) -> bool {
+        false
+    }
+
+    /// The size of the push as it would be sent, for checking it without sending
+    fn payload_size(&self, message: &PushMessage) -> Result<usize, Box<dyn std::error::Error>>;
+
+    /// Sends the push. Returns `None` if it was not attempted, e.g. because the provider is backing off
+    fn send<'a>(&'a self, message: &'a PushMessage) -> BoxFuture<'a, Option<PushReceipt>>;
+}
+
+/// A push to one device, independent of the provider that delivers it
+pub struct PushMessage {
+    pub token: String,
+    // The app the token belongs to, or `None` for the default one
+    pub tenant_id: Option<String>,
+    // `None` for silent pushes, which only wake the app to sync
+    pub alert: Option<PushAlert>,
+    // Lets the app's notification service extension rewrite the alert, and wakes the app to sync
+    pub is_processed_by_app: bool,
+    // `None` leaves the priority to the provider's default
+    pub priority: Option<PushPriority>,
+    // Pushes with the same collapse ID replace each other on the device
+    pub collapse_id: Option<String>,
+    pub data: Vec<(&'static str, serde_json::Value)>,
+}
+
+pub struct PushAlert {
+    pub title: String,
+    pub subtitle: Option<String>,
+    pub body: String,
+}
+
+#[derive(Debug, Clone, Copy, PartialEq)]
+pub enum PushPriority {
+    High,
+    // Lets the provider batch the push to save the device's power
+    Normal,
+}
+
+/// What the provider answered to a push
+pub struct PushReceipt {
+    // The ID the provider assigned to the push, if any
+    pub message_id: Option<String>,
+    // The status code of the provider's answer, if it answered
+    pub status: Option<u16>,
+    // Why the push failed, if it did
+    pub reason: Option<String>,
+    pub success: bool,
+    // The provider says the token will never be deliverable again, so the device should be pruned
+    pub is_token_unusable: bool,
+    // Where the push went (e.g. the APNS topic), recorded with the delivery
+    pub destination: String,
+}
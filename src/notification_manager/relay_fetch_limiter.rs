@@ -0,0 +1,172 @@
+use super::send_rate_limiter::SendRateLimiter;
+use std::collections::BTreeMap;
+use std::sync::atomic::{AtomicU64, AtomicUsize, Ordering};
+use std::sync::{Arc, Mutex};
+use tokio::sync::{OwnedSemaphorePermit, Semaphore};
+use tokio::time::{Duration, Instant};
+
+/// Limits the subscriptions opened against each relay, with a cap on concurrent subscriptions and a token bucket for their rate.
+/// Subscriptions above the limits queue up, so that a runaway fan-out does not get us throttled or banned by a relay.
+/// Opt-in: with neither limit configured, nothing is limited
+pub struct RelayFetchLimiter {
+    // Keyed by relay URL, sorted so that metrics are rendered in a stable order.
+    // Every relay gets its limits the first time it is used, e.g. the DM relays of users
+    relays: Mutex<BTreeMap<String, Arc<RelayLimits>>>,
+    max_concurrent_subscriptions: usize,
+    subscriptions_per_second: u32,
+    burst: u32,
+}
+
+struct RelayLimits {
+    rate_limiter: SendRateLimiter,
+    // Fetches waiting for a subscription slot right now
+    queued: AtomicUsize,
+    subscriptions: AtomicU64,
+    // Fetches that gave up waiting for a slot
+    queue_timeouts: AtomicU64,
+    queue_wait_seconds: Mutex<f64>,
+}
+
+/// Allows one subscription to a relay, which counts as open until this is dropped
+pub struct RelayFetchPermit {
+    _permit: Option<OwnedSemaphorePermit>,
+}
+
+// Keeps the queue depth right even if the waiting fetch is cancelled
+struct QueuedFetch<'a> {
+    queued: &'a AtomicUsize,
+}
+
+impl RelayFetchLimiter {
+    // MARK: - Initialization
+
+    /// Creates the limiter. A `max_concurrent_subscriptions` of 0 disables the concurrency cap, and a `subscriptions_per_second` of 0
+    /// disables rate limiting. With both disabled, nothing is limited
+    pub fn new(max_concurrent_subscriptions: usize, subscriptions_per_second: u32, burst: u32) -> Self {
+        RelayFetchLimiter {
+            relays: Mutex::new(BTreeMap::new()),
+            max_concurrent_subscriptions,
+            subscriptions_per_second,
+            burst,
+        }
+    }
+
+    fn is_enabled(&self) -> bool {
+        self.max_concurrent_subscriptions > 0 || self.subscriptions_per_second > 0
+    }
+
+    /// The limits of the relay, created on its first use
+    fn relay_limits(&self, relay_url: &str) -> Arc<RelayLimits> {
+        let mut relays = self.relays.lock().unwrap_or_else(|poisoned| poisoned.into_inner());
+        relays
+            .entry(relay_url.to_string())
+            .or_insert_with(|| {
+                let max_concurrent_subscriptions = match self.max_concurrent_subscriptions {
+                    0 => Semaphore::MAX_PERMITS,
+                    max_concurrent_subscriptions => max_concurrent_subscriptions,
+                };
+                Arc::new(RelayLimits {
+                    rate_limiter: SendRateLimiter::new(max_concurrent_subscriptions, self.subscriptions_per_second, self.burst),
+                    queued: AtomicUsize::new(0),
+                    subscriptions: AtomicU64::new(0),
+                    queue_timeouts: AtomicU64::new(0),
+                    queue_wait_seconds: Mutex::new(0.0),
+                })
+            })
+            .clone()
+    }
+
+    // MARK: - Acquiring
+
+    /// Waits until a subscription to the relay is allowed, or returns `None` if that takes longer than `max_wait`
+    pub async fn acquire(&self, relay_url: &str, max_wait: Duration) -> Option<RelayFetchPermit> {
+        if !self.is_enabled() {
+            return Some(RelayFetchPermit { _permit: None });
+        }
+        let relay_limits = self.relay_limits(relay_url);
+        let started_at = Instant::now();
+        let permit = {
+            let _queued_fetch = QueuedFetch::new(&relay_limits.queued);
+            tokio::time::timeout(max_wait, relay_limits.rate_limiter.acquire_owned()).await.ok()
+        };
+        *relay_limits.queue_wait_seconds.lock().unwrap_or_else(|poisoned| poisoned.into_inner()) += started_at.elapsed().as_secs_f64();
+        match permit {
+            Some(permit) => {
+                relay_limits.subscriptions.fetch_add(1, Ordering::SeqCst);
+                Some(RelayFetchPermit { _permit: Some(permit) })
+            }
+            None => {
+                relay_limits.queue_timeouts.fetch_add(1, Ordering::SeqCst);
+                log::warn!("Gave up waiting {:?} for a fetch subscription slot on relay {}", max_wait, relay_url);
+                None
+            }
+        }
+    }
+
+    // MARK: - Metrics
+
+    /// Renders the per-relay subscription gauges and counters in the Prometheus text exposition format
+    pub fn render_prometheus(&self) -> String {
+        let relays = self.relays.lock().unwrap_or_else(|poisoned| poisoned.into_inner()).clone();
+        let mut output = String::new();
+        output.push_str("# HELP notepush_relay_fetch_subscriptions_in_flight Fetch subscriptions currently open, by relay\n");
+        output.push_str("# TYPE notepush_relay_fetch_subscriptions_in_flight gauge\n");
+        for (relay_url, relay_limits) in &relays {
+            output.push_str(&format!(
+                "notepush_relay_fetch_subscriptions_in_flight{{relay=\"{}\"}} {}\n",
+                relay_url,
+                relay_limits.rate_limiter.in_flight()
+            ));
+        }
+        output.push_str("# HELP notepush_relay_fetch_queued Fetches waiting for a subscription slot, by relay\n");
+        output.push_str("# TYPE notepush_relay_fetch_queued gauge\n");
+        for (relay_url, relay_limits) in &relays {
+            output.push_str(&format!(
+                "notepush_relay_fetch_queued{{relay=\"{}\"}} {}\n",
+                relay_url,
+                relay_limits.queued.load(Ordering::SeqCst)
+            ));
+        }
+        output.push_str("# HELP notepush_relay_fetch_subscriptions_total Fetch subscriptions opened, by relay\n");
+        output.push_str("# TYPE notepush_relay_fetch_subscriptions_total counter\n");
+        for (relay_url, relay_limits) in &relays {
+            output.push_str(&format!(
+                "notepush_relay_fetch_subscriptions_total{{relay=\"{}\"}} {}\n",
+                relay_url,
+                relay_limits.subscriptions.load(Ordering::SeqCst)
+            ));
+        }
+        output.push_str("# HELP notepush_relay_fetch_queue_timeouts_total Fetches that gave up waiting for a subscription slot, by relay\n");
+        output.push_str("# TYPE notepush_relay_fetch_queue_timeouts_total counter\n");
+        for (relay_url, relay_limits) in &relays {
+            output.push_str(&format!(
+                "notepush_relay_fetch_queue_timeouts_total{{relay=\"{}\"}} {}\n",
+                relay_url,
+                relay_limits.queue_timeouts.load(Ordering::SeqCst)
+            ));
+        }
+        output.push_str("# HELP notepush_relay_fetch_queue_wait_seconds_total Time fetches spent waiting for a subscription slot, by relay\n");
+        output.push_str("# TYPE notepush_relay_fetch_queue_wait_seconds_total counter\n");
+        for (relay_url, relay_limits) in &relays {
+            output.push_str(&format!(
+                "notepush_relay_fetch_queue_wait_seconds_total{{relay=\"{}\"}} {}\n",
+                relay_url,
+                relay_limits.queue_wait_seconds.lock().unwrap_or_else(|poisoned| poisoned.into_inner())
+            ));
+        }
+        output
+    }
+}
+
+impl<'a> QueuedFetch<'a> {
+    fn new(queued: &'a AtomicUsize) -> Self {
+        queued.fetch_add(1, Ordering::SeqCst);
+        QueuedFetch { queued }
+    }
+}
+
+impl Drop for QueuedFetch<'_> {
+    fn drop(&mut self) {
+        self.queued.fetch_sub(1, Ordering::SeqCst);
+    }
+}
@@ -0,0 +1,97 @@
+use std::sync::Arc;
+use tokio::sync::{Mutex, OwnedSemaphorePermit, Semaphore, SemaphorePermit};
+use tokio::time::{Duration, Instant};
+
+/// Limits APNS sends globally, with a token bucket for the send rate and a cap on in-flight sends,
+/// so that bursts (e.g. a viral note with thousands of recipients) do not trip APNS throttling or starve other traffic.
+/// Also limits the fetch subscriptions to each relay
+pub struct SendRateLimiter {
+    in_flight_sends: Arc<Semaphore>,
+    max_in_flight_sends: usize,
+    // `None` means the send rate is not limited
+    token_bucket: Option<Mutex<TokenBucket>>,
+}
+
+struct TokenBucket {
+    tokens: f64,
+    // The bucket size, i.e. how many sends may burst at once
+    capacity: f64,
+    tokens_per_second: f64,
+    last_refill: Instant,
+}
+
+impl SendRateLimiter {
+    // MARK: - Initialization
+
+    /// Creates a limiter. A `sends_per_second` of 0 disables rate limiting, leaving only the in-flight cap
+    pub fn new(max_in_flight_sends: usize, sends_per_second: u32, burst: u32) -> Self {
+        let token_bucket = (sends_per_second > 0).then(|| {
+            let capacity = burst.max(1) as f64;
+            Mutex::new(TokenBucket {
+                tokens: capacity,
+                capacity,
+                tokens_per_second: sends_per_second as f64,
+                last_refill: Instant::now(),
+            })
+        });
+        SendRateLimiter {
+            in_flight_sends: Arc::new(Semaphore::new(max_in_flight_sends.max(1))),
+            max_in_flight_sends: max_in_flight_sends.max(1),
+            token_bucket,
+        }
+    }
+
+    // MARK: - Acquiring
+
+    /// Waits until a send is allowed. The send counts as in flight until the returned permit is dropped
+    pub async fn acquire(&self) -> SemaphorePermit<'_> {
+        self.take_token().await;
+        self.in_flight_sends
+            .acquire()
+            .await
+            .expect("The send semaphore is never closed")
+    }
+
+    /// Like `acquire`, but the permit does not borrow the limiter, so that it can outlive a lock on whatever holds it
+    pub async fn acquire_owned(&self) -> OwnedSemaphorePermit {
+        self.take_token().await;
+        self.in_flight_sends
+            .clone()
+            .acquire_owned()
+            .await
+            .expect("The send semaphore is never closed")
+    }
+
+    /// The number of sends currently holding a permit
+    pub fn in_flight(&self) -> usize {
+        self.max_in_flight_sends - self.in_flight_sends.available_permits()
+    }
+
+    async fn take_token(&self) {
+        let token_bucket = match &self.token_bucket {
+            Some(token_bucket) => token_bucket,
+            None => return,
+        };
+        loop {
+            let wait_time = {
+                let mut token_bucket = token_bucket.lock().await;
+                token_bucket.refill();
+                if token_bucket.tokens >= 1.0 {
+                    token_bucket.tokens -= 1.0;
+                    return;
+                }
+                Duration::from_secs_f64((1.0 - token_bucket.tokens) / token_bucket.tokens_per_second)
+            };   // Release the lock while waiting, so others can refill and take tokens
+            tokio::time::sleep(wait_time).await;
+        }
+    }
+}
+
+impl TokenBucket {
+    fn refill(&mut self) {
+        let now = Instant::now();
+        let elapsed = now.duration_since(self.last_refill).as_secs_f64();
+        self.tokens = (self.tokens + elapsed * self.tokens_per_second).min(self.capacity);
+        self.last_refill = now;
+    }
+}
@@ -0,0 +1,81 @@
+use nostr::Event;
+use regex::Regex;
+
+/// A server-wide rule that rejects blatant spam before any per-user work (relay fetches, APNS calls) is done
+#[derive(Debug, Clone)]
+pub enum SpamRule {
+    /// Rejects events whose content matches the pattern
+    ContentMatches(Regex),
+    /// Rejects events with less NIP-13 proof-of-work (leading zero bits of the event ID) than this
+    MinProofOfWork(u8),
+    /// Rejects events that mention more pubkeys (`p` tags) than this
+    MaxPubkeyTags(usize),
+}
+
+/// A chain of spam rules. An event is spam if any of the rules rejects it
+#[derive(Debug, Clone, Default)]
+pub struct SpamFilter {
+    rules: Vec<SpamRule>,
+}
+
+impl SpamFilter {
+    // MARK: - Initialization
+
+    pub fn new(rules: Vec<SpamRule>) -> Self {
+        SpamFilter { rules }
+    }
+
+    /// Builds the filter from the operator configuration. Rules that are not configured are left out
+    pub fn from_config(
+        content_denylist: &[String],
+        min_proof_of_work: Option<u8>,
+        max_pubkey_tags: Option<usize>,
+    ) -> Result<Self, regex::Error> {
+        let mut rules = Vec::new();
+        for pattern in content_denylist {
+            rules.push(SpamRule::ContentMatches(Regex::new(pattern)?));
+        }
+        if let Some(difficulty) = min_proof_of_work {
+            rules.push(SpamRule::MinProofOfWork(difficulty));
+        }
+        if let Some(max_pubkey_tags) = max_pubkey_tags {
+            rules.push(SpamRule::MaxPubkeyTags(max_pubkey_tags));
+        }
+        Ok(SpamFilter { rules })
+    }
+
+    /// Reads a denylist file with one regular expression per line. Empty lines and lines starting with `#` are ignored
+    pub fn read_content_denylist(path: &str) -> Result<Vec<String>, std::io::Error> {
+        let contents = std::fs::read_to_string(path)?;
+        Ok(contents
+            .lines()
+            .map(|line| line.trim())
+            .filter(|line| !line.is_empty() && !line.starts_with('#'))
+            .map(|line| line.to_string())
+            .collect())
+    }
+
+    // MARK: - Filtering
+
+    /// Returns the reason the event is rejected, or `None` if it passes all rules
+    pub fn rejection_reason(&self, event: &Event) -> Option<String> {
+        self.rules.iter().find_map(|rule| rule.rejection_reason(event))
+    }
+}
+
+impl SpamRule {
+    fn rejection_reason(&self, event: &Event) -> Option<String> {
+        match self {
+            SpamRule::ContentMatches(pattern) => pattern
+                .is_match(&event.content)
+                .then(|| format!("content matches denylisted pattern {}", pattern)),
+            SpamRule::MinProofOfWork(difficulty) => (!event.check_pow(*difficulty))
+                .then(|| format!("proof-of-work is below {} bits", difficulty)),
+            SpamRule::MaxPubkeyTags(max_pubkey_tags) => {
+                let pubkey_tag_count = event.public_keys().count();
+                (pubkey_tag_count > *max_pubkey_tags)
+                    .then(|| format!("mentions {} pubkeys, more than {}", pubkey_tag_count, max_pubkey_tags))
+            }
+        }
+    }
+}
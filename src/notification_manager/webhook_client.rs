@@ -0,0 +1,143 @@
+use base64::prelude::*;
+use nostr::bitcoin::hashes::hmac::{Hmac, HmacEngine};
+use nostr::bitcoin::hashes::sha256::Hash as Sha256Hash;
+use nostr::bitcoin::hashes::{Hash, HashEngine};
+use nostr::nips::nip98::{HttpData, HttpMethod};
+use nostr::util::hex;
+use nostr::{EventBuilder, JsonUtil, Keys, UncheckedUrl};
+use super::public_address;
+use serde::{Deserialize, Serialize};
+use serde_json::Value;
+use std::time::Duration;
+use thiserror::Error;
+
+const WEBHOOK_REQUEST_TIMEOUT: Duration = Duration::from_secs(10);
+
+/// A webhook registered by a device as an alternative to APNS (e.g. bots, bridges, self-hosted setups)
+#[derive(Serialize, Deserialize, Debug, Clone)]
+pub struct Webhook {
+    pub url: String,
+    pub secret: String,
+}
+
+impl Webhook {
+    /// Checks if the webhook can be used. We only allow HTTPS, since the payload can contain private information,
+    /// and only public hosts, so that webhooks cannot be used to reach this server's own network
+    pub async fn validate(&self) -> Result<(), String> {
+        public_address::resolve_public_host(&self.url, &["https"])
+            .await
+            .map_err(|e| format!("webhook url is not usable: {}", e))?;
+        if self.secret.is_empty() {
+            return Err("webhook secret must not be empty".to_string());
+        }
+        Ok(())
+    }
+}
+
+pub struct WebhookClient {
+    http_client: reqwest::Client,
+    // The server's nostr key, used to sign payloads so that receivers can tell they came from this instance without sharing a secret
+    signing_keys: Option<Keys>,
+}
+
+impl WebhookClient {
+    // MARK: - Initialization
+
+    pub fn new(signing_keys: Option<Keys>) -> Result<Self, reqwest::Error> {
+        // Host names are resolved again on every connection, since they may have been rebound to a private address after validation
+        let http_client = public_address::public_only_client_builder()
+            .timeout(WEBHOOK_REQUEST_TIMEOUT)
+            .build()?;
+        Ok(WebhookClient { http_client, signing_keys })
+    }
+
+    // MARK: - Sending
+
+    /// POSTs the payload as JSON to the webhook.
+    /// The request carries an HMAC-SHA256 signature of `<timestamp>.<body>` keyed with the webhook secret,
+    /// so that receivers can verify that it came from us and is not a replay.
+    /// If the server has a signing key, it also carries a NIP-98 `Authorization` header signed by it, covering the URL and body.
+    pub async fn send(&self, webhook: &Webhook, payload: &Value) -> Result<(), WebhookError> {
+        // IP address hosts are not resolved, so they are checked here
+        public_address::check_url(&webhook.url, &["https"]).map_err(WebhookError::Unusable)?;
+        let body = payload.to_string();
+        let timestamp = nostr::Timestamp::now().as_u64().to_string();
+        let signature = Self::sign(&webhook.secret, &timestamp, &body);
+
+        let mut request = self
+            .http_client
+            .post(&webhook.url)
+            .header("Content-Type", "application/json")
+            .header("X-Notepush-Timestamp", &timestamp)
+            .header("X-Notepush-Signature", format!("sha256={}", signature));
+        if let Some(signing_keys) = &self.signing_keys {
+            let auth_header = Self::nip98_auth_header(signing_keys, &webhook.url, &body).map_err(|e| WebhookError::Signing(e.to_string()))?;
+            request = request.header("Authorization", auth_header);
+        }
+        let response = request.body(body).send().await?;
+
+        if !response.status().is_success() {
+            return Err(WebhookError::Rejected(response.status()));
+        }
+        Ok(())
+    }
+
+    /// A NIP-98 auth note for the request, so that receivers can verify it with the server's pubkey
+    fn nip98_auth_header(signing_keys: &Keys, url: &str, body: &str) -> Result<String, Box<dyn std::error::Error>> {
+        let http_data = HttpData::new(UncheckedUrl::from(url), HttpMethod::POST)
+            .payload(Sha256Hash::hash(body.as_bytes()));
+        let auth_note = EventBuilder::http_auth(http_data).to_event(signing_keys)?;
+        Ok(format!("Nostr {}", BASE64_STANDARD.encode(auth_note.as_json())))
+    }
+
+    fn sign(secret: &str, timestamp: &str, body: &str) -> String {
+        let mut engine = HmacEngine::<Sha256Hash>::new(secret.as_bytes());
+        engine.input(timestamp.as_bytes());
+        engine.input(b".");
+        engine.input(body.as_bytes());
+        let hmac = Hmac::<Sha256Hash>::from_engine(engine);
+        hex::encode(hmac.to_byte_array())
+    }
+}
+
+/// Why a POST to a webhook failed
+#[derive(Debug, Error)]
+pub enum WebhookError {
+    #[error("Webhook responded with status {0}")]
+    Rejected(reqwest::StatusCode),
+    #[error("Webhook request failed: {0}")]
+    Request(#[from] reqwest::Error),
+    #[error("Webhook cannot be used: {0}")]
+    Unusable(String),
+    #[error("Failed to sign the webhook request: {0}")]
+    Signing(String),
+}
+
+impl WebhookError {
+    /// Network errors, throttling and server errors may go away, while other rejections (e.g. a bad payload or a gone endpoint) will not
+    pub fn is_retryable(&self) -> bool {
+        match self {
+            WebhookError::Rejected(status) => *status == reqwest::StatusCode::TOO_MANY_REQUESTS || status.is_server_error(),
+            WebhookError::Request(_) => true,
+            WebhookError::Unusable(_) | WebhookError::Signing(_) => false,
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn throttling_and_server_errors_are_retryable() {
+        assert!(WebhookError::Rejected(reqwest::StatusCode::TOO_MANY_REQUESTS).is_retryable());
+        assert!(WebhookError::Rejected(reqwest::StatusCode::BAD_GATEWAY).is_retryable());
+    }
+
+    #[test]
+    fn other_rejections_and_unusable_webhooks_are_not_retryable() {
+        assert!(!WebhookError::Rejected(reqwest::StatusCode::GONE).is_retryable());
+        assert!(!WebhookError::Rejected(reqwest::StatusCode::BAD_REQUEST).is_retryable());
+        assert!(!WebhookError::Unusable("not a valid URL".to_string()).is_retryable());
+    }
+}
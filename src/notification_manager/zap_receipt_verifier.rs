@@ -0,0 +1,155 @@
+use super::nostr_network_helper::NostrNetworkHelper;
+use super::public_address;
+use super::ExtendedEvent;
+use nostr::bitcoin::bech32;
+use nostr::{Event, Kind, PublicKey, Timestamp};
+use serde::Deserialize;
+use std::collections::HashMap;
+use tokio::sync::Mutex;
+use tokio::time::Duration;
+use crate::utils::time_delta::TimeDelta;
+
+const LNURL_REQUEST_TIMEOUT: Duration = Duration::from_secs(5);
+
+/// Verifies that zap receipts were issued by the recipient's lightning provider (NIP-57, Appendix F),
+/// so that fake receipts do not cause "Someone zapped you" notifications.
+pub struct ZapReceiptVerifier {
+    http_client: reqwest::Client,
+    // The pubkey allowed to issue zap receipts for each recipient, as announced by their LNURL pay endpoint.
+    // `None` means the recipient cannot receive nostr zaps as far as we know.
+    allowed_zapper_cache: Mutex<HashMap<PublicKey, (Option<PublicKey>, Timestamp)>>,
+    cache_max_age: Duration,
+}
+
+impl ZapReceiptVerifier {
+    // MARK: - Initialization
+
+    pub fn new(cache_max_age: Duration) -> Result<Self, reqwest::Error> {
+        // The LNURL pay endpoint comes from untrusted profile metadata, so it must not reach our own network
+        let http_client = public_address::public_only_client_builder()
+            .timeout(LNURL_REQUEST_TIMEOUT)
+            .build()?;
+        Ok(ZapReceiptVerifier {
+            http_client,
+            allowed_zapper_cache: Mutex::new(HashMap::new()),
+            cache_max_age,
+        })
+    }
+
+    // MARK: - Verification
+
+    /// Checks if the zap receipt was signed by the `nostrPubkey` of the recipient's LNURL pay endpoint
+    pub async fn is_zap_receipt_valid(&self, zap_receipt: &Event, nostr_network_helper: &NostrNetworkHelper) -> bool {
+        if zap_receipt.kind != Kind::ZapReceipt {
+            return false;
+        }
+        let recipient = match zap_receipt.zap_recipient() {
+            Some(recipient) => recipient,
+            None => return false,
+        };
+        match self.get_allowed_zapper_pubkey(&recipient, nostr_network_helper).await {
+            Some(allowed_zapper_pubkey) => allowed_zapper_pubkey == zap_receipt.pubkey,
+            None => {
+                log::debug!("Could not find the allowed zapper pubkey of {}, ignoring zap receipt {}", recipient, zap_receipt.id);
+                false
+            }
+        }
+    }
+
+    /// Checks the zap receipt against the cache only, without fetching anything. `None` means it cannot be told without fetching
+    pub async fn cached_zap_receipt_validity(&self, zap_receipt: &Event) -> Option<bool> {
+        if zap_receipt.kind != Kind::ZapReceipt {
+            return Some(false);
+        }
+        let recipient = zap_receipt.zap_recipient()?;
+        let allowed_zapper_pubkey = self.get_cached_allowed_zapper_pubkey(&recipient).await?;
+        Some(allowed_zapper_pubkey == Some(zap_receipt.pubkey))
+    }
+
+    async fn get_allowed_zapper_pubkey(&self, recipient: &PublicKey, nostr_network_helper: &NostrNetworkHelper) -> Option<PublicKey> {
+        if let Some(allowed_zapper_pubkey) = self.get_cached_allowed_zapper_pubkey(recipient).await {
+            return allowed_zapper_pubkey;
+        }
+        match self.fetch_allowed_zapper_pubkey(recipient, nostr_network_helper).await {
+            Ok(allowed_zapper_pubkey) => {
+                let mut cache_mutex_guard = self.allowed_zapper_cache.lock().await;
+                cache_mutex_guard.insert(recipient.clone(), (allowed_zapper_pubkey, Timestamp::now()));
+                allowed_zapper_pubkey
+            }
+            // Transient failures are not cached, so that a flaky LNURL server does not block the recipient's zaps until the cache expires
+            Err(e) => {
+                log::warn!("Failed to look up the allowed zapper pubkey of {}: {}", recipient, e);
+                None
+            }
+        }
+    }
+
+    /// The cached allowed zapper pubkey of the recipient, or `None` if nothing fresh is cached
+    async fn get_cached_allowed_zapper_pubkey(&self, recipient: &PublicKey) -> Option<Option<PublicKey>> {
+        let cache_mutex_guard = self.allowed_zapper_cache.lock().await;
+        let (allowed_zapper_pubkey, added_at) = cache_mutex_guard.get(recipient)?;
+        let age = TimeDelta::subtracting(Timestamp::now(), *added_at);
+        (!age.negative && age.delta_abs_seconds <= self.cache_max_age.as_secs()).then_some(*allowed_zapper_pubkey)
+    }
+
+    /// Looks up the allowed zapper pubkey. `Ok(None)` is a definitive answer (e.g. the recipient has no usable lightning address),
+    /// while errors are transient (e.g. the profile or the LNURL pay endpoint could not be fetched)
+    async fn fetch_allowed_zapper_pubkey(
+        &self,
+        recipient: &PublicKey,
+        nostr_network_helper: &NostrNetworkHelper,
+    ) -> Result<Option<PublicKey>, Box<dyn std::error::Error>> {
+        let metadata = nostr_network_helper
+            .get_metadata(recipient)
+            .await
+            .ok_or("the profile could not be found")?;
+        let lnurl_pay_url = match (metadata.lud16, metadata.lud06) {
+            (Some(lud16), _) => Self::lud16_to_url(&lud16),
+            (None, Some(lud06)) => Self::lud06_to_url(&lud06),
+            (None, None) => return Ok(None),
+        };
+        let lnurl_pay_url = match lnurl_pay_url.map(|url| public_address::check_url(&url, &["https"])) {
+            Some(Ok(url)) => url,
+            Some(Err(e)) => {
+                log::debug!("Not fetching the LNURL pay endpoint of {}: {}", recipient, e);
+                return Ok(None);
+            }
+            None => return Ok(None),
+        };
+
+        let response = self.http_client.get(lnurl_pay_url.as_str()).send().await?;
+        if !response.status().is_success() {
+            return Err(format!("LNURL pay endpoint {} responded with status {}", lnurl_pay_url, response.status()).into());
+        }
+        let response: LnurlPayResponse = response.json().await?;
+        if response.allows_nostr != Some(true) {
+            return Ok(None);
+        }
+        Ok(response.nostr_pubkey.and_then(|nostr_pubkey| PublicKey::from_hex(nostr_pubkey).ok()))
+    }
+
+    // MARK: - LNURL helpers
+
+    /// Converts a lightning address (`name@domain`) into its LNURL pay endpoint (LUD-16)
+    fn lud16_to_url(lud16: &str) -> Option<String> {
+        let (name, domain) = lud16.trim().split_once('@')?;
+        if name.is_empty() || domain.is_empty() {
+            return None;
+        }
+        Some(format!("https://{}/.well-known/lnurlp/{}", domain, name))
+    }
+
+    /// Decodes a bech32 encoded LNURL (LUD-06) into its URL
+    fn lud06_to_url(lud06: &str) -> Option<String> {
+        let (_, data) = bech32::decode(lud06.trim()).ok()?;
+        String::from_utf8(data).ok()
+    }
+}
+
+#[derive(Deserialize)]
+struct LnurlPayResponse {
+    #[serde(rename = "allowsNostr")]
+    allows_nostr: Option<bool>,
+    #[serde(rename = "nostrPubkey")]
+    nostr_pubkey: Option<String>,
+}
@@ -1,3 +1,5 @@
+use crate::event_rate_limiter::{EventRateLimiter, TokenBucket};
+use crate::ingestion_queue::{EnqueueError, IngestionQueue};
 use crate::notification_manager::NotificationManager;
 use futures::sink::SinkExt;
 use futures::StreamExt;
@@ -6,7 +8,7 @@ use hyper_tungstenite::{HyperWebsocket, WebSocketStream};
 use hyper_util::rt::TokioIo;
 use log;
 use nostr::util::JsonUtil;
-use nostr::{ClientMessage, RelayMessage};
+use nostr::{ClientMessage, Event, EventId, Filter, Kind, PublicKey, RelayMessage, TagKind, Timestamp};
 use serde_json::Value;
 use std::fmt::{self, Debug};
 use std::str::FromStr;
@@ -14,9 +16,69 @@ use std::sync::Arc;
 use tungstenite::{Error, Message};
 
 const MAX_CONSECUTIVE_ERRORS: u32 = 10;
+// How far the `created_at` of a NIP-42 auth event may be from the current time
+const AUTH_EVENT_MAX_AGE_SECONDS: u64 = 10 * 60;
+// The rate of NOTICE messages sent to a connection, and how many may burst above it. Excess notices are dropped,
+// so that a misbehaving client cannot make us spend more on answering it than it spends on sending
+const NOTICES_PER_SECOND: u32 = 1;
+const NOTICE_BURST: u32 = 5;
+
+/// The NIP-01 machine-readable prefixes of `OK`, `CLOSED` and `NOTICE` messages, so that client libraries can react programmatically
+#[derive(Debug, Clone, Copy, PartialEq)]
+enum MachineReadablePrefix {
+    RateLimited,
+    Invalid,
+    Blocked,
+    AuthRequired,
+}
+
+impl MachineReadablePrefix {
+    fn as_str(&self) -> &'static str {
+        match self {
+            MachineReadablePrefix::RateLimited => "rate-limited",
+            MachineReadablePrefix::Invalid => "invalid",
+            MachineReadablePrefix::Blocked => "blocked",
+            MachineReadablePrefix::AuthRequired => "auth-required",
+        }
+    }
+
+    /// Prefixes a human-readable reason, e.g. `invalid: too large`
+    fn message(&self, reason: impl fmt::Display) -> String {
+        format!("{}: {}", self.as_str(), reason)
+    }
+}
+
+/// How the embedded relay responds to published events
+#[derive(Debug, Clone)]
+pub struct RelayPolicy {
+    // Whether events are answered with `OK true`. Some client libraries retry forever on `OK false`
+    pub ok_accepts: bool,
+    pub ok_message: String,
+    // The event kinds that are accepted. Empty means all kinds
+    pub accepted_kinds: std::collections::HashSet<Kind>,
+    // The maximum size in bytes of a client message, and the maximum number of tags of a published event.
+    // Checked before the event is deserialized, so that pathological events never reach the pipeline
+    pub max_event_size: usize,
+    pub max_event_tags: usize,
+}
+
+impl RelayPolicy {
+    fn accepts_kind(&self, kind: Kind) -> bool {
+        self.accepted_kinds.is_empty() || self.accepted_kinds.contains(&kind)
+    }
+}
 
 pub struct RelayConnection {
     notification_manager: Arc<NotificationManager>,
+    ingestion_queue: Arc<IngestionQueue>,
+    relay_policy: RelayPolicy,
+    event_rate_limiter: Arc<EventRateLimiter>,
+    // The rate limit of this connection's messages. `None` means it is not limited
+    connection_bucket: Option<TokenBucket>,
+    notice_bucket: TokenBucket,
+    // The NIP-42 challenge sent to the client when the connection is opened
+    auth_challenge: String,
+    authenticated_pubkey: Option<PublicKey>,
 }
 
 impl RelayConnection {
@@ -24,18 +86,31 @@ impl RelayConnection {
 
     pub async fn new(
         notification_manager: Arc<NotificationManager>,
+        ingestion_queue: Arc<IngestionQueue>,
+        relay_policy: RelayPolicy,
+        event_rate_limiter: Arc<EventRateLimiter>,
     ) -> Result<Self, Box<dyn std::error::Error>> {
         log::info!("Accepted websocket connection");
         Ok(RelayConnection {
             notification_manager,
+            ingestion_queue,
+            relay_policy,
+            connection_bucket: event_rate_limiter.connection_bucket(),
+            notice_bucket: TokenBucket::new(NOTICES_PER_SECOND, NOTICE_BURST),
+            event_rate_limiter,
+            auth_challenge: uuid::Uuid::new_v4().to_string(),
+            authenticated_pubkey: None,
         })
     }
 
     pub async fn run(
         websocket: HyperWebsocket,
         notification_manager: Arc<NotificationManager>,
+        ingestion_queue: Arc<IngestionQueue>,
+        relay_policy: RelayPolicy,
+        event_rate_limiter: Arc<EventRateLimiter>,
     ) -> Result<(), Box<dyn std::error::Error>> {
-        let mut connection = RelayConnection::new(notification_manager).await?;
+        let mut connection = RelayConnection::new(notification_manager, ingestion_queue, relay_policy, event_rate_limiter).await?;
         Ok(connection.run_loop(websocket).await?)
     }
 
@@ -48,6 +123,9 @@ impl RelayConnection {
         let mut consecutive_errors = 0;
         log::debug!("Starting run loop for connection with {:?}", websocket);
         let mut websocket_stream = websocket.await?;
+        // Offer NIP-42 authentication right away, so that clients can use the features that require it (e.g. COUNT)
+        let auth_message = RelayMessage::Auth { challenge: self.auth_challenge.clone() };
+        websocket_stream.send(tungstenite::Message::text(auth_message.try_as_json()?)).await?;
         while let Some(raw_message) = websocket_stream.next().await {
             match self
                 .run_loop_iteration_if_raw_message_is_ok(raw_message, &mut websocket_stream)
@@ -84,41 +162,136 @@ impl RelayConnection {
         stream: &mut WebSocketStream<TokioIo<Upgraded>>,
     ) -> Result<(), Box<dyn std::error::Error>> {
         if raw_message.is_text() {
-            let message: ClientMessage =
-                ClientMessage::from_value(Value::from_str(raw_message.to_text()?)?)?;
+            let text = raw_message.to_text()?;
+            // Early return if the message is too large to be parsed
+            if text.len() > self.relay_policy.max_event_size {
+                log::warn!("Rejecting client message of {} bytes, above the limit of {}", text.len(), self.relay_policy.max_event_size);
+                let response = Self::too_large_response(Self::event_id_hint(text));
+                return self.send_response(stream, response).await;
+            }
+            // Early return if the message is not JSON. The error still counts towards closing the connection
+            let value = match Value::from_str(text) {
+                Ok(value) => value,
+                Err(e) => {
+                    let notice = RelayMessage::Notice { message: MachineReadablePrefix::Invalid.message("message is not valid JSON") };
+                    self.send_response(stream, notice).await?;
+                    return Err(e.into());
+                }
+            };
+            // Early return if the event has too many tags to be deserialized
+            if let Some(tag_count) = Self::event_tag_count(&value).filter(|count| *count > self.relay_policy.max_event_tags) {
+                log::warn!("Rejecting event with {} tags, above the limit of {}", tag_count, self.relay_policy.max_event_tags);
+                let response = Self::too_large_response(Self::event_id(&value));
+                return self.send_response(stream, response).await;
+            }
+            // Early return if the connection or its authenticated pubkey is sending too fast
+            if let Some(response) = self.rate_limited_response(&value).await {
+                return self.send_response(stream, response).await;
+            }
+            // Early return if the message is not a well-formed client message. The error still counts towards closing the connection
+            let message: ClientMessage = match ClientMessage::from_value(value) {
+                Ok(message) => message,
+                Err(e) => {
+                    let notice = RelayMessage::Notice { message: MachineReadablePrefix::Invalid.message(&e) };
+                    self.send_response(stream, notice).await?;
+                    return Err(e.into());
+                }
+            };
             let response = self.handle_client_message(message).await?;
-            stream
-                .send(tungstenite::Message::text(response.try_as_json()?))
-                .await?;
+            self.send_response(stream, response).await?;
+        }
+        Ok(())
+    }
+
+    /// Sends a response to the client. Notices above the notice rate limit are dropped
+    async fn send_response(
+        &mut self,
+        stream: &mut WebSocketStream<TokioIo<Upgraded>>,
+        response: RelayMessage,
+    ) -> Result<(), Box<dyn std::error::Error>> {
+        if matches!(response, RelayMessage::Notice { .. }) && !self.notice_bucket.try_take() {
+            log::debug!("Dropping notice above the notice rate limit: {:?}", response);
+            return Ok(());
         }
+        stream.send(tungstenite::Message::text(response.try_as_json()?)).await?;
         Ok(())
     }
 
     // MARK: - Message handling
 
     async fn handle_client_message(
-        &self,
+        &mut self,
         message: ClientMessage,
     ) -> Result<RelayMessage, Box<dyn std::error::Error>> {
         match message {
             ClientMessage::Event(event) => {
                 log::info!("Received event with id: {:?}", event.id.to_hex());
                 log::debug!("Event received: {:?}", event);
-                self.notification_manager.send_notifications_if_needed(&event).await?;
-                let notice_message = format!("blocked: This relay does not store events");
+                if !self.relay_policy.accepts_kind(event.kind) {
+                    return Ok(RelayMessage::Ok {
+                        event_id: event.id,
+                        status: false,
+                        message: MachineReadablePrefix::Blocked.message(format!("event kind {} is not accepted", event.kind.as_u16())),
+                    });
+                }
+                let event_id = event.id;
+                match self.ingestion_queue.try_enqueue(*event) {
+                    Ok(()) => {}
+                    Err(EnqueueError::Backpressure) => {
+                        log::warn!("Ingestion queue is above its high-water mark, rejecting event {}", event_id.to_hex());
+                        return Ok(RelayMessage::Ok {
+                            event_id,
+                            status: false,
+                            message: MachineReadablePrefix::RateLimited.message("too many events are waiting to be processed, try again later"),
+                        });
+                    }
+                    Err(EnqueueError::Closed) => return Err("Ingestion queue is closed".into()),
+                }
                 let response = RelayMessage::Ok {
-                    event_id: event.id,
-                    status: false,
-                    message: notice_message,
+                    event_id,
+                    status: self.relay_policy.ok_accepts,
+                    message: self.relay_policy.ok_message.clone(),
+                };
+                Ok(response)
+            }
+            ClientMessage::Auth(event) => {
+                let response = match self.verify_auth_event(&event) {
+                    Ok(()) => {
+                        log::info!("Websocket connection authenticated as {}", event.pubkey);
+                        self.authenticated_pubkey = Some(event.pubkey);
+                        RelayMessage::Ok { event_id: event.id, status: true, message: "".to_string() }
+                    }
+                    Err(reason) => RelayMessage::Ok {
+                        event_id: event.id,
+                        status: false,
+                        message: MachineReadablePrefix::AuthRequired.message(reason),
+                    },
                 };
                 Ok(response)
             }
+            ClientMessage::Count { subscription_id, filters } => {
+                let pubkey = match self.authenticated_pubkey {
+                    Some(pubkey) => pubkey,
+                    None => return Ok(RelayMessage::Closed {
+                        subscription_id,
+                        message: MachineReadablePrefix::AuthRequired.message("counting notifications requires NIP-42 authentication"),
+                    }),
+                };
+                let count = self.notification_manager.count_notifications(&pubkey, &Self::time_ranges(&filters)).await?;
+                Ok(RelayMessage::Count { subscription_id, count })
+            }
+            ClientMessage::Req { subscription_id, .. } => {
+                log::info!("Received unsupported REQ for subscription {}", subscription_id);
+                Ok(RelayMessage::Closed {
+                    subscription_id,
+                    message: MachineReadablePrefix::Blocked.message("this relay does not serve subscriptions, it only answers EVENT, AUTH and COUNT"),
+                })
+            }
             _ => {
                 log::info!("Received unsupported Nostr client message");
                 log::debug!("Unsupported Nostr client message: {:?}", message);
-                let notice_message = format!("Unsupported message.");
                 let response = RelayMessage::Notice {
-                    message: notice_message,
+                    message: MachineReadablePrefix::Blocked.message("unsupported message, this relay only answers EVENT, AUTH and COUNT"),
                 };
                 Ok(response)
             }
@@ -126,6 +299,92 @@ impl RelayConnection {
     }
 }
 
+// MARK: - Authentication and counting helpers
+
+impl RelayConnection {
+    /// Checks that a NIP-42 auth event is validly signed, recent, and answers this connection's challenge
+    fn verify_auth_event(&self, event: &Event) -> Result<(), String> {
+        if event.kind != Kind::Authentication {
+            return Err("auth event must be of kind 22242".to_string());
+        }
+        event.verify().map_err(|_| "invalid auth event signature".to_string())?;
+        let now = Timestamp::now().as_u64();
+        if now.abs_diff(event.created_at.as_u64()) > AUTH_EVENT_MAX_AGE_SECONDS {
+            return Err("auth event is too old or in the future".to_string());
+        }
+        if event.get_tag_content(TagKind::Challenge) != Some(self.auth_challenge.as_str()) {
+            return Err("auth event does not answer the challenge".to_string());
+        }
+        Ok(())
+    }
+
+    /// The `since`/`until` ranges of the COUNT filters. Notifications are always scoped to the authenticated pubkey,
+    /// so other filter fields are ignored
+    fn time_ranges(filters: &[Filter]) -> Vec<(Option<Timestamp>, Option<Timestamp>)> {
+        filters.iter().map(|filter| (filter.since, filter.until)).collect()
+    }
+}
+
+// MARK: - Inbound size validation and rate limiting helpers
+
+impl RelayConnection {
+    /// Takes a token for the message from the connection's bucket and, for events of an authenticated connection,
+    /// from the pubkey's bucket. Returns the response to send if the message is rate limited
+    async fn rate_limited_response(&mut self, value: &Value) -> Option<RelayMessage> {
+        let is_event = value.get(0).and_then(Value::as_str) == Some("EVENT");
+        let connection_allowed = self.connection_bucket.as_mut().map_or(true, |bucket| bucket.try_take());
+        let reason = if !connection_allowed {
+            "this connection is sending messages too fast"
+        } else {
+            match self.authenticated_pubkey {
+                Some(pubkey) if is_event && !self.event_rate_limiter.allow_pubkey_event(&pubkey).await => {
+                    "this pubkey is publishing events too fast"
+                }
+                _ => return None,
+            }
+        };
+        log::warn!("Rate limiting websocket message: {}", reason);
+        if !is_event {
+            return Some(RelayMessage::Notice { message: MachineReadablePrefix::RateLimited.message(reason) });
+        }
+        Some(RelayMessage::Ok {
+            event_id: Self::event_id(value).unwrap_or(EventId::all_zeros()),
+            status: false,
+            message: MachineReadablePrefix::RateLimited.message(reason),
+        })
+    }
+
+    fn too_large_response(event_id: Option<EventId>) -> RelayMessage {
+        RelayMessage::Ok {
+            event_id: event_id.unwrap_or(EventId::all_zeros()),
+            status: false,
+            message: MachineReadablePrefix::Invalid.message("too large"),
+        }
+    }
+
+    /// The number of tags of an `EVENT` message, without deserializing the event itself
+    fn event_tag_count(value: &Value) -> Option<usize> {
+        if value.get(0)?.as_str()? != "EVENT" {
+            return None;
+        }
+        value.get(1)?.get("tags")?.as_array().map(|tags| tags.len())
+    }
+
+    /// The id of an `EVENT` message, without deserializing the event itself
+    fn event_id(value: &Value) -> Option<EventId> {
+        EventId::from_hex(value.get(1)?.get("id")?.as_str()?).ok()
+    }
+
+    /// Finds the event id in a message that is too large to be parsed, by looking for the first `"id"` key
+    /// followed by a hex id. Quotes inside strings are escaped, so content cannot be mistaken for the key
+    fn event_id_hint(text: &str) -> Option<EventId> {
+        text.match_indices("\"id\"").find_map(|(index, key)| {
+            let rest = text[index + key.len()..].trim_start().strip_prefix(':')?.trim_start().strip_prefix('"')?;
+            EventId::from_hex(rest.get(..64)?).ok()
+        })
+    }
+}
+
 impl Debug for RelayConnection {
     fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
         write!(f, "RelayConnection with websocket")